@@ -8,5 +8,5 @@ del /q .\bin\*.*
 cargo build
 del .\bin\mpl.exe 
 copy .\target\debug\mpl.exe .\bin\mpl.exe
-.\bin\mpl.exe -c .\examples\hello.mpl -o .\bin\app.wasm -a .\bin\app.wat
-.\bin\mpl.exe -r .\examples\hello.mpl
+.\bin\mpl.exe compile .\examples\hello.mpl -o .\bin\app.wasm -a .\bin\app.wat
+.\bin\mpl.exe run .\examples\hello.mpl
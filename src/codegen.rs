@@ -1,18 +1,43 @@
 use crate::{
+    diagnostic::did_you_mean,
+    grammar::{self, MathFn},
+    ir::{self, Num, NumKind, NumTy},
     lexer::Position,
+    messages,
+    meta::{self, BuildInfo, META_SECTION, POS_GLOBAL, SOURCE_SECTION},
+    optimize::{self, OptLevel},
+    peephole,
+    runtime::{self, BuilderRuntime, MapRuntime, MemoryOps, Runtime, RuntimeFn},
+    sourcemap::{self, FunctionLines},
+    visit::{Visitor, walk_expr, walk_num_expr, walk_str_expr, walk_stadment},
     parser::{
-        BinOp, Expr, Function as ParserFunction, NumExpr, ParseError, Program, Stadment, StrExpr,
-        Variable,
+        BinOp, Expr, FnExpr, Function as ParserFunction, MatchArm, NumExpr, ParseError, Program,
+        Stadment, StrExpr, Variable,
     },
 };
 
+use rayon::prelude::*;
+use serde::Serialize;
 use wasm_encoder::{
-    BlockType, CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection,
-    FunctionSection, GlobalSection, GlobalType, ImportSection, IndirectNameMap, Instruction,
-    MemoryType, Module, NameMap, NameSection, TypeSection, ValType,
+    BlockType, CodeSection, ConstExpr, CustomSection, DataCountSection, DataSection, ElementSection, Elements, EntityType,
+    ExportKind, ExportSection, Function, FunctionSection, GlobalSection, GlobalType, ImportSection, IndirectNameMap,
+    InstructionSink, MemoryType, Module, NameMap, NameSection, RefType, TableSection, TableType,
+    TypeSection, ValType,
 };
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Index of the 'mpl.pos' global, after heap_ptr, data_end and free_list
+const POS_GLOBAL_IDX: u32 = 3;
+
+// A match becomes a br_table when it has at least BR_TABLE_MIN_CASES values and they are dense:
+// the table (one entry per int from the smallest value to the largest) is at most
+// BR_TABLE_MAX_SPREAD times as long as the list of values. Otherwise: a chain of comparisons.
+const BR_TABLE_MIN_CASES: usize = 3;
+const BR_TABLE_MAX_SPREAD: usize = 2;
 
 #[derive(Clone, Copy)]
 struct Blob {
@@ -20,11 +45,102 @@ struct Blob {
     len: u32,
 }
 
-struct FunctionContext {
-    fn_id: u32,
-    param_count: u32,
-    function: &ParserFunction,
-    instr: &mut wasm_encoder::InstructionSink<'_>,
+/// What an instrumentation hook can see about the function being generated.
+pub struct HookContext<'a> {
+    pub function: &'a ParserFunction, // function whose body is being emitted
+    pub fn_id: u32,                   // wasm function index of `function`
+    fn_map: &'a HashMap<String, i32>,
+}
+
+impl HookContext<'_> {
+    /// Wasm function index of a declared function or import, to be used with `call`.
+    /// Imports (e.g. those registered with `CodegenHooks::import`) are named "module.name".
+    pub fn func_index(&self, name: &str) -> Option<u32> {
+        self.fn_map.get(name).map(|&i| i as u32)
+    }
+}
+
+pub type FunctionEnterHook = Box<dyn FnMut(&HookContext<'_>, &mut InstructionSink<'_>)>;
+pub type StatementHook = Box<dyn FnMut(&HookContext<'_>, &Stadment, &mut InstructionSink<'_>)>;
+
+// Extra host function imported for the hooks, declared after the built-in imports.
+struct HostImport {
+    module: String,
+    name: String,
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+}
+
+/// Codegen callbacks injecting extra instructions at well-defined points:
+/// - `on_function_enter`: at the start of every generated function body,
+/// - `on_function_exit`: before each `return` and the end of the body (after the value returned),
+/// - `on_statement`: right before each statement (nested ones included),
+/// - `on_statement_end`: right after each statement (after the body of a loop or a match).
+///
+/// Whatever a hook emits must leave the wasm stack unchanged.
+#[derive(Default)]
+pub struct CodegenHooks {
+    pub on_function_enter: Option<FunctionEnterHook>,
+    pub on_function_exit: Option<FunctionEnterHook>,
+    pub on_statement: Option<StatementHook>,
+    pub on_statement_end: Option<StatementHook>,
+    imports: Vec<HostImport>,
+}
+
+impl CodegenHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a host import the hooks can call; its index is found with
+    /// `HookContext::func_index("module.name")`. The host must provide it at instantiation.
+    pub fn import(
+        mut self,
+        module: &str,
+        name: &str,
+        params: &[ValType],
+        results: &[ValType],
+    ) -> Self {
+        self.imports.push(HostImport {
+            module: module.to_string(),
+            name: name.to_string(),
+            params: params.to_vec(),
+            results: results.to_vec(),
+        });
+        self
+    }
+
+    pub fn on_function_enter(
+        mut self,
+        hook: impl FnMut(&HookContext<'_>, &mut InstructionSink<'_>) + 'static,
+    ) -> Self {
+        self.on_function_enter = Some(Box::new(hook));
+        self
+    }
+
+    pub fn on_function_exit(
+        mut self,
+        hook: impl FnMut(&HookContext<'_>, &mut InstructionSink<'_>) + 'static,
+    ) -> Self {
+        self.on_function_exit = Some(Box::new(hook));
+        self
+    }
+
+    pub fn on_statement(
+        mut self,
+        hook: impl FnMut(&HookContext<'_>, &Stadment, &mut InstructionSink<'_>) + 'static,
+    ) -> Self {
+        self.on_statement = Some(Box::new(hook));
+        self
+    }
+
+    pub fn on_statement_end(
+        mut self,
+        hook: impl FnMut(&HookContext<'_>, &Stadment, &mut InstructionSink<'_>) + 'static,
+    ) -> Self {
+        self.on_statement_end = Some(Box::new(hook));
+        self
+    }
 }
 
 #[inline]
@@ -37,94 +153,627 @@ fn align_up(x: u32, align: u32) -> u32 {
     (x + a) & !a
 }
 
-/// Insert `text` into the DataSection if needed, returning its (ptr,len).
-/// - De-duplicates via `interner` (text -> Blob).
-/// - Respects `align`. If an existing entry for the same text does not meet the requested
-///   alignment (`ptr % align != 0`), a new copy is emitted at a properly aligned address.
-fn push_text(
-    data: &mut DataSection,
-    mem_index: u32,
-    cursor: &mut u32,
-    text: &str,
-    align: u32,
-    interner: &mut HashMap<String, Blob>,
-) -> Blob {
-    // If we already have this text and it satisfies the requested alignment, reuse it.
-    if let Some(&blob) = interner.get(text) {
-        if align <= 1 || blob.ptr % align == 0 {
-            return blob;
-        }
-        // Otherwise, fall through and allocate a new, stricter-aligned copy.
-    }
-
-    // Allocate a new slice with proper alignment.
-    *cursor = align_up(*cursor, align.max(1));
-    let ptr = *cursor;
-    let bytes = text.as_bytes();
-
-    // Emit active data segment at `ptr`.
-    data.active(
-        mem_index,
-        &ConstExpr::i32_const(ptr as i32),
-        bytes.iter().copied(),
-    );
-
-    // Advance the cursor.
-    *cursor += bytes.len() as u32;
-
-    let blob = Blob {
-        ptr,
-        len: bytes.len() as u32,
-    };
+/// The constant strings of the module and where they are in memory.
+/// Every string is requested before any code is generated (`request`), then `plan` lays them
+/// all out in one pass, longest texts first, each aligned for its strictest request: a text
+/// ending a longer one ("\n" and "text\n") points into it when that address meets its
+/// alignment. The result is one image of the data, written by a single active segment.
+/// With --passive-data, the texts of PASSIVE_DATA_MIN bytes or more come after it, unshared,
+/// each in a passive segment written the first time the text is used (see gen_data_init).
+#[derive(Default)]
+struct DataLayout {
+    requests: BTreeMap<String, u32>, // text -> alignment
+    blobs: HashMap<String, Blob>,
+    placed: Vec<Blob>, // in placement order, for suffix sharing
+    image: Vec<u8>,    // the constant data from address 0 (alignment gaps are zeros)
+    passive_min: Option<u32>, // texts at least this long get a passive segment
+    active_end: u32,          // the active segment is image[..active_end]
+    passive: Vec<Blob>,       // the texts of the passive segments, in segment order
+}
+
+impl DataLayout {
+    fn request(&mut self, text: &str, align: u32) {
+        let strictest = self.requests.entry(text.to_owned()).or_insert(1);
+        *strictest = (*strictest).max(align);
+    }
+
+    // Place the requested texts, longest first, equal lengths in text order, those of the
+    // passive segments last
+    fn plan(&mut self) {
+        let mut texts: Vec<(String, u32)> = std::mem::take(&mut self.requests).into_iter().collect();
+        texts.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        let (passive, active): (Vec<_>, Vec<_>) = texts
+            .into_iter()
+            .partition(|(text, _)| self.passive_min.is_some_and(|min| text.len() as u32 >= min));
+        for (text, align) in active {
+            self.place(&text, align, true);
+        }
+        self.active_end = self.end();
+        for (text, align) in passive {
+            self.place(&text, align, false);
+            self.passive.push(self.blobs[&text]);
+        }
+    }
+
+    fn place(&mut self, text: &str, align: u32, share: bool) {
+        let len = text.len() as u32;
+        let shared = self.placed.iter().filter(|_| share).find_map(|b| {
+            let bytes = &self.image[b.ptr as usize..(b.ptr + b.len) as usize];
+            bytes
+                .ends_with(text.as_bytes())
+                .then(|| b.ptr + b.len - len)
+                .filter(|ptr| ptr % align == 0)
+        });
+        let ptr = shared.unwrap_or_else(|| {
+            let ptr = align_up(self.end(), align);
+            self.image.resize(ptr as usize, 0);
+            self.image.extend_from_slice(text.as_bytes());
+            ptr
+        });
+        let blob = Blob { ptr, len };
+        if shared.is_none() {
+            self.placed.push(blob);
+        }
+        self.blobs.insert(text.to_owned(), blob);
+    }
 
-    // Update the best-known location for this text (now satisfies `align`).
-    interner.insert(text.to_owned(), blob);
+    // (ptr,len) of `text`, which was requested before `plan`
+    fn blob(&self, text: &str) -> Blob {
+        *self
+            .blobs
+            .get(text)
+            .unwrap_or_else(|| panic!("constant {:?} not requested before the data layout", text))
+    }
+
+    // first byte after the data
+    fn end(&self) -> u32 {
+        self.image.len() as u32
+    }
+
+    // The passive segment holding `blob`, counted from 0
+    fn passive_index(&self, blob: Blob) -> Option<usize> {
+        self.passive.iter().position(|b| b.ptr == blob.ptr)
+    }
 
-    blob
+    // Index of the first passive segment: after the active one, if any
+    fn first_passive(&self) -> u32 {
+        (self.active_end > 0) as u32
+    }
+}
+
+// Name of a data segment: its start as text (control characters as spaces), cut after
+// DATA_NAME_CHARS characters
+const DATA_NAME_CHARS: usize = 32;
+
+fn data_name(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let mut chars = text.chars().map(|c| if c.is_control() { ' ' } else { c });
+    let mut name: String = chars.by_ref().take(DATA_NAME_CHARS).collect();
+    if chars.next().is_some() {
+        name.push_str("...");
+    }
+    name
 }
 
+/// Size of the imported linear memory, in 64 KiB pages.
+/// The constant data must fit in `min_pages`; the heap grows up to `max_pages`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryLimits {
+    pub min_pages: u32,
+    pub max_pages: Option<u32>, // None: up to the 4 GiB of wasm32
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        Self {
+            min_pages: 1,
+            max_pages: None,
+        }
+    }
+}
+
+/// How the program is compiled, beyond the module layout.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub opt_level: OptLevel, // passes run on the program before emitting (see optimize.rs)
+    pub features: WasmFeatures,
+    pub passive_data: bool, // large texts in passive segments, see DataLayout (programs only)
+    pub float_format: FloatFormat, // how to_str writes a float (--float-format)
+}
+
+impl CompileOptions {
+    /// An error for the options that do not go together.
+    pub fn check(&self) -> Result<(), String> {
+        if self.passive_data && !self.features.bulk_memory {
+            return Err("passive data needs the bulk-memory wasm feature (memory.init)".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Size from which a constant text gets its own passive segment with --passive-data.
+pub const PASSIVE_DATA_MIN: u32 = 1024;
+
+/// The wasm proposals the module may use (--wasm-features), for the hosts lacking some.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WasmFeatures {
+    pub bulk_memory: bool, // memory.copy and memory.fill, else byte loops (see runtime::MEMORY)
+}
+
+impl Default for WasmFeatures {
+    fn default() -> Self {
+        Self { bulk_memory: true }
+    }
+}
+
+impl FromStr for WasmFeatures {
+    type Err = String;
+
+    // A comma-separated list: "mvp" (none), "bulk-memory"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut features = Self { bulk_memory: false };
+        for name in s.split(',').map(str::trim) {
+            match name {
+                "mvp" => {}
+                "bulk-memory" => features.bulk_memory = true,
+                _ => return Err(format!("unknown wasm feature '{}' (mvp or bulk-memory)", name)),
+            }
+        }
+        Ok(features)
+    }
+}
+
+impl fmt::Display for WasmFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.bulk_memory { "bulk-memory" } else { "mvp" })
+    }
+}
+
+/// How `to_str` writes a float (--float-format); to_str(x, decimals) always gives that many decimals.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FloatFormat {
+    /// At most 15 significant digits, by the module itself (rt.to_str_f64): 0.1 + 0.2 is 0.3.
+    #[default]
+    Digits,
+    /// The shortest text reading back as the same float, by the host (str.to_str_shortest):
+    /// 0.1 + 0.2 is 0.30000000000000004.
+    Shortest,
+}
+
+impl FromStr for FloatFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "digits" => Ok(Self::Digits),
+            "shortest" => Ok(Self::Shortest),
+            _ => Err(format!("unknown float format '{}' (digits or shortest)", s)),
+        }
+    }
+}
+
+impl fmt::Display for FloatFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Digits => "digits",
+            Self::Shortest => "shortest",
+        })
+    }
+}
+
+pub const PAGE_SIZE: u32 = 65536;
+
+/// Custom section of a library module (mpl compile --lib): the size of its constant data (u32, little endian).
+pub const LIBRARY_SECTION: &str = "mpl.lib";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum Ty {
     I32,
     F64,
+    Char, // a Unicode code point, stored as an i32 (see NumExpr::Char)
+    // A function reference, stored as its slot in the table (see CodeGenerator::table). The
+    // parser keeps fn, map and builder variables out of expressions, so the numeric code only
+    // meets them as i32s.
+    Fn,
+    Map,     // the address of a map, 0 while it is empty (see runtime::MapRuntime)
+    Builder, // the address of a string builder, 0 while it is empty (see runtime::BuilderRuntime)
+}
+
+impl Ty {
+    // MPL name of the type
+    pub fn name(self) -> &'static str {
+        match self {
+            Ty::I32 => grammar::KW_INT_TYPE,
+            Ty::F64 => grammar::KW_FLOAT_TYPE,
+            Ty::Char => grammar::KW_CHAR_TYPE,
+            Ty::Fn => grammar::KW_FN,
+            Ty::Map => grammar::KW_MAP_TYPE,
+            Ty::Builder => grammar::KW_BUILDER_TYPE,
+        }
+    }
+}
+
+// Wasm value type used to store a value of type `ty`
+fn val_type(ty: Ty) -> ValType {
+    match ty {
+        Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => ValType::I32,
+        Ty::F64 => ValType::F64,
+    }
+}
+
+const I32: ValType = ValType::I32;
+const F64: ValType = ValType::F64;
+
+// Host functions a module may import, known as "module.name".
+// Only env.log and the ones the program uses are imported (see scan_program).
+type HostFn = (&'static str, &'static str, &'static [ValType], &'static [ValType]);
+const HOST_IMPORTS: &[HostFn] = &[
+    ("env", "log", &[I32, I32], &[]),                  // (ptr,len) -> ()
+    ("env", "log_err", &[I32, I32], &[]),              // (ptr,len) -> (), on stderr
+    ("str", "len", &[I32, I32], &[I32]),               // (ptr,len) -> number of characters
+    ("str", "substr", &[I32, I32, I32, I32], &[I32, I32]), // (ptr,len,start,count) -> slice
+    ("str", "char_at", &[I32, I32, I32], &[I32, I32]), // (ptr,len,i) -> slice
+    ("str", "code_at", &[I32, I32, I32], &[I32]),      // (ptr,len,i) -> code point of character i
+    ("str", "char_text", &[I32], &[I32, I32]),         // (c) -> text of the char, traps if not a code point
+    ("str", "eq", &[I32, I32, I32, I32], &[I32]),      // (p1,l1,p2,l2) -> 1 if equal
+    ("str", "parse_i32", &[I32, I32], &[I32]),         // (ptr,len) -> n, traps if invalid
+    ("str", "parse_f64", &[I32, I32], &[F64]),         // (ptr,len) -> x, traps if invalid
+    ("str", "to_str_fixed", &[F64, I32], &[I32, I32]), // (x,decimals) -> text, traps if decimals is not in 0..=100
+    ("str", "to_str_shortest", &[F64], &[I32, I32]),   // (x) -> text, see FloatFormat::Shortest
+    ("str", "format_thousands", &[F64, I32], &[I32, I32]), // (x,decimals) -> text, decimals -1: as to_str(x)
+    ("str", "decimal_comma", &[I32, I32], &[I32, I32]), // (ptr,len) -> copy with ',' for '.'
+    ("env", "flush", &[], &[]),                        // () -> ()
+    ("env", "dump_heap", &[I32, I32], &[]),            // (start,len) -> (), start -1: the heap
+    ("env", "args_count", &[], &[I32]),                // () -> n
+    ("env", "args_get", &[I32], &[I32, I32]),          // (i) -> (ptr,len)
+    ("env", "random", &[], &[F64]),                    // () -> x in [0, 1)
+    ("env", "random_int", &[I32, I32], &[I32]),        // (lo,hi) -> n in [lo, hi]
+    ("math", "pow", &[F64, F64], &[F64]),              // (x,y) -> x^y
+    ("math", "sin", &[F64], &[F64]),
+    ("math", "cos", &[F64], &[F64]),
+    ("math", "tan", &[F64], &[F64]),
+    ("math", "log", &[F64], &[F64]),
+    ("math", "exp", &[F64], &[F64]),
+];
+
+// Declaration checks: every function name (extern fn included) and every local of a function declared once.
+// The error is at the second declaration and names the first one.
+fn check_declarations(prog: &Program) -> Result<(), ParseError> {
+    let at = |pos: &Position| format!("{}:{}:{}", pos.file_name.display(), pos.line, pos.col);
+    let functions = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main);
+    let mut defined: HashMap<&str, &Position> = HashMap::new();
+    for f in functions {
+        if let Some(first) = defined.insert(&f.name, &f.pos) {
+            return Err(ParseError::generator(
+                &messages::DUPLICATE_FUNCTION,
+                &[&f.name, &at(first)],
+                &f.pos,
+            ));
+        }
+        let mut declared: HashMap<&str, &Position> = HashMap::new();
+        for var in &f.variables {
+            if let Some(first) = declared.insert(&var.name, &var.pos) {
+                return Err(ParseError::generator(
+                    &messages::DUPLICATE_VARIABLE,
+                    &[&var.name, &f.name, &at(first)],
+                    &var.pos,
+                ));
+            }
+        }
+    }
+    for e in &prog.externs {
+        if let Some(first) = defined.insert(&e.name, &e.pos) {
+            return Err(ParseError::generator(&messages::DUPLICATE_FUNCTION, &[&e.name, &at(first)], &e.pos));
+        }
+        let mut declared: HashMap<&str, &Position> = HashMap::new();
+        for param in &e.params {
+            if let Some(first) = declared.insert(&param.name, &param.pos) {
+                return Err(ParseError::generator(
+                    &messages::DUPLICATE_VARIABLE,
+                    &[&param.name, &e.name, &at(first)],
+                    &param.pos,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// What the program needs from the module around it
+#[derive(Default)]
+struct Usage {
+    imports: BTreeSet<String>,  // "module.name" of every host function called
+    literals: BTreeSet<String>, // every constant string, placed before the code is generated
+    fn_refs: BTreeSet<String>,  // every function given to a fn variable (`&name`)
+    indirect_calls: bool,       // some `call_indirect`
+    maps: bool,                 // some `local map` (see runtime::MAPS)
+    builders: bool,             // some repeat() or `local builder` (see runtime::BUILDERS)
+    float_texts: bool,          // some to_str of a float
+}
+
+fn scan_program(prog: &Program) -> Usage {
+    let mut used = Usage::default();
+    used.visit_program(prog);
+    used
+}
+
+impl Visitor for Usage {
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Fn(FnExpr::Ref { name, .. }) = e {
+            self.fn_refs.insert(name.clone());
+        }
+        walk_expr(self, e);
+    }
+
+    fn visit_stadment(&mut self, st: &Stadment) {
+        match st {
+            Stadment::Println { .. } => {
+                self.literals.insert("\n".to_string());
+            }
+            Stadment::EPrint { .. } => {
+                self.imports.insert("env.log_err".to_string());
+            }
+            Stadment::EPrintln { .. } => {
+                self.literals.insert("\n".to_string());
+                self.imports.insert("env.log_err".to_string());
+            }
+            Stadment::Flush => {
+                self.imports.insert("env.flush".to_string());
+            }
+            Stadment::DumpHeap { .. } => {
+                self.imports.insert("env.dump_heap".to_string());
+            }
+            Stadment::CallIndirect { .. } => self.indirect_calls = true,
+            Stadment::MapSet { .. } | Stadment::MapDelete { .. } => self.maps = true,
+            Stadment::Append { .. } | Stadment::Clear { .. } => self.builders = true,
+            _ => {}
+        }
+        walk_stadment(self, st);
+    }
+
+    fn visit_str_expr(&mut self, e: &StrExpr) {
+        match e {
+            StrExpr::Arg(_) => {
+                self.imports.insert("env.args_get".to_string());
+            }
+            StrExpr::Substr { .. } => {
+                self.imports.insert("str.substr".to_string());
+            }
+            StrExpr::CharAt { .. } => {
+                self.imports.insert("str.char_at".to_string());
+            }
+            StrExpr::Str(s) => {
+                self.literals.insert(s.clone());
+            }
+            StrExpr::Nl => {
+                self.literals.insert("\n".to_string());
+            }
+            StrExpr::ToFixed { .. } => {
+                self.imports.insert("str.to_str_fixed".to_string());
+            }
+            StrExpr::Thousands { .. } => {
+                self.imports.insert("str.format_thousands".to_string());
+            }
+            StrExpr::DecimalComma(_) => {
+                self.imports.insert("str.decimal_comma".to_string());
+            }
+            StrExpr::NumToStr(n) if infer_type(n) == Ty::F64 => self.float_texts = true,
+            StrExpr::NumToStr(n) if infer_type(n) == Ty::Char => {
+                self.imports.insert("str.char_text".to_string());
+            }
+            StrExpr::Repeat { .. } | StrExpr::Builder { .. } => self.builders = true,
+            StrExpr::NumToStr(_) | StrExpr::Join { .. } => {}
+        }
+        walk_str_expr(self, e);
+    }
+
+    fn visit_num_expr(&mut self, e: &NumExpr) {
+        let import = match e {
+            NumExpr::Math { func, .. } => match func {
+                MathFn::Pow | MathFn::Sin | MathFn::Cos | MathFn::Tan | MathFn::Log | MathFn::Exp => {
+                    Some(format!("math.{}", func.name()))
+                }
+                _ => None,
+            },
+            NumExpr::Len(_) => Some("str.len".to_string()),
+            NumExpr::ToInt(_) => Some("str.parse_i32".to_string()),
+            NumExpr::ToFloat(_) => Some("str.parse_f64".to_string()),
+            NumExpr::StrEq { .. } => Some("str.eq".to_string()),
+            NumExpr::RandomInt { .. } => Some("env.random_int".to_string()),
+            NumExpr::Random => Some("env.random".to_string()),
+            NumExpr::ArgCount => Some("env.args_count".to_string()),
+            NumExpr::CharAt { .. } => Some("str.code_at".to_string()),
+            NumExpr::MapGet { .. } | NumExpr::MapHas { .. } => {
+                self.maps = true;
+                None
+            }
+            NumExpr::Binary { .. }
+            | NumExpr::Neg(_)
+            | NumExpr::Int(_)
+            | NumExpr::Float(_)
+            | NumExpr::Char(_)
+            | NumExpr::Ord(_)
+            | NumExpr::Chr(_)
+            | NumExpr::Var { .. } => None,
+        };
+        if let Some(import) = import {
+            self.imports.insert(import);
+        }
+        walk_num_expr(self, e);
+    }
 }
+
+// The same program always gives the same bytes (checked by -c --verify-deterministic): the
+// HashMaps below are only looked up, everything emitted comes from Vecs and ordered collections.
 pub struct CodeGenerator {
     // sections
     types: TypeSection,
     imports: ImportSection,
     functions: FunctionSection,
     code: CodeSection,
-    data: DataSection,
+    data: DataLayout, // the constant strings
     exports: ExportSection,
     names: NameSection,
     globals: GlobalSection,
-    string_interner: HashMap<String, Blob>, // Maps string literals to their memory locations (prevents duplicates).
 
     // bookkeeping
     fn_names: NameMap,
+    local_names: IndirectNameMap, // local names of every generated function
     fn_idx: u32,
     fn_map: HashMap<String, i32>,
-    data_idx: u32,
     ty_void: u32,
+    ty_main: u32, // () -> i32, main returns the program exit code
+    // the functions given to fn variables, in declaration order: slot i + 1 of the table holds
+    // table[i], slot 0 stays null so that calling a fn variable never given a function traps.
+    // None: no table, the program has no fn variable
+    table: Option<Vec<u32>>,
+    slots: HashMap<String, u32>, // function name -> its slot in the table
+    externs: HashMap<String, Vec<Ty>>, // extern fn name -> the types of its parameters
+    memory_ops: MemoryOps,       // how the runtime and the code copy bytes, see WasmFeatures
+
+    hooks: CodegenHooks,
+    memory: MemoryLimits,
+    strip: bool, // no NameSection, see with_strip()
+    library: bool, // library compiled on its own, see with_library()
+    data_base: u32, // library: index of the imported global holding the address of its data
+    meta: Option<String>, // text of the "mpl.meta" section, see with_meta()
+    sources: Vec<(PathBuf, String)>, // embedded sources, see with_sources()
+    source_map: Option<String>, // URL of the source map, see with_source_map()
+    lines: Vec<FunctionLines>, // statement starts of the generated functions, for the source map
+    options: CompileOptions,
 }
 
-fn get_variable_index(
-    variables: &Vec<Variable>,
+// The tables of the module a function body is generated with, read only once the functions
+// are declared: the bodies do not depend on each other.
+#[derive(Clone, Copy)]
+struct Shared<'a> {
+    fn_map: &'a HashMap<String, i32>,
+    data: &'a DataLayout,
+    data_base: u32,
+    library: bool,
+    options: CompileOptions,
+    sources: &'a [(PathBuf, String)],
+    track_position: bool, // see CodeGenerator::tracks_position()
+    debug: bool,          // mark the statement starts, see with_source_map()
+    slots: &'a HashMap<String, u32>,
+    externs: &'a HashMap<String, Vec<Ty>>,
+    ty_void: u32,
+    memory_ops: MemoryOps,
+}
+
+// Generates the body of one function. Without hooks, the bodies of a module are generated
+// in parallel, one FunctionGen each, and put in the code section in declaration order.
+struct FunctionGen<'a> {
+    fn_map: &'a HashMap<String, i32>,
+    data: &'a DataLayout,
+    data_base: u32,
+    library: bool,
+    options: CompileOptions,
+    sources: &'a [(PathBuf, String)],
+    track_position: bool,
+    debug: bool,
+    slots: &'a HashMap<String, u32>,
+    externs: &'a HashMap<String, Vec<Ty>>,
+    ty_void: u32,
+    memory_ops: MemoryOps,
+    hooks: Option<&'a mut CodegenHooks>,
+    tmp_base: u32,                     // index of the first temporary local
+    tmp_locals: Vec<(ValType, String)>, // temporaries of the function being generated
+    starts: Vec<Position>,              // positions of the statements marked so far (debug)
+}
+
+// A generated function: its code, the names of its locals and its statement starts (debug)
+type GeneratedBody = (wasm_encoder::Function, NameMap, Vec<(u32, Position)>);
+
+// Type a numeric expression is computed in when nothing imposes one
+pub(crate) fn infer_type(e: &NumExpr) -> Ty {
+    match e {
+        NumExpr::Int(_) => Ty::I32,
+        NumExpr::Float(_) => Ty::F64,
+        NumExpr::Char(_) | NumExpr::Chr(_) | NumExpr::CharAt { .. } => Ty::Char,
+        NumExpr::Binary { left, right, .. } => {
+            let lt = infer_type(left);
+            let rt = infer_type(right);
+            if lt == Ty::F64 || rt == Ty::F64 {
+                Ty::F64
+            } else {
+                Ty::I32
+            }
+        }
+        NumExpr::Var { var, .. } => var.ty,
+        // the opposite of a char is a number, not a char
+        NumExpr::Neg(inner) => match infer_type(inner) {
+            Ty::Char => Ty::I32,
+            ty => ty,
+        },
+        NumExpr::ArgCount
+        | NumExpr::Ord(_)
+        | NumExpr::RandomInt { .. }
+        | NumExpr::Len(_)
+        | NumExpr::ToInt(_)
+        | NumExpr::StrEq { .. }
+        | NumExpr::MapGet { .. }
+        | NumExpr::MapHas { .. } => Ty::I32,
+        NumExpr::Random | NumExpr::ToFloat(_) => Ty::F64,
+        NumExpr::Math { func, args } => match func {
+            MathFn::Sqrt | MathFn::Sin | MathFn::Cos | MathFn::Tan | MathFn::Log | MathFn::Exp => {
+                Ty::F64
+            }
+            // same rule as binary operators: F64 as soon as one argument is F64
+            _ => {
+                if args.iter().any(|a| infer_type(a) == Ty::F64) {
+                    Ty::F64
+                } else {
+                    Ty::I32
+                }
+            }
+        },
+    }
+}
+
+// Whether the string `e` is a new heap block, to free once used (the others are literals, host
+// strings or the text of a builder)
+fn owns(e: &StrExpr) -> bool {
+    matches!(
+        e,
+        StrExpr::NumToStr(_)
+            | StrExpr::ToFixed { .. }
+            | StrExpr::Thousands { .. }
+            | StrExpr::DecimalComma(_)
+            | StrExpr::Repeat { .. }
+            | StrExpr::Join { .. }
+    )
+}
+
+pub(crate) fn get_variable_index(
+    variables: &[Variable],
     name: &str,
     pos: &Position,
 ) -> Result<i32, ParseError> {
     let idx = match crate::parser::find_variable_index(variables, name) {
         Some(i) => i as u32,
         None => {
-            return Err(ParseError::Generator {
-                pos: pos.clone(),
-                msg: format!("unknown variable '{}'", name),
-            });
+            let hint = did_you_mean(name, variables.iter().map(|v| v.name.as_str()));
+            return Err(ParseError::generator(
+                &messages::UNKNOWN_VARIABLE,
+                &[&name, &hint],
+                pos,
+            ));
         }
     };
     Ok(idx as i32)
 }
 
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CodeGenerator {
     pub fn new() -> Self {
         Self {
@@ -132,19 +781,98 @@ impl CodeGenerator {
             imports: ImportSection::new(),
             functions: FunctionSection::new(),
             code: CodeSection::new(),
-            data: DataSection::new(),
+            data: DataLayout::default(),
             exports: ExportSection::new(),
             names: NameSection::new(),
             globals: GlobalSection::new(),
-            string_interner: HashMap::new(),
             fn_names: NameMap::new(),
+            local_names: IndirectNameMap::new(),
             fn_idx: 0,
             fn_map: HashMap::new(),
-            data_idx: 0,
             ty_void: 0, // sera 0 après ajout de ()->()
+            ty_main: 1,
+            table: None,
+            slots: HashMap::new(),
+            externs: HashMap::new(),
+            memory_ops: MemoryOps::Bulk,
+            hooks: CodegenHooks::default(),
+            memory: MemoryLimits::default(),
+            strip: false,
+            library: false,
+            data_base: 0,
+            meta: None,
+            sources: Vec::new(),
+            source_map: None,
+            lines: Vec::new(),
+            options: CompileOptions::default(),
         }
     }
 
+    // Code generator with instrumentation hooks
+    pub fn with_hooks(hooks: CodegenHooks) -> Self {
+        Self {
+            hooks,
+            ..Self::new()
+        }
+    }
+
+    // Size of the memory the module imports (default: one page, no maximum)
+    pub fn with_memory(mut self, memory: MemoryLimits) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    // No name section at all: smaller modules, anonymous functions in traps and WAT
+    pub fn with_strip(mut self, strip: bool) -> Self {
+        self.strip = strip;
+        self
+    }
+
+    // Compile a library (no main) into a module linked at run time with the program using it:
+    // it exports its public functions, imports the runtime functions ("rt.<name>") from the
+    // program, and its constant data is placed by the runner at the address `rt.data_base`
+    // (size in the "mpl.lib" custom section).
+    pub fn with_library(mut self, library: bool) -> Self {
+        self.library = library;
+        self
+    }
+
+    // Describe the build (compiler, sources, options) in an "mpl.meta" custom section
+    pub fn with_meta(mut self, info: &BuildInfo) -> Self {
+        self.meta = Some(info.to_text());
+        self
+    }
+
+    pub fn with_options(mut self, options: CompileOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    // Embed the sources (path, text) in an "mpl.source" section and keep the position of the
+    // running statement in the exported global "mpl.pos", for the runner to show where a trap
+    // happened (see meta.rs). Not for libraries.
+    pub fn with_sources(mut self, sources: Vec<(PathBuf, String)>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    // Mark where each statement starts, write the "sourceMappingURL" section naming `url` and
+    // keep what source_map() needs to write the map.
+    pub fn with_source_map(mut self, url: Option<String>) -> Self {
+        self.source_map = url;
+        self
+    }
+
+    /// The JSON source map of `wasm`, the module this generator made with a source map URL and
+    /// named `file`. `sources` gives the text of the MPL files (see sourcemap.rs).
+    pub fn source_map(&self, wasm: &[u8], file: &str, sources: &[(PathBuf, String)]) -> Result<String, String> {
+        sourcemap::build(wasm, file, &self.lines, sources).map_err(|e| e.to_string())
+    }
+
+    fn tracks_position(&self) -> bool {
+        !self.sources.is_empty() && !self.library
+    }
+
     // Enregistre un nom de fonction pour la NameSection et map nom -> index
     pub fn declare_function(&mut self, function: &ParserFunction) {
         self.fn_names.append(self.fn_idx, &function.name);
@@ -153,131 +881,296 @@ impl CodeGenerator {
         self.fn_idx += 1;
     }
 
-    // Decide the resulting type of an expression.
-    // Rule: if any side is F64, result is F64; otherwise I32.
-    fn infer_type(&self, e: &NumExpr) -> Ty {
-        match e {
-            NumExpr::Int(_) => Ty::I32,
-            NumExpr::Float(_) => Ty::F64,
-            NumExpr::Binary { left, right, .. } => {
-                let lt = self.infer_type(left);
-                let rt = self.infer_type(right);
-                if lt == Ty::F64 || rt == Ty::F64 {
-                    Ty::F64
+    fn shared(&self) -> Shared<'_> {
+        Shared {
+            fn_map: &self.fn_map,
+            data: &self.data,
+            data_base: self.data_base,
+            library: self.library,
+            options: self.options,
+            sources: &self.sources,
+            track_position: self.tracks_position(),
+            debug: self.source_map.is_some(),
+            slots: &self.slots,
+            externs: &self.externs,
+            ty_void: self.ty_void,
+            memory_ops: self.memory_ops,
+        }
+    }
+
+    pub fn gen_function(&mut self, function: &ParserFunction) -> Result<(), ParseError> {
+        // the hooks are lent to the generator of the body
+        let mut hooks = std::mem::take(&mut self.hooks);
+        let body = FunctionGen::new(self.shared(), Some(&mut hooks)).gen_body(function);
+        self.hooks = hooks;
+        self.add_function(function, body?);
+        Ok(())
+    }
+
+    // Declare a generated function: its type, its code and the names of its locals
+    fn add_function(&mut self, function: &ParserFunction, (code, locals, starts): GeneratedBody) {
+        if function.name == grammar::KW_MAIN {
+            self.functions.function(self.ty_main); // () -> i32
+        } else {
+            self.functions.function(self.ty_void); // () -> ()
+        }
+        self.local_names.append(self.fn_map[&function.name] as u32, &locals);
+        if !starts.is_empty() {
+            self.lines.push(FunctionLines {
+                code_index: self.code.len(),
+                starts,
+            });
+        }
+        self.code.function(&code);
+    }
+}
+
+impl<'a> FunctionGen<'a> {
+    fn new(shared: Shared<'a>, hooks: Option<&'a mut CodegenHooks>) -> Self {
+        FunctionGen {
+            fn_map: shared.fn_map,
+            data: shared.data,
+            data_base: shared.data_base,
+            library: shared.library,
+            options: shared.options,
+            sources: shared.sources,
+            track_position: shared.track_position,
+            debug: shared.debug,
+            slots: shared.slots,
+            externs: shared.externs,
+            ty_void: shared.ty_void,
+            memory_ops: shared.memory_ops,
+            hooks,
+            tmp_base: 0,
+            tmp_locals: Vec::new(),
+            starts: Vec::new(),
+        }
+    }
+
+    // Math builtins, computed in type `ty` (see ir::lower_math).
+    // Native wasm ops where they exist; i32 abs/min/max use `select`; pow and the
+    // transcendental functions (sin, cos, tan, log, exp) are math.* host imports.
+    fn gen_math(
+        &mut self,
+        func: MathFn,
+        args: &[Num],
+        ty: NumTy,
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+    ) -> Result<(), ParseError> {
+        match (func, ty) {
+            (MathFn::Sqrt, _) => {
+                self.gen_num(&args[0], instr, function)?;
+                instr.f64_sqrt();
+            }
+            (MathFn::Pow, _) => {
+                self.gen_num(&args[0], instr, function)?;
+                self.gen_num(&args[1], instr, function)?;
+                instr.call(self.fn_map["math.pow"] as u32); // (f64,f64)->(f64)
+            }
+            (MathFn::Sin | MathFn::Cos | MathFn::Tan | MathFn::Log | MathFn::Exp, _) => {
+                self.gen_num(&args[0], instr, function)?;
+                let import = format!("math.{}", func.name());
+                instr.call(self.fn_map[&import] as u32); // (f64)->(f64)
+            }
+            // integers are already whole (lowered to their argument)
+            (MathFn::Floor | MathFn::Ceil | MathFn::Round, NumTy::I32) => {
+                self.gen_num(&args[0], instr, function)?;
+            }
+            (MathFn::Floor, NumTy::F64) => {
+                self.gen_num(&args[0], instr, function)?;
+                instr.f64_floor();
+            }
+            (MathFn::Ceil, NumTy::F64) => {
+                self.gen_num(&args[0], instr, function)?;
+                instr.f64_ceil();
+            }
+            (MathFn::Round, NumTy::F64) => {
+                // halfway cases away from zero: copysign(floor(|x| + 0.5), x)
+                let x = self.alloc_tmp(Ty::F64, "round");
+                self.gen_num(&args[0], instr, function)?;
+                instr.local_tee(x).f64_abs();
+                instr.f64_const(0.5.into()).f64_add().f64_floor();
+                instr.local_get(x).f64_copysign();
+            }
+            (MathFn::Abs, NumTy::F64) => {
+                self.gen_num(&args[0], instr, function)?;
+                instr.f64_abs();
+            }
+            (MathFn::Abs, NumTy::I32) => {
+                // select(0 - x, x, x < 0)
+                let x = self.alloc_tmp(Ty::I32, "abs");
+                self.gen_num(&args[0], instr, function)?;
+                instr.local_set(x);
+                instr.i32_const(0).local_get(x).i32_sub();
+                instr.local_get(x);
+                instr.local_get(x).i32_const(0).i32_lt_s();
+                instr.select();
+            }
+            (MathFn::Min, NumTy::F64) => {
+                self.gen_num(&args[0], instr, function)?;
+                self.gen_num(&args[1], instr, function)?;
+                instr.f64_min();
+            }
+            (MathFn::Max, NumTy::F64) => {
+                self.gen_num(&args[0], instr, function)?;
+                self.gen_num(&args[1], instr, function)?;
+                instr.f64_max();
+            }
+            (MathFn::Min | MathFn::Max, NumTy::I32) => {
+                // min: select(a, b, a < b) / max: select(a, b, a > b)
+                let a = self.alloc_tmp(Ty::I32, func.name());
+                let b = self.alloc_tmp(Ty::I32, func.name());
+                self.gen_num(&args[0], instr, function)?;
+                instr.local_set(a);
+                self.gen_num(&args[1], instr, function)?;
+                instr.local_set(b);
+                instr.local_get(a).local_get(b);
+                instr.local_get(a).local_get(b);
+                if func == MathFn::Min {
+                    instr.i32_lt_s();
                 } else {
-                    Ty::I32
+                    instr.i32_gt_s();
                 }
+                instr.select();
             }
-            NumExpr::Var { var, pos } => var.ty,
-            NumExpr::Neg(inner) => self.infer_type(inner),
         }
+        Ok(())
     }
 
-    // Emit `expr` as `target` type, inserting implicit casts as needed.
+    // Emit `expr` as `target` type: lowered to the IR (see ir.rs), where the implicit casts
+    // are explicit, then translated.
     // Allowed: i32 -> f64 (widen) and f64 -> i32 (narrow via trunc toward zero).
     fn gen_expression_as(
         &mut self,
         expr: &NumExpr,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         target: Ty,
         function: &ParserFunction,
     ) -> Result<(), ParseError> {
-        match expr {
-            NumExpr::Neg(inner) => {
-                // Generate the inner value as the target type, then negate.
-                match target {
-                    Ty::F64 => {
-                        // f64: direct unary neg instruction
-                        self.gen_expression_as(inner, instr, Ty::F64, function)?;
-                        instr.f64_neg(); // stack: [-inner]
-                    }
-                    Ty::I32 => {
-                        // i32: there is no i32.neg; compute 0 - x
-                        instr.i32_const(0); // stack: [0]
-                        self.gen_expression_as(inner, instr, Ty::I32, function)?;
-                        instr.i32_sub(); // stack: [0 - x]
-                    }
-                }
-                return Ok(());
-            }
-            NumExpr::Int(i) => {
+        let num = ir::lower_num(expr, target, &function.variables)?;
+        self.gen_num(&num, instr, function)
+    }
+
+    // Emit a lowered number: each node is computed in its own type
+    fn gen_num(
+        &mut self,
+        num: &Num,
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+    ) -> Result<(), ParseError> {
+        match &num.kind {
+            NumKind::Int(i) => {
                 instr.i32_const(*i);
-                if target == Ty::F64 {
-                    // signed i32 -> f64
-                    instr.f64_convert_i32_s();
-                }
-                Ok(())
             }
-            NumExpr::Float(r) => {
-                match target {
-                    Ty::F64 => {
-                        instr.f64_const((*r).into());
-                        Ok(())
-                    }
-                    Ty::I32 => {
-                        // f64 -> i32 (trunc toward zero, traps on NaN or out-of-range)
-                        instr.f64_const((*r).into());
-                        instr.i32_trunc_f64_s();
-                        Ok(())
-                    }
-                }
+            NumKind::Float(r) => {
+                instr.f64_const((*r).into());
             }
-            NumExpr::Binary { op, left, right } => {
-                let target_ty = target;
-                // make both operands the same target type
-                self.gen_expression_as(left, instr, target_ty, function)?;
-                self.gen_expression_as(right, instr, target_ty, function)?;
-
-                match (op, target_ty) {
-                    (BinOp::Add, Ty::I32) => instr.i32_add(),
-                    (BinOp::Sub, Ty::I32) => instr.i32_sub(),
-                    (BinOp::Mul, Ty::I32) => instr.i32_mul(),
-                    (BinOp::Div, Ty::I32) => instr.i32_div_s(), // signed division
-
-                    (BinOp::Add, Ty::F64) => instr.f64_add(),
-                    (BinOp::Sub, Ty::F64) => instr.f64_sub(),
-                    (BinOp::Mul, Ty::F64) => instr.f64_mul(),
-                    (BinOp::Div, Ty::F64) => instr.f64_div(),
+            NumKind::Local { index, .. } => {
+                instr.local_get(*index);
+            }
+            NumKind::Convert(inner) => {
+                self.gen_num(inner, instr, function)?;
+                match num.ty {
+                    NumTy::F64 => instr.f64_convert_i32_s(), // signed i32 -> f64
+                    NumTy::I32 => instr.i32_trunc_f64_s(),   // trunc toward zero, traps on NaN or out-of-range
                 };
-                Ok(())
             }
-            NumExpr::Var { var, pos } => {
-                let idx = match crate::parser::find_variable_index(&function.variables, &var.name) {
-                    Some(i) => i as u32,
-                    None => {
-                        return Err(ParseError::Generator {
-                            pos: pos.clone(),
-                            msg: format!("unknown variable '{}'", var.name),
-                        });
-                    }
+            NumKind::Neg(inner) => match num.ty {
+                NumTy::F64 => {
+                    // f64: direct unary neg instruction
+                    self.gen_num(inner, instr, function)?;
+                    instr.f64_neg(); // stack: [-inner]
+                }
+                NumTy::I32 => {
+                    // i32: there is no i32.neg; compute 0 - x
+                    instr.i32_const(0); // stack: [0]
+                    self.gen_num(inner, instr, function)?;
+                    instr.i32_sub(); // stack: [0 - x]
+                }
+            },
+            NumKind::Binary { op, left, right } => {
+                self.gen_num(left, instr, function)?;
+                self.gen_num(right, instr, function)?;
+                match (op, num.ty) {
+                    (BinOp::Add, NumTy::I32) => instr.i32_add(),
+                    (BinOp::Sub, NumTy::I32) => instr.i32_sub(),
+                    (BinOp::Mul, NumTy::I32) => instr.i32_mul(),
+                    (BinOp::Div, NumTy::I32) => instr.i32_div_s(), // signed division
+
+                    (BinOp::Add, NumTy::F64) => instr.f64_add(),
+                    (BinOp::Sub, NumTy::F64) => instr.f64_sub(),
+                    (BinOp::Mul, NumTy::F64) => instr.f64_mul(),
+                    (BinOp::Div, NumTy::F64) => instr.f64_div(),
                 };
-                match var.ty {
-                    Ty::I32 => {
-                        instr.local_get(idx);
-                        if target == Ty::F64 {
-                            instr.f64_convert_i32_s();
-                        }
-                    }
-                    Ty::F64 => {
-                        instr.local_get(idx);
-                        if target == Ty::I32 {
-                            instr.i32_trunc_f64_s();
-                        }
-                    }
+            }
+            NumKind::ArgCount => {
+                instr.call(self.fn_map["env.args_count"] as u32); // ()->(i32)
+            }
+            NumKind::Random => {
+                instr.call(self.fn_map["env.random"] as u32); // ()->(f64)
+            }
+            NumKind::RandomInt { lo, hi } => {
+                self.gen_num(lo, instr, function)?;
+                self.gen_num(hi, instr, function)?;
+                instr.call(self.fn_map["env.random_int"] as u32); // (lo,hi)->(i32)
+            }
+            NumKind::Len(s) => {
+                self.gen_str_value(s, instr, function)?;
+                instr.call(self.fn_map["str.len"] as u32); // (ptr,len)->(i32)
+            }
+            NumKind::ToInt(s) => {
+                self.gen_str_value(s, instr, function)?;
+                instr.call(self.fn_map["str.parse_i32"] as u32); // (ptr,len)->(i32)
+            }
+            NumKind::ToFloat(s) => {
+                self.gen_str_value(s, instr, function)?;
+                instr.call(self.fn_map["str.parse_f64"] as u32); // (ptr,len)->(f64)
+            }
+            NumKind::StrEq { left, right, negated } => {
+                self.gen_str_value(left, instr, function)?;
+                self.gen_str_value(right, instr, function)?;
+                instr.call(self.fn_map["str.eq"] as u32); // (p1,l1,p2,l2)->(i32)
+                if *negated {
+                    instr.i32_eqz();
                 }
-                Ok(())
             }
+            NumKind::MapGet { var, key, pos } => {
+                self.gen_map_key(var, key, pos, instr, function)?;
+                instr.call(self.fn_map["rt.map_get"] as u32); // (m,kp,kl)->(i32)
+            }
+            NumKind::MapHas { var, key, pos } => {
+                self.gen_map_key(var, key, pos, instr, function)?;
+                instr.call(self.fn_map["rt.map_find"] as u32); // (m,kp,kl)->(entry, 0 if none)
+                instr.i32_const(0).i32_ne();
+            }
+            NumKind::CharAt { s, index } => {
+                self.gen_str_value(s, instr, function)?;
+                // a string made for char_at() is freed once read
+                let made = owns(s).then(|| {
+                    let (ptr, len) = (self.alloc_tmp(Ty::I32, "ca_ptr"), self.alloc_tmp(Ty::I32, "ca_len"));
+                    instr.local_set(len).local_tee(ptr).local_get(len);
+                    ptr
+                });
+                self.gen_num(index, instr, function)?;
+                instr.call(self.fn_map["str.code_at"] as u32); // (ptr,len,i)->(i32)
+                if let Some(ptr) = made {
+                    instr.local_get(ptr).call(self.fn_map["rt.free"] as u32);
+                }
+            }
+            NumKind::Math { func, args } => self.gen_math(*func, args, num.ty, instr, function)?,
         }
+        Ok(())
     }
 
     // Public entry: generate code and return the resulting type.
-    pub fn gen_expression(
+    fn gen_expression(
         &mut self,
         expr: &NumExpr,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
     ) -> Result<Ty, ParseError> {
-        let target = self.infer_type(expr);
+        let target = infer_type(expr);
         self.gen_expression_as(expr, instr, target, function)?;
         Ok(target)
     }
@@ -286,165 +1179,343 @@ impl CodeGenerator {
     fn gen_str_expression(
         &mut self,
         expr: &StrExpr,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
     ) -> Result<Option<Blob>, ParseError> {
         match expr {
-            StrExpr::Str(s) => {
-                // push string literal into data section
-                let blob = push_text(
-                    &mut self.data,
-                    0,
-                    &mut self.data_idx,
-                    s,
-                    16,
-                    &mut self.string_interner,
-                );
-                Ok(Some(blob))
-            }
-            StrExpr::Nl => {
-                let blob = push_text(
-                    &mut self.data,
-                    0,
-                    &mut self.data_idx,
-                    "\n",
-                    16,
-                    &mut self.string_interner,
-                );
-                Ok(Some(blob))
-            }
+            // literals were placed before the code was generated (see DataLayout)
+            StrExpr::Str(s) => Ok(Some(self.data.blob(s))),
+            StrExpr::Nl => Ok(Some(self.data.blob("\n"))),
             StrExpr::NumToStr(inner) => {
                 let inner = &**inner;
                 match self.gen_expression(inner, instr, function)? {
                     // push n
-                    Ty::I32 => {
-                        instr.call(self.fn_map["to_str_i32"] as u32); // (i32)->(i32,i32): [ptr,len]
+                    Ty::Char => {
+                        instr.call(self.fn_map["str.char_text"] as u32); // (i32)->(i32,i32): [ptr,len]
+                    }
+                    Ty::I32 | Ty::Fn | Ty::Map | Ty::Builder => {
+                        instr.call(self.fn_map["rt.to_str_i32"] as u32); // (i32)->(i32,i32): [ptr,len]
+                    }
+                    Ty::F64 if self.options.float_format == FloatFormat::Shortest => {
+                        instr.call(self.fn_map["str.to_str_shortest"] as u32); // (f64)->(i32,i32): [ptr,len]
                     }
                     Ty::F64 => {
-                        instr.call(self.fn_map["to_str_f64"] as u32); // (f64)->(i32,i32): [ptr,len]
+                        instr.call(self.fn_map["rt.to_str_f64"] as u32); // (f64)->(i32,i32): [ptr,len]
                     }
                 }
                 Ok(None)
             }
+            StrExpr::ToFixed { n, decimals } => {
+                self.gen_expression_as(n, instr, Ty::F64, function)?;
+                self.gen_expression_as(decimals, instr, Ty::I32, function)?;
+                instr.call(self.fn_map["str.to_str_fixed"] as u32); // (f64,i32)->(i32,i32): [ptr,len]
+                Ok(None)
+            }
+            StrExpr::Thousands { n, decimals } => {
+                self.gen_expression_as(n, instr, Ty::F64, function)?;
+                match decimals {
+                    Some(decimals) => self.gen_expression_as(decimals, instr, Ty::I32, function)?,
+                    None => {
+                        instr.i32_const(-1);
+                    }
+                }
+                instr.call(self.fn_map["str.format_thousands"] as u32); // (f64,i32)->(i32,i32): [ptr,len]
+                Ok(None)
+            }
+            StrExpr::DecimalComma(s) => {
+                self.gen_str_value(s, instr, function)?;
+                // a string made for decimal_comma() is freed once copied
+                let made = owns(s).then(|| {
+                    let (ptr, len) = (self.alloc_tmp(Ty::I32, "dc_ptr"), self.alloc_tmp(Ty::I32, "dc_len"));
+                    instr.local_set(len).local_tee(ptr).local_get(len);
+                    ptr
+                });
+                instr.call(self.fn_map["str.decimal_comma"] as u32); // (ptr,len)->(ptr,len)
+                if let Some(ptr) = made {
+                    instr.local_get(ptr).call(self.fn_map["rt.free"] as u32);
+                }
+                Ok(None)
+            }
+            StrExpr::Arg(index) => {
+                self.gen_expression_as(index, instr, Ty::I32, function)?;
+                instr.call(self.fn_map["env.args_get"] as u32); // (i32)->(i32,i32): [ptr,len]
+                Ok(None)
+            }
+            StrExpr::Substr { s, start, len } => {
+                self.gen_str_value(s, instr, function)?;
+                self.gen_expression_as(start, instr, Ty::I32, function)?;
+                self.gen_expression_as(len, instr, Ty::I32, function)?;
+                instr.call(self.fn_map["str.substr"] as u32); // (ptr,len,start,count)->(ptr,len)
+                Ok(None)
+            }
+            StrExpr::CharAt { s, index } => {
+                self.gen_str_value(s, instr, function)?;
+                self.gen_expression_as(index, instr, Ty::I32, function)?;
+                instr.call(self.fn_map["str.char_at"] as u32); // (ptr,len,i)->(ptr,len)
+                Ok(None)
+            }
+            StrExpr::Repeat { s, count } => {
+                self.gen_str_value(s, instr, function)?;
+                // a string made for repeat() is freed once repeated
+                let made = owns(s).then(|| {
+                    let (ptr, len) = (self.alloc_tmp(Ty::I32, "rep_ptr"), self.alloc_tmp(Ty::I32, "rep_len"));
+                    instr.local_set(len).local_tee(ptr).local_get(len);
+                    ptr
+                });
+                self.gen_expression_as(count, instr, Ty::I32, function)?;
+                instr.call(self.fn_map["rt.repeat"] as u32); // (ptr,len,n)->(ptr,len)
+                if let Some(ptr) = made {
+                    instr.local_get(ptr).call(self.fn_map["rt.free"] as u32);
+                }
+                Ok(None)
+            }
+            StrExpr::Join { sep, parts } => {
+                self.gen_join(sep, parts, instr, function)?;
+                Ok(None)
+            }
+            StrExpr::Builder { var, pos } => {
+                let idx = get_variable_index(&function.variables, &var.name, pos)?;
+                instr.local_get(idx as u32);
+                instr.call(self.fn_map["rt.builder_text"] as u32); // (b)->(ptr,len)
+                Ok(None)
+            }
+        }
+    }
+
+    // String expression as [ptr,len] on the stack, literals included
+    fn gen_str_value(
+        &mut self,
+        expr: &StrExpr,
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+    ) -> Result<(), ParseError> {
+        if let Some(blob) = self.gen_str_expression(expr, instr, function)? {
+            if let Some(k) = self.data.passive_index(blob) {
+                instr.call(self.fn_map[&format!("rt.init_data_{}", k)] as u32);
+            }
+            if self.library {
+                // relative to where the runner put the library data
+                instr.global_get(self.data_base).i32_const(blob.ptr as i32).i32_add();
+            } else {
+                instr.i32_const(blob.ptr as i32);
+            }
+            instr.i32_const(blob.len as i32);
+        }
+        Ok(())
+    }
+
+    // join(sep, parts...) as [ptr,len]: the lengths first, then one allocation the parts and
+    // separators are copied into
+    fn gen_join(
+        &mut self,
+        sep: &StrExpr,
+        parts: &[StrExpr],
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+    ) -> Result<(), ParseError> {
+        let free = self.fn_map["rt.free"] as u32;
+        let mut values = Vec::with_capacity(parts.len() + 1); // (ptr, len, made here) temporaries
+        for e in std::iter::once(sep).chain(parts) {
+            self.gen_str_value(e, instr, function)?;
+            let (ptr, len) = (self.alloc_tmp(Ty::I32, "join_ptr"), self.alloc_tmp(Ty::I32, "join_len"));
+            instr.local_set(len).local_set(ptr);
+            values.push((ptr, len, owns(e)));
+        }
+        let (sep_ptr, sep_len, _) = values[0];
+        let out = self.alloc_tmp(Ty::I32, "join_out");
+        let at = self.alloc_tmp(Ty::I32, "join_at");
+        // total = the lengths of the parts + (parts - 1) separators
+        instr.local_get(sep_len).i32_const(parts.len() as i32 - 1).i32_mul();
+        for &(_, len, _) in &values[1..] {
+            instr.local_get(len).i32_add();
+        }
+        instr.call(self.fn_map["rt.alloc"] as u32).local_tee(out).local_set(at);
+        for (i, &(ptr, len, _)) in values[1..].iter().enumerate() {
+            if i > 0 {
+                instr.local_get(at).local_get(sep_ptr).local_get(sep_len);
+                self.memory_ops.copy(instr);
+                instr.local_get(at).local_get(sep_len).i32_add().local_set(at);
+            }
+            instr.local_get(at).local_get(ptr).local_get(len);
+            self.memory_ops.copy(instr);
+            instr.local_get(at).local_get(len).i32_add().local_set(at);
+        }
+        for &(ptr, _, made) in &values {
+            if made {
+                instr.local_get(ptr).call(free);
+            }
         }
+        instr.local_get(out).local_get(at).local_get(out).i32_sub();
+        Ok(())
+    }
+
+    // [m, kp, kl]: the map held by `var`, then the key
+    fn gen_map_key(
+        &mut self,
+        var: &Variable,
+        key: &StrExpr,
+        pos: &Position,
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+    ) -> Result<(), ParseError> {
+        let idx = get_variable_index(&function.variables, &var.name, pos)?;
+        instr.local_get(idx as u32);
+        self.gen_str_value(key, instr, function)
     }
 
-    // print([...]) -> build (ptr,len) then call env.log(ptr,len)
-    pub fn gen_print(
+    // print([...]) -> build (ptr,len) then call env.log(ptr,len) (env.log_err for eprint)
+    // Strings made here (to_str results and concatenations) are freed once printed.
+    fn gen_print(
         &mut self,
-        str_expr: &Vec<StrExpr>,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        str_expr: &[StrExpr],
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
         nl: bool,
+        host_fn: &str,
     ) -> Result<(), ParseError> {
-        match str_expr.as_slice() {
-            [] => {}
-            [only] => {
-                if let Some(blob) = self.gen_str_expression(only, instr, function)? {
-                    instr.i32_const(blob.ptr as i32).i32_const(blob.len as i32);
-                }
+        let concat = self.fn_map["rt.concat"] as u32;
+        let free = self.fn_map["rt.free"] as u32;
+        let acc_ptr = self.alloc_tmp(Ty::I32, "print_ptr");
+        let acc_len = self.alloc_tmp(Ty::I32, "print_len");
+        let mut item = None; // (ptr,len) temporaries, only needed to concatenate
+        let mut acc_owned = false;
+
+        let newline = nl.then_some(StrExpr::Nl);
+        for (i, e) in str_expr.iter().chain(newline.iter()).enumerate() {
+            self.gen_str_value(e, instr, function)?;
+            let owned = owns(e);
+            if i == 0 {
+                instr.local_set(acc_len).local_set(acc_ptr);
+                acc_owned = owned;
+                continue;
             }
-            [first, rest @ ..] => {
-                if let Some(blob) = self.gen_str_expression(first, instr, function)? {
-                    instr.i32_const(blob.ptr as i32).i32_const(blob.len as i32);
-                }
-                for e in rest {
-                    if let Some(blob) = self.gen_str_expression(e, instr, function)? {
-                        instr.i32_const(blob.ptr as i32).i32_const(blob.len as i32);
-                    }
-                    // stack: ... s1_ptr s1_len s2_ptr s2_len -> concat -> s_ptr s_len
-                    instr.call(self.fn_map["concat"] as u32);
-                }
+            let (item_ptr, item_len) = *item.get_or_insert_with(|| {
+                (
+                    self.alloc_tmp(Ty::I32, "item_ptr"),
+                    self.alloc_tmp(Ty::I32, "item_len"),
+                )
+            });
+            instr.local_set(item_len).local_set(item_ptr);
+            // stack: acc_ptr acc_len item_ptr item_len -> concat -> s_ptr s_len
+            instr
+                .local_get(acc_ptr)
+                .local_get(acc_len)
+                .local_get(item_ptr)
+                .local_get(item_len)
+                .call(concat);
+            if acc_owned {
+                instr.local_get(acc_ptr).call(free);
+            }
+            if owned {
+                instr.local_get(item_ptr).call(free);
             }
+            instr.local_set(acc_len).local_set(acc_ptr);
+            acc_owned = true;
         }
-        if nl {
-            // append newline
-            let nl_blob = push_text(
-                &mut self.data,
-                0,
-                &mut self.data_idx,
-                "\n",
-                16,
-                &mut self.string_interner,
-            );
-            instr
-                .i32_const(nl_blob.ptr as i32)
-                .i32_const(nl_blob.len as i32);
-            // stack: ... s_ptr s_len nl_ptr nl_len -> concat -> s_ptr s_len
-            instr.call(self.fn_map["concat"] as u32);
+
+        instr.local_get(acc_ptr).local_get(acc_len);
+        instr.call(self.fn_map[host_fn] as u32);
+        if acc_owned {
+            instr.local_get(acc_ptr).call(free);
         }
-        instr.call(self.fn_map["log"] as u32);
         Ok(())
     }
 
-    pub fn gen_variables(
+    fn gen_variables(
         &mut self,
         variables: &[Variable],
-        fn_id: u32,
         param_count: u32,
+        fn_locals: &mut NameMap,
     ) -> Vec<(u32, ValType)> {
-        // mapping local index -> name
-        let mut fn_locals = NameMap::new();
-
         // build locals section
         let mut locals: Vec<(u32, ValType)> = Vec::with_capacity(variables.len());
 
         // logical index = param_count + local_index
-        let mut local_index = param_count;
-
-        for var in variables {
-            let val_ty = match var.ty {
-                Ty::I32 => ValType::I32,
-                Ty::F64 => ValType::F64,
-            };
-            locals.push((1, val_ty));
+        for (local_index, var) in (param_count..).zip(variables) {
+            locals.push((1, val_type(var.ty)));
             fn_locals.append(local_index, &var.name);
-            local_index += 1;
         }
 
-        //  add local names for this function
-        let mut local_names = IndirectNameMap::new();
-        local_names.append(fn_id, &fn_locals);
-        self.names.locals(&local_names);
-
         locals
     }
 
-    pub fn gen_call_function(
+    fn gen_call_function(
         &mut self,
         name: &str,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        args: &[NumExpr],
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
         pos: &Position,
     ) -> Result<(), ParseError> {
+        // only an extern fn takes arguments, computed in the types of its parameters
+        let params = self.externs.get(name).map_or(&[][..], Vec::as_slice);
+        if args.len() != params.len() {
+            let source_name = name.rsplit("::").next().unwrap_or(name);
+            return Err(ParseError::generator(
+                &messages::WRONG_ARG_COUNT,
+                &[&source_name, &params.len(), &args.len()],
+                pos,
+            ));
+        }
+        for (arg, &ty) in args.iter().zip(params) {
+            self.gen_expression_as(arg, instr, ty, function)?;
+        }
         if let Some(fid) = self.fn_map.get(name) {
             instr.call(*fid as u32);
+            // main returns the exit code, not needed by a caller
+            if name == grammar::KW_MAIN {
+                instr.drop();
+            }
             Ok(())
         } else {
-            Err(ParseError::Generator {
-                pos: pos.clone(),
-                msg: format!("unknown function '{}'", name),
-            })
+            Err(ParseError::generator(
+                &messages::UNKNOWN_FUNCTION,
+                &[&name, &self.function_hint(name)],
+                pos,
+            ))
         }
     }
 
-    pub fn gen_assignment(
+    // " (did you mean ...)" for the function `name` the module does not have
+    fn function_hint(&self, name: &str) -> String {
+        // the host and runtime functions ("module.name") are not called by their name
+        did_you_mean(name, self.fn_map.keys().map(String::as_str).filter(|f| !f.contains('.')))
+    }
+
+    fn gen_assignment(
         &mut self,
         var: &Variable,
         expr: &Expr,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
         pos: &Position,
     ) -> Result<(), ParseError> {
         // generate expression
         match &expr {
             Expr::Num(num_expr) => {
-                self.gen_expression_as(&num_expr, instr, var.ty, function)?;
+                self.gen_expression_as(num_expr, instr, var.ty, function)?;
             }
-            _ => {
-                return Err(ParseError::Generator {
-                    pos: pos.clone(),
-                    msg: "only numeric expressions are supported in assignments".to_string(),
-                });
+            Expr::Fn(FnExpr::Ref { name, pos, .. }) if self.externs.get(name).is_some_and(|p| !p.is_empty()) => {
+                return Err(ParseError::generator(&messages::EXTERN_FN_REF, &[name], pos));
+            }
+            Expr::Fn(FnExpr::Ref { name, pos, .. }) => match self.slots.get(name) {
+                Some(&slot) => {
+                    instr.i32_const(slot as i32);
+                }
+                None => {
+                    return Err(ParseError::generator(&messages::UNKNOWN_FUNCTION, &[name, &self.function_hint(name)], pos));
+                }
+            },
+            Expr::Fn(FnExpr::Var { var, pos }) => {
+                let idx = get_variable_index(&function.variables, &var.name, pos)?;
+                instr.local_get(idx as u32);
+            }
+            Expr::Str(_) => {
+                return Err(ParseError::generator(
+                    &messages::NUMERIC_ONLY,
+                    &[&grammar::KW_LET],
+                    pos,
+                ));
             }
         }
 
@@ -454,28 +1525,146 @@ impl CodeGenerator {
         Ok(())
     }
 
-    pub fn gen_for_loop(
+    // return expr: the value is the program exit code (int)
+    fn gen_return(
+        &mut self,
+        expr: &Expr,
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+        pos: &Position,
+    ) -> Result<(), ParseError> {
+        match expr {
+            Expr::Num(num_expr) => {
+                self.gen_expression_as(num_expr, instr, Ty::I32, function)?;
+            }
+            _ => {
+                return Err(ParseError::generator(
+                    &messages::NUMERIC_ONLY,
+                    &[&grammar::KW_RETURN],
+                    pos,
+                ));
+            }
+        }
+        self.gen_function_exit(instr, function);
+        instr.return_();
+        Ok(())
+    }
+
+    // The on_function_exit hook, where `function` returns
+    fn gen_function_exit(&mut self, instr: &mut InstructionSink<'_>, function: &ParserFunction) {
+        if let Some(hook) = self.hooks.as_deref_mut().and_then(|h| h.on_function_exit.as_mut()) {
+            let ctx = HookContext {
+                function,
+                fn_id: self.fn_map[&function.name] as u32,
+                fn_map: self.fn_map,
+            };
+            hook(&ctx, instr);
+        }
+    }
+
+    // match: the value is computed once, then either
+    //
+    //   block $end                              block $end
+    //     block $default                          (value in case 0) if .. br $end end
+    //       block $case_n-1                       (value in case 1) if .. br $end end
+    //         ...                                 ...
+    //           block $case_0                     default
+    //             br_table (value - min)        end
+    //           end  case 0; br $end
+    //         ...
+    //       end  case n-1; br $end
+    //     end  default
+    //   end
+    //
+    // (br_table for dense values, see BR_TABLE_MIN_CASES, else the comparisons on the right)
+    fn gen_match(
+        &mut self,
+        value: &NumExpr,
+        arms: &[MatchArm],
+        default: Option<&Vec<Stadment>>,
+        instr: &mut InstructionSink<'_>,
+        function: &ParserFunction,
+    ) -> Result<(), ParseError> {
+        let tmp = self.alloc_tmp(Ty::I32, "match");
+        self.gen_expression_as(value, instr, Ty::I32, function)?;
+        instr.local_set(tmp);
+
+        let values = arms.iter().flat_map(|arm| &arm.values);
+        let count = values.clone().count();
+        let (min, max) = values.fold((i32::MAX, i32::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+        let spread = max as i64 - min as i64 + 1; // no value: negative
+        let dense = count >= BR_TABLE_MIN_CASES && spread <= (count * BR_TABLE_MAX_SPREAD) as i64;
+
+        instr.block(BlockType::Empty); // $end
+        if dense {
+            let n = arms.len() as u32;
+            instr.block(BlockType::Empty); // $default
+            for _ in arms {
+                instr.block(BlockType::Empty); // $case_i, the last one outermost
+            }
+            // entry k: the depth of the block of the case holding min + k, else of $default
+            let mut targets = vec![n; spread as usize];
+            for (i, arm) in arms.iter().enumerate() {
+                for &v in &arm.values {
+                    targets[(v as i64 - min as i64) as usize] = i as u32;
+                }
+            }
+            instr.local_get(tmp);
+            instr.i32_const(min).i32_sub();
+            instr.br_table(targets, n);
+            for (i, arm) in arms.iter().enumerate() {
+                instr.end(); // $case_i
+                self.gen_statements(&arm.body, instr, function)?;
+                instr.br(n - i as u32); // $end
+            }
+            instr.end(); // $default
+        } else {
+            for arm in arms {
+                for (j, &v) in arm.values.iter().enumerate() {
+                    instr.local_get(tmp).i32_const(v).i32_eq();
+                    if j > 0 {
+                        instr.i32_or();
+                    }
+                }
+                instr.if_(BlockType::Empty);
+                self.gen_statements(&arm.body, instr, function)?;
+                instr.br(1); // $end
+                instr.end();
+            }
+        }
+        if let Some(default) = default {
+            self.gen_statements(default, instr, function)?;
+        }
+        instr.end(); // $end
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn gen_for_loop(
         &mut self,
         var: &Variable,
         start: &Expr,
         end: &Expr,
         step: Option<&Expr>,
         body: &Vec<Stadment>,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
-        fnc: &mut wasm_encoder::Function,
         pos: &Position,
     ) -> Result<(), ParseError> {
+        let end_idx = self.alloc_tmp(var.ty, "for_end");
+        let step_idx = self.alloc_tmp(var.ty, "for_step");
+
         // --- i = start ---
         match start {
             Expr::Num(num_expr) => {
-                self.gen_expression_as(&num_expr, instr, var.ty, function)?;
+                self.gen_expression_as(num_expr, instr, var.ty, function)?;
             }
             _ => {
-                return Err(ParseError::Generator {
-                    pos: pos.clone(),
-                    msg: "only numeric expressions are supported in for loop start".to_string(),
-                });
+                return Err(ParseError::generator(
+                    &messages::NUMERIC_ONLY,
+                    &[&grammar::KW_FOR],
+                    pos,
+                ));
             }
         }
         let var_idx = get_variable_index(&function.variables, &var.name, pos)? as u32;
@@ -484,42 +1673,39 @@ impl CodeGenerator {
         // --- end ---
         match end {
             Expr::Num(num_expr) => {
-                self.gen_expression_as(&num_expr, instr, var.ty, function)?;
+                self.gen_expression_as(num_expr, instr, var.ty, function)?;
             }
             _ => {
-                return Err(ParseError::Generator {
-                    pos: pos.clone(),
-                    msg: "only numeric expressions are supported in for loop end".to_string(),
-                });
+                return Err(ParseError::generator(
+                    &messages::NUMERIC_ONLY,
+                    &[&grammar::KW_TO],
+                    pos,
+                ));
             }
         }
-
-        let var_idx = function.variables.len() as u32; // next free local index 
-        locals.push((1, ValType::I32));
-        instr.local_set(var_idx + 1); // end
-        let fn_id = self.fn_map[&function.name] as u32;
-        let mut local_names = IndirectNameMap::new();
-        local_names.append(fn_id, &fn_locals);
-        self.names.locals(&local_names);
+        instr.local_set(end_idx); // end
 
         // --- step (par défaut = 1) ---
         if let Some(step_expr) = step {
             match step_expr {
                 Expr::Num(num_expr) => {
-                    self.gen_expression_as(&num_expr, instr, var.ty, function)?;
+                    self.gen_expression_as(num_expr, instr, var.ty, function)?;
                 }
                 _ => {
-                    return Err(ParseError::Generator {
-                        pos: pos.clone(),
-                        msg: "only numeric expressions are supported in for loop step".to_string(),
-                    });
+                    return Err(ParseError::generator(
+                        &messages::NUMERIC_ONLY,
+                        &[&grammar::KW_STEP],
+                        pos,
+                    ));
                 }
             }
-            instr.local_set(var_idx + 2); // step
         } else {
-            instr.i32_const(1);
-            instr.local_set(var_idx + 2); // step = 1
+            match var.ty {
+                Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => instr.i32_const(1),
+                Ty::F64 => instr.f64_const(1.0.into()),
+            };
         }
+        instr.local_set(step_idx); // step
 
         // ------------------------------------------------------------------
         //  block $exit
@@ -538,35 +1724,46 @@ impl CodeGenerator {
         instr.loop_(BlockType::Empty);
 
         // step > 0 ?
-        instr.local_get(var_idx + 2);
-        instr.i32_const(0);
-        instr.i32_gt_s();
+        instr.local_get(step_idx);
+        match var.ty {
+            Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => instr.i32_const(0).i32_gt_s(),
+            Ty::F64 => instr.f64_const(0.0.into()).f64_gt(),
+        };
         instr.if_(BlockType::Empty);
         {
             // --- branche step > 0 : sortir si i > end ---
             instr.local_get(var_idx); // i
-            instr.local_get(var_idx + 1); // end
-            instr.i32_gt_s();
-            // br_if depth=1 => saute le 'loop' et va au 'block' (break)
-            instr.br_if(1);
+            instr.local_get(end_idx); // end
+            match var.ty {
+                Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => instr.i32_gt_s(),
+                Ty::F64 => instr.f64_gt(),
+            };
+            // br_if depth=2 => saute le 'if' et le 'loop' et va au 'block' (break)
+            instr.br_if(2);
         }
         instr.else_();
         {
             // --- branche step <= 0 : sortir si i < end ---
             instr.local_get(var_idx); // i
-            instr.local_get(var_idx + 1); // end
-            instr.i32_lt_s();
-            instr.br_if(1); // br_if depth=1 => saute le 'loop' et va au 'block' (break)
+            instr.local_get(end_idx); // end
+            match var.ty {
+                Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => instr.i32_lt_s(),
+                Ty::F64 => instr.f64_lt(),
+            };
+            instr.br_if(2); // br_if depth=2 => saute le 'if' et le 'loop' et va au 'block' (break)
         }
         instr.end(); // fin du if
 
         // --- body ---
-        self.gen_statements(body, instr, function, fnc)?;
+        self.gen_statements(body, instr, function)?;
 
         // --- i = i + step ---
         instr.local_get(var_idx);
-        instr.local_get(var_idx + 2);
-        instr.i32_add();
+        instr.local_get(step_idx);
+        match var.ty {
+            Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => instr.i32_add(),
+            Ty::F64 => instr.f64_add(),
+        };
         instr.local_set(var_idx);
 
         // br $loop
@@ -579,30 +1776,97 @@ impl CodeGenerator {
         Ok(())
     }
 
-    pub fn gen_statements(
+    fn gen_statements(
         &mut self,
         statements: &Vec<Stadment>,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
-        fnc: &mut wasm_encoder::Function,
     ) -> Result<(), ParseError> {
         for st in statements {
-            self.gen_statement(st, instr, function, fnc)?;
+            self.gen_statement(st, instr, function)?;
         }
         Ok(())
     }
 
-    pub fn gen_statement(
+    fn gen_statement(
         &mut self,
         stdm: &Stadment,
-        instr: &mut wasm_encoder::InstructionSink<'_>,
+        instr: &mut InstructionSink<'_>,
         function: &ParserFunction,
-        fnc: &mut wasm_encoder::Function,
     ) -> Result<(), ParseError> {
+        if self.debug
+            && let Some(pos) = stdm.pos()
+        {
+            instr.nop(); // taken out by gen_body
+            self.starts.push(pos.clone());
+        }
+        if let Some(hook) = self.hooks.as_deref_mut().and_then(|h| h.on_statement.as_mut()) {
+            let ctx = HookContext {
+                function,
+                fn_id: self.fn_map[&function.name] as u32,
+                fn_map: self.fn_map,
+            };
+            hook(&ctx, stdm, instr);
+        }
+        if self.track_position
+            && let Some(pos) = stdm.pos()
+            && let Some(file) = self.sources.iter().position(|(path, _)| *path == pos.file_name)
+        {
+            instr.i64_const(meta::encode_pos(file, pos.line, pos.col));
+            instr.global_set(POS_GLOBAL_IDX);
+        }
         match stdm {
-            Stadment::Print(str_expr) => self.gen_print(str_expr, instr, function, false)?,
-            Stadment::Println(str_expr) => self.gen_print(str_expr, instr, function, true)?,
-            Stadment::Call { name, pos } => self.gen_call_function(name, instr, pos)?,
+            Stadment::Print { items, .. } => self.gen_print(items, instr, function, false, "env.log")?,
+            Stadment::Println { items, .. } => self.gen_print(items, instr, function, true, "env.log")?,
+            Stadment::EPrint { items, .. } => self.gen_print(items, instr, function, false, "env.log_err")?,
+            Stadment::EPrintln { items, .. } => self.gen_print(items, instr, function, true, "env.log_err")?,
+            Stadment::Call { name, args, pos, .. } => self.gen_call_function(name, args, instr, function, pos)?,
+            Stadment::CallIndirect { var, pos } => {
+                // the table slot held by the variable; wasm checks at run time that the
+                // function there has the type () -> () (not main, which returns its exit code)
+                let idx = get_variable_index(&function.variables, &var.name, pos)?;
+                instr.local_get(idx as u32);
+                instr.call_indirect(0, self.ty_void);
+            }
+            Stadment::MapSet { var, key, value, pos } => {
+                // map_set creates the map when the variable still holds 0, and moves it when it
+                // grows: the variable takes the address it returns
+                self.gen_map_key(var, key, pos, instr, function)?;
+                self.gen_expression_as(value, instr, Ty::I32, function)?;
+                instr.call(self.fn_map["rt.map_set"] as u32); // (m,kp,kl,v)->(m)
+                let idx = get_variable_index(&function.variables, &var.name, pos)?;
+                instr.local_set(idx as u32);
+            }
+            Stadment::MapDelete { var, key, pos } => {
+                self.gen_map_key(var, key, pos, instr, function)?;
+                instr.call(self.fn_map["rt.map_delete"] as u32); // (m,kp,kl)->()
+            }
+            Stadment::Append { var, items, pos } => {
+                // builder_append makes the builder when the variable still holds 0
+                let idx = get_variable_index(&function.variables, &var.name, pos)? as u32;
+                let append = self.fn_map["rt.builder_append"] as u32;
+                let mut made = None; // (ptr,len) temporaries of the strings made here
+                for item in items {
+                    instr.local_get(idx);
+                    self.gen_str_value(item, instr, function)?;
+                    if owns(item) {
+                        let (ptr, len) = *made.get_or_insert_with(|| {
+                            (self.alloc_tmp(Ty::I32, "app_ptr"), self.alloc_tmp(Ty::I32, "app_len"))
+                        });
+                        instr.local_set(len).local_tee(ptr).local_get(len);
+                        instr.call(append).local_set(idx); // (b,ptr,len)->(b)
+                        instr.local_get(ptr).call(self.fn_map["rt.free"] as u32);
+                    } else {
+                        instr.call(append).local_set(idx);
+                    }
+                }
+            }
+            Stadment::Clear { var, pos } => {
+                let idx = get_variable_index(&function.variables, &var.name, pos)? as u32;
+                instr.local_get(idx).if_(BlockType::Empty);
+                instr.local_get(idx).i32_const(0).i32_store(runtime::B_LEN);
+                instr.end();
+            }
             Stadment::Assignment { var, expr, pos } => {
                 self.gen_assignment(var, expr, instr, function, pos)?
             }
@@ -622,81 +1886,104 @@ impl CodeGenerator {
                     body,
                     instr,
                     function,
-                    fnc,
                     pos,
                 )?;
             }
+            Stadment::Return { expr, pos } => self.gen_return(expr, instr, function, pos)?,
+            Stadment::Match { value, arms, default, .. } => {
+                self.gen_match(value, arms, default.as_ref(), instr, function)?
+            }
+            Stadment::Flush => {
+                instr.call(self.fn_map["env.flush"] as u32);
+            }
+            Stadment::DumpHeap { region, .. } => {
+                match region {
+                    Some((start, len)) => {
+                        self.gen_expression_as(start, instr, Ty::I32, function)?;
+                        self.gen_expression_as(len, instr, Ty::I32, function)?;
+                    }
+                    None => {
+                        instr.i32_const(-1).i32_const(0);
+                    }
+                }
+                instr.call(self.fn_map["env.dump_heap"] as u32);
+            }
+        }
+        if let Some(hook) = self.hooks.as_deref_mut().and_then(|h| h.on_statement_end.as_mut()) {
+            let ctx = HookContext {
+                function,
+                fn_id: self.fn_map[&function.name] as u32,
+                fn_map: self.fn_map,
+            };
+            hook(&ctx, stdm, instr);
         }
         Ok(())
     }
 
-    pub fn get_tmp_locals(
-        &mut self,
-        statements: &[Stadment], // ← &[] plutôt que &Vec
-        start_index: u32,
-        fn_id: u32,
-    ) -> Vec<(u32, ValType)> {
-        use wasm_encoder::{IndirectNameMap, NameMap, ValType};
+    // Reserve a fresh temporary local of type `ty` in the function being generated.
+    // Temporaries come after the declared variables; `name` shows up in the WAT.
+    fn alloc_tmp(&mut self, ty: Ty, name: &str) -> u32 {
+        let idx = self.tmp_base + self.tmp_locals.len() as u32;
+        self.tmp_locals.push((val_type(ty), format!("_{}_{}", name, idx)));
+        idx
+    }
 
-        let mut fn_locals = NameMap::new();
+    // The code of `function` (its locals, then its body) and the names of its locals
+    fn gen_body(mut self, function: &ParserFunction) -> Result<GeneratedBody, ParseError> {
+        let is_main = function.name == grammar::KW_MAIN;
+        let fn_id = self.fn_map[&function.name] as u32;
 
-        // index logique courant (params + locales déjà allouées avant ces temporaires)
-        let mut local_index = start_index;
+        // si ta fonction n'a pas de paramètres :
+        let param_count = 0;
 
-        // on ne crée qu’un seul groupe (count, ValType::I32) à la fin
-        let mut tmp_i32_count: u32 = 0;
+        let mut fn_locals = NameMap::new();
+        let mut locals = self.gen_variables(&function.variables, param_count, &mut fn_locals);
+        self.tmp_base = param_count + function.variables.len() as u32;
+        self.tmp_locals.clear();
+
+        // The body is generated first: temporaries are only known once it is done.
+        let mut body = Vec::new();
+        let mut instr = InstructionSink::new(&mut body);
+
+        if let Some(hook) = self.hooks.as_deref_mut().and_then(|h| h.on_function_enter.as_mut()) {
+            let ctx = HookContext {
+                function,
+                fn_id,
+                fn_map: self.fn_map,
+            };
+            hook(&ctx, &mut instr);
+        }
 
-        for st in statements {
-            if let Stadment::ForLoop { .. } = st {
-                // Deux temporaires i32 par for: end et step
-                tmp_i32_count += 2;
+        self.gen_statements(&function.body, &mut instr, function)?;
 
-                // Noms lisibles pour chaque local alloué
-                local_index += 1;
-                fn_locals.append(local_index, &format!("_for_end_{}", local_index));
-                local_index += 1;
-                fn_locals.append(local_index, &format!("_for_step_{}", local_index));
-            }
+        // main without a final return exits with 0
+        if is_main && !matches!(function.body.last(), Some(Stadment::Return { .. })) {
+            instr.i32_const(0);
         }
+        self.gen_function_exit(&mut instr, function);
 
-        // Ajoute les noms de locaux à la section Name (si besoin)
-        if tmp_i32_count > 0 {
-            let mut local_names = IndirectNameMap::new();
-            local_names.append(fn_id, &fn_locals);
-            self.names.locals(&local_names);
+        instr.end();
+        let mut marks = Vec::new();
+        if self.debug {
+            (body, marks) = sourcemap::take_marks(&body);
         }
-
-        // Retour direct: un seul groupe I32 si nécessaire, sans recopie
-        if tmp_i32_count > 0 {
-            vec![(tmp_i32_count, ValType::I32)]
-        } else {
-            Vec::new()
+        if self.options.opt_level >= OptLevel::O1 {
+            body = peephole::optimize_body(&body, &mut marks);
         }
-    }
-
-    pub fn gen_function(&mut self, function: &ParserFunction) -> Result<(), ParseError> {
-        self.functions.function(self.ty_void); // () -> ()
-
-        let fn_id = self.fn_map[&function.name] as u32;
 
-        // si ta fonction n'a pas de paramètres :
-        let param_count = 0;
-
-        let mut locals = self.gen_variables(&function.variables, fn_id, param_count);
-        locals.append(&mut  self.get_tmp_locals(&function.body, locals.len() as u32, fn_id));
+        for (idx, (val_ty, name)) in (self.tmp_base..).zip(&self.tmp_locals) {
+            locals.push((1, *val_ty));
+            fn_locals.append(idx, name);
+        }
 
         let mut fnc = wasm_encoder::Function::new(locals);
-
-        let mut instr = fnc.instructions();
-
-        self.gen_statements(&function.body, &mut instr, function, &mut fnc)?;
-
-        instr.end();
-        self.code.function(&fnc);
-        Ok(())
+        fnc.raw(body);
+        Ok((fnc, fn_locals, marks.into_iter().zip(self.starts).collect()))
     }
+}
 
-    // Define an imported function (module, name, (params)->(results))
+impl CodeGenerator {
+    // Define an imported function (module, name, (params)->(results)), known as "module.name"
     pub fn push_imported_function(
         &mut self,
         module: &str,
@@ -710,9 +1997,131 @@ impl CodeGenerator {
             .function(params.iter().copied(), results.iter().copied());
         self.imports
             .import(module, name, EntityType::Function(fn_type));
-        self.fn_names.append(self.fn_idx, name);
-        self.fn_map.insert(name.into(), self.fn_idx as i32);
+        // imports are known as "module.name" so they never clash with MPL functions
+        let qualified = format!("{}.{}", module, name);
+        self.fn_names.append(self.fn_idx, &qualified);
+        self.fn_map.insert(qualified, self.fn_idx as i32);
+        self.fn_idx += 1;
+    }
+
+    // Emit the runtime functions, known as "rt.<name>" (see runtime.rs)
+    fn gen_runtime(&mut self) {
+        let [nan, minus_inf, minus_zero] = runtime::TEXTS.map(|s| {
+            let blob = self.data.blob(s);
+            (blob.ptr, blob.len)
+        });
+        let runtime = Runtime {
+            heap_ptr: 0,
+            data_end: 1,
+            free_list: 2,
+            alloc: self.fn_idx, // first runtime function
+            memory: self.memory_ops,
+            nan,
+            minus_inf,
+            minus_zero,
+        };
+        for rt in &runtime::ALL {
+            self.push_runtime_function(rt, &runtime.body(rt));
+        }
+    }
+
+    // One function per passive segment, known as "rt.init_data_<k>", called before each use of
+    // its text: the first call writes the text (growing memory up to it) and drops the segment,
+    // the flag global of the segment telling the next calls it is done.
+    fn gen_data_init(&mut self) {
+        let first_flag = self.data_flags();
+        for (k, blob) in self.data.passive.iter().enumerate() {
+            let segment = self.data.first_passive() + k as u32;
+            let flag = first_flag + k as u32;
+            let end = blob.ptr as u64 + blob.len as u64;
+            let mut f = Function::new([]);
+            let mut i = f.instructions();
+            i.global_get(flag).if_(BlockType::Empty).return_().end();
+            i.i32_const(end as i32).memory_size(0).i32_const(16).i32_shl().i32_gt_u();
+            i.if_(BlockType::Empty);
+            i.i32_const(end.div_ceil(PAGE_SIZE as u64) as i32).memory_size(0).i32_sub();
+            i.memory_grow(0).i32_const(-1).i32_eq();
+            i.if_(BlockType::Empty).unreachable().end();
+            i.end();
+            i.i32_const(blob.ptr as i32).i32_const(0).i32_const(blob.len as i32);
+            i.memory_init(0, segment);
+            i.data_drop(segment);
+            i.i32_const(1).global_set(flag);
+            i.end();
+
+            self.functions.function(self.ty_void);
+            let name = format!("rt.init_data_{}", k);
+            self.fn_names.append(self.fn_idx, &name);
+            self.fn_map.insert(name, self.fn_idx as i32);
+            self.fn_idx += 1;
+            self.code.function(&f);
+        }
+    }
+
+    // Index of the flag global of the first passive segment, after heap_ptr, data_end,
+    // free_list and mpl.pos
+    fn data_flags(&self) -> u32 {
+        3 + self.tracks_position() as u32
+    }
+
+    // Without bulk memory, emit the byte loops copying and filling memory
+    fn gen_memory_runtime(&mut self) {
+        let first = self.fn_idx;
+        for rt in &runtime::MEMORY {
+            self.push_runtime_function(rt, &runtime::memory_body(rt));
+        }
+        self.memory_ops = MemoryOps::Calls {
+            copy: first,
+            fill: first + 1,
+        };
+    }
+
+    // Emit the map functions, in the program or in a library (they call rt.alloc and rt.free,
+    // imported by a library)
+    fn gen_map_runtime(&mut self) {
+        let maps = MapRuntime {
+            alloc: self.fn_map["rt.alloc"] as u32,
+            free: self.fn_map["rt.free"] as u32,
+            first: self.fn_idx,
+            memory: self.memory_ops,
+        };
+        for rt in &runtime::MAPS {
+            self.push_runtime_function(rt, &maps.body(rt));
+        }
+    }
+
+    // Emit repeat() and the builder functions, like the map functions
+    fn gen_builder_runtime(&mut self) {
+        let builders = BuilderRuntime {
+            alloc: self.fn_map["rt.alloc"] as u32,
+            free: self.fn_map["rt.free"] as u32,
+            memory: self.memory_ops,
+        };
+        for rt in &runtime::BUILDERS {
+            self.push_runtime_function(rt, &builders.body(rt));
+        }
+    }
+
+    // Declare the runtime function `rt` as "rt.<name>", with its body
+    fn push_runtime_function(&mut self, rt: &RuntimeFn, body: &Function) {
+        let fn_type = self.types.len();
+        self.types
+            .ty()
+            .function(rt.params.iter().copied(), rt.results.iter().copied());
+        self.functions.function(fn_type);
+
+        let qualified = format!("rt.{}", rt.name);
+        self.fn_names.append(self.fn_idx, &qualified);
+        let mut locals = NameMap::new();
+        let names = rt.param_names.iter().chain(rt.locals.iter().map(|(n, _)| n));
+        for (idx, name) in names.enumerate() {
+            locals.append(idx as u32, name);
+        }
+        self.local_names.append(self.fn_idx, &locals);
+        self.fn_map.insert(qualified, self.fn_idx as i32);
         self.fn_idx += 1;
+
+        self.code.function(body);
     }
 
     pub fn generate_wasm(
@@ -721,83 +2130,209 @@ impl CodeGenerator {
         prog: &Program,
     ) -> Result<Vec<u8>, ParseError> {
         self.names.module(&prog_name);
+        check_declarations(prog)?;
+        let optimized;
+        let prog = if self.options.opt_level > OptLevel::O0 {
+            let mut copy = prog.clone();
+            optimize::optimize(&mut copy, self.options.opt_level);
+            optimized = copy;
+            &optimized
+        } else {
+            prog
+        };
 
-        // 1) Types: ()->() en type 0
+        // 1) Types: ()->() en type 0, ()->i32 (main) en type 1
         self.types.ty().function([], []); // () -> ()
         self.ty_void = 0;
+        self.types.ty().function([], [ValType::I32]); // () -> i32
+        self.ty_main = 1;
 
-        // 2) Imports (fonctions + mémoire)
-        // env.log(ptr,len) -> ()
-        self.push_imported_function("env", "log", &[ValType::I32, ValType::I32], &[]);
-        // str.to_str_i32(n) -> (ptr,len)
-        self.push_imported_function(
-            "str",
-            "to_str_i32",
-            &[ValType::I32],
-            &[ValType::I32, ValType::I32],
-        );
-        // str.to_str_f64(n) -> (ptr,len)
-        self.push_imported_function(
-            "str",
-            "to_str_f64",
-            &[ValType::F64],
-            &[ValType::I32, ValType::I32],
-        );
-        // str.concat(s1_ptr,s1_len,s2_ptr,s2_len) -> (ptr,len)
-        self.push_imported_function(
-            "str",
-            "concat",
-            &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
-            &[ValType::I32, ValType::I32],
-        );
+        // 2) Imports (fonctions + mémoire): only the host functions the program uses
+        let mut used = scan_program(prog);
+        if used.float_texts && self.options.float_format == FloatFormat::Shortest {
+            used.imports.insert("str.to_str_shortest".to_string());
+        }
+        for &(module, name, params, results) in HOST_IMPORTS {
+            if (module, name) == ("env", "log") || used.imports.contains(&format!("{}.{}", module, name)) {
+                self.push_imported_function(module, name, params, results);
+            }
+        }
+        // host functions requested by the instrumentation hooks
+        for import in std::mem::take(&mut self.hooks.imports) {
+            self.push_imported_function(&import.module, &import.name, &import.params, &import.results);
+        }
+        // host functions declared `extern fn`, called by their (mangled) name; the runner
+        // defines the names of the runtime with their own types
+        for e in &prog.externs {
+            let taken = (e.module.as_str(), e.field.as_str()) == ("env", "memory")
+                || HOST_IMPORTS.iter().any(|&(module, name, ..)| (module, name) == (e.module.as_str(), e.field.as_str()));
+            if taken {
+                let import = format!("{}.{}", e.module, e.field);
+                return Err(ParseError::generator(&messages::EXTERN_NAME_TAKEN, &[&e.name, &import], &e.pos));
+            }
+            let params: Vec<ValType> = e.params.iter().map(|p| val_type(p.ty)).collect();
+            self.push_imported_function(&e.module, &e.field, &params, &[]);
+            self.fn_map.insert(e.name.clone(), self.fn_idx as i32 - 1);
+            self.externs.insert(e.name.clone(), e.params.iter().map(|p| p.ty).collect());
+        }
+        // functions of the wasm libraries, called by their (mangled) name
+        for linked in &prog.linked {
+            self.push_imported_function(&linked.module, &linked.field, &[], &[]);
+            self.fn_map.insert(linked.name.clone(), self.fn_idx as i32 - 1);
+        }
+        // a library uses the runtime of the program, and its data is where the runner put it
+        if self.library {
+            for rt in &runtime::ALL {
+                self.push_imported_function("rt", rt.name, rt.params, rt.results);
+            }
+            self.imports.import(
+                "rt",
+                "data_base",
+                EntityType::Global(GlobalType {
+                    val_type: ValType::I32,
+                    mutable: false,
+                    shared: false,
+                }),
+            );
+            self.data_base = 0; // first global
+        }
 
         // Mémoire importée: env.memory
         self.imports.import(
             "env",
             "memory",
             EntityType::Memory(MemoryType {
-                minimum: 1,
-                maximum: None,
+                minimum: self.memory.min_pages as u64,
+                maximum: self.memory.max_pages.map(u64::from),
                 memory64: false,
                 shared: false,
                 page_size_log2: None,
             }),
         );
 
-        // 3) Déclarations des fonctions (lib + programme + main)
-        for f in &prog.functions {
-            self.declare_function(f);
+        // Constant strings: laid out before any code refers to them
+        if self.options.passive_data && !self.library {
+            self.data.passive_min = Some(PASSIVE_DATA_MIN);
+        }
+        for text in &used.literals {
+            self.data.request(text, 1);
+        }
+        if !self.library {
+            for text in runtime::TEXTS {
+                self.data.request(text, 1);
+            }
+        }
+        self.data.plan();
+
+        // 3) Fonctions du runtime (allocation, concat, to_str), puis celles du programme
+        if !self.options.features.bulk_memory {
+            self.gen_memory_runtime();
         }
-        for f in &prog.main_program.functions {
+        if !self.library {
+            self.gen_runtime();
+            self.gen_data_init();
+        }
+        if used.maps {
+            self.gen_map_runtime();
+        }
+        if used.builders {
+            self.gen_builder_runtime();
+        }
+
+        let functions = prog
+            .functions
+            .iter()
+            .chain(&prog.main_program.functions)
+            .chain(&prog.main_program.main);
+        // every function is declared before any body is generated: a call can go to a
+        // function defined further down, or in a file loaded later
+        for f in functions.clone() {
             self.declare_function(f);
         }
-        self.declare_function(&prog.main_program.main);
+        // the table of the functions given to fn variables
+        let mut referenced: Vec<(u32, &String)> = used
+            .fn_refs
+            .iter()
+            .filter_map(|name| self.fn_map.get(name).map(|&idx| (idx as u32, name)))
+            .collect();
+        referenced.sort_unstable();
+        if !referenced.is_empty() || used.indirect_calls {
+            let mut table = Vec::new();
+            for (slot, (idx, name)) in referenced.into_iter().enumerate() {
+                table.push(idx);
+                self.slots.insert(name.clone(), slot as u32 + 1);
+            }
+            self.table = Some(table);
+        }
 
         // 4) Noms
         self.names.functions(&self.fn_names);
 
-        // 5) Génération du code
-        for f in &prog.functions {
-            self.gen_function(&f)?;
+        // 5) Génération du code: the bodies in parallel, unless hooks have to see them in order
+        if self.hooks.on_function_enter.is_some()
+            || self.hooks.on_function_exit.is_some()
+            || self.hooks.on_statement.is_some()
+        {
+            for f in functions {
+                self.gen_function(f)?;
+            }
+        } else {
+            let functions: Vec<&ParserFunction> = functions.collect();
+            let shared = self.shared();
+            let bodies: Vec<_> = functions
+                .par_iter()
+                .map(|f| FunctionGen::new(shared, None).gen_body(f))
+                .collect();
+            for (f, body) in functions.into_iter().zip(bodies) {
+                self.add_function(f, body?);
+            }
         }
-        for f in &prog.main_program.functions {
-            self.gen_function(&f)?;
+        self.names.locals(&self.local_names);
+
+        if self.library {
+            return Ok(self.finish_library(prog));
         }
-        self.gen_function(&prog.main_program.main)?;
 
         // 6) Export de main (dernier index déclaré dans notre mapping)
-        self.exports.export(
-            "main",
-            ExportKind::Func,
-            self.fn_map.len().saturating_sub(1).try_into().unwrap(),
-        );
+        self.exports
+            .export("main", ExportKind::Func, self.fn_map[grammar::KW_MAIN] as u32);
+        // the runtime, for the wasm libraries
+        if !prog.linked.is_empty() {
+            for rt in &runtime::ALL {
+                let name = format!("rt.{}", rt.name);
+                self.exports.export(&name, ExportKind::Func, self.fn_map[&name] as u32);
+            }
+        }
+
+        // `export fn`: under their source name, for the hosts that embed the module
+        let mut taken: HashSet<String> = ["main", "heap_ptr", "data_end", "free_list"].map(String::from).into();
+        if !prog.linked.is_empty() {
+            taken.extend(runtime::ALL.iter().map(|rt| format!("rt.{}", rt.name)));
+        }
+        let exported = prog.functions.iter().chain(&prog.main_program.functions).filter(|f| f.export);
+        for f in exported {
+            let name = f.name.rsplit("::").next().unwrap_or(&f.name);
+            if !taken.insert(name.to_string()) {
+                return Err(ParseError::generator(&messages::EXPORT_NAME_TAKEN, &[&name], &f.pos));
+            }
+            self.exports.export(name, ExportKind::Func, self.fn_map[&f.name] as u32);
+        }
 
         // 7) Global 'heap_ptr' exporté
         //
         //     - valeur initiale = fin de la zone de données (alignée à 16)
-        //     - mutable: l'hôte Wasmi mettra à jour ce pointeur (bump allocator)
+        //     - mutable: rt.alloc et l'hôte (args_get) mettent à jour ce pointeur
         //
-        let heap_start = align_up(self.data_idx, 16);
+        let heap_start = align_up(self.data.end(), 16);
+        // the active data segment is written at instantiation: it must fit in min_pages
+        let initial = self.memory.min_pages as u64 * PAGE_SIZE as u64;
+        if align_up(self.data.active_end, 16) as u64 > initial {
+            return Err(ParseError::generator(
+                &messages::DATA_TOO_LARGE,
+                &[&heap_start, &self.memory.min_pages],
+                &prog.main_program.main.as_ref().map_or_else(|| Position::new(prog_name.into()), |m| m.pos.clone()),
+            ));
+        }
         self.globals.global(
             GlobalType {
                 val_type: ValType::I32,
@@ -806,19 +2341,156 @@ impl CodeGenerator {
             },
             &ConstExpr::i32_const(heap_start as i32),
         );
-        // C’est le premier (et unique) global => index 0.
+        // C’est le premier global => index 0.
         self.exports.export("heap_ptr", ExportKind::Global, 0);
 
-        // 8) Module final
+        // 8) Global 'data_end' exporté (index 1, immuable)
+        //
+        //     - fin des données constantes: l'hôte n'alloue jamais en dessous
+        //
+        self.globals.global(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: false,
+                shared: false,
+            },
+            &ConstExpr::i32_const(self.data.end() as i32),
+        );
+        self.exports.export("data_end", ExportKind::Global, 1);
+
+        // 8b) Global 'free_list' (index 2): premier bloc libre de rt.alloc, 0 si aucun
+        //     exported for the host to tell the free blocks from the live ones (dump_heap())
+        self.globals.global(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i32_const(0),
+        );
+        self.exports.export("free_list", ExportKind::Global, 2);
+
+        // 8c) Global 'mpl.pos' exporté (index 3): position de l'instruction en cours (--embed-source)
+        if self.tracks_position() {
+            self.globals.global(
+                GlobalType {
+                    val_type: ValType::I64,
+                    mutable: true,
+                    shared: false,
+                },
+                &ConstExpr::i64_const(0),
+            );
+            self.exports.export(POS_GLOBAL, ExportKind::Global, POS_GLOBAL_IDX);
+        }
+
+        // 8d) Flags of the passive data segments (from data_flags()): 1 once written
+        for _ in &self.data.passive {
+            self.globals.global(
+                GlobalType {
+                    val_type: ValType::I32,
+                    mutable: true,
+                    shared: false,
+                },
+                &ConstExpr::i32_const(0),
+            );
+        }
+
+        // 9) Module final
+        let mut data = DataSection::new();
+        let active = &self.data.image[..self.data.active_end as usize];
+        if !active.is_empty() {
+            data.active(0, &ConstExpr::i32_const(0), active.iter().copied());
+        }
+        for blob in &self.data.passive {
+            data.passive(self.data.image[blob.ptr as usize..(blob.ptr + blob.len) as usize].iter().copied());
+        }
+        Ok(self.finish_module(&data, None))
+    }
+
+    // Library module: public functions exported, all the data in one segment at rt.data_base
+    fn finish_library(&mut self, prog: &Program) -> Vec<u8> {
+        for f in prog.main_program.functions.iter().filter(|f| f.public) {
+            self.exports.export(&f.name, ExportKind::Func, self.fn_map[&f.name] as u32);
+        }
+        let mut data = DataSection::new();
+        if !self.data.image.is_empty() {
+            data.active(0, &ConstExpr::global_get(self.data_base), self.data.image.iter().copied());
+        }
+        let info = CustomSection {
+            name: LIBRARY_SECTION.into(),
+            data: self.data.end().to_le_bytes().to_vec().into(),
+        };
+        self.finish_module(&data, Some(&info))
+    }
+
+    fn finish_module(&mut self, data: &DataSection, custom: Option<&CustomSection<'_>>) -> Vec<u8> {
         let mut module = Module::new();
         module.section(&self.types);
         module.section(&self.imports);
         module.section(&self.functions);
+        if let Some(table) = &self.table {
+            let mut tables = TableSection::new();
+            let size = table.len() as u64 + 1; // slot 0 is null
+            tables.table(TableType {
+                element_type: RefType::FUNCREF,
+                table64: false,
+                minimum: size,
+                maximum: Some(size),
+                shared: false,
+            });
+            module.section(&tables);
+        }
         module.section(&self.globals);
         module.section(&self.exports);
+        if let Some(table) = self.table.as_ref().filter(|table| !table.is_empty()) {
+            let mut elements = ElementSection::new();
+            elements.active(None, &ConstExpr::i32_const(1), Elements::Functions(table.as_slice().into()));
+            module.section(&elements);
+        }
+        if !self.data.passive.is_empty() {
+            // memory.init and data.drop name their segment: the count comes before the code
+            module.section(&DataCountSection { count: data.len() });
+        }
         module.section(&self.code);
-        module.section(&self.data);
-        module.section(&self.names);
-        Ok(module.finish())
+        module.section(data);
+        if let Some(custom) = custom {
+            module.section(custom);
+        }
+        if self.tracks_position() {
+            let files: Vec<(String, String)> = self
+                .sources
+                .iter()
+                .map(|(path, text)| (path.to_string_lossy().into_owned(), text.clone()))
+                .collect();
+            module.section(&CustomSection {
+                name: SOURCE_SECTION.into(),
+                data: meta::encode_sources(&files).into(),
+            });
+        }
+        if let Some(meta) = &self.meta {
+            module.section(&CustomSection {
+                name: META_SECTION.into(),
+                data: meta.as_bytes().into(),
+            });
+        }
+        if let Some(url) = &self.source_map {
+            module.section(&sourcemap::url_section(url));
+        }
+        if !self.strip {
+            // the data segments are named after the texts they hold
+            if !self.data.image.is_empty() {
+                let mut data_names = NameMap::new();
+                if self.data.active_end > 0 {
+                    data_names.append(0, &data_name(&self.data.image[..self.data.active_end as usize]));
+                }
+                for (k, blob) in self.data.passive.iter().enumerate() {
+                    let text = &self.data.image[blob.ptr as usize..(blob.ptr + blob.len) as usize];
+                    data_names.append(self.data.first_passive() + k as u32, &data_name(text));
+                }
+                self.names.data(&data_names);
+            }
+            module.section(&self.names);
+        }
+        module.finish()
     }
 }
@@ -0,0 +1,185 @@
+// My Programming Language
+// Line coverage of `mpl run --coverage`: which lines of the sources had a statement run, and
+// how many times. The summary goes to stderr at the end of the run,
+//
+//   coverage:
+//     main.mpl             12/15 lines (80.0%), not run: 7, 8, 21
+//
+// or, with --coverage FILE, an lcov tracefile (SF/DA/LF/LH records, one per source file)
+// for genhtml and the editors.
+//
+// The code generator calls the host before every statement (see CodegenHooks):
+//
+//   i32.const <line id>; call cov.hit
+//
+// Each file:line holding a statement gets a line id when its code is generated: the lines of
+// the functions the optimizer dropped (-O2) are not counted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use wasm_encoder::ValType;
+
+use crate::codegen::CodegenHooks;
+use crate::runner::{HostFunction, HostType, HostValue};
+
+#[derive(Default)]
+struct State {
+    lines: Vec<(PathBuf, usize)>, // file and line of each line id
+    ids: HashMap<(PathBuf, usize), u32>,
+    hits: Vec<u64>, // by line id
+}
+
+impl State {
+    fn line_id(&mut self, file: &Path, line: usize) -> u32 {
+        let key = (file.to_path_buf(), line);
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        let id = self.lines.len() as u32;
+        self.lines.push(key.clone());
+        self.ids.insert(key, id);
+        self.hits.push(0);
+        id
+    }
+
+    // The counts of the lines of each file, files and lines in order
+    fn by_file(&self) -> BTreeMap<&PathBuf, BTreeMap<usize, u64>> {
+        let mut files: BTreeMap<&PathBuf, BTreeMap<usize, u64>> = BTreeMap::new();
+        for ((file, line), hits) in self.lines.iter().zip(&self.hits) {
+            files.entry(file).or_default().insert(*line, *hits);
+        }
+        files
+    }
+}
+
+/// The line coverage of a run: the instrumentation given to the code generator and the host
+/// function given to the runner share it; its Display is the summary.
+#[derive(Clone, Default)]
+pub struct Coverage {
+    state: Arc<Mutex<State>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The call to cov.hit before each statement.
+    pub fn hooks(&self) -> CodegenHooks {
+        let state = Arc::clone(&self.state);
+        CodegenHooks::new()
+            .import("cov", "hit", &[ValType::I32], &[])
+            .on_statement(move |ctx, stdm, instr| {
+                let Some(pos) = stdm.pos() else {
+                    return;
+                };
+                let id = state.lock().unwrap().line_id(&pos.file_name, pos.line);
+                instr.i32_const(id as i32);
+                instr.call(ctx.func_index("cov.hit").expect("cov imports declared"));
+            })
+    }
+
+    /// The cov.hit function of the instrumented module.
+    pub fn host_functions(&self) -> Vec<HostFunction> {
+        let state = Arc::clone(&self.state);
+        vec![HostFunction::new("cov", "hit", &[HostType::I32], &[], move |args| {
+            if let Some(HostValue::I32(id)) = args.first()
+                && let Some(hits) = state.lock().unwrap().hits.get_mut(*id as usize)
+            {
+                *hits += 1;
+            }
+            Ok(Vec::new())
+        })]
+    }
+
+    /// Write the lcov tracefile of the run.
+    pub fn write_lcov<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        writeln!(out, "TN:")?;
+        for (file, lines) in state.by_file() {
+            writeln!(out, "SF:{}", file.display())?;
+            for (line, hits) in &lines {
+                writeln!(out, "DA:{},{}", line, hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|&&hits| hits > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(f, "coverage:")?;
+        for (file, lines) in state.by_file() {
+            let missed: Vec<String> = lines
+                .iter()
+                .filter(|&(_, &hits)| hits == 0)
+                .map(|(line, _)| line.to_string())
+                .collect();
+            let run = lines.len() - missed.len();
+            write!(
+                f,
+                "\n  {:<20} {}/{} lines ({:.1}%)",
+                file.display(),
+                run,
+                lines.len(),
+                run as f64 * 100.0 / lines.len() as f64
+            )?;
+            if !missed.is_empty() {
+                write!(f, ", not run: {}", missed.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::CodeGenerator;
+    use crate::runner::RunOptions;
+    use crate::testing;
+
+    const LOOP: &str = "main() {\n    local int i\n    for i = 1 to 3\n        println(to_str(i))\n    next\n    match i {\n    case 1:\n        println(\"one\")\n    default:\n        println(\"other\")\n    }\n}\n";
+
+    // The coverage of a run of `text`
+    fn covered(text: &str) -> Coverage {
+        let coverage = Coverage::new();
+        let wasm = testing::compile_with(CodeGenerator::with_hooks(coverage.hooks()), text);
+        let options = RunOptions {
+            host: coverage.host_functions().into_iter().collect(),
+            ..RunOptions::default()
+        };
+        let (outcome, _) = testing::run_wasm(&wasm, &options);
+        outcome.expect("the program runs");
+        coverage
+    }
+
+    #[test]
+    fn counts_the_runs_of_each_line() {
+        let mut lcov = Vec::new();
+        covered(LOOP).write_lcov(&mut lcov).unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:test.mpl\n"), "{}", lcov);
+        assert!(lcov.contains("DA:4,3\n"), "{}", lcov);
+        assert!(lcov.contains("DA:8,0\n"), "{}", lcov);
+        assert!(lcov.contains("DA:10,1\n"), "{}", lcov);
+        assert!(lcov.ends_with("end_of_record\n"), "{}", lcov);
+    }
+
+    #[test]
+    fn the_summary_lists_the_lines_not_run() {
+        let summary = covered(LOOP).to_string();
+        assert!(summary.starts_with("coverage:\n  test.mpl"), "{}", summary);
+        assert!(summary.ends_with(", not run: 8"), "{}", summary);
+    }
+}
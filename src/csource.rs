@@ -0,0 +1,1081 @@
+// My Programming Language
+// C backend (--emit=c): the program as readable C, with a tiny runtime giving the host
+// functions of runner.rs, for the machines that have a C compiler but no wasm runtime and for
+// reading what a program really does. The numbers are written from the IR (see ir.rs).
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::codegen::{FloatFormat, Ty, infer_type};
+use crate::grammar::{self, MathFn};
+use crate::ir::{self, Num, NumKind, NumTy};
+use crate::lexer::Position;
+use crate::messages;
+use crate::parser::{BinOp, Expr, FnExpr, Function, MatchArm, NumExpr, ParseError, Program, Stadment, StrExpr, Variable};
+use crate::visit::{Visitor, walk_str_expr};
+
+// The runtime, in C; same behavior as runner.rs (traps print their message and exit with 1).
+// The strings the program makes live until the end of the statement that makes them: MPL has
+// no string variables, so mpl_release() frees them all after each statement.
+const RUNTIME_C: &str = r#"#include <math.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+/* ---- Runtime of MPL programs compiled to C (the host functions of the mpl runner) ---- */
+
+typedef struct { const char *p; int32_t len; } mpl_str; /* UTF-8, not NUL-terminated */
+typedef struct { char *p; int32_t len, cap; } mpl_builder;
+typedef struct { char *key; int32_t key_len, value; } mpl_entry;
+typedef struct { mpl_entry *entries; int32_t len, cap; } mpl_map;
+typedef void (*mpl_fn)(void);
+
+#define mpl_lit(s) ((mpl_str){s, (int32_t)sizeof(s) - 1})
+#define MPL_STRS(...) (mpl_str[]){__VA_ARGS__}, (int32_t)(sizeof((mpl_str[]){__VA_ARGS__}) / sizeof(mpl_str))
+
+static int mpl_argc;
+static char **mpl_argv;
+
+static inline void mpl_trap(const char *format, ...)
+{
+    va_list ap;
+    fflush(stdout);
+    va_start(ap, format);
+    vfprintf(stderr, format, ap);
+    va_end(ap);
+    fputc('\n', stderr);
+    exit(1);
+}
+
+/* The strings made by a statement, freed once it is done */
+typedef struct mpl_block { struct mpl_block *next; char data[]; } mpl_block;
+static mpl_block *mpl_blocks;
+
+static inline char *mpl_alloc(int32_t len)
+{
+    mpl_block *b = malloc(sizeof(mpl_block) + (size_t)len + 1);
+    if (!b) mpl_trap("out of memory");
+    b->next = mpl_blocks;
+    mpl_blocks = b;
+    return b->data;
+}
+
+static inline void mpl_release(void)
+{
+    while (mpl_blocks) {
+        mpl_block *b = mpl_blocks;
+        mpl_blocks = b->next;
+        free(b);
+    }
+}
+
+static inline mpl_str mpl_copy(const char *p, int32_t len)
+{
+    char *out = mpl_alloc(len);
+    memcpy(out, p, (size_t)len);
+    return (mpl_str){out, len};
+}
+
+static inline mpl_str mpl_concat(const mpl_str *items, int32_t n)
+{
+    int32_t len = 0, at = 0;
+    for (int32_t i = 0; i < n; i++) len += items[i].len;
+    char *out = mpl_alloc(len);
+    for (int32_t i = 0; i < n; i++) {
+        memcpy(out + at, items[i].p, (size_t)items[i].len);
+        at += items[i].len;
+    }
+    return (mpl_str){out, len};
+}
+
+/* ---- print, eprint (on stderr, after what was printed) ---- */
+
+static inline void mpl_write(FILE *f, const mpl_str *items, int32_t n, int nl)
+{
+    mpl_str text = mpl_concat(items, n);
+    if (f == stderr) fflush(stdout);
+    fwrite(text.p, 1, (size_t)text.len, f);
+    if (nl) fputc('\n', f);
+    if (f == stderr) fflush(stderr);
+    mpl_release();
+}
+
+#define mpl_print(...) mpl_write(stdout, MPL_STRS(__VA_ARGS__), 0)
+#define mpl_println(...) mpl_write(stdout, MPL_STRS(__VA_ARGS__), 1)
+#define mpl_eprint(...) mpl_write(stderr, MPL_STRS(__VA_ARGS__), 0)
+#define mpl_eprintln(...) mpl_write(stderr, MPL_STRS(__VA_ARGS__), 1)
+
+static inline void mpl_flush(void) { fflush(stdout); }
+
+static inline void mpl_dump_heap(int32_t start, int32_t len)
+{
+    (void)start;
+    (void)len;
+    fflush(stdout);
+    fprintf(stderr, "dump_heap(): a program compiled to C has no wasm heap\n");
+}
+
+/* ---- numbers ---- */
+
+static inline int32_t mpl_div(int32_t a, int32_t b)
+{
+    if (b == 0) mpl_trap("integer divide by zero");
+    if (a == INT32_MIN && b == -1) mpl_trap("integer overflow");
+    return a / b;
+}
+
+/* float to int, toward zero */
+static inline int32_t mpl_trunc(double x)
+{
+    if (x != x) mpl_trap("invalid conversion to integer");
+    if (x <= -2147483649.0 || x >= 2147483648.0) mpl_trap("integer overflow");
+    return (int32_t)x;
+}
+
+static inline int32_t mpl_abs(int32_t x) { return x < 0 ? (int32_t)(0u - (uint32_t)x) : x; }
+static inline int32_t mpl_min(int32_t a, int32_t b) { return a < b ? a : b; }
+static inline int32_t mpl_max(int32_t a, int32_t b) { return a > b ? a : b; }
+
+/* wasm f64.min/max: NaN if either is, -0 below +0 */
+static inline double mpl_fmin(double a, double b)
+{
+    if (a != a || b != b) return NAN;
+    if (a == b) return signbit(a) ? a : b;
+    return a < b ? a : b;
+}
+
+static inline double mpl_fmax(double a, double b)
+{
+    if (a != a || b != b) return NAN;
+    if (a == b) return signbit(a) ? b : a;
+    return a > b ? a : b;
+}
+
+/* halfway cases away from zero */
+static inline double mpl_round(double x) { return copysign(floor(fabs(x) + 0.5), x); }
+
+/* splitmix64, as in the runner: the same seed (MPL_SEED) gives the same numbers */
+static uint64_t mpl_rng;
+
+static inline uint64_t mpl_next_u64(void)
+{
+    uint64_t z = mpl_rng += 0x9E3779B97F4A7C15u;
+    z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9u;
+    z = (z ^ (z >> 27)) * 0x94D049BB133111EBu;
+    return z ^ (z >> 31);
+}
+
+static inline double mpl_random(void) { return (double)(mpl_next_u64() >> 11) / 9007199254740992.0; }
+
+static inline int32_t mpl_random_int(int32_t lo, int32_t hi)
+{
+    if (lo > hi) mpl_trap("random_int(%d, %d): the lower bound is greater than the upper bound", lo, hi);
+    uint64_t span = (uint64_t)((int64_t)hi - lo + 1);
+    return (int32_t)(lo + (int64_t)(mpl_next_u64() % span));
+}
+
+/* ---- to_str ---- */
+
+static inline mpl_str mpl_int_str(int32_t n)
+{
+    char buf[16];
+    return mpl_copy(buf, (int32_t)snprintf(buf, sizeof buf, "%d", n));
+}
+
+/* 15 significant digits, x rounded half to even as rt.to_str_f64 does (printf rounds the exact
+   value); or, with `shortest`, the shortest text reading back as x. Never an exponent */
+static inline mpl_str mpl_float_text(double x, int shortest)
+{
+    char digits[24], sci[40];
+    int n = 0, exp = 0;
+    if (x != x) return mpl_lit("NaN");
+    if (isinf(x)) return x < 0 ? mpl_lit("-inf") : mpl_lit("inf");
+    if (x == 0) return signbit(x) ? mpl_lit("-0") : mpl_lit("0");
+    if (shortest) {
+        for (int p = 0; p < 17; p++) {
+            snprintf(sci, sizeof sci, "%.*e", p, fabs(x));
+            if (strtod(sci, NULL) == fabs(x)) break;
+        }
+    } else {
+        snprintf(sci, sizeof sci, "%.14e", fabs(x));
+    }
+    char *e = strchr(sci, 'e');
+    for (char *c = sci; c < e; c++)
+        if (*c != '.') digits[n++] = *c;
+    exp = atoi(e + 1);
+    while (n > 1 && digits[n - 1] == '0') n--;
+    int len = 0;
+    char *out = mpl_alloc(n + (exp < 0 ? -exp : exp) + 4);
+    if (signbit(x)) out[len++] = '-';
+    if (exp < 0) {
+        out[len++] = '0';
+        out[len++] = '.';
+        for (int i = 0; i < -exp - 1; i++) out[len++] = '0';
+        for (int i = 0; i < n; i++) out[len++] = digits[i];
+    } else {
+        for (int i = 0; i < n || i <= exp; i++) {
+            if (i == exp + 1) out[len++] = '.';
+            out[len++] = i < n ? digits[i] : '0';
+        }
+    }
+    return (mpl_str){out, len};
+}
+
+static inline mpl_str mpl_float_str(double x) { return mpl_float_text(x, 0); }
+static inline mpl_str mpl_float_str_shortest(double x) { return mpl_float_text(x, 1); }
+
+/* to_str(x, decimals) */
+static inline mpl_str mpl_fixed(double x, int32_t decimals)
+{
+    if (decimals < 0 || decimals > 100)
+        mpl_trap("to_str(_, %d): the number of decimals must be between 0 and 100", decimals);
+    if (isnan(x) || isinf(x)) return mpl_float_str(x);
+    int32_t len = (int32_t)snprintf(NULL, 0, "%.*f", decimals, x);
+    char *out = mpl_alloc(len);
+    snprintf(out, (size_t)len + 1, "%.*f", decimals, x);
+    return (mpl_str){out, len};
+}
+
+/* format_thousands(x [, decimals]): the digits before the dot grouped by three */
+static inline mpl_str mpl_thousands(double x, int32_t decimals)
+{
+    mpl_str text = decimals == -1 ? mpl_float_str(x) : mpl_fixed(x, decimals);
+    int32_t sign = text.len > 0 && text.p[0] == '-', digits = 0, len = 0;
+    while (sign + digits < text.len && text.p[sign + digits] >= '0' && text.p[sign + digits] <= '9') digits++;
+    if (digits == 0 || (sign + digits < text.len && text.p[sign + digits] != '.')) return text; /* NaN, inf */
+    char *out = mpl_alloc(text.len + digits / 3);
+    if (sign) out[len++] = '-';
+    for (int32_t i = 0; i < digits; i++) {
+        if (i > 0 && (digits - i) % 3 == 0) out[len++] = ' ';
+        out[len++] = text.p[sign + i];
+    }
+    memcpy(out + len, text.p + sign + digits, (size_t)(text.len - sign - digits));
+    return (mpl_str){out, len + text.len - sign - digits};
+}
+
+static inline mpl_str mpl_decimal_comma(mpl_str s)
+{
+    mpl_str out = mpl_copy(s.p, s.len);
+    for (int32_t i = 0; i < out.len; i++)
+        if (out.p[i] == '.') ((char *)out.p)[i] = ',';
+    return out;
+}
+
+/* ---- characters ---- */
+
+static inline int mpl_utf8_size(unsigned char b) { return b < 0x80 ? 1 : b < 0xe0 ? 2 : b < 0xf0 ? 3 : 4; }
+
+static inline int32_t mpl_len(mpl_str s)
+{
+    int32_t n = 0;
+    for (int32_t i = 0; i < s.len; i += mpl_utf8_size((unsigned char)s.p[i])) n++;
+    return n;
+}
+
+/* The characters [start, start + count) of s, 0 if out of range */
+static inline int mpl_char_range(mpl_str s, int32_t start, int32_t count, mpl_str *out)
+{
+    int32_t at = 0, i = 0, from = 0;
+    if (start < 0 || count < 0) return 0;
+    for (; i < start + count; i++) {
+        if (i == start) from = at;
+        if (at >= s.len) return 0;
+        at += mpl_utf8_size((unsigned char)s.p[at]);
+    }
+    if (count == 0) from = at;
+    *out = (mpl_str){s.p + from, at - from};
+    return 1;
+}
+
+static inline mpl_str mpl_substr(mpl_str s, int32_t start, int32_t count)
+{
+    mpl_str out;
+    if (!mpl_char_range(s, start, count, &out))
+        mpl_trap("substr(_, %d, %d) out of range: the string has %d character(s)", start, count, mpl_len(s));
+    return out;
+}
+
+static inline mpl_str mpl_char_at(mpl_str s, int32_t i)
+{
+    mpl_str out;
+    if (!mpl_char_range(s, i, 1, &out))
+        mpl_trap("char_at(_, %d) out of range: the string has %d character(s)", i, mpl_len(s));
+    return out;
+}
+
+/* char_at(s, i) as a char: its code point */
+static inline int32_t mpl_code_at(mpl_str s, int32_t i)
+{
+    mpl_str c = mpl_char_at(s, i);
+    const unsigned char *b = (const unsigned char *)c.p;
+    static const unsigned char masks[] = {0, 0x7f, 0x1f, 0x0f, 0x07};
+    int32_t code = b[0] & masks[c.len];
+    for (int32_t k = 1; k < c.len; k++) code = (code << 6) | (b[k] & 0x3f);
+    return code;
+}
+
+static inline mpl_str mpl_char_str(int32_t c)
+{
+    char *out;
+    if (c < 0 || c > 0x10ffff || (c >= 0xd800 && c <= 0xdfff))
+        mpl_trap("chr(%d): not a Unicode code point (0 to 0x10FFFF, except 0xD800 to 0xDFFF)", c);
+    if (c < 0x80) {
+        out = mpl_alloc(1);
+        out[0] = (char)c;
+        return (mpl_str){out, 1};
+    }
+    int32_t len = c < 0x800 ? 2 : c < 0x10000 ? 3 : 4;
+    static const unsigned char firsts[] = {0, 0, 0xc0, 0xe0, 0xf0};
+    out = mpl_alloc(len);
+    for (int32_t k = len - 1; k > 0; k--, c >>= 6) out[k] = (char)(0x80 | (c & 0x3f));
+    out[0] = (char)(firsts[len] | c);
+    return (mpl_str){out, len};
+}
+
+/* ---- other strings ---- */
+
+static inline int32_t mpl_str_eq(mpl_str a, mpl_str b) { return a.len == b.len && memcmp(a.p, b.p, (size_t)a.len) == 0; }
+
+static inline mpl_str mpl_repeat(mpl_str s, int32_t n)
+{
+    if (n < 0) n = 0;
+    char *out = mpl_alloc(s.len * n);
+    for (int32_t i = 0; i < n; i++) memcpy(out + (size_t)i * s.len, s.p, (size_t)s.len);
+    return (mpl_str){out, s.len * n};
+}
+
+static inline mpl_str mpl_join_strs(mpl_str sep, const mpl_str *parts, int32_t n)
+{
+    mpl_str *items = (mpl_str *)mpl_alloc((int32_t)sizeof(mpl_str) * 2 * n);
+    for (int32_t i = 0; i < n; i++) {
+        items[2 * i] = i > 0 ? sep : mpl_lit("");
+        items[2 * i + 1] = parts[i];
+    }
+    return mpl_concat(items, 2 * n);
+}
+
+#define mpl_join(sep, ...) mpl_join_strs(sep, MPL_STRS(__VA_ARGS__))
+
+static inline mpl_str mpl_arg(int32_t i)
+{
+    if (i < 0 || i >= mpl_argc)
+        mpl_trap("arg(%d) out of range: the program has %d argument(s)", i, mpl_argc);
+    return (mpl_str){mpl_argv[i], (int32_t)strlen(mpl_argv[i])};
+}
+
+static inline int32_t mpl_arg_count(void) { return mpl_argc; }
+
+/* surrounding whitespace is ignored; anything else that is not a number traps */
+static inline mpl_str mpl_trim(mpl_str s)
+{
+    while (s.len > 0 && strchr(" \t\n\r\f\v", s.p[0])) s.p++, s.len--;
+    while (s.len > 0 && strchr(" \t\n\r\f\v", s.p[s.len - 1])) s.len--;
+    return s;
+}
+
+static inline int32_t mpl_to_int(mpl_str s)
+{
+    mpl_str t = mpl_trim(s);
+    int32_t i = t.len > 0 && (t.p[0] == '+' || t.p[0] == '-');
+    int64_t n = 0;
+    if (i == t.len) goto invalid;
+    for (; i < t.len; i++) {
+        if (t.p[i] < '0' || t.p[i] > '9') goto invalid;
+        n = n * 10 + (t.p[i] - '0');
+        if (n > 2147483648) goto invalid;
+    }
+    if (t.p[0] == '-') n = -n;
+    if (n > INT32_MAX) goto invalid;
+    return (int32_t)n;
+invalid:
+    mpl_trap("to_int(\"%.*s\"): not a valid integer", s.len, s.p);
+    return 0;
+}
+
+/* `word` in any case */
+static inline int mpl_is_word(const char *s, const char *word)
+{
+    for (; *s && *word; s++, word++)
+        if ((*s | 0x20) != *word) return 0;
+    return !*s && !*word;
+}
+
+static inline double mpl_to_float(mpl_str s)
+{
+    mpl_str t = mpl_trim(s);
+    char *buf = mpl_alloc(t.len);
+    int32_t i = t.len > 0 && (t.p[0] == '+' || t.p[0] == '-'), digits = 0;
+    memcpy(buf, t.p, (size_t)t.len);
+    buf[t.len] = 0;
+    if (mpl_is_word(buf + i, "inf") || mpl_is_word(buf + i, "infinity") || mpl_is_word(buf + i, "nan"))
+        return strtod(buf, NULL);
+    for (; i < t.len && buf[i] >= '0' && buf[i] <= '9'; i++) digits++;
+    if (i < t.len && buf[i] == '.')
+        for (i++; i < t.len && buf[i] >= '0' && buf[i] <= '9'; i++) digits++;
+    if (digits == 0) goto invalid;
+    if (i < t.len && (buf[i] == 'e' || buf[i] == 'E')) {
+        int32_t exp_digits = 0;
+        i += i + 1 < t.len && (buf[i + 1] == '+' || buf[i + 1] == '-') ? 2 : 1;
+        for (; i < t.len && buf[i] >= '0' && buf[i] <= '9'; i++) exp_digits++;
+        if (exp_digits == 0) goto invalid;
+    }
+    if (i != t.len) goto invalid;
+    return strtod(buf, NULL);
+invalid:
+    mpl_trap("to_float(\"%.*s\"): not a valid number", s.len, s.p);
+    return 0;
+}
+
+/* ---- maps (string -> int) ---- */
+
+static inline mpl_entry *mpl_map_find(mpl_map *m, mpl_str key)
+{
+    for (int32_t i = 0; i < m->len; i++) {
+        mpl_entry *e = &m->entries[i];
+        if (e->key_len == key.len && memcmp(e->key, key.p, (size_t)key.len) == 0) return e;
+    }
+    return NULL;
+}
+
+/* the value of the key, 0 if the map does not have it */
+static inline int32_t mpl_map_get(mpl_map *m, mpl_str key)
+{
+    mpl_entry *e = mpl_map_find(m, key);
+    return e ? e->value : 0;
+}
+
+static inline int32_t mpl_map_has(mpl_map *m, mpl_str key) { return mpl_map_find(m, key) != NULL; }
+
+static inline void mpl_map_set(mpl_map *m, mpl_str key, int32_t value)
+{
+    mpl_entry *e = mpl_map_find(m, key);
+    if (!e) {
+        if (m->len == m->cap) {
+            m->cap = m->cap ? 2 * m->cap : 8;
+            m->entries = realloc(m->entries, sizeof(mpl_entry) * (size_t)m->cap);
+            if (!m->entries) mpl_trap("out of memory");
+        }
+        e = &m->entries[m->len++];
+        e->key = malloc((size_t)key.len + 1);
+        if (!e->key) mpl_trap("out of memory");
+        memcpy(e->key, key.p, (size_t)key.len);
+        e->key_len = key.len;
+    }
+    e->value = value;
+    mpl_release();
+}
+
+static inline void mpl_map_delete(mpl_map *m, mpl_str key)
+{
+    mpl_entry *e = mpl_map_find(m, key);
+    if (e) {
+        free(e->key);
+        *e = m->entries[--m->len];
+    }
+    mpl_release();
+}
+
+static inline void mpl_map_free(mpl_map *m)
+{
+    for (int32_t i = 0; i < m->len; i++) free(m->entries[i].key);
+    free(m->entries);
+}
+
+/* ---- string builders ---- */
+
+static inline void mpl_append_strs(mpl_builder *b, const mpl_str *items, int32_t n)
+{
+    mpl_str text = mpl_concat(items, n); /* copied first: it may be the text of b itself */
+    if (b->len + text.len > b->cap) {
+        b->cap = 2 * (b->len + text.len) + 16;
+        b->p = realloc(b->p, (size_t)b->cap);
+        if (!b->p) mpl_trap("out of memory");
+    }
+    memcpy(b->p + b->len, text.p, (size_t)text.len);
+    b->len += text.len;
+    mpl_release();
+}
+
+#define mpl_append(b, ...) mpl_append_strs(b, MPL_STRS(__VA_ARGS__))
+
+static inline void mpl_clear(mpl_builder *b) { b->len = 0; }
+static inline mpl_str mpl_builder_text(mpl_builder *b) { return (mpl_str){b->p ? b->p : "", b->len}; }
+static inline void mpl_builder_free(mpl_builder *b) { free(b->p); }
+
+/* ---- fn variables ---- */
+
+static inline mpl_fn mpl_callable(mpl_fn f)
+{
+    if (!f) mpl_trap("uninitialized element: the fn variable holds no function");
+    return f;
+}
+
+static inline void mpl_start(int argc, char **argv)
+{
+    const char *seed = getenv("MPL_SEED");
+    mpl_argc = argc - 1;
+    mpl_argv = argv + 1;
+    mpl_rng = seed ? strtoull(seed, NULL, 10) : (uint64_t)time(NULL) ^ (uint64_t)clock();
+}
+"#;
+
+// C keywords and the names the program text uses besides its own: a function or variable named
+// like one of them takes a `_` after its name
+const RESERVED: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum", "extern",
+    "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return", "short", "signed",
+    "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void", "volatile", "while", "bool",
+    "true", "false", "main", "int32_t", "int64_t", "uint32_t", "uint64_t", "sqrt", "pow", "floor", "ceil",
+    "fabs", "sin", "cos", "tan", "log", "exp", "copysign", "NAN", "INFINITY", "INT32_MIN", "abs", "exit", "free",
+    "malloc", "realloc", "printf", "puts", "putchar", "remove", "rename", "time", "clock", "rand", "srand",
+    "signal", "errno", "stdin", "stdout", "stderr", "fflush", "memcpy", "strlen", "strchr", "getenv", "atoi",
+];
+
+// A name of the program as a C identifier: `math::square` (see modules.rs) is math__square,
+// `helper.x` (a local of an inlined function, see optimize.rs) helper_x
+fn c_name(name: &str) -> String {
+    let name = name.replace("::", "__").replace('.', "_");
+    if RESERVED.contains(&name.as_str()) || name.starts_with("mpl_") {
+        name + "_"
+    } else {
+        name
+    }
+}
+
+// A string literal in C: UTF-8 as it is, the other bytes escaped
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    let mut previous = '\0';
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '?' if previous == '?' => out.push_str("\\?"), // not a trigraph
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                let _ = write!(out, "\\{:03o}", c as u32);
+            }
+            c => out.push(c),
+        }
+        previous = c;
+    }
+    out.push('"');
+    out
+}
+
+/// The program as C source (--emit=c), after the optimizations: its functions, then a `main`
+/// that gives them the command line arguments. Wasm libraries cannot be used (see main.rs).
+///
+/// Ints are int32_t and computed in C: build with -fwrapv for the wrapping arithmetic of wasm,
+/// e.g. `cc -O2 -fwrapv main.c -lm`. C leaves the order of the operands of an expression open,
+/// so two random() of one statement may come in another order than with the mpl runner.
+pub fn program_c(prog: &Program, c_file: &str, float_format: FloatFormat) -> Result<String, ParseError> {
+    let mut out = format!(
+        "/* Written by mpl compile --emit=c; build with: cc -O2 -fwrapv {} -lm\n   MPL_SEED=N gives the random numbers of `mpl run --seed N`. */\n",
+        c_file
+    );
+    out.push_str(RUNTIME_C);
+
+    // a call of an extern fn is a call of the C function of its field
+    let mut names: HashMap<&str, String> = HashMap::new();
+    out.push_str("\n/* ---- Program ---- */\n\n");
+    for e in &prog.externs {
+        let params: Vec<String> = e.params.iter().map(|p| format!("{} {}", c_type(p.ty), c_name(&p.name))).collect();
+        let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+        let _ = writeln!(out, "extern void {}({}); /* from {}.{} */", e.field, params, e.module, e.field);
+        names.insert(&e.name, e.field.clone());
+    }
+    let functions: Vec<&Function> = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main)
+        .collect();
+    for f in &functions {
+        let name = if f.name == grammar::KW_MAIN { "mpl_main".to_string() } else { c_name(&f.name) };
+        names.insert(&f.name, name);
+    }
+    for f in &functions {
+        let _ = writeln!(out, "{};", signature(f, &names[f.name.as_str()]));
+    }
+    for f in &functions {
+        out.push('\n');
+        FunctionWriter {
+            function: f,
+            names: &names,
+            externs: prog.externs.iter().map(|e| (e.name.as_str(), &e.params[..])).collect(),
+            float_format,
+            loops: 0,
+            out: &mut out,
+        }
+        .function()?;
+    }
+    if prog.main_program.main.is_some() {
+        out.push_str("\nint main(int argc, char **argv)\n{\n    mpl_start(argc, argv);\n");
+        out.push_str("    int32_t code = mpl_main();\n");
+        out.push_str("    if (code < 0 || code > 255)\n        mpl_trap(\"main returned %d: an exit code is between 0 and 255\", code);\n");
+        out.push_str("    fflush(stdout);\n    return code;\n}\n");
+    }
+    Ok(out)
+}
+
+fn c_type(ty: Ty) -> &'static str {
+    match ty {
+        Ty::I32 | Ty::Char => "int32_t",
+        Ty::F64 => "double",
+        Ty::Fn => "mpl_fn",
+        Ty::Map => "mpl_map",
+        Ty::Builder => "mpl_builder",
+    }
+}
+
+// main returns the exit code; an `export fn` can be called from other C files
+fn signature(f: &Function, name: &str) -> String {
+    if f.name == grammar::KW_MAIN {
+        return format!("static int32_t {}(void)", name);
+    }
+    let linkage = if f.export { "" } else { "static " };
+    format!("{}void {}(void)", linkage, name)
+}
+
+// Binding powers of C operators, for the parentheses
+const PREC_ADD: u8 = 1;
+const PREC_MUL: u8 = 2;
+const PREC_UNARY: u8 = 3;
+const PREC_ATOM: u8 = 4;
+
+struct FunctionWriter<'p> {
+    function: &'p Function,
+    names: &'p HashMap<&'p str, String>, // function name -> C name
+    externs: HashMap<&'p str, &'p [Variable]>,
+    float_format: FloatFormat,
+    loops: usize, // for loops so far, to name their bounds
+    out: &'p mut String,
+}
+
+impl FunctionWriter<'_> {
+    fn function(&mut self) -> Result<(), ParseError> {
+        let f = self.function;
+        let _ = writeln!(self.out, "{}\n{{", signature(f, &self.names[f.name.as_str()]));
+        for var in &f.variables {
+            let init = match var.ty {
+                Ty::Map | Ty::Builder => "{0}",
+                _ => "0",
+            };
+            let comment = if var.ty == Ty::Char { " /* char */" } else { "" };
+            self.line(1, &format!("{} {} = {};{}", c_type(var.ty), c_name(&var.name), init, comment));
+        }
+        self.body(&f.body, 1)?;
+        // the maps and builders of main go with the process
+        if f.name != grammar::KW_MAIN {
+            for var in &f.variables {
+                match var.ty {
+                    Ty::Map => self.line(1, &format!("mpl_map_free(&{});", c_name(&var.name))),
+                    Ty::Builder => self.line(1, &format!("mpl_builder_free(&{});", c_name(&var.name))),
+                    _ => {}
+                }
+            }
+        } else if !matches!(f.body.last(), Some(Stadment::Return { .. })) {
+            self.line(1, "return 0;");
+        }
+        self.out.push_str("}\n");
+        Ok(())
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        let _ = writeln!(self.out, "{}{}", "    ".repeat(depth), text);
+    }
+
+    fn body(&mut self, body: &[Stadment], depth: usize) -> Result<(), ParseError> {
+        for st in body {
+            self.stadment(st, depth)?;
+        }
+        Ok(())
+    }
+
+    // A statement whose numbers read strings: the strings are freed after it
+    fn line_releasing(&mut self, depth: usize, text: &str, nums: &[&NumExpr]) {
+        self.line(depth, text);
+        if nums.iter().any(|n| reads_strings(n)) {
+            self.line(depth, "mpl_release();");
+        }
+    }
+
+    fn stadment(&mut self, st: &Stadment, depth: usize) -> Result<(), ParseError> {
+        match st {
+            Stadment::Print { items, .. } => self.line(depth, &format!("mpl_print({});", self.strs(items)?)),
+            Stadment::Println { items, .. } => self.line(depth, &format!("mpl_println({});", self.strs(items)?)),
+            Stadment::EPrint { items, .. } => self.line(depth, &format!("mpl_eprint({});", self.strs(items)?)),
+            Stadment::EPrintln { items, .. } => self.line(depth, &format!("mpl_eprintln({});", self.strs(items)?)),
+            Stadment::Call { name, args, .. } => {
+                // only an extern fn takes arguments, computed in the types of its parameters
+                let params = self.externs.get(name.as_str()).copied().unwrap_or(&[]);
+                let mut texts = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    let ty = params.get(i).map_or_else(|| infer_type(arg), |p| p.ty);
+                    texts.push(self.num(arg, ty)?);
+                }
+                let name = self.names.get(name.as_str()).cloned().unwrap_or_else(|| c_name(name));
+                let text = format!("{}({});", name, texts.join(", "));
+                self.line_releasing(depth, &text, &args.iter().collect::<Vec<_>>());
+            }
+            Stadment::CallIndirect { var, .. } => self.line(depth, &format!("mpl_callable({})();", c_name(&var.name))),
+            Stadment::Assignment { var, expr, pos } => {
+                let value = match expr {
+                    Expr::Num(n) => self.num(n, var.ty)?,
+                    Expr::Fn(FnExpr::Ref { name, .. }) => {
+                        self.names.get(name.as_str()).cloned().unwrap_or_else(|| c_name(name))
+                    }
+                    Expr::Fn(FnExpr::Var { var, .. }) => c_name(&var.name),
+                    Expr::Str(_) => return Err(numeric_only(grammar::KW_LET, pos)),
+                };
+                let text = format!("{} = {};", c_name(&var.name), value);
+                self.line_releasing(depth, &text, &num_of(expr).into_iter().collect::<Vec<_>>());
+            }
+            Stadment::ForLoop {
+                var,
+                start,
+                end,
+                step,
+                body,
+                pos,
+            } => self.for_loop(var, start, end, step.as_ref(), body, pos, depth)?,
+            Stadment::Return { expr, pos } => {
+                let value = match expr {
+                    Expr::Num(n) => self.num(n, Ty::I32)?,
+                    _ => return Err(numeric_only(grammar::KW_RETURN, pos)),
+                };
+                self.line(depth, &format!("return {};", value));
+            }
+            Stadment::MapSet { var, key, value, .. } => {
+                let text = format!("mpl_map_set(&{}, {}, {});", c_name(&var.name), self.str(key)?, self.num(value, Ty::I32)?);
+                self.line(depth, &text);
+            }
+            Stadment::MapDelete { var, key, .. } => {
+                let text = format!("mpl_map_delete(&{}, {});", c_name(&var.name), self.str(key)?);
+                self.line(depth, &text);
+            }
+            Stadment::Append { var, items, .. } => {
+                // wasm appends the items one by one: an item reading the builder sees the ones before it
+                if items.len() > 1 && reads_builder(items, &var.name) {
+                    for item in items {
+                        let text = format!("mpl_append(&{}, {});", c_name(&var.name), self.strs(std::slice::from_ref(item))?);
+                        self.line(depth, &text);
+                    }
+                } else {
+                    let text = format!("mpl_append(&{}, {});", c_name(&var.name), self.strs(items)?);
+                    self.line(depth, &text);
+                }
+            }
+            Stadment::Clear { var, .. } => self.line(depth, &format!("mpl_clear(&{});", c_name(&var.name))),
+            Stadment::Match { value, arms, default, .. } => self.match_(value, arms, default.as_deref(), depth)?,
+            Stadment::Flush => self.line(depth, "mpl_flush();"),
+            Stadment::DumpHeap { region, .. } => match region {
+                Some((start, len)) => {
+                    let text = format!("mpl_dump_heap({}, {});", self.num(start, Ty::I32)?, self.num(len, Ty::I32)?);
+                    self.line_releasing(depth, &text, &[start, len]);
+                }
+                None => self.line(depth, "mpl_dump_heap(-1, 0);"),
+            },
+        }
+        Ok(())
+    }
+
+    // i = start, then end and step once: the loop stops past end, below it unless the step is
+    // positive
+    #[allow(clippy::too_many_arguments)]
+    fn for_loop(
+        &mut self,
+        var: &Variable,
+        start: &Expr,
+        end: &Expr,
+        step: Option<&Expr>,
+        body: &[Stadment],
+        pos: &Position,
+        depth: usize,
+    ) -> Result<(), ParseError> {
+        let i = c_name(&var.name);
+        let ty = c_type(var.ty);
+        fn bound<'e>(e: &'e Expr, keyword: &str, pos: &Position) -> Result<&'e NumExpr, ParseError> {
+            num_of(e).ok_or_else(|| numeric_only(keyword, pos))
+        }
+        let start = bound(start, grammar::KW_FOR, pos)?;
+        let end = bound(end, grammar::KW_TO, pos)?;
+        let step = step.map(|step| bound(step, grammar::KW_STEP, pos)).transpose()?;
+        self.loops += 1;
+        let start_text = format!("{} = {}", i, self.num(start, var.ty)?);
+        // constants stay in the loop header; the other bounds are computed before the loop,
+        // after i = start
+        let in_header = is_constant(end) && step.is_none_or(is_constant) && !reads_strings(start);
+        if !in_header {
+            self.line_releasing(depth, &format!("{};", start_text), &[start]);
+        }
+        let end_text = self.num(end, var.ty)?;
+        let end_text = if is_constant(end) {
+            end_text
+        } else {
+            let name = format!("end_{}", self.loops);
+            self.line_releasing(depth, &format!("{} {} = {};", ty, name, end_text), &[end]);
+            name
+        };
+        let (condition, increment) = match step {
+            None => (format!("{} <= {}", i, end_text), format!("{}++", i)),
+            Some(step) if is_constant(step) => {
+                let text = self.num(step, var.ty)?;
+                let increment = match text.strip_prefix('-') {
+                    Some(magnitude) => format!("{} -= {}", i, magnitude),
+                    None => format!("{} += {}", i, text),
+                };
+                // a zero step counts down, as a negative one
+                let comparison = if text.trim_start_matches(['-', '0', '.']).is_empty() || text.starts_with('-') {
+                    ">="
+                } else {
+                    "<="
+                };
+                (format!("{} {} {}", i, comparison, end_text), increment)
+            }
+            Some(step) => {
+                let name = format!("step_{}", self.loops);
+                let text = self.num(step, var.ty)?;
+                self.line_releasing(depth, &format!("{} {} = {};", ty, name, text), &[step]);
+                let condition = format!("{} > 0 ? {} <= {} : {} >= {}", name, i, end_text, i, end_text);
+                (condition, format!("{} += {}", i, name))
+            }
+        };
+        let init = if in_header { start_text } else { String::new() };
+        self.line(depth, &format!("for ({}; {}; {}) {{", init, condition, increment));
+        self.body(body, depth + 1)?;
+        self.line(depth, "}");
+        Ok(())
+    }
+
+    fn match_(&mut self, value: &NumExpr, arms: &[MatchArm], default: Option<&[Stadment]>, depth: usize) -> Result<(), ParseError> {
+        let mut value_text = self.num(value, Ty::I32)?;
+        if reads_strings(value) {
+            self.loops += 1;
+            let name = format!("value_{}", self.loops);
+            self.line(depth, &format!("int32_t {} = {};", name, value_text));
+            self.line(depth, "mpl_release();");
+            value_text = name;
+        }
+        self.line(depth, &format!("switch ({}) {{", value_text));
+        for arm in arms {
+            for v in &arm.values {
+                self.line(depth, &format!("case {}:", int_text(*v).0));
+            }
+            self.body(&arm.body, depth + 1)?;
+            self.line(depth + 1, "break;");
+        }
+        if let Some(default) = default {
+            self.line(depth, "default:");
+            self.body(default, depth + 1)?;
+            self.line(depth + 1, "break;");
+        }
+        self.line(depth, "}");
+        Ok(())
+    }
+
+    fn num(&self, e: &NumExpr, ty: Ty) -> Result<String, ParseError> {
+        let num = ir::lower_num(e, ty, &self.function.variables)?;
+        Ok(self.num_text(&num)?.0)
+    }
+
+    // The C expression of a number, and how tightly it binds
+    fn num_text(&self, num: &Num) -> Result<(String, u8), ParseError> {
+        let call = |name: &str, args: Vec<String>| (format!("{}({})", name, args.join(", ")), PREC_ATOM);
+        Ok(match &num.kind {
+            NumKind::Int(i) => int_text(*i),
+            NumKind::Float(r) => float_text(*r),
+            NumKind::Local { name, .. } => (c_name(name), PREC_ATOM),
+            NumKind::Convert(inner) => match (num.ty, &inner.kind) {
+                (NumTy::F64, NumKind::Int(i)) => float_text(*i as f64),
+                (NumTy::F64, _) => (format!("(double){}", self.operand(inner, PREC_UNARY)?), PREC_UNARY),
+                (NumTy::I32, _) => call("mpl_trunc", vec![self.num_text(inner)?.0]),
+            },
+            NumKind::Neg(inner) => {
+                let operand = self.operand(inner, PREC_UNARY)?;
+                // not `--x`
+                let operand = if operand.starts_with('-') { format!("({})", operand) } else { operand };
+                (format!("-{}", operand), PREC_UNARY)
+            }
+            NumKind::Binary { op, left, right } => {
+                let (symbol, prec) = match (op, num.ty) {
+                    (BinOp::Add, _) => ("+", PREC_ADD),
+                    (BinOp::Sub, _) => ("-", PREC_ADD),
+                    (BinOp::Mul, _) => ("*", PREC_MUL),
+                    (BinOp::Div, NumTy::F64) => ("/", PREC_MUL),
+                    // traps on a zero divisor, as in wasm
+                    (BinOp::Div, NumTy::I32) => {
+                        return Ok(call("mpl_div", vec![self.num_text(left)?.0, self.num_text(right)?.0]));
+                    }
+                };
+                let left = self.operand(left, prec)?;
+                let right = self.operand(right, prec + 1)?;
+                (format!("{} {} {}", left, symbol, right), prec)
+            }
+            NumKind::ArgCount => call("mpl_arg_count", vec![]),
+            NumKind::Random => call("mpl_random", vec![]),
+            NumKind::RandomInt { lo, hi } => call("mpl_random_int", vec![self.num_text(lo)?.0, self.num_text(hi)?.0]),
+            NumKind::Len(s) => call("mpl_len", vec![self.str(s)?]),
+            NumKind::ToInt(s) => call("mpl_to_int", vec![self.str(s)?]),
+            NumKind::ToFloat(s) => call("mpl_to_float", vec![self.str(s)?]),
+            NumKind::StrEq { left, right, negated } => {
+                let eq = call("mpl_str_eq", vec![self.str(left)?, self.str(right)?]);
+                if *negated { (format!("!{}", eq.0), PREC_UNARY) } else { eq }
+            }
+            NumKind::MapGet { var, key, .. } => call("mpl_map_get", vec![format!("&{}", c_name(&var.name)), self.str(key)?]),
+            NumKind::MapHas { var, key, .. } => call("mpl_map_has", vec![format!("&{}", c_name(&var.name)), self.str(key)?]),
+            NumKind::CharAt { s, index } => call("mpl_code_at", vec![self.str(s)?, self.num_text(index)?.0]),
+            NumKind::Math { func, args } => {
+                let args = args.iter().map(|a| Ok(self.num_text(a)?.0)).collect::<Result<Vec<_>, ParseError>>()?;
+                let name = match (func, num.ty) {
+                    (MathFn::Abs, NumTy::F64) => "fabs",
+                    (MathFn::Min, NumTy::F64) => "mpl_fmin",
+                    (MathFn::Max, NumTy::F64) => "mpl_fmax",
+                    (MathFn::Abs | MathFn::Min | MathFn::Max | MathFn::Round, _) => match func {
+                        MathFn::Abs => "mpl_abs",
+                        MathFn::Min => "mpl_min",
+                        MathFn::Max => "mpl_max",
+                        _ => "mpl_round",
+                    },
+                    _ => func.name(),
+                };
+                call(name, args)
+            }
+        })
+    }
+
+    // An operand of an operator binding `prec`, in parentheses if it binds less
+    fn operand(&self, num: &Num, prec: u8) -> Result<String, ParseError> {
+        let (text, operand_prec) = self.num_text(num)?;
+        Ok(if operand_prec < prec { format!("({})", text) } else { text })
+    }
+
+    fn strs(&self, items: &[StrExpr]) -> Result<String, ParseError> {
+        if items.is_empty() {
+            return Ok("mpl_lit(\"\")".to_string());
+        }
+        let texts = items.iter().map(|s| self.str(s)).collect::<Result<Vec<_>, _>>()?;
+        Ok(texts.join(", "))
+    }
+
+    // A string expression: an mpl_str
+    fn str(&self, e: &StrExpr) -> Result<String, ParseError> {
+        Ok(match e {
+            StrExpr::Str(s) => format!("mpl_lit({})", c_string(s)),
+            StrExpr::Nl => "mpl_lit(\"\\n\")".to_string(),
+            StrExpr::NumToStr(n) => match infer_type(n) {
+                Ty::Char => format!("mpl_char_str({})", self.num(n, Ty::Char)?),
+                Ty::F64 if self.float_format == FloatFormat::Shortest => {
+                    format!("mpl_float_str_shortest({})", self.num(n, Ty::F64)?)
+                }
+                Ty::F64 => format!("mpl_float_str({})", self.num(n, Ty::F64)?),
+                ty => format!("mpl_int_str({})", self.num(n, ty)?),
+            },
+            StrExpr::ToFixed { n, decimals } => {
+                format!("mpl_fixed({}, {})", self.num(n, Ty::F64)?, self.num(decimals, Ty::I32)?)
+            }
+            StrExpr::Thousands { n, decimals } => {
+                let decimals = match decimals {
+                    Some(d) => self.num(d, Ty::I32)?,
+                    None => "-1".to_string(),
+                };
+                format!("mpl_thousands({}, {})", self.num(n, Ty::F64)?, decimals)
+            }
+            StrExpr::DecimalComma(s) => format!("mpl_decimal_comma({})", self.str(s)?),
+            StrExpr::Arg(index) => format!("mpl_arg({})", self.num(index, Ty::I32)?),
+            StrExpr::Substr { s, start, len } => {
+                format!("mpl_substr({}, {}, {})", self.str(s)?, self.num(start, Ty::I32)?, self.num(len, Ty::I32)?)
+            }
+            StrExpr::CharAt { s, index } => format!("mpl_char_at({}, {})", self.str(s)?, self.num(index, Ty::I32)?),
+            StrExpr::Repeat { s, count } => format!("mpl_repeat({}, {})", self.str(s)?, self.num(count, Ty::I32)?),
+            StrExpr::Join { sep, parts } => format!("mpl_join({}, {})", self.str(sep)?, self.strs(parts)?),
+            StrExpr::Builder { var, .. } => format!("mpl_builder_text(&{})", c_name(&var.name)),
+        })
+    }
+}
+
+fn int_text(i: i32) -> (String, u8) {
+    match i {
+        i32::MIN => ("(-2147483647 - 1)".to_string(), PREC_ATOM),
+        i if i < 0 => (i.to_string(), PREC_UNARY),
+        i => (i.to_string(), PREC_ATOM),
+    }
+}
+
+fn float_text(r: f64) -> (String, u8) {
+    let text = if r.is_nan() {
+        "NAN".to_string()
+    } else if r.is_infinite() {
+        if r < 0.0 { "-INFINITY" } else { "INFINITY" }.to_string()
+    } else {
+        // always with a dot or an exponent: 2.0, not the int 2
+        format!("{:?}", r)
+    };
+    let prec = if text.starts_with('-') { PREC_UNARY } else { PREC_ATOM };
+    (text, prec)
+}
+
+pub(crate) fn numeric_only(keyword: &str, pos: &Position) -> ParseError {
+    ParseError::generator(&messages::NUMERIC_ONLY, &[&keyword], pos)
+}
+
+pub(crate) fn num_of(expr: &Expr) -> Option<&NumExpr> {
+    match expr {
+        Expr::Num(n) => Some(n),
+        _ => None,
+    }
+}
+
+// Whether an item of `append(var, ...)` reads the text of var
+pub(crate) fn reads_builder(items: &[StrExpr], var: &str) -> bool {
+    struct Reads<'a> {
+        var: &'a str,
+        found: bool,
+    }
+    impl Visitor for Reads<'_> {
+        fn visit_str_expr(&mut self, e: &StrExpr) {
+            if let StrExpr::Builder { var, .. } = e {
+                self.found |= var.name == self.var;
+            }
+            walk_str_expr(self, e);
+        }
+    }
+    let mut reads = Reads { var, found: false };
+    items.iter().for_each(|item| reads.visit_str_expr(item));
+    reads.found
+}
+
+// A literal, possibly negated: the same value each time it is read
+pub(crate) fn is_constant(e: &NumExpr) -> bool {
+    match e {
+        NumExpr::Int(_) | NumExpr::Float(_) | NumExpr::Char(_) => true,
+        NumExpr::Neg(inner) => is_constant(inner),
+        _ => false,
+    }
+}
+
+// Whether computing `e` makes strings (to free once read)
+fn reads_strings(e: &NumExpr) -> bool {
+    match e {
+        NumExpr::Len(_)
+        | NumExpr::ToInt(_)
+        | NumExpr::ToFloat(_)
+        | NumExpr::StrEq { .. }
+        | NumExpr::MapGet { .. }
+        | NumExpr::MapHas { .. }
+        | NumExpr::CharAt { .. } => true,
+        NumExpr::Binary { left, right, .. } => reads_strings(left) || reads_strings(right),
+        NumExpr::RandomInt { lo, hi } => reads_strings(lo) || reads_strings(hi),
+        NumExpr::Neg(inner) | NumExpr::Ord(inner) | NumExpr::Chr(inner) => reads_strings(inner),
+        NumExpr::Math { args, .. } => args.iter().any(reads_strings),
+        NumExpr::Int(_) | NumExpr::Float(_) | NumExpr::Char(_) | NumExpr::Var { .. } | NumExpr::ArgCount | NumExpr::Random => {
+            false
+        }
+    }
+}
@@ -0,0 +1,374 @@
+// My Programming Language
+// Interactive debugger of `mpl run --debug`: breakpoints by file:line, stepping from statement
+// to statement and the values of the locals.
+//
+// The code generator calls the host before every statement (see CodegenHooks):
+//
+//   i32.const <file>; i32.const <line>; call dbg.step   ;; 1: stop before this statement
+//   if
+//     i32.const 0; local.get 0; call dbg.local_i32       ;; every local of the function,
+//     ...                                               ;; dbg.local_f64 for the floats
+//     i32.const <function>; call dbg.pause              ;; the prompt
+//   end
+//
+// dbg.step answers 0 while the program runs between two stops: a host call per statement.
+// The prompt reads its commands from stdin and writes to stderr, the program output stays on
+// stdout (flushed by the runner before each call).
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use wasm_encoder::{BlockType, ValType};
+
+use crate::codegen::{CodegenHooks, Ty};
+use crate::parser::Variable;
+use crate::runner::{HostFunction, HostType, HostValue};
+
+const HELP: &str = "\
+commands:
+  s, step             run to the next statement (also an empty line)
+  c, continue         run to the next breakpoint
+  b, break [FILE:]LINE
+                      stop before the statements of a line (of the main file without FILE)
+  b, break            list the breakpoints
+  d, delete [N]       remove breakpoint N, or all of them
+  p, print NAME       value of a local variable
+  l, locals           values of all the local variables
+  list                source lines around the current one
+  q, quit             stop the program
+  h, help             this help";
+
+/// A breakpoint given as `[FILE:]LINE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub file: Option<PathBuf>, // None: the main file
+    pub line: usize,
+}
+
+impl std::str::FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (file, line) = match s.rsplit_once(':') {
+            Some((file, line)) => (Some(PathBuf::from(file)), line),
+            None => (None, s),
+        };
+        match line.trim().parse() {
+            Ok(line) if line > 0 => Ok(Breakpoint { file, line }),
+            _ => Err(format!("bad breakpoint '{}' (expected [FILE:]LINE)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file.display(), self.line),
+            None => write!(f, "{}", self.line),
+        }
+    }
+}
+
+// A generated function, by wasm function index
+struct FunctionInfo {
+    name: String,
+    variables: Vec<(String, Ty)>,
+}
+
+/// The source files an instrumented module refers to by number, and their lines (read when
+/// first shown).
+#[derive(Default)]
+pub(crate) struct SourceFiles {
+    files: Vec<PathBuf>,
+    lines: HashMap<PathBuf, Vec<String>>,
+}
+
+impl SourceFiles {
+    /// The number of a file, given when it is first seen.
+    pub(crate) fn id(&mut self, path: &Path) -> u32 {
+        match self.files.iter().position(|f| f == path) {
+            Some(k) => k as u32,
+            None => {
+                self.files.push(path.to_path_buf());
+                self.files.len() as u32 - 1
+            }
+        }
+    }
+
+    pub(crate) fn path(&self, file: u32) -> &Path {
+        &self.files[file as usize]
+    }
+
+    /// Line `line` (from 1) of a file, if it can be read.
+    pub(crate) fn line(&mut self, file: u32, line: usize) -> Option<&str> {
+        let path = self.files.get(file as usize)?;
+        let lines = self.lines.entry(path.clone()).or_insert_with(|| {
+            fs::read_to_string(path)
+                .map(|text| text.lines().map(str::to_string).collect())
+                .unwrap_or_default()
+        });
+        lines.get(line.checked_sub(1)?).map(String::as_str)
+    }
+}
+
+struct State {
+    main_file: PathBuf,
+    files: SourceFiles, // the file numbers of dbg.step
+    functions: HashMap<u32, FunctionInfo>,
+    breakpoints: Vec<Breakpoint>,
+    stepping: bool,
+    at: (u32, usize), // file and line of the last dbg.step
+    locals: Vec<HostValue>,
+}
+
+impl State {
+    fn matches(&self, breakpoint: &Breakpoint, file: u32, line: usize) -> bool {
+        let path = self.files.path(file);
+        breakpoint.line == line
+            && match &breakpoint.file {
+                Some(f) => path.ends_with(f),
+                None => path == self.main_file,
+            }
+    }
+
+    fn show_lines(&mut self, out: &mut impl Write, file: u32, from: usize, to: usize) -> io::Result<()> {
+        let current = self.at.1;
+        for line in from.max(1)..=to {
+            if let Some(text) = self.files.line(file, line) {
+                let marker = if line == current { '>' } else { ' ' };
+                writeln!(out, "{} {:4} | {}", marker, line, text)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&self, function: &FunctionInfo, k: usize) -> String {
+        let Some((_, ty)) = function.variables.get(k) else {
+            return String::new();
+        };
+        match (ty, self.locals.get(k)) {
+            (Ty::I32, Some(HostValue::I32(v))) => v.to_string(),
+            (Ty::F64, Some(HostValue::F64(v))) => v.to_string(),
+            (Ty::Char, Some(HostValue::I32(v))) => match char::from_u32(*v as u32) {
+                Some(c) => format!("{:?} ({})", c, v),
+                None => format!("char {} (not a code point)", v),
+            },
+            (Ty::Fn, Some(HostValue::I32(0))) => "fn (none)".to_string(),
+            (Ty::Fn, Some(HostValue::I32(v))) => format!("fn (table slot {})", v),
+            (Ty::Map, Some(HostValue::I32(v))) => format!("map @0x{:x}", v),
+            (Ty::Builder, Some(HostValue::I32(v))) => format!("builder @0x{:x}", v),
+            _ => "?".to_string(),
+        }
+    }
+
+    // The prompt, until a command resumes the program; Err stops it
+    fn pause(&mut self, function: u32) -> Result<(), String> {
+        let mut err = io::stderr().lock();
+        let (file, line) = self.at;
+        let info = self.functions.remove(&function);
+        let name = info.as_ref().map_or("?", |f| f.name.as_str());
+        let _ = writeln!(err, "stopped at {}:{} in {}", self.files.path(file).display(), line, name);
+        let _ = self.show_lines(&mut err, file, line, line);
+        let result = self.prompt(&mut err, info.as_ref());
+        if let Some(info) = info {
+            self.functions.insert(function, info);
+        }
+        result
+    }
+
+    fn prompt(&mut self, err: &mut impl Write, function: Option<&FunctionInfo>) -> Result<(), String> {
+        let stdin = io::stdin();
+        loop {
+            let _ = write!(err, "(mpl-dbg) ");
+            let _ = err.flush();
+            let mut input = String::new();
+            if stdin.lock().read_line(&mut input).map_err(|e| e.to_string())? == 0 {
+                // end of the commands: the program runs to its end
+                let _ = writeln!(err);
+                self.stepping = false;
+                self.breakpoints.clear();
+                return Ok(());
+            }
+            let mut words = input.split_whitespace();
+            let command = words.next().unwrap_or("step");
+            let arg = words.next();
+            match command {
+                "s" | "step" => {
+                    self.stepping = true;
+                    return Ok(());
+                }
+                "c" | "continue" => {
+                    self.stepping = false;
+                    return Ok(());
+                }
+                "b" | "break" => match arg.map(str::parse::<Breakpoint>) {
+                    Some(Ok(breakpoint)) => {
+                        let _ = writeln!(err, "breakpoint {} at {}", self.breakpoints.len() + 1, breakpoint);
+                        self.breakpoints.push(breakpoint);
+                    }
+                    Some(Err(e)) => {
+                        let _ = writeln!(err, "{}", e);
+                    }
+                    None => {
+                        if self.breakpoints.is_empty() {
+                            let _ = writeln!(err, "no breakpoints");
+                        }
+                        for (k, breakpoint) in self.breakpoints.iter().enumerate() {
+                            let _ = writeln!(err, "{}: {}", k + 1, breakpoint);
+                        }
+                    }
+                },
+                "d" | "delete" => match arg.map(str::parse::<usize>) {
+                    None => self.breakpoints.clear(),
+                    Some(Ok(k)) if (1..=self.breakpoints.len()).contains(&k) => {
+                        self.breakpoints.remove(k - 1);
+                    }
+                    Some(_) => {
+                        let _ = writeln!(err, "no breakpoint {}", arg.unwrap_or_default());
+                    }
+                },
+                "p" | "print" => {
+                    let found = function.and_then(|f| {
+                        let k = f.variables.iter().position(|(name, _)| Some(name.as_str()) == arg)?;
+                        Some(self.value(f, k))
+                    });
+                    let _ = match (arg, found) {
+                        (None, _) => writeln!(err, "print what? (print NAME)"),
+                        (Some(name), Some(value)) => writeln!(err, "{} = {}", name, value),
+                        (Some(name), None) => writeln!(err, "no local variable '{}' here", name),
+                    };
+                }
+                "l" | "locals" => match function.filter(|f| !f.variables.is_empty()) {
+                    Some(f) => {
+                        for (k, (name, _)) in f.variables.iter().enumerate() {
+                            let _ = writeln!(err, "{} = {}", name, self.value(f, k));
+                        }
+                    }
+                    None => {
+                        let _ = writeln!(err, "no local variables");
+                    }
+                },
+                "list" => {
+                    let (file, line) = self.at;
+                    let _ = self.show_lines(err, file, line.saturating_sub(5), line + 5);
+                }
+                "q" | "quit" => return Err("stopped by the debugger".to_string()),
+                "h" | "help" => {
+                    let _ = writeln!(err, "{}", HELP);
+                }
+                _ => {
+                    let _ = writeln!(err, "unknown command '{}' (help lists them)", command);
+                }
+            }
+        }
+    }
+}
+
+/// The debugging session of a run: the instrumentation given to the code generator and the
+/// host functions given to the runner share it.
+#[derive(Clone)]
+pub struct Debugger {
+    state: Arc<Mutex<State>>,
+}
+
+impl Debugger {
+    /// A session for the program of `main_file`: it stops before the first statement, or at
+    /// the first breakpoint if some are given.
+    pub fn new(main_file: &Path, breakpoints: Vec<Breakpoint>) -> Self {
+        let state = State {
+            main_file: main_file.to_path_buf(),
+            files: SourceFiles::default(),
+            functions: HashMap::new(),
+            stepping: breakpoints.is_empty(),
+            breakpoints,
+            at: (0, 0),
+            locals: Vec::new(),
+        };
+        Debugger {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// The calls to the dbg.* imports before every statement.
+    pub fn hooks(&self) -> CodegenHooks {
+        let state = Arc::clone(&self.state);
+        CodegenHooks::new()
+            .import("dbg", "step", &[ValType::I32, ValType::I32], &[ValType::I32])
+            .import("dbg", "local_i32", &[ValType::I32, ValType::I32], &[])
+            .import("dbg", "local_f64", &[ValType::I32, ValType::F64], &[])
+            .import("dbg", "pause", &[ValType::I32], &[])
+            .on_statement(move |ctx, stdm, instr| {
+                let Some(pos) = stdm.pos() else {
+                    return;
+                };
+                let mut state = state.lock().unwrap();
+                let file = state.files.id(&pos.file_name);
+                state.functions.entry(ctx.fn_id).or_insert_with(|| FunctionInfo {
+                    name: ctx.function.name.clone(),
+                    variables: variables(&ctx.function.variables),
+                });
+                let index = |name: &str| ctx.func_index(name).expect("dbg imports declared");
+                instr.i32_const(file as i32);
+                instr.i32_const(pos.line as i32);
+                instr.call(index("dbg.step"));
+                instr.if_(BlockType::Empty);
+                for (k, variable) in ctx.function.variables.iter().enumerate() {
+                    instr.i32_const(k as i32);
+                    instr.local_get(k as u32);
+                    instr.call(index(if variable.ty == Ty::F64 { "dbg.local_f64" } else { "dbg.local_i32" }));
+                }
+                instr.i32_const(ctx.fn_id as i32);
+                instr.call(index("dbg.pause"));
+                instr.end();
+            })
+    }
+
+    /// The dbg.* functions of the instrumented module.
+    pub fn host_functions(&self) -> Vec<HostFunction> {
+        use HostType::{F64, I32};
+        let step = Arc::clone(&self.state);
+        let local_i32 = Arc::clone(&self.state);
+        let local_f64 = Arc::clone(&self.state);
+        let pause = Arc::clone(&self.state);
+        vec![
+            HostFunction::new("dbg", "step", &[I32, I32], &[I32], move |args| {
+                let mut state = step.lock().unwrap();
+                let (file, line) = (int(args, 0) as u32, int(args, 1) as usize);
+                state.at = (file, line);
+                let stop = state.stepping || state.breakpoints.iter().any(|b| state.matches(b, file, line));
+                if stop {
+                    state.locals.clear();
+                }
+                Ok(vec![HostValue::I32(stop as i32)])
+            }),
+            HostFunction::new("dbg", "local_i32", &[I32, I32], &[], move |args| {
+                local_i32.lock().unwrap().locals.push(args[1]);
+                Ok(Vec::new())
+            }),
+            HostFunction::new("dbg", "local_f64", &[I32, F64], &[], move |args| {
+                local_f64.lock().unwrap().locals.push(args[1]);
+                Ok(Vec::new())
+            }),
+            HostFunction::new("dbg", "pause", &[I32], &[], move |args| {
+                pause.lock().unwrap().pause(int(args, 0) as u32)?;
+                Ok(Vec::new())
+            }),
+        ]
+    }
+}
+
+fn variables(variables: &[Variable]) -> Vec<(String, Ty)> {
+    variables.iter().map(|v| (v.name.clone(), v.ty)).collect()
+}
+
+fn int(args: &[HostValue], k: usize) -> i32 {
+    match args.get(k) {
+        Some(HostValue::I32(v)) => *v,
+        _ => 0,
+    }
+}
@@ -0,0 +1,118 @@
+// My Programming Language
+// What the compiler reports about a source: errors, that stop the compilation, and warnings,
+// that do not (see lint.rs) unless --deny makes them errors.
+//
+// Every report is a Diagnostic, displayed the same way:
+//
+//  Grammar error [E0201] : Expected }, found Println
+//  in file bad.mpl
+//  at line 3
+//  col 9
+
+use std::fmt;
+
+use crate::lexer::{Position, Span};
+use crate::messages::{self, Message};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: &'static Message, // its header: token error, grammar error, warning...
+    pub code: &'static str,     // stable message code (see messages.rs)
+    pub message: String,
+    pub span: Span, // shown by its start
+}
+
+impl Diagnostic {
+    pub fn error(kind: &'static Message, code: &'static str, message: String, pos: &Position) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            kind,
+            code,
+            message,
+            span: Span::at(pos),
+        }
+    }
+
+    pub fn warning(m: &Message, args: &[&dyn fmt::Display], pos: &Position) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            kind: &messages::WARNING,
+            code: m.code,
+            message: m.format(args),
+            span: Span::at(pos),
+        }
+    }
+
+    /// The same, over `span` (a token, a name) rather than at its start only
+    pub fn with_span(self, span: Span) -> Self {
+        Diagnostic { span, ..self }
+    }
+
+    /// The warning made an error (--deny)
+    pub fn denied(self) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            kind: &messages::DENIED_WARNING,
+            ..self
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pos = &self.span.start;
+        write!(
+            f,
+            " {} [{}] : {}\n {}\n",
+            self.kind.text(),
+            self.code,
+            self.message,
+            messages::LOCATION.format(&[&pos.file_name.to_string_lossy(), &pos.line, &pos.col])
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// " (did you mean 'counter'?)" for a name that is not declared: the closest of the
+/// `candidates` when it is only a typo away (an edit per three characters at most), else "".
+pub fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    let max = (name.chars().count() / 3).max(1);
+    let closest = candidates
+        .into_iter()
+        .filter(|c| *c != name)
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _)| d <= max)
+        .min();
+    match closest {
+        Some((_, c)) => messages::DID_YOU_MEAN.format(&[&c]),
+        None => String::new(),
+    }
+}
+
+// Levenshtein distance: the insertions, deletions and replacements of characters from a to b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let replaced = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,137 @@
+// My Programming Language
+// `mpl doc`: the reference of a program and its libraries, made of the `///` comments written
+// on the lines right above the functions, in Markdown or HTML.
+//
+// Each source file is a section named after its module (the file name without .mpl, what
+// `import ... as` usually calls it). It lists main and the functions other files can call:
+// the `pub fn` and `export fn`, or every function of a file without any (see modules.rs).
+// A doc comment is Markdown; the HTML keeps its text and its paragraphs.
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::grammar;
+use crate::lexer::{Lexer, Source};
+use crate::parser::{Function, Parser};
+
+/// What `mpl doc` writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DocFormat {
+    #[default]
+    Markdown,
+    Html,
+}
+
+impl FromStr for DocFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            _ => Err(format!("unknown doc format '{}' (markdown or html)", s)),
+        }
+    }
+}
+
+impl fmt::Display for DocFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Markdown => "markdown",
+            Self::Html => "html",
+        })
+    }
+}
+
+/// The documented functions of a source file.
+pub struct FileDoc {
+    pub path: PathBuf,
+    pub module: String,
+    pub functions: Vec<Function>, // in source order, main last
+}
+
+impl FileDoc {
+    /// Parse `path`, a main program if `main` (else a library), keeping the doc comments
+    pub fn read(path: &Path, main: bool) -> Result<Self, Box<dyn Error>> {
+        let src = Source::open(path).map_err(|e| format!("cannot read '{}': {}", path.display(), e))?;
+        let mut parser = Parser::new(Lexer::new(path, src).with_trivia())?;
+        let mut functions = if main {
+            let program = parser.parse_main_program()?;
+            program.functions.into_iter().chain(program.main).collect()
+        } else {
+            parser.parse_library()?.functions
+        };
+        let all_public = !functions.iter().any(|f| f.public);
+        functions.retain(|f| f.public || all_public);
+        Ok(FileDoc {
+            path: path.to_path_buf(),
+            module: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            functions,
+        })
+    }
+}
+
+// How the function is written: `pub fn name()`, `main()`
+fn signature(f: &Function) -> String {
+    if f.name == grammar::KW_MAIN {
+        return format!("{}()", grammar::KW_MAIN);
+    }
+    let visibility = if f.export {
+        "export "
+    } else if f.public {
+        "pub "
+    } else {
+        ""
+    };
+    format!("{}{} {}()", visibility, grammar::KW_FN, f.name)
+}
+
+/// The reference of `files` titled `title`, their paths shown relative to `base`.
+pub fn render(title: &str, files: &[FileDoc], base: &Path, format: DocFormat) -> String {
+    let shown = |path: &Path| path.strip_prefix(base).unwrap_or(path).display().to_string();
+    let mut out = String::new();
+    match format {
+        DocFormat::Markdown => {
+            out.push_str(&format!("# {}\n", title));
+            for file in files {
+                out.push_str(&format!("\n## `{}` ({})\n", file.module, shown(&file.path)));
+                for f in &file.functions {
+                    out.push_str(&format!("\n### `{}`\n", signature(f)));
+                    if let Some(doc) = &f.doc {
+                        out.push_str(&format!("\n{}\n", doc));
+                    }
+                }
+            }
+        }
+        DocFormat::Html => {
+            out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+            out.push_str(&format!("<title>{}</title>\n</head>\n<body>\n", html_escape(title)));
+            out.push_str(&format!("<h1>{}</h1>\n", html_escape(title)));
+            for file in files {
+                out.push_str(&format!(
+                    "<h2><code>{}</code> ({})</h2>\n",
+                    html_escape(&file.module),
+                    html_escape(&shown(&file.path))
+                ));
+                for f in &file.functions {
+                    out.push_str(&format!("<h3><code>{}</code></h3>\n", html_escape(&signature(f))));
+                    let paragraphs = f.doc.iter().flat_map(|doc| doc.split("\n\n"));
+                    for paragraph in paragraphs.filter(|p| !p.trim().is_empty()) {
+                        out.push_str(&format!("<p>{}</p>\n", html_escape(paragraph.trim())));
+                    }
+                }
+            }
+            out.push_str("</body>\n</html>\n");
+        }
+    }
+    out
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,281 @@
+// My Programming Language
+// `mpl fmt`: the canonical layout of a source file, worked out from its tokens and its
+// comments (the lexer's trivia), so that a file can be formatted even when it does not compile.
+//
+// - the line breaks of the source are kept, at most one blank line in a row (none right
+//   after `{` or before `}`); a statement, a `}` and what follows a `{` or a `//` comment
+//   start a new line; the `;` between statements is dropped
+// - a line is indented by INDENT for each `{` and `for` still open, and inside a `match` for
+//   the `case` (or `default`) the line is in
+// - inside a line the tokens are separated by one space, except after `(` and a sign, before
+//   `)` and `,`, around `.` and between a name and its `(`: `m.f()`, `to_str(-x)`
+// - the text of a token is kept as written (the spelling of a number, a string, a comment)
+
+use std::path::PathBuf;
+
+use crate::grammar::Token;
+use crate::lexer::{LexError, Lexer, Position, Trivia, TriviaKind};
+
+const INDENT: &str = "  ";
+
+/// `text` in the canonical layout. Formatting it again changes nothing.
+pub fn format_source(file_name: impl Into<PathBuf>, text: &str) -> Result<String, LexError> {
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    // byte offset of a position (its line and column count chars from 1)
+    let offset = |pos: &Position| {
+        let start = line_starts[pos.line - 1];
+        start + text[start..].chars().take(pos.col - 1).map(char::len_utf8).sum::<usize>()
+    };
+    let mut lexer = Lexer::new(file_name, text).with_trivia();
+    let mut layout = Layout::default();
+    loop {
+        let (token, span) = lexer.next_spanned()?;
+        // the comments before the token
+        for comment in lexer.take_trivia() {
+            layout.comment(&comment);
+        }
+        if token == Token::Eof {
+            return Ok(layout.finish());
+        }
+        if token == Token::Semicolon {
+            layout.break_after = true; // the statements it separates go on lines of their own
+            continue;
+        }
+        let written = &text[offset(&span.start)..offset(&span.end)];
+        layout.token(token, written, span.start.line, span.end.line);
+    }
+}
+
+#[derive(Default)]
+struct Layout {
+    out: String,
+    line: String,        // the line being laid out, without its indentation
+    line_depth: usize,   // indentation of that line
+    depth: usize,        // indentation of the next line
+    prev: Option<Token>, // the last token
+    last_line: usize,    // source line where the last token or comment ends
+    operand: bool,       // the last token ends an operand: a `-` after it is binary
+    sign: bool,          // the last token is a sign (`-x`)
+    opened: bool,        // the last token or comment is a `{`
+    after_comment: bool, // the last token or comment is a comment
+    break_after: bool,   // the last token or comment ends its line
+    braces: Vec<bool>,   // the `{` still open: whether a `case` or `default` is open in it
+}
+
+impl Layout {
+    fn token(&mut self, token: Token, written: &str, start_line: usize, end_line: usize) {
+        if matches!(token, Token::RBrace | Token::Next) {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        // a case ends at the next one and at the `}` of its match
+        if matches!(token, Token::RBrace | Token::Case | Token::Default) {
+            let in_case = match token {
+                Token::RBrace => self.braces.pop(),
+                _ => self.braces.last_mut().map(|open| std::mem::replace(open, false)),
+            };
+            if in_case == Some(true) {
+                self.depth = self.depth.saturating_sub(1);
+            }
+        }
+        let (break_before, space) = match &self.prev {
+            Some(prev) => (
+                starts_line(&token, prev),
+                self.after_comment || spaced(prev, self.sign, &token),
+            ),
+            None => (false, true),
+        };
+        self.place(written, start_line, break_before, space, token == Token::RBrace);
+        if matches!(token, Token::LBrace | Token::For) {
+            self.depth += 1;
+        }
+        match token {
+            Token::LBrace => self.braces.push(false),
+            Token::Case | Token::Default => {
+                if let Some(open) = self.braces.last_mut() {
+                    *open = true;
+                    self.depth += 1;
+                }
+            }
+            _ => {}
+        }
+        self.opened = token == Token::LBrace;
+        self.after_comment = false;
+        self.break_after = self.opened;
+        self.sign = matches!(token, Token::Plus | Token::Minus) && !self.operand;
+        self.operand = ends_operand(&token);
+        self.last_line = end_line;
+        self.prev = Some(token);
+    }
+
+    fn comment(&mut self, comment: &Trivia) {
+        self.place(comment.text.trim_end(), comment.span.start.line, false, true, false);
+        self.opened = false;
+        self.after_comment = true;
+        self.break_after = comment.kind == TriviaKind::Line;
+        self.last_line = comment.span.end.line;
+    }
+
+    // Put `written` after what is laid out: on the same line unless the source or the rules
+    // break the line there
+    fn place(&mut self, written: &str, start_line: usize, break_before: bool, space: bool, closing: bool) {
+        if !self.out.is_empty() || !self.line.is_empty() {
+            let gap = start_line.saturating_sub(self.last_line);
+            if gap > 0 || self.break_after || break_before {
+                self.end_line();
+                if gap > 1 && !self.opened && !closing {
+                    self.out.push('\n');
+                }
+            } else if space {
+                self.line.push(' ');
+            }
+        }
+        if self.line.is_empty() {
+            self.line_depth = self.depth;
+        }
+        self.line.push_str(written);
+    }
+
+    fn end_line(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        for _ in 0..self.line_depth {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(self.line.trim_end());
+        self.out.push('\n');
+        self.line.clear();
+    }
+
+    fn finish(mut self) -> String {
+        self.end_line();
+        self.out
+    }
+}
+
+// Tokens that begin a statement or a declaration, on a line of their own
+fn starts_line(token: &Token, prev: &Token) -> bool {
+    match token {
+        Token::Fn => !matches!(prev, Token::Pub | Token::Export | Token::Extern | Token::Local),
+        Token::Extern => !matches!(prev, Token::Pub | Token::RBracket),
+        Token::Pub => !matches!(prev, Token::RBracket),
+        Token::Import => !matches!(prev, Token::LBracket),
+        Token::Main => !matches!(prev, Token::Call | Token::Amp),
+        Token::Hash
+        | Token::Export
+        | Token::Local
+        | Token::Let
+        | Token::For
+        | Token::Next
+        | Token::Break
+        | Token::Print
+        | Token::Println
+        | Token::EPrint
+        | Token::EPrintln
+        | Token::Call
+        | Token::CallIndirect
+        | Token::Enum
+        | Token::Match
+        | Token::Case
+        | Token::Default
+        | Token::Return
+        | Token::Flush
+        | Token::DumpHeap
+        | Token::Delete
+        | Token::Append
+        | Token::Clear
+        | Token::RBrace => true,
+        _ => false,
+    }
+}
+
+// Whether a space goes between `prev` and `token` on a line
+fn spaced(prev: &Token, sign: bool, token: &Token) -> bool {
+    match (prev, token) {
+        (_, Token::RParen | Token::Comma | Token::Dot | Token::Colon | Token::LBracket | Token::RBracket)
+        | (Token::LParen | Token::LBracket | Token::Dot | Token::Amp, _) => false,
+        _ if sign => false,
+        (_, Token::LParen) => !is_callee(prev),
+        _ => true,
+    }
+}
+
+// Names written right before their `(`
+fn is_callee(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Ident(_)
+            | Token::Main
+            | Token::Print
+            | Token::Println
+            | Token::EPrint
+            | Token::EPrintln
+            | Token::ToStr
+            | Token::Arg
+            | Token::ArgCount
+            | Token::Random
+            | Token::RandomInt
+            | Token::Len
+            | Token::ToInt
+            | Token::ToFloat
+            | Token::Substr
+            | Token::CharAt
+            | Token::Ord
+            | Token::Chr
+            | Token::Math(_)
+            | Token::Flush
+            | Token::DumpHeap
+            | Token::Has
+            | Token::Delete
+            | Token::Repeat
+            | Token::FormatThousands
+            | Token::DecimalComma
+            | Token::Join
+            | Token::Append
+            | Token::Clear
+            | Token::Import
+    )
+}
+
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Ident(_)
+            | Token::Integer(_)
+            | Token::Float(_)
+            | Token::Str(_)
+            | Token::Char(_)
+            | Token::Nl
+            | Token::True
+            | Token::False
+            | Token::RParen
+            | Token::RBracket
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "fn   f( ) {\nprintln( \"x\" ) ; println(\"y\")\n}\n\n\n// main\nmain() {\n    local int i\n  for i = 1 to 2\n  f()\nnext\n    return to_str( -i )==1\n}\n";
+
+    const CANONICAL: &str = "fn f() {\n  println(\"x\")\n  println(\"y\")\n}\n\n// main\nmain() {\n  local int i\n  for i = 1 to 2\n    f()\n  next\n  return to_str(-i) == 1\n}\n";
+
+    #[test]
+    fn lays_out_lines_indentation_and_spaces() {
+        assert_eq!(format_source("messy.mpl", MESSY).unwrap(), CANONICAL);
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        assert_eq!(format_source("canonical.mpl", CANONICAL).unwrap(), CANONICAL);
+    }
+
+    #[test]
+    fn a_source_that_does_not_compile_is_still_formatted() {
+        // an unknown function and a missing `next`: only the tokens matter
+        assert_eq!(format_source("bad.mpl", "main() {\nfor i = 1 to 2\nnope( )\n}\n").unwrap(), "main() {\n  for i = 1 to 2\n    nope()\n  }\n");
+    }
+}
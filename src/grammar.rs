@@ -2,16 +2,30 @@
 // My Programming Language
 // all the keywords, operators ...
 
-#[derive(Debug, Clone)]
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Import,
+    As,
+    Pub,
+    Export,
+    Extern,
     Fn,
     Main,
     Print,
     Println,
+    EPrint,
+    EPrintln,
     Call,
+    CallIndirect,
+    Enum,
+    Match,
+    Case,
+    Default,
     Ident(String),
     Str(String),
+    Char(char),
     Integer(i32),
     Float(f64),
     ToStr,
@@ -19,11 +33,24 @@ pub enum Token {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    Hash,
     Comma,
+    Dot,
+    Colon,
+    Semicolon,
+    Amp,
     Plus,
     Minus,
     Star,
     Slash,
+    EqEq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
     Nl,
     Local,
     True,
@@ -31,21 +58,104 @@ pub enum Token {
     Equal,
     IntType,
     FloatType,
+    CharType,
+    MapType,
+    BuilderType,
     Let,
     For,
     To,
     Step,
     Next,
     Break,
+    Return,
+    ArgCount,
+    Arg,
+    Random,
+    RandomInt,
+    Len,
+    ToInt,
+    ToFloat,
+    Substr,
+    CharAt,
+    Ord,
+    Chr,
+    Has,
+    Delete,
+    Repeat,
+    Join,
+    FormatThousands,
+    DecimalComma,
+    Append,
+    Clear,
+    Math(MathFn),
+    Flush,
+    DumpHeap,
     Eof,
 }
 
+// Built-in math functions (numeric expressions)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MathFn {
+    Sqrt,
+    Abs,
+    Min,
+    Max,
+    Pow,
+    Floor,
+    Ceil,
+    Round,
+    Sin,
+    Cos,
+    Tan,
+    Log,
+    Exp,
+}
+
+impl MathFn {
+    pub fn name(self) -> &'static str {
+        match self {
+            MathFn::Sqrt => KW_SQRT,
+            MathFn::Abs => KW_ABS,
+            MathFn::Min => KW_MIN,
+            MathFn::Max => KW_MAX,
+            MathFn::Pow => KW_POW,
+            MathFn::Floor => KW_FLOOR,
+            MathFn::Ceil => KW_CEIL,
+            MathFn::Round => KW_ROUND,
+            MathFn::Sin => KW_SIN,
+            MathFn::Cos => KW_COS,
+            MathFn::Tan => KW_TAN,
+            MathFn::Log => KW_LOG,
+            MathFn::Exp => KW_EXP,
+        }
+    }
+
+    // number of arguments
+    pub fn arity(self) -> usize {
+        match self {
+            MathFn::Min | MathFn::Max | MathFn::Pow => 2,
+            _ => 1,
+        }
+    }
+}
+
 pub const KW_IMPORT: &str = "import";
+pub const KW_AS: &str = "as";
 pub const KW_FN: &str = "fn";
+pub const KW_PUB: &str = "pub";
+pub const KW_EXPORT: &str = "export";
+pub const KW_EXTERN: &str = "extern";
 pub const KW_MAIN: &str = "main";
 pub const KW_PRINT: &str = "print";
 pub const KW_PRINTLN: &str = "println";
+pub const KW_EPRINT: &str = "eprint";
+pub const KW_EPRINTLN: &str = "eprintln";
 pub const KW_CALL: &str = "call";
+pub const KW_CALL_INDIRECT: &str = "call_indirect";
+pub const KW_ENUM: &str = "enum";
+pub const KW_MATCH: &str = "match";
+pub const KW_CASE: &str = "case";
+pub const KW_DEFAULT: &str = "default";
 pub const KW_TO_STR: &str = "to_str";
 pub const KW_NL: &str = "nl";
 pub const KW_LOCAL: &str = "local";
@@ -53,23 +163,74 @@ pub const KW_TRUE: &str = "true";
 pub const KW_FALSE: &str = "false";
 pub const KW_INT_TYPE: &str = "int";
 pub const KW_FLOAT_TYPE: &str = "float";
+pub const KW_CHAR_TYPE: &str = "char";
+pub const KW_MAP_TYPE: &str = "map";
+pub const KW_BUILDER_TYPE: &str = "builder";
 pub const KW_LET: &str = "let";
 pub const KW_FOR: &str = "for";
 pub const KW_TO: &str = "to";
 pub const KW_STEP: &str = "step";   
 pub const KW_NEXT: &str = "next";
 pub const KW_BREAK: &str = "break";
+pub const KW_RETURN: &str = "return";
+pub const KW_ARG_COUNT: &str = "arg_count";
+pub const KW_ARG: &str = "arg";
+pub const KW_FLUSH: &str = "flush";
+pub const KW_DUMP_HEAP: &str = "dump_heap";
+pub const KW_RANDOM: &str = "random";
+pub const KW_RANDOM_INT: &str = "random_int";
+pub const KW_LEN: &str = "len";
+pub const KW_TO_INT: &str = "to_int";
+pub const KW_TO_FLOAT: &str = "to_float";
+pub const KW_SUBSTR: &str = "substr";
+pub const KW_CHAR_AT: &str = "char_at";
+pub const KW_ORD: &str = "ord";
+pub const KW_CHR: &str = "chr";
+pub const KW_HAS: &str = "has";
+pub const KW_DELETE: &str = "delete";
+pub const KW_REPEAT: &str = "repeat";
+pub const KW_JOIN: &str = "join";
+pub const KW_FORMAT_THOUSANDS: &str = "format_thousands";
+pub const KW_DECIMAL_COMMA: &str = "decimal_comma";
+pub const KW_APPEND: &str = "append";
+pub const KW_CLEAR: &str = "clear";
+pub const KW_SQRT: &str = "sqrt";
+pub const KW_ABS: &str = "abs";
+pub const KW_MIN: &str = "min";
+pub const KW_MAX: &str = "max";
+pub const KW_POW: &str = "pow";
+pub const KW_FLOOR: &str = "floor";
+pub const KW_CEIL: &str = "ceil";
+pub const KW_ROUND: &str = "round";
+pub const KW_SIN: &str = "sin";
+pub const KW_COS: &str = "cos";
+pub const KW_TAN: &str = "tan";
+pub const KW_LOG: &str = "log";
+pub const KW_EXP: &str = "exp";
 
 pub const LPAREN: &str = "(";
 pub const RPAREN: &str = ")";
 pub const LBRACE: &str = "{";
 pub const RBRACE: &str = "}";
+pub const LBRACKET: &str = "[";
+pub const RBRACKET: &str = "]";
+pub const HASH: &str = "#";
 pub const COMMA: &str = ",";
+pub const DOT: &str = ".";
+pub const COLON: &str = ":";
+pub const SEMICOLON: &str = ";";
+pub const AMP: &str = "&";
 pub const PLUS: &str = "+";
 pub const MINUS: &str = "-";
 pub const STAR: &str = "*";
 pub const SLASH: &str = "/";
 pub const EQUAL: &str = "=";
+pub const EQ_EQ: &str = "==";
+pub const NOT_EQ: &str = "!=";
+pub const LESS: &str = "<";
+pub const LESS_EQ: &str = "<=";
+pub const GREATER: &str = ">";
+pub const GREATER_EQ: &str = ">=";
 
 pub const EOF: &str = "end of file";
 
@@ -80,61 +241,25 @@ pub const EOF: &str = "end of file";
 macro_rules! expect {
     // --- With payload: Ok((out, pos))
     ($self:ident, $pat:pat => $out:expr, $expected:expr) => {{
-        // Move the current token out to match on it, keep a snapshot of its position.
-        let tok = ::std::mem::replace(&mut $self.token, Token::Eof);
-        let pos_snapshot = $self.pos.clone(); // position of the consumed token
-
-        match tok {
-            $pat => {
-                // Bind the payload (e.g. an identifier String)
-                let __v = $out;
-
-                // Advance to the next token after successful match
-                $self.next_token()?;
-
-                // Return both the extracted value and the position of the consumed token
-                Ok((__v, pos_snapshot))
-            }
-            other => {
-                // Build a precise error using the current position
-                let err = ParseError::Unexpected {
-                    found: other.clone(),
-                    expected: $expected,
-                    pos: $self.pos.clone(),
-                };
-
-                // Restore the token to keep parser state consistent
-                $self.token = other;
-
-                Err(err)
+        #[allow(unused_variables)] // the payload is only bound once the token is consumed
+        let found = matches!(&$self.token, $pat);
+        if found {
+            // Consume the token, then bind its payload (e.g. an identifier String)
+            match $self.advance()? {
+                ($pat, pos) => Ok(($out, pos)),
+                _ => unreachable!("expect!: the token was checked"),
             }
+        } else {
+            Err($self.unexpected($expected))
         }
     }};
     // --- Without payload: Ok(pos)
     ($self:ident, $pat:pat, $expected:expr) => {{
-        // Same as above, but we only care about the position
-        let tok = ::std::mem::replace(&mut $self.token, Token::Eof);
-        let pos_snapshot = $self.pos.clone();
-
-        match tok {
-            $pat => {
-                // Advance to the next token on success
-                $self.next_token()?;
-                // Return the position of the consumed token
-                Ok(pos_snapshot)
-            }
-            other => {
-                let err = ParseError::Unexpected {
-                    found: other.clone(),
-                    expected: $expected,
-                    pos: $self.pos.clone(),
-                };
-
-                // Restore parser state
-                $self.token = other;
-
-                Err(err)
-            }
+        if matches!(&$self.token, $pat) {
+            // Consume the token, return its position
+            $self.advance().map(|(_, pos)| pos)
+        } else {
+            Err($self.unexpected($expected))
         }
     }};
 }
\ No newline at end of file
@@ -0,0 +1,473 @@
+// Vincent Pineau 04/10/2025
+// My Programming Language
+// Typed IR of the numeric expressions, between the AST and the wasm
+//
+// A NumExpr says what was written; a Num says what is computed. Every node carries the wasm
+// type it is computed in, the int/float conversions are explicit Convert nodes (chars, ord()
+// and chr() are only i32s) and variables are local indices. The typing rules live in lower_num;
+// codegen lowers each expression before emitting it and only translates nodes to instructions.
+// Strings, maps and statements are still generated from the AST: the string operands of a Num
+// point into it.
+//
+// --emit=ir prints a program with its numeric expressions lowered (print_program): only they
+// are IR, the rest is printed as the AST has it.
+
+use std::fmt::Write;
+
+use crate::codegen::{Ty, get_variable_index, infer_type};
+use crate::grammar::{self, MathFn};
+use crate::lexer::Position;
+use crate::parser::{BinOp, Expr, FnExpr, Function, NumExpr, ParseError, Program, Stadment, StrExpr, Variable};
+
+// The wasm type a number is computed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumTy {
+    I32,
+    F64,
+}
+
+impl NumTy {
+    // chars, fn, map and builder values are i32s
+    pub fn of(ty: Ty) -> Self {
+        match ty {
+            Ty::F64 => NumTy::F64,
+            Ty::I32 | Ty::Char | Ty::Fn | Ty::Map | Ty::Builder => NumTy::I32,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NumTy::I32 => "i32",
+            NumTy::F64 => "f64",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Num<'a> {
+    pub ty: NumTy,
+    pub kind: NumKind<'a>,
+}
+
+#[derive(Debug, Clone)]
+pub enum NumKind<'a> {
+    Int(i32),   // i32
+    Float(f64), // f64
+    Local {
+        index: u32,
+        name: &'a str,
+    },
+    Convert(Box<Num<'a>>), // from its type to the type of the node: f64 to i32 truncates toward zero
+    Neg(Box<Num<'a>>),
+    Binary {
+        op: BinOp,
+        left: Box<Num<'a>>, // both in the type of the node
+        right: Box<Num<'a>>,
+    },
+    ArgCount,
+    Random,
+    RandomInt {
+        lo: Box<Num<'a>>,
+        hi: Box<Num<'a>>,
+    },
+    Len(&'a StrExpr),
+    ToInt(&'a StrExpr),
+    ToFloat(&'a StrExpr),
+    StrEq {
+        left: &'a StrExpr,
+        right: &'a StrExpr,
+        negated: bool,
+    },
+    MapGet {
+        var: &'a Variable,
+        key: &'a StrExpr,
+        pos: &'a Position,
+    },
+    MapHas {
+        var: &'a Variable,
+        key: &'a StrExpr,
+        pos: &'a Position,
+    },
+    CharAt {
+        s: &'a StrExpr,
+        index: Box<Num<'a>>,
+    },
+    Math {
+        func: MathFn, // floor, ceil and round of an int are the int itself, not a Math node
+        args: Vec<Num<'a>>,
+    },
+}
+
+impl<'a> Num<'a> {
+    fn new(ty: NumTy, kind: NumKind<'a>) -> Self {
+        Num { ty, kind }
+    }
+
+    // This number as `ty`
+    fn convert(self, ty: NumTy) -> Self {
+        if self.ty == ty {
+            self
+        } else {
+            Num::new(ty, NumKind::Convert(Box::new(self)))
+        }
+    }
+}
+
+// `e` computed as `target`, the variables being the locals of its function: an operator is
+// computed in the type its value goes to, its operands converted to it
+pub fn lower_num<'a>(e: &'a NumExpr, target: Ty, variables: &[Variable]) -> Result<Num<'a>, ParseError> {
+    let target = NumTy::of(target);
+    let lower = |e: &'a NumExpr, ty: NumTy| lower_num(e, ty_of(ty), variables).map(Box::new);
+    let num = match e {
+        NumExpr::Int(i) => Num::new(NumTy::I32, NumKind::Int(*i)),
+        NumExpr::Float(r) => Num::new(NumTy::F64, NumKind::Float(*r)),
+        NumExpr::Char(c) => Num::new(NumTy::I32, NumKind::Int(*c as i32)),
+        // the same i32, seen as another type
+        NumExpr::Ord(inner) | NumExpr::Chr(inner) => lower_num(inner, Ty::I32, variables)?,
+        NumExpr::Var { var, pos } => {
+            let index = get_variable_index(variables, &var.name, pos)? as u32;
+            let name = &var.name;
+            Num::new(NumTy::of(var.ty), NumKind::Local { index, name })
+        }
+        NumExpr::Neg(inner) => Num::new(target, NumKind::Neg(lower(inner, target)?)),
+        NumExpr::Binary { op, left, right } => Num::new(
+            target,
+            NumKind::Binary {
+                op: *op,
+                left: lower(left, target)?,
+                right: lower(right, target)?,
+            },
+        ),
+        NumExpr::ArgCount => Num::new(NumTy::I32, NumKind::ArgCount),
+        NumExpr::Random => Num::new(NumTy::F64, NumKind::Random),
+        NumExpr::RandomInt { lo, hi } => Num::new(
+            NumTy::I32,
+            NumKind::RandomInt {
+                lo: lower(lo, NumTy::I32)?,
+                hi: lower(hi, NumTy::I32)?,
+            },
+        ),
+        NumExpr::Len(s) => Num::new(NumTy::I32, NumKind::Len(s)),
+        NumExpr::ToInt(s) => Num::new(NumTy::I32, NumKind::ToInt(s)),
+        NumExpr::ToFloat(s) => Num::new(NumTy::F64, NumKind::ToFloat(s)),
+        NumExpr::StrEq { left, right, negated } => Num::new(
+            NumTy::I32,
+            NumKind::StrEq {
+                left,
+                right,
+                negated: *negated,
+            },
+        ),
+        NumExpr::MapGet { var, key, pos } => Num::new(NumTy::I32, NumKind::MapGet { var, key, pos }),
+        NumExpr::MapHas { var, key, pos } => Num::new(NumTy::I32, NumKind::MapHas { var, key, pos }),
+        NumExpr::CharAt { s, index } => Num::new(
+            NumTy::I32,
+            NumKind::CharAt {
+                s,
+                index: lower(index, NumTy::I32)?,
+            },
+        ),
+        NumExpr::Math { func, args } => lower_math(*func, args, NumTy::of(infer_type(e)), variables)?,
+    };
+    Ok(num.convert(target))
+}
+
+// A math builtin computed in `ty` (see infer_type): pow and the transcendental functions are
+// computed in f64 whatever `ty`
+fn lower_math<'a>(func: MathFn, args: &'a [NumExpr], ty: NumTy, variables: &[Variable]) -> Result<Num<'a>, ParseError> {
+    let computed = match (func, ty) {
+        (MathFn::Sqrt | MathFn::Pow | MathFn::Sin | MathFn::Cos | MathFn::Tan | MathFn::Log | MathFn::Exp, _) => {
+            NumTy::F64
+        }
+        // integers are already whole
+        (MathFn::Floor | MathFn::Ceil | MathFn::Round, NumTy::I32) => {
+            return lower_num(&args[0], Ty::I32, variables);
+        }
+        _ => ty,
+    };
+    let args = args
+        .iter()
+        .map(|a| lower_num(a, ty_of(computed), variables))
+        .collect::<Result<_, _>>()?;
+    Ok(Num::new(computed, NumKind::Math { func, args }).convert(ty))
+}
+
+fn ty_of(ty: NumTy) -> Ty {
+    match ty {
+        NumTy::I32 => Ty::I32,
+        NumTy::F64 => Ty::F64,
+    }
+}
+
+/// The numeric expression IR dump (--emit=ir): each function with its locals, then its statements
+/// with their numeric expressions lowered, written like wasm folded instructions:
+/// `(f64.add (local.get $x) (f64.convert_i32_s (i32.const 1)))`. The functions of the wasm
+/// libraries and the host functions come first, as imports.
+pub fn print_program(prog: &Program) -> Result<String, ParseError> {
+    let mut out = String::new();
+    for e in &prog.externs {
+        let params: Vec<&str> = e.params.iter().map(|p| NumTy::of(p.ty).name()).collect();
+        let _ = writeln!(out, "import {}({}) from {}.{}", e.name, params.join(", "), e.module, e.field);
+    }
+    for linked in &prog.linked {
+        let _ = writeln!(out, "import {}() from {}.{}", linked.name, linked.module, linked.field);
+    }
+    let functions = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main);
+    for function in functions {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        Printer { prog, function, out: &mut out }.function()?;
+    }
+    Ok(out)
+}
+
+struct Printer<'p> {
+    prog: &'p Program,
+    function: &'p Function,
+    out: &'p mut String,
+}
+
+impl Printer<'_> {
+    fn function(&mut self) -> Result<(), ParseError> {
+        let f = self.function;
+        let export = if f.export { "export " } else { "" };
+        let _ = writeln!(self.out, "{}fn {}", export, f.name);
+        for (i, var) in f.variables.iter().enumerate() {
+            let _ = writeln!(self.out, "  local {} ${}: {} ({})", i, var.name, NumTy::of(var.ty).name(), var.ty.name());
+        }
+        self.body(&f.body, 1)
+    }
+
+    fn body(&mut self, body: &[Stadment], depth: usize) -> Result<(), ParseError> {
+        for st in body {
+            self.stadment(st, depth)?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        let _ = writeln!(self.out, "{}{}", "  ".repeat(depth), text);
+    }
+
+    fn stadment(&mut self, st: &Stadment, depth: usize) -> Result<(), ParseError> {
+        let text = match st {
+            Stadment::Print { items, .. } => format!("print {}", self.strs(items)?),
+            Stadment::Println { items, .. } => format!("println {}", self.strs(items)?),
+            Stadment::EPrint { items, .. } => format!("eprint {}", self.strs(items)?),
+            Stadment::EPrintln { items, .. } => format!("eprintln {}", self.strs(items)?),
+            Stadment::Call { name, args, .. } => {
+                // the arguments of an extern fn, in the types of its parameters
+                let params = self.prog.externs.iter().find(|e| e.name == *name).map(|e| &e.params[..]);
+                let mut texts = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    let ty = params.and_then(|p| p.get(i)).map_or_else(|| infer_type(arg), |p| p.ty);
+                    texts.push(self.num(arg, ty)?);
+                }
+                format!("call {}({})", name, texts.join(", "))
+            }
+            Stadment::CallIndirect { var, .. } => format!("call_indirect ${}", var.name),
+            Stadment::Assignment { var, expr, .. } => format!("local.set ${} {}", var.name, self.expr(expr, var.ty)?),
+            Stadment::ForLoop {
+                var,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                let mut text = format!("for ${} = {} to {}", var.name, self.expr(start, var.ty)?, self.expr(end, var.ty)?);
+                if let Some(step) = step {
+                    text += &format!(" step {}", self.expr(step, var.ty)?);
+                }
+                self.line(depth, &text);
+                self.body(body, depth + 1)?;
+                self.line(depth, "end");
+                return Ok(());
+            }
+            Stadment::Return { expr, .. } => format!("return {}", self.expr(expr, Ty::I32)?),
+            Stadment::MapSet { var, key, value, .. } => {
+                format!("map_set ${} {} {}", var.name, self.str(key)?, self.num(value, Ty::I32)?)
+            }
+            Stadment::MapDelete { var, key, .. } => format!("map_delete ${} {}", var.name, self.str(key)?),
+            Stadment::Append { var, items, .. } => format!("append ${} {}", var.name, self.strs(items)?),
+            Stadment::Clear { var, .. } => format!("clear ${}", var.name),
+            Stadment::Match { value, arms, default, .. } => {
+                let text = format!("match {}", self.num(value, Ty::I32)?);
+                self.line(depth, &text);
+                for arm in arms {
+                    let values: Vec<String> = arm.values.iter().map(i32::to_string).collect();
+                    self.line(depth + 1, &format!("case {}:", values.join(", ")));
+                    self.body(&arm.body, depth + 2)?;
+                }
+                if let Some(default) = default {
+                    self.line(depth + 1, "default:");
+                    self.body(default, depth + 2)?;
+                }
+                self.line(depth, "end");
+                return Ok(());
+            }
+            Stadment::Flush => grammar::KW_FLUSH.to_string(),
+            Stadment::DumpHeap { region, .. } => match region {
+                Some((start, len)) => format!("dump_heap {} {}", self.num(start, Ty::I32)?, self.num(len, Ty::I32)?),
+                None => "dump_heap".to_string(),
+            },
+        };
+        self.line(depth, &text);
+        Ok(())
+    }
+
+    fn expr(&self, expr: &Expr, ty: Ty) -> Result<String, ParseError> {
+        match expr {
+            Expr::Num(n) => self.num(n, ty),
+            Expr::Str(s) => self.str(s),
+            Expr::Fn(FnExpr::Ref { name, .. }) => Ok(format!("(ref.func {})", name)),
+            Expr::Fn(FnExpr::Var { var, .. }) => Ok(format!("(local.get ${})", var.name)),
+        }
+    }
+
+    fn num(&self, e: &NumExpr, ty: Ty) -> Result<String, ParseError> {
+        let num = lower_num(e, ty, &self.function.variables)?;
+        let mut text = String::new();
+        self.num_text(&num, &mut text)?;
+        Ok(text)
+    }
+
+    fn num_text(&self, num: &Num, out: &mut String) -> Result<(), ParseError> {
+        let ty = num.ty.name();
+        match &num.kind {
+            NumKind::Int(i) => {
+                let _ = write!(out, "(i32.const {})", i);
+            }
+            NumKind::Float(r) => {
+                let _ = write!(out, "(f64.const {:?})", r);
+            }
+            NumKind::Local { name, .. } => {
+                let _ = write!(out, "(local.get ${})", name);
+            }
+            NumKind::Convert(inner) => {
+                let op = match num.ty {
+                    NumTy::F64 => "f64.convert_i32_s",
+                    NumTy::I32 => "i32.trunc_f64_s",
+                };
+                let _ = write!(out, "({} ", op);
+                self.num_text(inner, out)?;
+                out.push(')');
+            }
+            NumKind::Neg(inner) => {
+                let _ = write!(out, "({}.neg ", ty);
+                self.num_text(inner, out)?;
+                out.push(')');
+            }
+            NumKind::Binary { op, left, right } => {
+                let op = match (op, num.ty) {
+                    (BinOp::Add, _) => "add",
+                    (BinOp::Sub, _) => "sub",
+                    (BinOp::Mul, _) => "mul",
+                    (BinOp::Div, NumTy::I32) => "div_s",
+                    (BinOp::Div, NumTy::F64) => "div",
+                };
+                let _ = write!(out, "({}.{} ", ty, op);
+                self.num_text(left, out)?;
+                out.push(' ');
+                self.num_text(right, out)?;
+                out.push(')');
+            }
+            NumKind::ArgCount => {
+                let _ = write!(out, "({}.{})", ty, grammar::KW_ARG_COUNT);
+            }
+            NumKind::Random => {
+                let _ = write!(out, "({}.{})", ty, grammar::KW_RANDOM);
+            }
+            NumKind::RandomInt { lo, hi } => {
+                let _ = write!(out, "({}.{} ", ty, grammar::KW_RANDOM_INT);
+                self.num_text(lo, out)?;
+                out.push(' ');
+                self.num_text(hi, out)?;
+                out.push(')');
+            }
+            NumKind::Len(s) => {
+                let _ = write!(out, "({}.{} {})", ty, grammar::KW_LEN, self.str(s)?);
+            }
+            NumKind::ToInt(s) => {
+                let _ = write!(out, "({}.{} {})", ty, grammar::KW_TO_INT, self.str(s)?);
+            }
+            NumKind::ToFloat(s) => {
+                let _ = write!(out, "({}.{} {})", ty, grammar::KW_TO_FLOAT, self.str(s)?);
+            }
+            NumKind::StrEq { left, right, negated } => {
+                let op = if *negated { "str_ne" } else { "str_eq" };
+                let _ = write!(out, "({}.{} {} {})", ty, op, self.str(left)?, self.str(right)?);
+            }
+            NumKind::MapGet { var, key, .. } => {
+                let _ = write!(out, "({}.map_get ${} {})", ty, var.name, self.str(key)?);
+            }
+            NumKind::MapHas { var, key, .. } => {
+                let _ = write!(out, "({}.{} ${} {})", ty, grammar::KW_HAS, var.name, self.str(key)?);
+            }
+            NumKind::CharAt { s, index } => {
+                let _ = write!(out, "({}.{} {} ", ty, grammar::KW_CHAR_AT, self.str(s)?);
+                self.num_text(index, out)?;
+                out.push(')');
+            }
+            NumKind::Math { func, args } => {
+                let _ = write!(out, "({}.{}", ty, func.name());
+                for arg in args {
+                    out.push(' ');
+                    self.num_text(arg, out)?;
+                }
+                out.push(')');
+            }
+        }
+        Ok(())
+    }
+
+    fn strs(&self, items: &[StrExpr]) -> Result<String, ParseError> {
+        let texts = items.iter().map(|s| self.str(s)).collect::<Result<Vec<_>, _>>()?;
+        Ok(texts.join(" "))
+    }
+
+    // A string expression, its numbers lowered in the types codegen computes them in
+    fn str(&self, e: &StrExpr) -> Result<String, ParseError> {
+        Ok(match e {
+            StrExpr::Str(s) => format!("{:?}", s),
+            StrExpr::Nl => "nl".to_string(),
+            // a char is an i32 here, but its text is the character
+            StrExpr::NumToStr(n) => match infer_type(n) {
+                Ty::Char => format!("({}_char {})", grammar::KW_TO_STR, self.num(n, Ty::Char)?),
+                ty => format!("({} {})", grammar::KW_TO_STR, self.num(n, ty)?),
+            },
+            StrExpr::ToFixed { n, decimals } => {
+                format!("({} {} {})", grammar::KW_TO_STR, self.num(n, Ty::F64)?, self.num(decimals, Ty::I32)?)
+            }
+            StrExpr::Thousands { n, decimals } => {
+                let mut text = format!("({} {}", grammar::KW_FORMAT_THOUSANDS, self.num(n, Ty::F64)?);
+                if let Some(decimals) = decimals {
+                    text += &format!(" {}", self.num(decimals, Ty::I32)?);
+                }
+                text + ")"
+            }
+            StrExpr::DecimalComma(s) => format!("({} {})", grammar::KW_DECIMAL_COMMA, self.str(s)?),
+            StrExpr::Arg(index) => format!("({} {})", grammar::KW_ARG, self.num(index, Ty::I32)?),
+            StrExpr::Substr { s, start, len } => format!(
+                "({} {} {} {})",
+                grammar::KW_SUBSTR,
+                self.str(s)?,
+                self.num(start, Ty::I32)?,
+                self.num(len, Ty::I32)?
+            ),
+            StrExpr::CharAt { s, index } => {
+                format!("({} {} {})", grammar::KW_CHAR_AT, self.str(s)?, self.num(index, Ty::I32)?)
+            }
+            StrExpr::Repeat { s, count } => {
+                format!("({} {} {})", grammar::KW_REPEAT, self.str(s)?, self.num(count, Ty::I32)?)
+            }
+            StrExpr::Join { sep, parts } => format!("({} {} {})", grammar::KW_JOIN, self.str(sep)?, self.strs(parts)?),
+            StrExpr::Builder { var, .. } => format!("(local.get ${})", var.name),
+        })
+    }
+}
@@ -0,0 +1,378 @@
+// My Programming Language
+// JavaScript glue: runs a compiled module in a browser or with Node.js,
+// with the host functions of runner.rs
+
+use crate::codegen::MemoryLimits;
+use crate::symbols::json_string;
+
+// Host functions, in JavaScript; same behavior as runner.rs (traps become exceptions).
+// `exports` is filled with the instance exports once the module is instantiated.
+const IMPORTS_JS: &str = r#"// Host functions of an MPL module (the same as the mpl runner)
+function makeImports(memory, exports, { args = [], seed = null, write, writeErr, flush }) {
+  const encoder = new TextEncoder();
+  const decoder = new TextDecoder();
+  const bytes = (ptr, len) => new Uint8Array(memory.buffer, ptr, len);
+  const text = (ptr, len) => decoder.decode(bytes(ptr, len));
+
+  // Copy `data` into a new heap block at heap_ptr ([size][next = 0] header, 16-byte aligned)
+  // and bump heap_ptr.
+  function alloc(data) {
+    const ptr = exports.heap_ptr.value >>> 0;
+    const dataEnd = exports.data_end ? exports.data_end.value >>> 0 : 0;
+    if (ptr < dataEnd) {
+      throw new Error("heap_ptr is below data_end: allocating would overwrite constant data");
+    }
+    const end = (ptr + 8 + data.length + 15) & ~15;
+    const missing = Math.ceil(end / 65536) - memory.buffer.byteLength / 65536;
+    if (missing > 0) {
+      try {
+        memory.grow(missing);
+      } catch (e) {
+        throw new Error("out of memory: the program needs more than its maximum memory");
+      }
+    }
+    new DataView(memory.buffer).setUint32(ptr, end - ptr - 8, true);
+    new DataView(memory.buffer).setUint32(ptr + 4, 0, true);
+    bytes(ptr + 8, data.length).set(data);
+    exports.heap_ptr.value = end;
+    return [ptr + 8, data.length];
+  }
+
+  // dump_heap(): heap_ptr and a hex dump of the heap, or of [start, start + len), on the console
+  // (the mpl runner also lists the heap blocks).
+  const heapStart = () => (exports.data_end ? exports.data_end.value >>> 0 : 0);
+  function dumpHeap(start, len) {
+    flush();
+    const heapPtr = exports.heap_ptr.value >>> 0;
+    if (start === -1) {
+      start = heapStart();
+      len = heapPtr - start;
+    }
+    const hex = (n, width) => n.toString(16).padStart(width, "0");
+    console.error(`heap_ptr = 0x${hex(heapPtr, 8)} (${heapPtr})`);
+    const end = Math.min(start + Math.max(len, 0), memory.buffer.byteLength);
+    for (let line = start - (start % 16); line < end; line += 16) {
+      const b = bytes(line, Math.min(16, memory.buffer.byteLength - line));
+      console.error(`${hex(line, 8)}  ${Array.from(b, (x) => hex(x, 2)).join(" ")}`);
+    }
+  }
+
+  // Byte offsets of the characters [start, start + count) of a UTF-8 string.
+  function charRange(ptr, len, start, count, what) {
+    const b = bytes(ptr, len);
+    const offsets = [];
+    for (let i = 0; i < b.length; ) {
+      offsets.push(i);
+      i += b[i] < 0x80 ? 1 : b[i] < 0xe0 ? 2 : b[i] < 0xf0 ? 3 : 4;
+    }
+    offsets.push(b.length);
+    if (start < 0 || count < 0 || start + count > offsets.length - 1) {
+      throw new Error(`${what} out of range: the string has ${offsets.length - 1} character(s)`);
+    }
+    return [ptr + offsets[start], offsets[start + count] - offsets[start]];
+  }
+
+  // The text rt.to_str_f64 makes of x: at most 15 significant digits, never an exponent;
+  // the shortest text reading back as x with `shortest`.
+  function floatText(x, shortest = false) {
+    if (Number.isNaN(x)) return "NaN";
+    const sign = x < 0 || Object.is(x, -0) ? "-" : "";
+    if (!Number.isFinite(x)) return sign + "inf";
+    if (x === 0) return sign + "0";
+    const [mantissa, exponent] = Math.abs(x).toExponential(shortest ? undefined : 14).split("e");
+    const digits = mantissa.replace(".", "").replace(/0+$/, "");
+    const e = Number(exponent);
+    if (e < 0) return sign + "0." + "0".repeat(-e - 1) + digits;
+    if (digits.length <= e + 1) return sign + digits + "0".repeat(e + 1 - digits.length);
+    return sign + digits.slice(0, e + 1) + "." + digits.slice(e + 1);
+  }
+
+  // to_str(x, decimals)
+  function fixedText(x, decimals) {
+    if (decimals < 0 || decimals > 100) {
+      throw new Error(`to_str(_, ${decimals}): the number of decimals must be between 0 and 100`);
+    }
+    if (!Number.isFinite(x)) return floatText(x);
+    const dot = decimals > 0 ? "." + "0".repeat(decimals) : "";
+    const text = Math.abs(x) < 1e21 ? x.toFixed(decimals) : BigInt(x).toString() + dot;
+    return Object.is(x, -0) ? "-" + text : text;
+  }
+
+  // format_thousands(x [, decimals]): the digits before the dot grouped by three
+  function groupThousands(text) {
+    const [, sign, int, fraction] = /^(-?)(\d*)(.*)$/s.exec(text);
+    if (int === "") return text;
+    return sign + int.replace(/\B(?=(\d{3})+$)/g, " ") + fraction;
+  }
+
+  // splitmix64, as in the runner: the same seed gives the same numbers.
+  const MASK = (1n << 64n) - 1n;
+  let state = BigInt.asUintN(64, BigInt(seed ?? Math.floor(Math.random() * 2 ** 53)));
+  function nextU64() {
+    state = (state + 0x9e3779b97f4a7c15n) & MASK;
+    let z = state;
+    z = ((z ^ (z >> 30n)) * 0xbf58476d1ce4e5b9n) & MASK;
+    z = ((z ^ (z >> 27n)) * 0x94d049bb133111ebn) & MASK;
+    return z ^ (z >> 31n);
+  }
+
+  return {
+    env: {
+      memory,
+      log: (ptr, len) => write(text(ptr, len)),
+      log_err: (ptr, len) => writeErr(text(ptr, len)),
+      flush: () => flush(),
+      args_count: () => args.length,
+      args_get: (i) => {
+        if (i < 0 || i >= args.length) {
+          throw new Error(`arg(${i}) out of range: the program has ${args.length} argument(s)`);
+        }
+        return alloc(encoder.encode(String(args[i])));
+      },
+      dump_heap: dumpHeap,
+      random: () => Number(nextU64() >> 11n) / 2 ** 53,
+      random_int: (lo, hi) => {
+        if (lo > hi) {
+          throw new Error(`random_int(${lo}, ${hi}): the lower bound is greater than the upper bound`);
+        }
+        return lo + Number(nextU64() % BigInt(hi - lo + 1));
+      },
+    },
+    math: {
+      pow: Math.pow,
+      sin: Math.sin,
+      cos: Math.cos,
+      tan: Math.tan,
+      log: Math.log,
+      exp: Math.exp,
+    },
+    str: {
+      len: (ptr, len) => Array.from(text(ptr, len)).length,
+      substr: (ptr, len, start, count) =>
+        charRange(ptr, len, start, count, `substr(_, ${start}, ${count})`),
+      char_at: (ptr, len, i) => charRange(ptr, len, i, 1, `char_at(_, ${i})`),
+      code_at: (ptr, len, i) => {
+        const [at, n] = charRange(ptr, len, i, 1, `char_at(_, ${i})`);
+        return text(at, n).codePointAt(0);
+      },
+      char_text: (c) => {
+        if (c < 0 || c > 0x10ffff || (c >= 0xd800 && c <= 0xdfff)) {
+          throw new Error(`chr(${c}): not a Unicode code point (0 to 0x10FFFF, except 0xD800 to 0xDFFF)`);
+        }
+        return alloc(encoder.encode(String.fromCodePoint(c)));
+      },
+      eq: (p1, l1, p2, l2) =>
+        l1 === l2 && bytes(p1, l1).every((b, i) => b === bytes(p2, l2)[i]) ? 1 : 0,
+      parse_i32: (ptr, len) => {
+        const s = text(ptr, len);
+        const n = Number(s.trim());
+        if (!/^[+-]?\d+$/.test(s.trim()) || n < -2147483648 || n > 2147483647) {
+          throw new Error(`to_int("${s}"): not a valid integer`);
+        }
+        return n;
+      },
+      parse_f64: (ptr, len) => {
+        const s = text(ptr, len);
+        const t = s.trim();
+        if (/^[+-]?(inf|infinity)$/i.test(t)) return t.startsWith("-") ? -Infinity : Infinity;
+        if (/^[+-]?nan$/i.test(t)) return NaN;
+        if (!/^[+-]?(\d+\.?\d*|\.\d+)([eE][+-]?\d+)?$/.test(t)) {
+          throw new Error(`to_float("${s}"): not a valid number`);
+        }
+        return Number(t);
+      },
+      to_str_shortest: (x) => alloc(encoder.encode(floatText(x, true))),
+      to_str_fixed: (x, decimals) => alloc(encoder.encode(fixedText(x, decimals))),
+      format_thousands: (x, decimals) =>
+        alloc(encoder.encode(groupThousands(decimals === -1 ? floatText(x) : fixedText(x, decimals)))),
+      decimal_comma: (ptr, len) => alloc(bytes(ptr, len).map((b) => (b === 0x2e ? 0x2c : b))),
+      // only imported by modules built by older versions of mpl
+      to_str_i32: (n) => alloc(encoder.encode(String(n))),
+      to_str_f64: (x) => alloc(encoder.encode(floatText(x))),
+      concat: (p1, l1, p2, l2) => {
+        const joined = new Uint8Array(l1 + l2);
+        joined.set(bytes(p1, l1));
+        joined.set(bytes(p2, l2), l1);
+        return alloc(joined);
+      },
+    },
+  };
+}
+"#;
+
+const BROWSER_JS: &str = r#"
+// run({ args, seed, output }): runs main and returns its exit code.
+// The output goes to the `output` element if given, to the console otherwise; eprint and
+// eprintln go to console.error.
+export async function run({ args = [], seed = null, output = null } = {}) {
+  const memory = new WebAssembly.Memory(@MEMORY@);
+  let pending = "";
+  let pendingErr = "";
+  // The complete lines of `text` to `log`, one call per line; returns the rest
+  const logLines = (text, log) => {
+    const nl = text.lastIndexOf("\n");
+    if (nl < 0) return text;
+    text.slice(0, nl).split("\n").forEach((line) => log(line));
+    return text.slice(nl + 1);
+  };
+  const write = (s) => {
+    if (output) {
+      output.textContent += s;
+      return;
+    }
+    pending = logLines(pending + s, console.log);
+  };
+  const flushOut = () => {
+    if (pending) {
+      console.log(pending);
+      pending = "";
+    }
+  };
+  // after what the program printed
+  const writeErr = (s) => {
+    flushOut();
+    pendingErr = logLines(pendingErr + s, console.error);
+  };
+  const flush = () => {
+    flushOut();
+    if (pendingErr) {
+      console.error(pendingErr);
+      pendingErr = "";
+    }
+  };
+
+  const exports = {};
+  const imports = makeImports(memory, exports, { args, seed, write, writeErr, flush });
+  const response = await fetch(new URL("@WASM@", import.meta.url));
+  const { instance } = await WebAssembly.instantiate(await response.arrayBuffer(), imports);
+  Object.assign(exports, instance.exports);
+  try {
+    return instance.exports.main() ?? 0;
+  } finally {
+    flush();
+  }
+}
+"#;
+
+const NODE_JS: &str = r#"
+// run({ args, seed, stdout, stderr }): runs main and returns its exit code.
+export async function run({ args = [], seed = null, stdout = process.stdout, stderr = process.stderr } = {}) {
+  const memory = new WebAssembly.Memory(@MEMORY@);
+  const write = (s) => stdout.write(s);
+  const writeErr = (s) => stderr.write(s);
+  const flush = () => {};
+
+  const exports = {};
+  const imports = makeImports(memory, exports, { args, seed, write, writeErr, flush });
+  const bytes = await readFile(new URL("@WASM@", import.meta.url));
+  const { instance } = await WebAssembly.instantiate(bytes, imports);
+  Object.assign(exports, instance.exports);
+  return instance.exports.main() ?? 0;
+}
+"#;
+
+const NODE_CLI: &str = r#"#!/usr/bin/env node
+// Generated by mpl: node cli.js [args...]
+import { run } from "./index.js";
+
+try {
+  process.exitCode = await run({ args: process.argv.slice(2) });
+} catch (e) {
+  console.error(e.message);
+  process.exitCode = 1;
+}
+"#;
+
+const PAGE_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>@TITLE@</title>
+</head>
+<body>
+  <pre id="output"></pre>
+  <!-- browsers do not load modules from file://: serve this directory over http -->
+  <script type="module">
+    import { run } from "./@JS@";
+    const output = document.getElementById("output");
+    run({ output }).catch((e) => { output.textContent += "\n" + e; });
+  </script>
+</body>
+</html>
+"#;
+
+// Argument of `new WebAssembly.Memory(...)` matching the memory the module imports
+pub fn memory_descriptor(memory: MemoryLimits) -> String {
+    match memory.max_pages {
+        Some(max) => format!("{{ initial: {}, maximum: {} }}", memory.min_pages, max),
+        None => format!("{{ initial: {} }}", memory.min_pages),
+    }
+}
+
+// The host functions, shared by every JavaScript output
+pub fn imports_js() -> &'static str {
+    IMPORTS_JS
+}
+
+// ES module loading `wasm_file` (relative to the script) and exporting `run()`
+pub fn loader_js(wasm_file: &str, memory: MemoryLimits) -> String {
+    let header = format!("// Generated by mpl: runs {} (import {{ run }} from this module)\n\n", wasm_file);
+    header
+        + IMPORTS_JS
+        + &BROWSER_JS
+            .replace("@MEMORY@", &memory_descriptor(memory))
+            .replace("@WASM@", wasm_file)
+}
+
+// npm package names: lowercase letters, digits, '-', '.' and '_'
+fn package_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '.' | '_' => c,
+            _ => '-',
+        })
+        .collect();
+    name.trim_start_matches(['.', '_']).to_string()
+}
+
+// Files of a Node.js package running `wasm_file` (to be written next to them):
+// index.js exports `run()`, cli.js runs the program with the command-line arguments.
+pub fn node_package(name: &str, wasm_file: &str, memory: MemoryLimits) -> Vec<(&'static str, String)> {
+    let name = package_name(name);
+    let index = format!(
+        "// Generated by mpl: runs {} (import {{ run }} from this package)\nimport {{ readFile }} from \"node:fs/promises\";\n\n",
+        wasm_file
+    ) + IMPORTS_JS
+        + &NODE_JS
+            .replace("@MEMORY@", &memory_descriptor(memory))
+            .replace("@WASM@", wasm_file);
+    let package = format!(
+        r#"{{
+  "name": {name},
+  "version": "0.1.0",
+  "description": "MPL program compiled to WebAssembly",
+  "type": "module",
+  "main": "index.js",
+  "exports": "./index.js",
+  "bin": {{ {name}: "cli.js" }},
+  "scripts": {{ "start": "node cli.js" }},
+  "files": ["index.js", "cli.js", {wasm}],
+  "engines": {{ "node": ">=16" }}
+}}
+"#,
+        name = json_string(&name),
+        wasm = json_string(wasm_file)
+    );
+    vec![
+        ("index.js", index),
+        ("cli.js", NODE_CLI.to_string()),
+        ("package.json", package),
+    ]
+}
+
+// Page running the loader `js_file` and showing the program output
+pub fn page_html(title: &str, js_file: &str) -> String {
+    PAGE_HTML.replace("@TITLE@", title).replace("@JS@", js_file)
+}
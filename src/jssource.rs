@@ -0,0 +1,725 @@
+// My Programming Language
+// JavaScript backend (--emit=js-src): the program as plain JavaScript, not the glue of a wasm
+// module (see jsglue.rs), for the places without WebAssembly and for comparing what a program
+// does in another language. The numbers are written from the IR (see ir.rs).
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::codegen::{FloatFormat, Ty, infer_type};
+use crate::csource::{is_constant, num_of, numeric_only, reads_builder};
+use crate::grammar::{self, MathFn};
+use crate::ir::{self, Num, NumKind, NumTy};
+use crate::lexer::Position;
+use crate::parser::{BinOp, Expr, FnExpr, Function, MatchArm, NumExpr, ParseError, Program, Stadment, StrExpr, Variable};
+
+// The runtime, in JavaScript; same behavior as runner.rs (traps print their message and exit
+// with 1). Ints are numbers kept in the i32 range by `| 0` and Math.imul, strings are
+// JavaScript strings whose characters are code points, maps are Maps and builders strings.
+const RUNTIME_JS: &str = r#"
+// ---- Runtime of MPL programs compiled to JavaScript (the host functions of the mpl runner) ----
+
+const mplNode = typeof process !== "undefined" && process.versions?.node !== undefined;
+const mplArgs = mplNode ? process.argv.slice(2) : [];
+
+function mplTrap(message) {
+  throw new Error(message);
+}
+
+// print, eprint (on stderr, after what was printed); in a browser, one console.log or
+// console.error per line
+const mplPending = ["", ""];
+const mplLog = [(line) => console.log(line), (line) => console.error(line)];
+
+function mplWrite(stream, text) {
+  if (mplNode) {
+    (stream === 0 ? process.stdout : process.stderr).write(text);
+    return;
+  }
+  if (stream === 1) mplFlushStream(0);
+  const lines = (mplPending[stream] + text).split("\n");
+  mplPending[stream] = lines.pop();
+  lines.forEach(mplLog[stream]);
+}
+
+function mplFlushStream(stream) {
+  if (mplPending[stream]) {
+    mplLog[stream](mplPending[stream]);
+    mplPending[stream] = "";
+  }
+}
+
+const mplPrint = (text) => mplWrite(0, text);
+const mplPrintln = (text) => mplWrite(0, text + "\n");
+const mplEprint = (text) => mplWrite(1, text);
+const mplEprintln = (text) => mplWrite(1, text + "\n");
+
+function mplFlush() {
+  mplFlushStream(0);
+  mplFlushStream(1);
+}
+
+function mplDumpHeap(start, len) {
+  mplWrite(1, "dump_heap(): a program compiled to JavaScript has no wasm heap\n");
+}
+
+// ---- numbers ----
+
+function mplDiv(a, b) {
+  if (b === 0) mplTrap("integer divide by zero");
+  if (a === -2147483648 && b === -1) mplTrap("integer overflow");
+  return (a / b) | 0;
+}
+
+// float to int, toward zero
+function mplTrunc(x) {
+  if (Number.isNaN(x)) mplTrap("invalid conversion to integer");
+  if (x <= -2147483649 || x >= 2147483648) mplTrap("integer overflow");
+  return Math.trunc(x) | 0;
+}
+
+// halfway cases away from zero
+const mplRound = (x) => Math.sign(x) * Math.floor(Math.abs(x) + 0.5);
+
+// splitmix64, as in the runner: the same seed (MPL_SEED) gives the same numbers
+const MPL_MASK = (1n << 64n) - 1n;
+let mplRng = BigInt.asUintN(64, BigInt(mplNode && process.env.MPL_SEED ? process.env.MPL_SEED : Math.floor(Math.random() * 2 ** 53)));
+
+function mplNextU64() {
+  mplRng = (mplRng + 0x9e3779b97f4a7c15n) & MPL_MASK;
+  let z = mplRng;
+  z = ((z ^ (z >> 30n)) * 0xbf58476d1ce4e5b9n) & MPL_MASK;
+  z = ((z ^ (z >> 27n)) * 0x94d049bb133111ebn) & MPL_MASK;
+  return z ^ (z >> 31n);
+}
+
+const mplRandom = () => Number(mplNextU64() >> 11n) / 2 ** 53;
+
+function mplRandomInt(lo, hi) {
+  if (lo > hi) mplTrap(`random_int(${lo}, ${hi}): the lower bound is greater than the upper bound`);
+  return lo + Number(mplNextU64() % BigInt(hi - lo + 1));
+}
+
+// ---- to_str ----
+
+// |x| * 10^q exactly (x is mantissa * 2^exponent), rounded half to even to an integer
+function mplScaled(x, q) {
+  const view = new DataView(new ArrayBuffer(8));
+  view.setFloat64(0, Math.abs(x));
+  const bits = view.getBigUint64(0);
+  const biased = Number(bits >> 52n);
+  const mantissa = biased === 0 ? bits & ((1n << 52n) - 1n) : (bits & ((1n << 52n) - 1n)) | (1n << 52n);
+  const exponent = biased === 0 ? -1074 : biased - 1075;
+  let num = q >= 0 ? mantissa * 10n ** BigInt(q) : mantissa;
+  let den = q >= 0 ? 1n : 10n ** BigInt(-q);
+  if (exponent >= 0) num <<= BigInt(exponent);
+  else den <<= BigInt(-exponent);
+  let n = num / den;
+  const twice = 2n * (num % den);
+  if (twice > den || (twice === den && n % 2n === 1n)) n++;
+  return n;
+}
+
+// 15 significant digits, rounded half to even as rt.to_str_f64 does; or, with `shortest`, the
+// shortest text reading back as x. Never an exponent
+function mplFloatText(x, shortest) {
+  if (Number.isNaN(x)) return "NaN";
+  const sign = x < 0 || Object.is(x, -0) ? "-" : "";
+  if (!Number.isFinite(x)) return sign + "inf";
+  if (x === 0) return sign + "0";
+  let digits;
+  let exp = 0;
+  if (shortest) {
+    const [mantissa, exponent] = Math.abs(x).toExponential().split("e");
+    digits = mantissa.replace(".", "");
+    exp = Number(exponent);
+  } else {
+    // the exponent of the shortest text, corrected when the 15 digits round up to 10^15 or
+    // the shortest text rounded up to the next power of ten (1e23 is 9.99...e22)
+    exp = Number(Math.abs(x).toExponential().split("e")[1]);
+    let m = mplScaled(x, 14 - exp);
+    if (m < 10n ** 14n) m = mplScaled(x, 14 - --exp);
+    if (m >= 10n ** 15n) m = mplScaled(x, 14 - ++exp);
+    digits = m.toString();
+  }
+  digits = digits.replace(/0+$/, "");
+  if (exp < 0) return sign + "0." + "0".repeat(-exp - 1) + digits;
+  if (digits.length <= exp + 1) return sign + digits + "0".repeat(exp + 1 - digits.length);
+  return sign + digits.slice(0, exp + 1) + "." + digits.slice(exp + 1);
+}
+
+const mplFloatStr = (x) => mplFloatText(x, false);
+const mplFloatStrShortest = (x) => mplFloatText(x, true);
+
+// to_str(x, decimals): x exactly (mantissa * 2^exponent), rounded half to even
+function mplFixed(x, decimals) {
+  if (decimals < 0 || decimals > 100) {
+    mplTrap(`to_str(_, ${decimals}): the number of decimals must be between 0 and 100`);
+  }
+  if (!Number.isFinite(x)) return mplFloatStr(x);
+  const digits = mplScaled(x, decimals).toString().padStart(decimals + 1, "0");
+  const text = decimals > 0 ? digits.slice(0, -decimals) + "." + digits.slice(-decimals) : digits;
+  return (x < 0 || Object.is(x, -0) ? "-" : "") + text;
+}
+
+// format_thousands(x [, decimals]): the digits before the dot grouped by three
+function mplThousands(x, decimals) {
+  const text = decimals === -1 ? mplFloatStr(x) : mplFixed(x, decimals);
+  const [, sign, int, fraction] = /^(-?)(\d*)(.*)$/s.exec(text);
+  if (int === "") return text;
+  return sign + int.replace(/\B(?=(\d{3})+$)/g, " ") + fraction;
+}
+
+// ---- characters ----
+
+const mplLen = (s) => Array.from(s).length;
+
+function mplSubstr(s, start, count) {
+  const chars = Array.from(s);
+  if (start < 0 || count < 0 || start + count > chars.length) {
+    mplTrap(`substr(_, ${start}, ${count}) out of range: the string has ${chars.length} character(s)`);
+  }
+  return chars.slice(start, start + count).join("");
+}
+
+function mplCharAt(s, i) {
+  const chars = Array.from(s);
+  if (i < 0 || i >= chars.length) {
+    mplTrap(`char_at(_, ${i}) out of range: the string has ${chars.length} character(s)`);
+  }
+  return chars[i];
+}
+
+// char_at(s, i) as a char: its code point
+const mplCodeAt = (s, i) => mplCharAt(s, i).codePointAt(0);
+
+function mplCharStr(c) {
+  if (c < 0 || c > 0x10ffff || (c >= 0xd800 && c <= 0xdfff)) {
+    mplTrap(`chr(${c}): not a Unicode code point (0 to 0x10FFFF, except 0xD800 to 0xDFFF)`);
+  }
+  return String.fromCodePoint(c);
+}
+
+// ---- other strings ----
+
+const mplRepeat = (s, n) => s.repeat(Math.max(n, 0));
+
+function mplArg(i) {
+  if (i < 0 || i >= mplArgs.length) {
+    mplTrap(`arg(${i}) out of range: the program has ${mplArgs.length} argument(s)`);
+  }
+  return mplArgs[i];
+}
+
+// surrounding whitespace is ignored; anything else that is not a number traps
+function mplToInt(s) {
+  const t = s.trim();
+  const n = Number(t);
+  if (!/^[+-]?\d+$/.test(t) || n < -2147483648 || n > 2147483647) {
+    mplTrap(`to_int("${s}"): not a valid integer`);
+  }
+  return n | 0;
+}
+
+function mplToFloat(s) {
+  const t = s.trim();
+  if (/^[+-]?(inf|infinity)$/i.test(t)) return t.startsWith("-") ? -Infinity : Infinity;
+  if (/^[+-]?nan$/i.test(t)) return NaN;
+  if (!/^[+-]?(\d+\.?\d*|\.\d+)([eE][+-]?\d+)?$/.test(t)) {
+    mplTrap(`to_float("${s}"): not a valid number`);
+  }
+  return Number(t);
+}
+
+// ---- fn variables ----
+
+function mplCallable(f) {
+  if (!f) mplTrap("uninitialized element: the fn variable holds no function");
+  return f;
+}
+"#;
+
+// JavaScript reserved words and the names the program text uses besides its own: a function or
+// variable named like one of them takes a `_` after its name
+const RESERVED: &[&str] = &[
+    "await", "break", "case", "catch", "class", "const", "continue", "debugger", "default", "delete", "do", "else",
+    "enum", "export", "extends", "false", "finally", "for", "function", "if", "implements", "import", "in",
+    "instanceof", "interface", "let", "new", "null", "package", "private", "protected", "public", "return",
+    "static", "super", "switch", "this", "throw", "true", "try", "typeof", "var", "void", "while", "with", "yield",
+    "arguments", "eval", "undefined", "NaN", "Infinity", "Math", "Number", "String", "BigInt", "Array", "Map",
+    "Object", "Error", "DataView", "ArrayBuffer", "console", "process", "globalThis", "MPL_MASK",
+];
+
+// A name of the program as a JavaScript identifier: `math::square` (see modules.rs) is
+// math__square, `helper.x` (a local of an inlined function, see optimize.rs) helper_x
+fn js_name(name: &str) -> String {
+    let name = name.replace("::", "__").replace('.', "_");
+    if RESERVED.contains(&name.as_str()) || name.starts_with("mpl") {
+        name + "_"
+    } else {
+        name
+    }
+}
+
+// A string literal in JavaScript
+fn js_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' || c == '\u{2028}' || c == '\u{2029}' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The program as a JavaScript module (--emit=js-src), after the optimizations: its functions,
+/// then the call of main. With Node.js it takes the command line arguments and MPL_SEED, in a
+/// browser its output goes to the console. Wasm libraries cannot be used (see main.rs); an
+/// extern fn is imported from `./<module>.mjs`.
+///
+/// Math.exp, Math.pow and the like of the JavaScript engine may differ from those of the runner
+/// in the last bit, which only the shortest float format shows.
+pub fn program_js(prog: &Program, js_file: &str, float_format: FloatFormat) -> Result<String, ParseError> {
+    let mut out = format!(
+        "// Written by mpl compile --emit=js-src; run with: node {} [args...]\n// MPL_SEED=N gives the random numbers of `mpl run --seed N`.\n",
+        js_file
+    );
+    let mut names: HashMap<&str, String> = HashMap::new();
+    if !prog.externs.is_empty() {
+        out.push('\n');
+    }
+    for e in &prog.externs {
+        let name = js_name(&e.name);
+        let binding = if name == e.field { name.clone() } else { format!("{} as {}", e.field, name) };
+        let _ = writeln!(out, "import {{ {} }} from \"./{}.mjs\";", binding, e.module);
+        names.insert(&e.name, name);
+    }
+    out.push_str(RUNTIME_JS);
+
+    out.push_str("\n// ---- Program ----\n");
+    let functions: Vec<&Function> = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main)
+        .collect();
+    for f in &functions {
+        let name = if f.name == grammar::KW_MAIN { "main".to_string() } else { js_name(&f.name) };
+        names.insert(&f.name, name);
+    }
+    for f in &functions {
+        out.push('\n');
+        FunctionWriter {
+            function: f,
+            names: &names,
+            externs: prog.externs.iter().map(|e| (e.name.as_str(), &e.params[..])).collect(),
+            float_format,
+            loops: 0,
+            out: &mut out,
+        }
+        .function()?;
+    }
+    if prog.main_program.main.is_some() {
+        out.push_str(MAIN_JS);
+    }
+    Ok(out)
+}
+
+// main returns the exit code (0 to 255); a trap ends the program with 1
+const MAIN_JS: &str = r#"
+try {
+  const code = main() ?? 0;
+  if (code < 0 || code > 255) mplTrap(`main returned ${code}: an exit code is between 0 and 255`);
+  mplFlush();
+  if (mplNode) process.exitCode = code;
+} catch (e) {
+  mplFlush();
+  if (!mplNode) throw e;
+  process.stderr.write(e.message + "\n");
+  process.exitCode = 1;
+}
+"#;
+
+fn initial_value(ty: Ty) -> &'static str {
+    match ty {
+        Ty::I32 | Ty::Char | Ty::F64 => "0",
+        Ty::Fn => "null",
+        Ty::Map => "new Map()",
+        Ty::Builder => "\"\"",
+    }
+}
+
+// Binding powers of JavaScript operators, for the parentheses
+const PREC_OR: u8 = 0;
+const PREC_ADD: u8 = 1;
+const PREC_MUL: u8 = 2;
+const PREC_UNARY: u8 = 3;
+const PREC_ATOM: u8 = 4;
+
+struct FunctionWriter<'p> {
+    function: &'p Function,
+    names: &'p HashMap<&'p str, String>, // function name -> JavaScript name
+    externs: HashMap<&'p str, &'p [Variable]>,
+    float_format: FloatFormat,
+    loops: usize, // for loops so far, to name their bounds
+    out: &'p mut String,
+}
+
+impl FunctionWriter<'_> {
+    fn function(&mut self) -> Result<(), ParseError> {
+        let f = self.function;
+        // an `export fn` can be imported from other modules
+        let export = if f.export { "export " } else { "" };
+        let _ = writeln!(self.out, "{}function {}() {{", export, self.names[f.name.as_str()]);
+        for var in &f.variables {
+            let comment = if var.ty == Ty::Char { " // char" } else { "" };
+            self.line(1, &format!("let {} = {};{}", js_name(&var.name), initial_value(var.ty), comment));
+        }
+        self.body(&f.body, 1)?;
+        self.out.push_str("}\n");
+        Ok(())
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        let _ = writeln!(self.out, "{}{}", "  ".repeat(depth), text);
+    }
+
+    fn body(&mut self, body: &[Stadment], depth: usize) -> Result<(), ParseError> {
+        for st in body {
+            self.stadment(st, depth)?;
+        }
+        Ok(())
+    }
+
+    fn stadment(&mut self, st: &Stadment, depth: usize) -> Result<(), ParseError> {
+        match st {
+            Stadment::Print { items, .. } => self.line(depth, &format!("mplPrint({});", self.strs(items)?)),
+            Stadment::Println { items, .. } => self.line(depth, &format!("mplPrintln({});", self.strs(items)?)),
+            Stadment::EPrint { items, .. } => self.line(depth, &format!("mplEprint({});", self.strs(items)?)),
+            Stadment::EPrintln { items, .. } => self.line(depth, &format!("mplEprintln({});", self.strs(items)?)),
+            Stadment::Call { name, args, .. } => {
+                // only an extern fn takes arguments, computed in the types of its parameters
+                let params = self.externs.get(name.as_str()).copied().unwrap_or(&[]);
+                let mut texts = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    let ty = params.get(i).map_or_else(|| infer_type(arg), |p| p.ty);
+                    texts.push(self.num(arg, ty)?);
+                }
+                let name = self.names.get(name.as_str()).cloned().unwrap_or_else(|| js_name(name));
+                self.line(depth, &format!("{}({});", name, texts.join(", ")));
+            }
+            Stadment::CallIndirect { var, .. } => self.line(depth, &format!("mplCallable({})();", js_name(&var.name))),
+            Stadment::Assignment { var, expr, pos } => {
+                let value = match expr {
+                    Expr::Num(n) => self.num(n, var.ty)?,
+                    Expr::Fn(FnExpr::Ref { name, .. }) => {
+                        self.names.get(name.as_str()).cloned().unwrap_or_else(|| js_name(name))
+                    }
+                    Expr::Fn(FnExpr::Var { var, .. }) => js_name(&var.name),
+                    Expr::Str(_) => return Err(numeric_only(grammar::KW_LET, pos)),
+                };
+                self.line(depth, &format!("{} = {};", js_name(&var.name), value));
+            }
+            Stadment::ForLoop {
+                var,
+                start,
+                end,
+                step,
+                body,
+                pos,
+            } => self.for_loop(var, start, end, step.as_ref(), body, pos, depth)?,
+            Stadment::Return { expr, pos } => {
+                let value = match expr {
+                    Expr::Num(n) => self.num(n, Ty::I32)?,
+                    _ => return Err(numeric_only(grammar::KW_RETURN, pos)),
+                };
+                self.line(depth, &format!("return {};", value));
+            }
+            Stadment::MapSet { var, key, value, .. } => {
+                let text = format!("{}.set({}, {});", js_name(&var.name), self.str(key)?, self.num(value, Ty::I32)?);
+                self.line(depth, &text);
+            }
+            Stadment::MapDelete { var, key, .. } => {
+                self.line(depth, &format!("{}.delete({});", js_name(&var.name), self.str(key)?));
+            }
+            Stadment::Append { var, items, .. } => {
+                // wasm appends the items one by one: an item reading the builder sees the ones before it
+                if items.len() > 1 && reads_builder(items, &var.name) {
+                    for item in items {
+                        self.line(depth, &format!("{} += {};", js_name(&var.name), self.str(item)?));
+                    }
+                } else {
+                    self.line(depth, &format!("{} += {};", js_name(&var.name), self.strs(items)?));
+                }
+            }
+            Stadment::Clear { var, .. } => self.line(depth, &format!("{} = \"\";", js_name(&var.name))),
+            Stadment::Match { value, arms, default, .. } => self.match_(value, arms, default.as_deref(), depth)?,
+            Stadment::Flush => self.line(depth, "mplFlush();"),
+            Stadment::DumpHeap { region, .. } => match region {
+                Some((start, len)) => {
+                    let text = format!("mplDumpHeap({}, {});", self.num(start, Ty::I32)?, self.num(len, Ty::I32)?);
+                    self.line(depth, &text);
+                }
+                None => self.line(depth, "mplDumpHeap(-1, 0);"),
+            },
+        }
+        Ok(())
+    }
+
+    // i = start, then end and step once: the loop stops past end, below it unless the step is
+    // positive
+    #[allow(clippy::too_many_arguments)]
+    fn for_loop(
+        &mut self,
+        var: &Variable,
+        start: &Expr,
+        end: &Expr,
+        step: Option<&Expr>,
+        body: &[Stadment],
+        pos: &Position,
+        depth: usize,
+    ) -> Result<(), ParseError> {
+        let i = js_name(&var.name);
+        fn bound<'e>(e: &'e Expr, keyword: &str, pos: &Position) -> Result<&'e NumExpr, ParseError> {
+            num_of(e).ok_or_else(|| numeric_only(keyword, pos))
+        }
+        let start = bound(start, grammar::KW_FOR, pos)?;
+        let end = bound(end, grammar::KW_TO, pos)?;
+        let step = step.map(|step| bound(step, grammar::KW_STEP, pos)).transpose()?;
+        self.loops += 1;
+        let start_text = format!("{} = {}", i, self.num(start, var.ty)?);
+        // constants stay in the loop header; the other bounds are computed before the loop,
+        // after i = start
+        let in_header = is_constant(end) && step.is_none_or(is_constant);
+        if !in_header {
+            self.line(depth, &format!("{};", start_text));
+        }
+        let end_text = self.num(end, var.ty)?;
+        let end_text = if is_constant(end) {
+            end_text
+        } else {
+            let name = format!("end_{}", self.loops);
+            self.line(depth, &format!("const {} = {};", name, end_text));
+            name
+        };
+        let (condition, increment) = match step {
+            None => (format!("{} <= {}", i, end_text), format!("{}++", i)),
+            Some(step) if is_constant(step) => {
+                let text = self.num(step, var.ty)?;
+                let increment = match text.strip_prefix('-') {
+                    Some(magnitude) => format!("{} -= {}", i, magnitude),
+                    None => format!("{} += {}", i, text),
+                };
+                // a zero step counts down, as a negative one
+                let comparison = if text.trim_start_matches(['-', '0', '.']).is_empty() || text.starts_with('-') {
+                    ">="
+                } else {
+                    "<="
+                };
+                (format!("{} {} {}", i, comparison, end_text), increment)
+            }
+            Some(step) => {
+                let name = format!("step_{}", self.loops);
+                let text = self.num(step, var.ty)?;
+                self.line(depth, &format!("const {} = {};", name, text));
+                let condition = format!("{} > 0 ? {} <= {} : {} >= {}", name, i, end_text, i, end_text);
+                (condition, format!("{} += {}", i, name))
+            }
+        };
+        let init = if in_header { start_text } else { String::new() };
+        self.line(depth, &format!("for ({}; {}; {}) {{", init, condition, increment));
+        self.body(body, depth + 1)?;
+        self.line(depth, "}");
+        Ok(())
+    }
+
+    fn match_(&mut self, value: &NumExpr, arms: &[MatchArm], default: Option<&[Stadment]>, depth: usize) -> Result<(), ParseError> {
+        let value_text = self.num(value, Ty::I32)?;
+        self.line(depth, &format!("switch ({}) {{", value_text));
+        for arm in arms {
+            for v in &arm.values {
+                self.line(depth + 1, &format!("case {}:", v));
+            }
+            self.body(&arm.body, depth + 2)?;
+            self.line(depth + 2, "break;");
+        }
+        if let Some(default) = default {
+            self.line(depth + 1, "default:");
+            self.body(default, depth + 2)?;
+            self.line(depth + 2, "break;");
+        }
+        self.line(depth, "}");
+        Ok(())
+    }
+
+    fn num(&self, e: &NumExpr, ty: Ty) -> Result<String, ParseError> {
+        let num = ir::lower_num(e, ty, &self.function.variables)?;
+        Ok(self.num_text(&num)?.0)
+    }
+
+    // The JavaScript expression of a number, and how tightly it binds
+    fn num_text(&self, num: &Num) -> Result<(String, u8), ParseError> {
+        let call = |name: &str, args: Vec<String>| (format!("{}({})", name, args.join(", ")), PREC_ATOM);
+        Ok(match &num.kind {
+            NumKind::Int(i) => {
+                let prec = if *i < 0 { PREC_UNARY } else { PREC_ATOM };
+                (i.to_string(), prec)
+            }
+            NumKind::Float(r) => float_text(*r),
+            NumKind::Local { name, .. } => (js_name(name), PREC_ATOM),
+            NumKind::Convert(inner) => match (num.ty, &inner.kind) {
+                (NumTy::F64, NumKind::Int(i)) => float_text(*i as f64),
+                // an int is already a number
+                (NumTy::F64, _) => self.num_text(inner)?,
+                (NumTy::I32, _) => call("mplTrunc", vec![self.num_text(inner)?.0]),
+            },
+            // i32 sums are exact in a double: wrapped once, at the top
+            NumKind::Neg(_) | NumKind::Binary { op: BinOp::Add | BinOp::Sub, .. } if num.ty == NumTy::I32 => {
+                let (sum, prec) = self.sum_text(num)?;
+                let sum = if prec < PREC_UNARY { format!("({})", sum) } else { sum };
+                (format!("{} | 0", sum), PREC_OR)
+            }
+            NumKind::Neg(_) | NumKind::Binary { op: BinOp::Add | BinOp::Sub, .. } => self.sum_text(num)?,
+            NumKind::Binary { op, left, right } => match (op, num.ty) {
+                (BinOp::Mul, NumTy::I32) => call("Math.imul", vec![self.num_text(left)?.0, self.num_text(right)?.0]),
+                // traps on a zero divisor, as in wasm
+                (BinOp::Div, NumTy::I32) => call("mplDiv", vec![self.num_text(left)?.0, self.num_text(right)?.0]),
+                _ => {
+                    let symbol = if *op == BinOp::Mul { "*" } else { "/" };
+                    let left = self.operand(left, PREC_MUL)?;
+                    let right = self.operand(right, PREC_MUL + 1)?;
+                    (format!("{} {} {}", left, symbol, right), PREC_MUL)
+                }
+            },
+            NumKind::ArgCount => ("mplArgs.length".to_string(), PREC_ATOM),
+            NumKind::Random => call("mplRandom", vec![]),
+            NumKind::RandomInt { lo, hi } => call("mplRandomInt", vec![self.num_text(lo)?.0, self.num_text(hi)?.0]),
+            NumKind::Len(s) => call("mplLen", vec![self.str(s)?]),
+            NumKind::ToInt(s) => call("mplToInt", vec![self.str(s)?]),
+            NumKind::ToFloat(s) => call("mplToFloat", vec![self.str(s)?]),
+            NumKind::StrEq { left, right, negated } => {
+                let op = if *negated { "!==" } else { "===" };
+                call("Number", vec![format!("{} {} {}", self.str(left)?, op, self.str(right)?)])
+            }
+            // a missing key reads 0
+            NumKind::MapGet { var, key, .. } => {
+                (format!("({}.get({}) ?? 0)", js_name(&var.name), self.str(key)?), PREC_ATOM)
+            }
+            NumKind::MapHas { var, key, .. } => {
+                call("Number", vec![format!("{}.has({})", js_name(&var.name), self.str(key)?)])
+            }
+            NumKind::CharAt { s, index } => call("mplCodeAt", vec![self.str(s)?, self.num_text(index)?.0]),
+            NumKind::Math { func, args } => {
+                let args = args.iter().map(|a| Ok(self.num_text(a)?.0)).collect::<Result<Vec<_>, ParseError>>()?;
+                match (func, num.ty) {
+                    // abs(-2147483648) wraps, as in wasm
+                    (MathFn::Abs, NumTy::I32) => (format!("{} | 0", call("Math.abs", args).0), PREC_OR),
+                    (MathFn::Round, _) => call("mplRound", args),
+                    _ => call(&format!("Math.{}", func.name()), args),
+                }
+            }
+        })
+    }
+
+    // A sum of i32s or f64s, not wrapped to i32: the i32 terms that are sums too are not wrapped
+    // either
+    fn sum_text(&self, num: &Num) -> Result<(String, u8), ParseError> {
+        let term = |n: &Num, prec: u8| -> Result<String, ParseError> {
+            let (text, term_prec) = match n.kind {
+                NumKind::Neg(_) | NumKind::Binary { op: BinOp::Add | BinOp::Sub, .. } if n.ty == NumTy::I32 => {
+                    self.sum_text(n)?
+                }
+                _ => self.num_text(n)?,
+            };
+            Ok(if term_prec < prec { format!("({})", text) } else { text })
+        };
+        Ok(match &num.kind {
+            NumKind::Neg(inner) => {
+                let operand = term(inner, PREC_UNARY)?;
+                // not `--x`
+                let operand = if operand.starts_with('-') { format!("({})", operand) } else { operand };
+                (format!("-{}", operand), PREC_UNARY)
+            }
+            NumKind::Binary { op, left, right } => {
+                let symbol = if *op == BinOp::Add { "+" } else { "-" };
+                (format!("{} {} {}", term(left, PREC_ADD)?, symbol, term(right, PREC_ADD + 1)?), PREC_ADD)
+            }
+            _ => self.num_text(num)?,
+        })
+    }
+
+    // An operand of an operator binding `prec`, in parentheses if it binds less
+    fn operand(&self, num: &Num, prec: u8) -> Result<String, ParseError> {
+        let (text, operand_prec) = self.num_text(num)?;
+        Ok(if operand_prec < prec { format!("({})", text) } else { text })
+    }
+
+    // The items of print and append, concatenated
+    fn strs(&self, items: &[StrExpr]) -> Result<String, ParseError> {
+        if items.is_empty() {
+            return Ok("\"\"".to_string());
+        }
+        let texts = items.iter().map(|s| self.str(s)).collect::<Result<Vec<_>, _>>()?;
+        Ok(texts.join(" + "))
+    }
+
+    // A string expression, binding as an operand of `+`
+    fn str(&self, e: &StrExpr) -> Result<String, ParseError> {
+        Ok(match e {
+            StrExpr::Str(s) => js_string(s),
+            StrExpr::Nl => "\"\\n\"".to_string(),
+            StrExpr::NumToStr(n) => match infer_type(n) {
+                Ty::Char => format!("mplCharStr({})", self.num(n, Ty::Char)?),
+                Ty::F64 if self.float_format == FloatFormat::Shortest => {
+                    format!("mplFloatStrShortest({})", self.num(n, Ty::F64)?)
+                }
+                Ty::F64 => format!("mplFloatStr({})", self.num(n, Ty::F64)?),
+                ty => format!("String({})", self.num(n, ty)?),
+            },
+            StrExpr::ToFixed { n, decimals } => {
+                format!("mplFixed({}, {})", self.num(n, Ty::F64)?, self.num(decimals, Ty::I32)?)
+            }
+            StrExpr::Thousands { n, decimals } => {
+                let decimals = match decimals {
+                    Some(d) => self.num(d, Ty::I32)?,
+                    None => "-1".to_string(),
+                };
+                format!("mplThousands({}, {})", self.num(n, Ty::F64)?, decimals)
+            }
+            StrExpr::DecimalComma(s) => format!("{}.replaceAll(\".\", \",\")", self.str(s)?),
+            StrExpr::Arg(index) => format!("mplArg({})", self.num(index, Ty::I32)?),
+            StrExpr::Substr { s, start, len } => {
+                format!("mplSubstr({}, {}, {})", self.str(s)?, self.num(start, Ty::I32)?, self.num(len, Ty::I32)?)
+            }
+            StrExpr::CharAt { s, index } => format!("mplCharAt({}, {})", self.str(s)?, self.num(index, Ty::I32)?),
+            StrExpr::Repeat { s, count } => format!("mplRepeat({}, {})", self.str(s)?, self.num(count, Ty::I32)?),
+            StrExpr::Join { sep, parts } => {
+                let parts = parts.iter().map(|s| self.str(s)).collect::<Result<Vec<_>, _>>()?;
+                format!("[{}].join({})", parts.join(", "), self.str(sep)?)
+            }
+            StrExpr::Builder { var, .. } => js_name(&var.name),
+        })
+    }
+}
+
+fn float_text(r: f64) -> (String, u8) {
+    let text = if r.is_nan() {
+        "NaN".to_string()
+    } else if r.is_infinite() {
+        if r < 0.0 { "-Infinity" } else { "Infinity" }.to_string()
+    } else {
+        // always with a dot or an exponent: 2.0, not the int 2
+        format!("{:?}", r)
+    };
+    let prec = if text.starts_with('-') { PREC_UNARY } else { PREC_ATOM };
+    (text, prec)
+}
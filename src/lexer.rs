@@ -2,11 +2,22 @@
 // My Programming Language
 // Lexer to read tokens and keywords
 
-use crate::grammar::{self, Token};
-use std::path::PathBuf;
+use crate::diagnostic::Diagnostic;
+use crate::grammar::{self, MathFn, Token};
+use crate::messages::{self, Message};
+use crate::meta;
+use crate::stats;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+// Streaming: the text already tokenized is dropped once it is this long
+const CHUNK: usize = 64 * 1024;
 
 // Position in a source file
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Position {
     pub file_name: PathBuf, // source file name
     pub line: usize,        // line number
@@ -23,41 +34,224 @@ impl Position {
     }
 }
 
+// Extent of a token: `start` is its first char, `end` just after its last one
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    // Empty span at `pos`, for what is only known by where it starts
+    pub fn at(pos: &Position) -> Self {
+        Span {
+            start: pos.clone(),
+            end: pos.clone(),
+        }
+    }
+}
+
+/// A comment, kept by a lexer `with_trivia` instead of being skipped
+#[derive(Debug, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String, // as written, delimiters included
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Line,  // `// ...` up to the end of the line
+    Block, // `/* ... */`
+}
+
+/// A place in the source to come back to with `Lexer::restore`
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    offset: usize, // bytes from the start of the source
+    pos: Position,
+}
+
 // Lexer error
 #[derive(Debug)]
 pub struct LexError {
+    pub code: &'static str, // stable message code (see messages.rs)
     pub message: String,
     pub pos: Position,
 }
 
+impl LexError {
+    fn new(m: &Message, args: &[&dyn std::fmt::Display], pos: &Position) -> Self {
+        LexError {
+            code: m.code,
+            message: m.format(args),
+            pos: pos.clone(),
+        }
+    }
+}
+
+impl LexError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::error(&messages::TOKEN_ERROR, self.code, self.message.clone(), &self.pos)
+    }
+}
+
 // Format how a lex error is displayed
 impl std::fmt::Display for LexError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            " Token error : {}\n in file {}\n at line {}\n col {}\n",
-            self.message,
-            self.pos.file_name.to_string_lossy(),
-            self.pos.line,
-            self.pos.col
-        )
+        self.diagnostic().fmt(f)
     }
 }
 
 impl std::error::Error for LexError {}
 
+/// Where the lexer reads the source: all of it in memory, or a reader it goes through a line
+/// at a time, so that a large file is never held whole (only its longest token or line is).
+pub enum Source {
+    Text(String),
+    Reader(Box<dyn BufRead>),
+}
+
+impl Source {
+    /// A file, read as the lexer goes
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Source::Reader(Box::new(BufReader::new(File::open(path)?))))
+    }
+}
+
+impl From<String> for Source {
+    fn from(text: String) -> Self {
+        Source::Text(text)
+    }
+}
+
+impl From<&str> for Source {
+    fn from(text: &str) -> Self {
+        Source::Text(text.to_string())
+    }
+}
+
 pub struct Lexer {
     pos: Position,    // current position (file, line, col)
-    src_code: String, // full source code
+    src_code: String, // source code (streaming: the part read and not yet dropped)
     i: usize,         // byte index (always on a UTF-8 char boundary)
+    reader: Option<Box<dyn BufRead>>, // streaming: the rest of the source, None once at its end
+    read_error: Option<io::Error>,    // streaming: why the reader stopped early
+    dropped: usize,                   // streaming: bytes dropped before src_code
+    hash: u64,                        // meta::hash of the source read so far
+    kept: Option<usize>,              // offset of the first checkpoint: never dropped
+    done: bool,                       // the iterator reached Eof or an error
+    trivia: Option<Vec<Trivia>>,      // the comments read and not taken yet, if kept
 }
 
 impl Lexer {
-    pub fn new(file_name: impl Into<PathBuf>, src_code: impl Into<String>) -> Self {
+    pub fn new(file_name: impl Into<PathBuf>, src_code: impl Into<Source>) -> Self {
+        let (src_code, reader) = match src_code.into() {
+            Source::Text(text) => (text, None),
+            Source::Reader(reader) => (String::new(), Some(reader)),
+        };
+        let hash = meta::hash(src_code.as_bytes());
         Self {
-            src_code: src_code.into(),
+            src_code,
             i: 0,
             pos: Position::new(file_name.into()),
+            reader,
+            read_error: None,
+            dropped: 0,
+            hash,
+            kept: None,
+            done: false,
+            trivia: None,
+        }
+    }
+
+    /// Keep the comments, with their spans, for `take_trivia` (the formatter, the doc
+    /// generator). The tokens are the same as without.
+    pub fn with_trivia(mut self) -> Self {
+        self.trivia = Some(Vec::new());
+        self
+    }
+
+    /// The comments read since the last call, in source order: those before the last token
+    /// read (and before the one peeked, for a parser). Empty unless `with_trivia`.
+    pub fn take_trivia(&mut self) -> Vec<Trivia> {
+        self.trivia.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// meta::hash of the whole source, hashed as it is read (what is left is read now, and
+    /// not kept): the hash of the bytes lexed, without reading the file again
+    pub fn source_hash(&mut self) -> u64 {
+        let mut line = String::new();
+        while let Some(reader) = self.reader.as_mut() {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(0) => self.reader = None,
+                Ok(_) => self.hash = meta::hash_more(self.hash, line.as_bytes()),
+                Err(e) => {
+                    self.read_error = Some(e);
+                    self.reader = None;
+                }
+            }
+        }
+        self.hash
+    }
+
+    // --- streaming ---
+
+    // Make at least `bytes` bytes available after `i` (fewer at the end of the source).
+    // Whole lines are read, so the text always ends on a char boundary.
+    fn fill(&mut self, bytes: usize) {
+        while self.src_code.len() < self.i + bytes {
+            let Some(reader) = self.reader.as_mut() else {
+                return;
+            };
+            let start = self.src_code.len();
+            match reader.read_line(&mut self.src_code) {
+                Ok(0) => self.reader = None,
+                Ok(_) => self.hash = meta::hash_more(self.hash, &self.src_code.as_bytes()[start..]),
+                Err(e) => {
+                    self.read_error = Some(e);
+                    self.reader = None;
+                }
+            }
+        }
+    }
+
+    // Drop the text before `i` (never inside a token, whose start is an index in src_code,
+    // nor after a checkpoint)
+    #[inline]
+    fn discard(&mut self) {
+        if self.reader.is_none() || self.i < CHUNK {
+            return;
+        }
+        let upto = self.kept.map_or(self.i, |kept| self.i.min(kept - self.dropped));
+        if upto >= CHUNK {
+            self.src_code.drain(..upto);
+            self.i -= upto;
+            self.dropped += upto;
+        }
+    }
+
+    // --- checkpoints ---
+
+    /// Where the lexer is now. The text from there stays in memory, even when streaming.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let offset = self.dropped + self.i;
+        self.kept = Some(self.kept.map_or(offset, |kept| kept.min(offset)));
+        Checkpoint {
+            offset,
+            pos: self.pos.clone(),
+        }
+    }
+
+    /// Go back to a checkpoint: the tokens (and the comments not taken) after it are read again
+    pub fn restore(&mut self, checkpoint: &Checkpoint) {
+        self.i = checkpoint.offset - self.dropped;
+        self.pos = checkpoint.pos.clone();
+        self.done = false;
+        if let Some(trivia) = &mut self.trivia {
+            let at = (checkpoint.pos.line, checkpoint.pos.col);
+            trivia.retain(|t| (t.span.start.line, t.span.start.col) < at);
         }
     }
 
@@ -71,19 +265,22 @@ impl Lexer {
 
     // End-of-file?
     #[inline]
-    fn eof(&self) -> bool {
+    fn eof(&mut self) -> bool {
+        self.fill(1);
         self.i >= self.src_code.len()
     }
 
     // Peek next char without consuming it
     #[inline]
-    fn peek_char(&self) -> Option<char> {
+    fn peek_char(&mut self) -> Option<char> {
+        self.fill(4); // longest UTF-8 char
         self.rest().chars().next()
     }
 
     // Lookahead by 1 (second char)
     #[inline]
-    fn peek_next_char(&self) -> Option<char> {
+    fn peek_next_char(&mut self) -> Option<char> {
+        self.fill(8);
         let mut it = self.rest().chars();
         let _ = it.next()?;
         it.next()
@@ -105,7 +302,8 @@ impl Lexer {
 
     // Check if remaining input starts with a given ASCII prefix (byte-based)
     #[inline]
-    fn starts_with(&self, s: &str) -> bool {
+    fn starts_with(&mut self, s: &str) -> bool {
+        self.fill(s.len());
         let tail = self.src_code.as_bytes().get(self.i..).unwrap_or(&[]);
         tail.starts_with(s.as_bytes())
     }
@@ -134,6 +332,7 @@ impl Lexer {
 
     fn skip_ws_and_comments(&mut self) -> Result<(), LexError> {
         loop {
+            self.discard();
             // 1) Skip ASCII whitespace
             while let Some(ch) = self.peek_char() {
                 match ch {
@@ -144,45 +343,68 @@ impl Lexer {
                 }
             }
 
-            // 2) Line comments: //
-            if self.starts_with("//") {
-                self.eat_prefix("//");
-                while let Some(ch) = self.peek_char() {
-                    if ch == '\n' {
-                        break;
-                    }
-                    self.bump();
-                }
-                continue;
+            // 2) Comments (kept with_trivia)
+            let start = self.trivia.is_some().then(|| self.pos.clone());
+            let mut text = String::new();
+            if !self.skip_comment(start.is_some().then_some(&mut text))? {
+                break;
+            }
+            if let (Some(trivia), Some(start)) = (&mut self.trivia, start) {
+                let kind = if text.starts_with("/*") {
+                    TriviaKind::Block
+                } else {
+                    TriviaKind::Line
+                };
+                let span = Span {
+                    start,
+                    end: self.pos.clone(),
+                };
+                trivia.push(Trivia { kind, text, span });
             }
+        }
+        Ok(())
+    }
 
-            // 3) Block comments: /* ... */
-            if self.starts_with("/*") {
-                self.eat_prefix("/*");
-                let mut closed = false;
-                while let Some(ch) = self.peek_char() {
-                    if ch == '*' && self.peek_next_char() == Some('/') {
-                        // Consume "*/"
-                        self.bump(); // '*'
-                        self.bump(); // '/'
-                        closed = true;
-                        break;
-                    } else {
-                        self.bump(); // advance by one UTF-8 char
-                    }
+    // Skip the comment starting here, `// ...` up to the end of the line or `/* ... */`,
+    // appending its text to `text` if given. false if there is no comment here.
+    fn skip_comment(&mut self, mut text: Option<&mut String>) -> Result<bool, LexError> {
+        let block = if self.starts_with("//") {
+            false
+        } else if self.starts_with("/*") {
+            true
+        } else {
+            return Ok(false);
+        };
+        let mut keep = |s: &str| {
+            if let Some(text) = text.as_deref_mut() {
+                text.push_str(s);
+            }
+        };
+        keep(if block { "/*" } else { "//" });
+        self.eat_prefix(if block { "/*" } else { "//" });
+        loop {
+            match self.peek_char() {
+                Some('*') if block && self.peek_next_char() == Some('/') => {
+                    self.eat_prefix("*/");
+                    keep("*/");
+                    return Ok(true);
+                }
+                Some('\n') if !block => return Ok(true),
+                Some(ch) => {
+                    self.bump(); // advance by one UTF-8 char
+                    keep(ch.encode_utf8(&mut [0; 4]));
+                    self.discard();
                 }
-                if !closed {
-                    return Err(LexError {
-                        message: "block comment not terminated (*/ missing)".into(),
-                        pos: self.pos.clone(),
-                    });
+                None if block => {
+                    return Err(LexError::new(
+                        &messages::UNTERMINATED_COMMENT,
+                        &[],
+                        &self.pos,
+                    ));
                 }
-                continue;
+                None => return Ok(true),
             }
-
-            break;
         }
-        Ok(())
     }
 
     // --- ASCII symbols / fixed tokens ---
@@ -205,9 +427,30 @@ impl Lexer {
         if self.try_take(grammar::RBRACE) {
             return Some(Token::RBrace);
         }
+        if self.try_take(grammar::LBRACKET) {
+            return Some(Token::LBracket);
+        }
+        if self.try_take(grammar::RBRACKET) {
+            return Some(Token::RBracket);
+        }
+        if self.try_take(grammar::HASH) {
+            return Some(Token::Hash);
+        }
         if self.try_take(grammar::COMMA) {
             return Some(Token::Comma);
         }
+        if self.try_take(grammar::DOT) {
+            return Some(Token::Dot);
+        }
+        if self.try_take(grammar::COLON) {
+            return Some(Token::Colon);
+        }
+        if self.try_take(grammar::SEMICOLON) {
+            return Some(Token::Semicolon);
+        }
+        if self.try_take(grammar::AMP) {
+            return Some(Token::Amp);
+        }
         if self.try_take(grammar::PLUS) {
             return Some(Token::Plus);
         }
@@ -220,6 +463,25 @@ impl Lexer {
         if self.try_take(grammar::SLASH) {
             return Some(Token::Slash);
         }
+        // two-character operators before their one-character prefixes
+        if self.try_take(grammar::EQ_EQ) {
+            return Some(Token::EqEq);
+        }
+        if self.try_take(grammar::NOT_EQ) {
+            return Some(Token::NotEq);
+        }
+        if self.try_take(grammar::LESS_EQ) {
+            return Some(Token::LessEq);
+        }
+        if self.try_take(grammar::GREATER_EQ) {
+            return Some(Token::GreaterEq);
+        }
+        if self.try_take(grammar::LESS) {
+            return Some(Token::Less);
+        }
+        if self.try_take(grammar::GREATER) {
+            return Some(Token::Greater);
+        }
         if self.try_take(grammar::EQUAL) {
             return Some(Token::Equal);
         }
@@ -234,10 +496,11 @@ impl Lexer {
         match self.bump() {
             Some('"') => {}
             _ => {
-                return Err(LexError {
-                    message: "internal: expected opening '\"'".into(),
-                    pos: self.pos.clone(),
-                })
+                return Err(LexError::new(
+                    &messages::EXPECTED_OPENING_QUOTE,
+                    &[],
+                    &self.pos,
+                ))
             }
         }
 
@@ -257,10 +520,78 @@ impl Lexer {
             }
         }
 
-        Err(LexError {
-            message: "incomplete string (\" missing)".into(),
-            pos: self.pos.clone(),
-        })
+        Err(LexError::new(
+            &messages::UNTERMINATED_STRING,
+            &[],
+            &self.pos,
+        ))
+    }
+
+    // Read a character literal: 'a', or an escape: '\n' '\t' '\r' '\0' '\\' '\'' '\"' '\u{1F600}'
+    fn read_char(&mut self) -> Result<Token, LexError> {
+        self.bump(); // consume opening '
+        let ch = match self.bump() {
+            None | Some('\n') => return Err(LexError::new(&messages::UNTERMINATED_CHAR, &[], &self.pos)),
+            Some('\'') => return Err(LexError::new(&messages::CHAR_LITERAL_LENGTH, &[], &self.pos)),
+            Some('\\') => self.read_escape()?,
+            Some(ch) => ch,
+        };
+        match self.bump() {
+            Some('\'') => Ok(Token::Char(ch)),
+            Some(c) if c != '\n' => {
+                // 'ab': more than one character, up to the closing ' of the line
+                while let Some(c) = self.peek_char() {
+                    match c {
+                        '\'' => {
+                            self.bump();
+                            return Err(LexError::new(&messages::CHAR_LITERAL_LENGTH, &[], &self.pos));
+                        }
+                        '\n' => break,
+                        _ => {
+                            self.bump();
+                        }
+                    }
+                }
+                Err(LexError::new(&messages::UNTERMINATED_CHAR, &[], &self.pos))
+            }
+            _ => Err(LexError::new(&messages::UNTERMINATED_CHAR, &[], &self.pos)),
+        }
+    }
+
+    // The character of an escape, its '\' consumed
+    fn read_escape(&mut self) -> Result<char, LexError> {
+        let escape = match self.bump() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '\'' | '"')) => c,
+            Some('u') => {
+                // \u{...}: 1 to 6 hex digits, a code point that is not a surrogate
+                if self.bump() != Some('{') {
+                    return Err(LexError::new(&messages::INVALID_UNICODE_ESCAPE, &[], &self.pos));
+                }
+                let mut digits = String::new();
+                while let Some(c) = self.peek_char() {
+                    if !c.is_ascii_hexdigit() {
+                        break;
+                    }
+                    digits.push(c);
+                    self.bump();
+                }
+                let code = (self.bump() == Some('}') && (1..=6).contains(&digits.len()))
+                    .then(|| u32::from_str_radix(&digits, 16).ok())
+                    .flatten();
+                return code
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| LexError::new(&messages::INVALID_UNICODE_ESCAPE, &[], &self.pos));
+            }
+            Some(c) if c != '\n' => {
+                return Err(LexError::new(&messages::UNKNOWN_ESCAPE, &[&c], &self.pos));
+            }
+            _ => return Err(LexError::new(&messages::UNTERMINATED_CHAR, &[], &self.pos)),
+        };
+        Ok(escape)
     }
 
     // ASCII digit check
@@ -298,16 +629,35 @@ impl Lexer {
         (&self.src_code[s..self.i], s, self.i)
     }
 
-    // Identifier start: ASCII letter or underscore
+    // Identifier start: a letter (Unicode XID_Start, `été`) or underscore
     #[inline]
     fn is_ident_start(ch: char) -> bool {
-        ch == '_' || ch.is_ascii_alphabetic()
+        ch == '_' || unicode_ident::is_xid_start(ch)
     }
 
-    // Identifier continue: letter/underscore/digit
+    // Identifier continue: letter/underscore/digit (Unicode XID_Continue)
     #[inline]
     fn is_ident_continue(ch: char) -> bool {
-        Self::is_ident_start(ch) || ch.is_ascii_digit()
+        unicode_ident::is_xid_continue(ch)
+    }
+
+    // An identifier that would not read as it looks: a letter of another script looking like
+    // a Latin one among Latin letters (`pаris` with a Cyrillic `а`), or a combining accent
+    // (`e` + U+0301: the same name as `é` on screen, another one for the compiler)
+    fn check_ident(id: &str, pos: &Position) -> Result<(), LexError> {
+        if let Some(mark) = id.chars().find(|&c| is_combining_mark(c)) {
+            return Err(LexError::new(&messages::COMBINING_MARK, &[&id, &code_point(mark)], pos));
+        }
+        if id.chars().any(|c| c.is_ascii_alphabetic())
+            && let Some((c, latin)) = id.chars().find_map(|c| confusable(c).map(|latin| (c, latin)))
+        {
+            return Err(LexError::new(
+                &messages::MIXED_SCRIPT_IDENT,
+                &[&id, &c, &code_point(c), &latin],
+                pos,
+            ));
+        }
+        Ok(())
     }
 
     // Read an identifier (variable or function name)
@@ -325,9 +675,29 @@ impl Lexer {
 
     // --- main tokenization entry point ---
 
+    /// The next token and where it starts
     pub fn next_token(&mut self) -> Result<(Token, Position), LexError> {
-        self.skip_ws_and_comments()?; // propagate comment/whitespace errors
+        self.next_spanned().map(|(token, span)| (token, span.start))
+    }
 
+    /// The next token and where it is
+    pub fn next_spanned(&mut self) -> Result<(Token, Span), LexError> {
+        let token = stats::lexing(|| {
+            self.skip_ws_and_comments()?; // propagate comment/whitespace errors
+            let start = self.pos.clone();
+            // a token that cannot be read is reported where it starts (the opening quote of a
+            // string never closed, the first digit of a number too large...)
+            let (token, end) = self.read_token().map_err(|e| LexError { pos: start.clone(), ..e })?;
+            Ok((token, Span { start, end }))
+        }); // timed for --timings
+        // a source that could not be read to the end: that is the error, whatever was lexed
+        match self.read_error.take() {
+            Some(e) => Err(LexError::new(&messages::READ_ERROR, &[&e], &self.pos)),
+            None => token,
+        }
+    }
+
+    fn read_token(&mut self) -> Result<(Token, Position), LexError> {
         if self.eof() {
             return Ok((Token::Eof, self.pos.clone()));
         }
@@ -341,18 +711,36 @@ impl Lexer {
             return Ok((tok, self.pos.clone()));
         }
 
+        if self.peek_char() == Some('\'') {
+            let tok = self.read_char()?;
+            return Ok((tok, self.pos.clone()));
+        }
+
         if let Some(ch) = self.peek_char() {
             // identifier or keyword
             if Self::is_ident_start(ch) {
                 let (id, _, _) = self.read_ident();
-                let token = match id {
+                let id = id.to_string();
+                Self::check_ident(&id, &self.pos)?;
+                let token = match id.as_str() {
                     // keywords
                     grammar::KW_IMPORT => Token::Import,
+                    grammar::KW_AS => Token::As,
                     grammar::KW_CALL => Token::Call,
+                    grammar::KW_CALL_INDIRECT => Token::CallIndirect,
+                    grammar::KW_ENUM => Token::Enum,
+                    grammar::KW_MATCH => Token::Match,
+                    grammar::KW_CASE => Token::Case,
+                    grammar::KW_DEFAULT => Token::Default,
                     grammar::KW_FN => Token::Fn,
+                    grammar::KW_PUB => Token::Pub,
+                    grammar::KW_EXPORT => Token::Export,
+                    grammar::KW_EXTERN => Token::Extern,
                     grammar::KW_MAIN => Token::Main,
                     grammar::KW_PRINT => Token::Print,
                     grammar::KW_PRINTLN => Token::Println,
+                    grammar::KW_EPRINT => Token::EPrint,
+                    grammar::KW_EPRINTLN => Token::EPrintln,
                     grammar::KW_TO_STR => Token::ToStr,
                     grammar::KW_NL => Token::Nl,
                     grammar::KW_LOCAL => Token::Local,
@@ -360,14 +748,52 @@ impl Lexer {
                     grammar::KW_FALSE => Token::False,
                     grammar::KW_INT_TYPE => Token::IntType,
                     grammar::KW_FLOAT_TYPE => Token::FloatType,
+                    grammar::KW_CHAR_TYPE => Token::CharType,
+                    grammar::KW_MAP_TYPE => Token::MapType,
+                    grammar::KW_BUILDER_TYPE => Token::BuilderType,
                     grammar::KW_LET => Token::Let,
                     grammar::KW_FOR => Token::For,
                     grammar::KW_TO => Token::To,
                     grammar::KW_STEP => Token::Step,
                     grammar::KW_NEXT => Token::Next,
                     grammar::KW_BREAK => Token::Break,
+                    grammar::KW_RETURN => Token::Return,
+                    grammar::KW_ARG_COUNT => Token::ArgCount,
+                    grammar::KW_ARG => Token::Arg,
+                    grammar::KW_FLUSH => Token::Flush,
+                    grammar::KW_DUMP_HEAP => Token::DumpHeap,
+                    grammar::KW_RANDOM => Token::Random,
+                    grammar::KW_RANDOM_INT => Token::RandomInt,
+                    grammar::KW_LEN => Token::Len,
+                    grammar::KW_TO_INT => Token::ToInt,
+                    grammar::KW_TO_FLOAT => Token::ToFloat,
+                    grammar::KW_SUBSTR => Token::Substr,
+                    grammar::KW_CHAR_AT => Token::CharAt,
+                    grammar::KW_ORD => Token::Ord,
+                    grammar::KW_CHR => Token::Chr,
+                    grammar::KW_HAS => Token::Has,
+                    grammar::KW_DELETE => Token::Delete,
+                    grammar::KW_REPEAT => Token::Repeat,
+                    grammar::KW_FORMAT_THOUSANDS => Token::FormatThousands,
+                    grammar::KW_DECIMAL_COMMA => Token::DecimalComma,
+                    grammar::KW_JOIN => Token::Join,
+                    grammar::KW_APPEND => Token::Append,
+                    grammar::KW_CLEAR => Token::Clear,
+                    grammar::KW_SQRT => Token::Math(MathFn::Sqrt),
+                    grammar::KW_ABS => Token::Math(MathFn::Abs),
+                    grammar::KW_MIN => Token::Math(MathFn::Min),
+                    grammar::KW_MAX => Token::Math(MathFn::Max),
+                    grammar::KW_POW => Token::Math(MathFn::Pow),
+                    grammar::KW_FLOOR => Token::Math(MathFn::Floor),
+                    grammar::KW_CEIL => Token::Math(MathFn::Ceil),
+                    grammar::KW_ROUND => Token::Math(MathFn::Round),
+                    grammar::KW_SIN => Token::Math(MathFn::Sin),
+                    grammar::KW_COS => Token::Math(MathFn::Cos),
+                    grammar::KW_TAN => Token::Math(MathFn::Tan),
+                    grammar::KW_LOG => Token::Math(MathFn::Log),
+                    grammar::KW_EXP => Token::Math(MathFn::Exp),
                     // otherwise, plain identifier
-                    _ => Token::Ident(id.to_string()),
+                    _ => Token::Ident(id),
                 };
                 return Ok((token, self.pos.clone()));
             }
@@ -386,17 +812,21 @@ impl Lexer {
                         lexeme.to_string()
                     };
 
-                    let value = value_str.parse::<f64>().map_err(|_| LexError {
-                        message: "invalid float number format".to_string(),
-                        pos: self.pos.clone(),
+                    let value = value_str.parse::<f64>().map_err(|_| {
+                        LexError::new(&messages::INVALID_FLOAT, &[], &self.pos)
                     })?;
 
                     return Ok((Token::Float(value), self.pos.clone()));
                 } else {
-                    let value = lexeme.parse::<i32>().map_err(|_| LexError {
-                        message: "invalid integer format".to_string(),
-                        pos: self.pos.clone(),
-                    })?;
+                    // only digits: the only way to fail is to be too large
+                    let Ok(value) = lexeme.parse::<i32>() else {
+                        let text = lexeme.to_string();
+                        return Err(LexError::new(
+                            &messages::INTEGER_OUT_OF_RANGE,
+                            &[&text, &i32::MAX, &text],
+                            &self.pos,
+                        ));
+                    };
 
                     return Ok((Token::Integer(value), self.pos.clone()));
                 }
@@ -405,21 +835,127 @@ impl Lexer {
 
         // Unexpected character: show readable char + code point
         if let Some(ch) = self.peek_char() {
+            // a character that looks like one of the language: “ ” for ", – for -, a no-break space
+            if let Some(ascii) = confusable(ch).filter(|c| !c.is_ascii_alphabetic()) {
+                return Err(LexError::new(
+                    &messages::CONFUSABLE_CHAR,
+                    &[&ch, &code_point(ch), &ascii],
+                    &self.pos,
+                ));
+            }
             let cp = ch as u32;
-            let msg = if ch.is_ascii() {
-                format!("unexpected token: '{}' (0x{:02X})", ch.escape_default(), cp)
+            let (shown, code) = if ch.is_ascii() {
+                (ch.escape_default().to_string(), format!("0x{:02X}", cp))
             } else {
-                format!("unexpected char: '{}' (U+{:04X})", ch, cp)
+                (ch.to_string(), format!("U+{:04X}", cp))
             };
-            Err(LexError {
-                message: msg,
-                pos: self.pos.clone(),
-            })
+            Err(LexError::new(
+                &messages::UNEXPECTED_CHAR,
+                &[&shown, &code],
+                &self.pos,
+            ))
         } else {
-            Err(LexError {
-                message: "unexpected end of input".into(),
-                pos: self.pos.clone(),
-            })
+            Err(LexError::new(&messages::UNEXPECTED_EOF, &[], &self.pos))
+        }
+    }
+}
+
+// Characters that look like an ASCII one (what they look like): the typographic quotes, dashes
+// and spaces a word processor puts in, and the Cyrillic and Greek letters drawn as Latin ones
+const CONFUSABLES: &[(char, char)] = &[
+    ('\u{201C}', '"'), // “
+    ('\u{201D}', '"'), // ”
+    ('\u{201E}', '"'), // „
+    ('\u{00AB}', '"'), // «
+    ('\u{00BB}', '"'), // »
+    ('\u{2018}', '\''), // ‘
+    ('\u{2019}', '\''), // ’
+    ('\u{2013}', '-'), // – en dash
+    ('\u{2014}', '-'), // — em dash
+    ('\u{2212}', '-'), // − minus sign
+    ('\u{00D7}', '*'), // ×
+    ('\u{00F7}', '/'), // ÷
+    ('\u{00A0}', ' '), // no-break space
+    ('\u{202F}', ' '), // narrow no-break space (French typography, before : ; ! ?)
+    ('\u{2009}', ' '), // thin space
+    ('\u{FF08}', '('), // fullwidth (
+    ('\u{FF09}', ')'), // fullwidth )
+    ('\u{FF0C}', ','), // fullwidth ,
+    ('\u{FF1A}', ':'), // fullwidth :
+    ('\u{FF1D}', '='), // fullwidth =
+    ('\u{0430}', 'a'), // Cyrillic
+    ('\u{0435}', 'e'),
+    ('\u{043E}', 'o'),
+    ('\u{0440}', 'p'),
+    ('\u{0441}', 'c'),
+    ('\u{0443}', 'y'),
+    ('\u{0445}', 'x'),
+    ('\u{0455}', 's'),
+    ('\u{0456}', 'i'),
+    ('\u{0458}', 'j'),
+    ('\u{0410}', 'A'),
+    ('\u{0412}', 'B'),
+    ('\u{0415}', 'E'),
+    ('\u{041A}', 'K'),
+    ('\u{041C}', 'M'),
+    ('\u{041D}', 'H'),
+    ('\u{041E}', 'O'),
+    ('\u{0420}', 'P'),
+    ('\u{0421}', 'C'),
+    ('\u{0422}', 'T'),
+    ('\u{0425}', 'X'),
+    ('\u{03B1}', 'a'), // Greek
+    ('\u{03BD}', 'v'),
+    ('\u{03BF}', 'o'),
+    ('\u{0391}', 'A'),
+    ('\u{0392}', 'B'),
+    ('\u{0395}', 'E'),
+    ('\u{0397}', 'H'),
+    ('\u{0399}', 'I'),
+    ('\u{039A}', 'K'),
+    ('\u{039C}', 'M'),
+    ('\u{039D}', 'N'),
+    ('\u{039F}', 'O'),
+    ('\u{03A1}', 'P'),
+    ('\u{03A4}', 'T'),
+    ('\u{03A7}', 'X'),
+    ('\u{03A5}', 'Y'),
+    ('\u{0396}', 'Z'),
+];
+
+// The ASCII character `ch` looks like, if it is one of the CONFUSABLES
+fn confusable(ch: char) -> Option<char> {
+    CONFUSABLES.iter().find(|(c, _)| *c == ch).map(|&(_, ascii)| ascii)
+}
+
+// The combining diacritical marks (blocks), which put an accent on the letter before them
+fn is_combining_mark(ch: char) -> bool {
+    matches!(ch, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FE20}'..='\u{FE2F}')
+}
+
+// U+00E9
+fn code_point(ch: char) -> String {
+    format!("U+{:04X}", ch as u32)
+}
+
+// The tokens up to the end of the source (Eof is not yielded), or up to the first error
+impl Iterator for Lexer {
+    type Item = Result<(Token, Span), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_spanned() {
+            Ok((Token::Eof, _)) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+            token => Some(token),
         }
     }
 }
@@ -0,0 +1,36 @@
+// Vincent Pineau 04/10/2025
+// My Programming Language
+// Library entry point: the compiler pipeline, usable by embedders
+
+pub mod codegen;
+pub mod coverage;
+pub mod csource;
+pub mod debugger;
+pub mod diagnostic;
+pub mod doc;
+pub mod formatter;
+pub mod grammar;
+pub mod ir;
+pub mod jsglue;
+pub mod jssource;
+pub mod lint;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod lexer;
+pub mod manifest;
+pub mod messages;
+pub mod meta;
+pub mod modules;
+pub mod optimize;
+pub mod parser;
+pub mod peephole;
+pub mod profiler;
+pub mod runner;
+pub mod runtime;
+pub mod sourcemap;
+pub mod stats;
+pub mod symbols;
+#[cfg(test)]
+mod testing;
+pub mod trace;
+pub mod visit;
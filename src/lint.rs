@@ -0,0 +1,565 @@
+// My Programming Language
+// `mpl lint`: warnings about code that compiles but probably does not do what was meant.
+//
+// unused-variable   a `local` never assigned nor read
+// unused-function   a function no `call` nor `&` names (main, `export fn` and the public functions of a
+//                   library are called from outside)
+// shadowed-literal  `let x = 3` replaced by another `let x = ...` before x is read
+// unread-variable   a variable given values that are never read (loop variables aside)
+// float-division    a `/` of floats computed in int because its value goes to an int:
+//                   `substr(s, 0, n / 2.5)` truncates n and 2.5, then divides
+// implicit-narrowing  a float expression given to an int, computed in int: `let i = f * 1.5`
+//                   truncates f and 1.5, then multiplies
+// call-keyword      `call f()`, the call of earlier versions: `f()` is enough
+//
+// The compiler reports these warnings too (mpl compile, run, check and build). --allow <rule>
+// silences a rule, --deny <rule> makes its warnings errors that fail the compilation,
+// --deny warnings all of them.
+//
+// The program is the loaded one (calls resolved, see modules.rs), so a call names the function
+// it reaches whatever file it is in.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::codegen::{Ty, infer_type};
+use crate::diagnostic::Diagnostic;
+use crate::lexer::Position;
+use crate::grammar;
+use crate::messages::{self, Message};
+use crate::parser::{BinOp, Expr, FnExpr, Function, NumExpr, Program, Stadment, StrExpr};
+use crate::visit::{Visitor, walk_expr, walk_num_expr, walk_program, walk_stadment, walk_str_expr};
+
+/// A check of `mpl lint`, turned off with --allow <name>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    UnusedVariable,
+    UnusedFunction,
+    ShadowedLiteral,
+    UnreadVariable,
+    FloatDivision,
+    ImplicitNarrowing,
+    CallKeyword,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 7] = [
+        Rule::UnusedVariable,
+        Rule::UnusedFunction,
+        Rule::ShadowedLiteral,
+        Rule::UnreadVariable,
+        Rule::FloatDivision,
+        Rule::ImplicitNarrowing,
+        Rule::CallKeyword,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UnusedVariable => "unused-variable",
+            Rule::UnusedFunction => "unused-function",
+            Rule::ShadowedLiteral => "shadowed-literal",
+            Rule::UnreadVariable => "unread-variable",
+            Rule::FloatDivision => "float-division",
+            Rule::ImplicitNarrowing => "implicit-narrowing",
+            Rule::CallKeyword => "call-keyword",
+        }
+    }
+
+    // Catalog message of its warnings
+    fn message(self) -> &'static Message {
+        match self {
+            Rule::UnusedVariable => &messages::UNUSED_VARIABLE,
+            Rule::UnusedFunction => &messages::UNUSED_FUNCTION,
+            Rule::ShadowedLiteral => &messages::SHADOWED_LITERAL,
+            Rule::UnreadVariable => &messages::UNREAD_VARIABLE,
+            Rule::FloatDivision => &messages::FLOAT_DIVISION,
+            Rule::ImplicitNarrowing => &messages::IMPLICIT_NARROWING,
+            Rule::CallKeyword => &messages::CALL_KEYWORD,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Rule::ALL.into_iter().find(|r| r.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = Rule::ALL.iter().map(|r| r.name()).collect();
+            format!("unknown lint rule '{}' ({})", s, names.join(", "))
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What --allow and --deny make of the warnings: by default every rule warns.
+#[derive(Debug, Clone, Default)]
+pub struct Levels {
+    allowed: Vec<Rule>,
+    denied: Vec<Rule>,
+    deny_warnings: bool, // --deny warnings
+}
+
+impl Levels {
+    pub fn allow(&mut self, rule: Rule) {
+        self.allowed.push(rule);
+    }
+
+    pub fn deny(&mut self, rule: Rule) {
+        self.denied.push(rule);
+    }
+
+    pub fn deny_warnings(&mut self) {
+        self.deny_warnings = true;
+    }
+
+    // The warning of `rule` as reported: None if allowed, an error if denied
+    fn report(&self, rule: Rule, warning: Diagnostic) -> Option<Diagnostic> {
+        if self.allowed.contains(&rule) {
+            None
+        } else if self.deny_warnings || self.denied.contains(&rule) {
+            Some(warning.denied())
+        } else {
+            Some(warning)
+        }
+    }
+}
+
+// A warning of a rule
+struct Lint {
+    rule: Rule,
+    warning: Diagnostic,
+}
+
+impl Lint {
+    fn new(rule: Rule, args: &[&dyn fmt::Display], pos: &Position) -> Self {
+        let mut warning = Diagnostic::warning(rule.message(), args, pos);
+        warning.message = format!("{} ({})", warning.message, rule);
+        Lint { rule, warning }
+    }
+}
+
+/// The warnings of `prog` at their `levels` (those denied are errors), in source order.
+pub fn lint(prog: &Program, levels: &Levels) -> Vec<Diagnostic> {
+    let mut lints = Vec::new();
+    unused_functions(prog, &mut lints);
+    call_keywords(prog, &mut lints);
+    let functions = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main);
+    for f in functions {
+        variables(f, &mut lints);
+        shadowed_literals(&f.body, &mut lints);
+        conversions(&f.body, &mut lints);
+    }
+    let mut reported: Vec<Diagnostic> = lints.into_iter().filter_map(|l| levels.report(l.rule, l.warning)).collect();
+    reported.sort_by(|a, b| {
+        let (a, b) = (&a.span.start, &b.span.start);
+        (&a.file_name, a.line, a.col).cmp(&(&b.file_name, b.line, b.col))
+    });
+    reported
+}
+
+// --- unused-function
+
+// Names of the called functions, and of those given to fn variables
+#[derive(Default)]
+struct Calls(HashSet<String>);
+
+impl Visitor for Calls {
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Fn(FnExpr::Ref { name, .. }) = e {
+            self.0.insert(name.clone());
+        }
+        walk_expr(self, e);
+    }
+
+    fn visit_stadment(&mut self, st: &Stadment) {
+        if let Stadment::Call { name, .. } = st {
+            self.0.insert(name.clone());
+        }
+        walk_stadment(self, st);
+    }
+}
+
+fn unused_functions(prog: &Program, lints: &mut Vec<Lint>) {
+    let mut calls = Calls::default();
+    walk_program(&mut calls, prog);
+    // the functions of the main file (none calls them from outside but an `export fn`), then
+    // those of the libraries, which their public ones leave; a library compiled on its own
+    // (no main) is all library
+    let program = prog.main_program.main.is_some();
+    let main_file = prog.main_program.functions.iter().map(|f| (f, program));
+    let libraries = prog.functions.iter().map(|f| (f, false));
+    for (f, in_main_file) in main_file.chain(libraries) {
+        if !calls.0.contains(&f.name) && !f.export && (in_main_file || !f.public) {
+            // the name as written (see the mangling of modules.rs)
+            let name = f.name.rsplit("::").next().unwrap_or(&f.name);
+            lints.push(Lint::new(Rule::UnusedFunction, &[&name], &f.pos));
+        }
+    }
+}
+
+// --- call-keyword
+
+#[derive(Default)]
+struct CallKeywords(Vec<Lint>);
+
+impl Visitor for CallKeywords {
+    fn visit_stadment(&mut self, st: &Stadment) {
+        if let Stadment::Call { name, module, keyword: true, pos, .. } = st {
+            // the call as written (see the mangling of modules.rs)
+            let name = name.rsplit("::").next().unwrap_or(name);
+            let call = match module {
+                Some(module) => format!("{}.{}()", module, name),
+                None => format!("{}()", name),
+            };
+            self.0.push(Lint::new(Rule::CallKeyword, &[&call], pos));
+        }
+        walk_stadment(self, st);
+    }
+}
+
+fn call_keywords(prog: &Program, lints: &mut Vec<Lint>) {
+    let mut calls = CallKeywords::default();
+    walk_program(&mut calls, prog);
+    lints.append(&mut calls.0);
+}
+
+// --- unused-variable, unread-variable
+
+#[derive(Default)]
+struct Uses {
+    read: HashSet<String>,
+    assigned: HashSet<String>,
+    loops: HashSet<String>, // loop variables
+}
+
+impl Visitor for Uses {
+    fn visit_stadment(&mut self, st: &Stadment) {
+        match st {
+            Stadment::Assignment { var, .. }
+            | Stadment::MapSet { var, .. }
+            | Stadment::MapDelete { var, .. }
+            | Stadment::Append { var, .. }
+            | Stadment::Clear { var, .. } => {
+                self.assigned.insert(var.name.clone());
+            }
+            Stadment::ForLoop { var, .. } => {
+                self.loops.insert(var.name.clone());
+            }
+            Stadment::CallIndirect { var, .. } => {
+                self.read.insert(var.name.clone());
+            }
+            _ => {}
+        }
+        walk_stadment(self, st);
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        if let Expr::Fn(FnExpr::Var { var, .. }) = e {
+            self.read.insert(var.name.clone());
+        }
+        walk_expr(self, e);
+    }
+
+    fn visit_num_expr(&mut self, e: &NumExpr) {
+        if let NumExpr::Var { var, .. } | NumExpr::MapGet { var, .. } | NumExpr::MapHas { var, .. } = e {
+            self.read.insert(var.name.clone());
+        }
+        walk_num_expr(self, e);
+    }
+
+    fn visit_str_expr(&mut self, e: &StrExpr) {
+        if let StrExpr::Builder { var, .. } = e {
+            self.read.insert(var.name.clone());
+        }
+        walk_str_expr(self, e);
+    }
+}
+
+fn variables(f: &Function, lints: &mut Vec<Lint>) {
+    let mut uses = Uses::default();
+    uses.visit_function(f);
+    for var in &f.variables {
+        if uses.read.contains(&var.name) || uses.loops.contains(&var.name) {
+            continue;
+        }
+        let rule = if uses.assigned.contains(&var.name) {
+            Rule::UnreadVariable
+        } else {
+            Rule::UnusedVariable
+        };
+        lints.push(Lint::new(rule, &[&var.name], &var.pos));
+    }
+}
+
+// --- shadowed-literal
+
+// Whether a statement (or an expression) reads or sets the variable `name`
+struct Mentions<'a> {
+    name: &'a str,
+    found: bool,
+}
+
+impl Visitor for Mentions<'_> {
+    fn visit_stadment(&mut self, st: &Stadment) {
+        if let Stadment::Assignment { var, .. } | Stadment::ForLoop { var, .. } = st {
+            self.found |= var.name == self.name;
+        }
+        walk_stadment(self, st);
+    }
+
+    fn visit_num_expr(&mut self, e: &NumExpr) {
+        if let NumExpr::Var { var, .. } = e {
+            self.found |= var.name == self.name;
+        }
+        walk_num_expr(self, e);
+    }
+}
+
+// The literal `e` is, as written
+fn literal(e: &NumExpr) -> Option<String> {
+    match e {
+        NumExpr::Int(i) => Some(i.to_string()),
+        NumExpr::Float(x) => Some(format!("{:?}", x)),
+        NumExpr::Neg(inner) => literal(inner).map(|l| format!("-{}", l)),
+        _ => None,
+    }
+}
+
+fn shadowed_literals(body: &[Stadment], lints: &mut Vec<Lint>) {
+    for (i, st) in body.iter().enumerate() {
+        match st {
+            Stadment::ForLoop { body, .. } => shadowed_literals(body, lints),
+            Stadment::Match { arms, default, .. } => {
+                arms.iter().for_each(|arm| shadowed_literals(&arm.body, lints));
+                if let Some(default) = default {
+                    shadowed_literals(default, lints);
+                }
+            }
+            _ => {}
+        }
+        let Stadment::Assignment { var, expr: Expr::Num(value), pos } = st else {
+            continue;
+        };
+        let Some(literal) = literal(value) else {
+            continue;
+        };
+        // the next statement reading or setting the variable only sets it
+        let mentions = |st: &Stadment| {
+            let mut m = Mentions { name: &var.name, found: false };
+            m.visit_stadment(st);
+            m.found
+        };
+        if let Some(Stadment::Assignment { var: next, expr, .. }) = body[i + 1..].iter().find(|st| mentions(st))
+            && next.name == var.name
+        {
+            let mut read = Mentions { name: &var.name, found: false };
+            read.visit_expr(expr);
+            if !read.found {
+                lints.push(Lint::new(Rule::ShadowedLiteral, &[&literal, &var.name], pos));
+            }
+        }
+    }
+}
+
+// --- float-division, implicit-narrowing
+
+// Same types as the code generator: an expression is computed in the type of where its value
+// goes (see gen_expression_as)
+fn conversions(body: &[Stadment], lints: &mut Vec<Lint>) {
+    for st in body {
+        match st {
+            Stadment::Print { items, pos }
+            | Stadment::Println { items, pos }
+            | Stadment::EPrint { items, pos }
+            | Stadment::EPrintln { items, pos }
+            | Stadment::Append { items, pos, .. } => {
+                items.iter().for_each(|s| str_divisions(s, pos, lints));
+            }
+            Stadment::Assignment { var, expr, pos } => {
+                let taker = format!("`{} {}`", grammar::KW_LET, var.name);
+                root_conversions(expr, var.ty, &taker, pos, lints);
+            }
+            Stadment::ForLoop {
+                var,
+                start,
+                end,
+                step,
+                body,
+                pos,
+            } => {
+                let taker = format!("`{} {}`", grammar::KW_FOR, var.name);
+                for e in [start, end].into_iter().chain(step) {
+                    root_conversions(e, var.ty, &taker, pos, lints);
+                }
+                conversions(body, lints);
+            }
+            Stadment::Return { expr, pos } => {
+                let taker = format!("`{}`", grammar::KW_RETURN);
+                root_conversions(expr, Ty::I32, &taker, pos, lints);
+            }
+            Stadment::MapSet { var, key, value, pos } => {
+                str_divisions(key, pos, lints);
+                if infer_type(value) == Ty::F64 {
+                    let taker = format!("`{} {}[...]`", grammar::KW_LET, var.name);
+                    lints.push(Lint::new(Rule::ImplicitNarrowing, &[&taker], pos));
+                } else {
+                    num_divisions(value, Ty::I32, pos, lints);
+                }
+            }
+            Stadment::MapDelete { key, pos, .. } => str_divisions(key, pos, lints),
+            Stadment::Match { value, arms, default, pos } => {
+                num_divisions(value, Ty::I32, pos, lints);
+                arms.iter().for_each(|arm| conversions(&arm.body, lints));
+                if let Some(default) = default {
+                    conversions(default, lints);
+                }
+            }
+            Stadment::DumpHeap { region, pos } => {
+                if let Some((start, len)) = region {
+                    num_divisions(start, Ty::I32, pos, lints);
+                    num_divisions(len, Ty::I32, pos, lints);
+                }
+            }
+            Stadment::Call { .. } | Stadment::CallIndirect { .. } | Stadment::Clear { .. } | Stadment::Flush => {}
+        }
+    }
+}
+
+// An expression whose value goes to `ty`: one warning if it is a float computed as an int,
+// else those of its divisions
+fn root_conversions(e: &Expr, ty: Ty, taker: &str, pos: &Position, lints: &mut Vec<Lint>) {
+    match e {
+        Expr::Num(n) if ty == Ty::I32 && infer_type(n) == Ty::F64 => {
+            lints.push(Lint::new(Rule::ImplicitNarrowing, &[&taker], pos));
+        }
+        Expr::Num(n) => num_divisions(n, ty, pos, lints),
+        Expr::Str(s) => str_divisions(s, pos, lints),
+        Expr::Fn(_) => {}
+    }
+}
+
+fn num_divisions(e: &NumExpr, ty: Ty, pos: &Position, lints: &mut Vec<Lint>) {
+    match e {
+        NumExpr::Binary { op, left, right } => {
+            let floats = infer_type(left) == Ty::F64 || infer_type(right) == Ty::F64;
+            if *op == BinOp::Div && ty == Ty::I32 && floats {
+                lints.push(Lint::new(Rule::FloatDivision, &[], pos));
+            }
+            num_divisions(left, ty, pos, lints);
+            num_divisions(right, ty, pos, lints);
+        }
+        NumExpr::Neg(inner) => num_divisions(inner, ty, pos, lints),
+        NumExpr::Ord(inner) | NumExpr::Chr(inner) => num_divisions(inner, Ty::I32, pos, lints),
+        NumExpr::CharAt { s, index } => {
+            str_divisions(s, pos, lints);
+            num_divisions(index, Ty::I32, pos, lints);
+        }
+        NumExpr::Math { args, .. } => {
+            let ty = infer_type(e);
+            args.iter().for_each(|a| num_divisions(a, ty, pos, lints));
+        }
+        NumExpr::RandomInt { lo, hi } => {
+            num_divisions(lo, Ty::I32, pos, lints);
+            num_divisions(hi, Ty::I32, pos, lints);
+        }
+        NumExpr::Len(s) | NumExpr::ToInt(s) | NumExpr::ToFloat(s) => str_divisions(s, pos, lints),
+        NumExpr::MapGet { key, .. } | NumExpr::MapHas { key, .. } => str_divisions(key, pos, lints),
+        NumExpr::StrEq { left, right, .. } => {
+            str_divisions(left, pos, lints);
+            str_divisions(right, pos, lints);
+        }
+        NumExpr::Int(_)
+        | NumExpr::Float(_)
+        | NumExpr::Char(_)
+        | NumExpr::Var { .. }
+        | NumExpr::ArgCount
+        | NumExpr::Random => {}
+    }
+}
+
+fn str_divisions(e: &StrExpr, pos: &Position, lints: &mut Vec<Lint>) {
+    match e {
+        StrExpr::NumToStr(n) => num_divisions(n, infer_type(n), pos, lints),
+        StrExpr::Arg(n) => num_divisions(n, Ty::I32, pos, lints),
+        StrExpr::ToFixed { n, decimals } => {
+            num_divisions(n, Ty::F64, pos, lints);
+            num_divisions(decimals, Ty::I32, pos, lints);
+        }
+        StrExpr::Thousands { n, decimals } => {
+            num_divisions(n, Ty::F64, pos, lints);
+            if let Some(decimals) = decimals {
+                num_divisions(decimals, Ty::I32, pos, lints);
+            }
+        }
+        StrExpr::DecimalComma(s) => str_divisions(s, pos, lints),
+        StrExpr::Substr { s, start, len } => {
+            str_divisions(s, pos, lints);
+            num_divisions(start, Ty::I32, pos, lints);
+            num_divisions(len, Ty::I32, pos, lints);
+        }
+        StrExpr::CharAt { s, index } => {
+            str_divisions(s, pos, lints);
+            num_divisions(index, Ty::I32, pos, lints);
+        }
+        StrExpr::Repeat { s, count } => {
+            str_divisions(s, pos, lints);
+            num_divisions(count, Ty::I32, pos, lints);
+        }
+        StrExpr::Join { sep, parts } => {
+            str_divisions(sep, pos, lints);
+            parts.iter().for_each(|p| str_divisions(p, pos, lints));
+        }
+        StrExpr::Str(_) | StrExpr::Nl | StrExpr::Builder { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+    use crate::testing;
+
+    const PROGRAM: &str = "fn unused() {\n}\nmain() {\n    local int never\n    local int i\n    local float f\n    let i = 1\n    let f = 2.5\n    let i = f * 2.0\n    return i\n}\n";
+
+    fn codes(levels: &Levels) -> Vec<(&'static str, Severity)> {
+        lint(&testing::program(PROGRAM), levels).iter().map(|d| (d.code, d.severity)).collect()
+    }
+
+    #[test]
+    fn warns_about_each_rule_in_source_order() {
+        let warning = Severity::Warning;
+        assert_eq!(
+            codes(&Levels::default()),
+            [
+                (messages::UNUSED_FUNCTION.code, warning),
+                (messages::UNUSED_VARIABLE.code, warning),
+                (messages::SHADOWED_LITERAL.code, warning),
+                (messages::IMPLICIT_NARROWING.code, warning),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowed_rules_are_silent_and_denied_ones_are_errors() {
+        let mut levels = Levels::default();
+        levels.allow(Rule::UnusedFunction);
+        levels.allow(Rule::ShadowedLiteral);
+        levels.deny(Rule::UnusedVariable);
+        assert_eq!(
+            codes(&levels),
+            [(messages::UNUSED_VARIABLE.code, Severity::Error), (messages::IMPLICIT_NARROWING.code, Severity::Warning)]
+        );
+        levels.deny_warnings();
+        assert!(codes(&levels).iter().all(|&(_, severity)| severity == Severity::Error));
+    }
+}
@@ -0,0 +1,467 @@
+// My Programming Language
+// `mpl lsp`: a Language Server Protocol server on stdin/stdout, for editors (built with
+// --features lsp).
+//
+// - diagnostics: each time a document is opened or changed, it is loaded with what it imports
+//   and compiled in memory; the error that stops the compilation and the warnings of lint.rs
+//   are published for it
+// - go to definition and hover on a function or a variable name: the symbol index of the
+//   document (see symbols.rs), hover showing its declaration, type and `///` comment. When the
+//   document does not load (an unknown call, a missing import), it is indexed alone if it
+//   parses, and not at all if it does not
+//
+// Only the documents are read from the editor: the files they import are read from the disk.
+// The positions of the AST are just after their token, so a range is the name ending there.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{GotoDefinition, HoverRequest, Request as _};
+use lsp_types::{
+    DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, NumberOrString, OneOf,
+    PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+use crate::codegen::CodeGenerator;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::grammar;
+use crate::lexer::{LexError, Lexer, Position, Source};
+use crate::lint::{self, Levels};
+use crate::messages;
+use crate::modules;
+use crate::parser::{Function, ParseError, Parser, Program};
+use crate::symbols::{Symbol, SymbolIndex, SymbolKind};
+
+/// Serve the editor connected to stdin/stdout until it asks to exit.
+pub fn serve() -> Result<(), Box<dyn Error>> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    let mut server = Server::default();
+    for msg in &connection.receiver {
+        match msg {
+            Message::Request(req) => {
+                if connection.handle_shutdown(&req)? {
+                    break;
+                }
+                connection.sender.send(Message::Response(server.request(req)))?;
+            }
+            Message::Notification(not) => {
+                for published in server.notification(not) {
+                    connection.sender.send(Message::Notification(published))?;
+                }
+            }
+            Message::Response(_) => {}
+        }
+    }
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<PathBuf, String>, // text of the open documents
+    compiled: HashMap<PathBuf, Program>, // last version of each that compiled
+}
+
+impl Server {
+    // Answer a request (an error response for those not supported)
+    fn request(&mut self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match req.method.as_str() {
+            GotoDefinition::METHOD => serde_json::from_value(req.params)
+                .map(|params: GotoDefinitionParams| serde_json::to_value(self.definition(params))),
+            HoverRequest::METHOD => {
+                serde_json::from_value(req.params).map(|params: HoverParams| serde_json::to_value(self.hover(params)))
+            }
+            _ => return Response::new_err(id, lsp_server::ErrorCode::MethodNotFound as i32, req.method),
+        };
+        match result {
+            Ok(Ok(value)) => Response::new_ok(id, value),
+            Ok(Err(e)) | Err(e) => Response::new_err(id, lsp_server::ErrorCode::InvalidParams as i32, e.to_string()),
+        }
+    }
+
+    // Follow the changes of the documents; the diagnostics to publish
+    fn notification(&mut self, not: Notification) -> Vec<Notification> {
+        let changed = match not.method.as_str() {
+            DidOpenTextDocument::METHOD => serde_json::from_value(not.params)
+                .ok()
+                .map(|p: lsp_types::DidOpenTextDocumentParams| (p.text_document.uri, Some(p.text_document.text))),
+            // full sync: the last change is the whole text
+            DidChangeTextDocument::METHOD => serde_json::from_value(not.params)
+                .ok()
+                .map(|p: lsp_types::DidChangeTextDocumentParams| {
+                    (p.text_document.uri, p.content_changes.into_iter().last().map(|c| c.text))
+                }),
+            DidCloseTextDocument::METHOD => serde_json::from_value(not.params)
+                .ok()
+                .map(|p: lsp_types::DidCloseTextDocumentParams| (p.text_document.uri, None)),
+            _ => None,
+        };
+        let Some((uri, text)) = changed else {
+            return Vec::new();
+        };
+        let Ok(path) = uri.to_file_path() else {
+            return Vec::new();
+        };
+        let diagnostics = match text {
+            Some(text) => {
+                self.documents.insert(path.clone(), text);
+                self.check(&path)
+            }
+            None => {
+                self.documents.remove(&path);
+                self.compiled.remove(&path);
+                Vec::new()
+            }
+        };
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        vec![Notification::new(PublishDiagnostics::METHOD.to_string(), params)]
+    }
+
+    // Compile the document `path` in memory: its diagnostics
+    fn check(&mut self, path: &Path) -> Vec<lsp_types::Diagnostic> {
+        let text = self.documents[path].clone();
+        let search_path = modules::search_path(&[]);
+        let reported = match modules::load_program(path, Source::Text(text), &[], &search_path) {
+            Ok(loaded) => {
+                let mut reported = lint::lint(&loaded.program, &Levels::default());
+                let name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+                if let Err(e) = CodeGenerator::new().generate_wasm(name, &loaded.program) {
+                    reported.push(e.diagnostic());
+                }
+                self.compiled.insert(path.to_path_buf(), loaded.program);
+                reported
+            }
+            Err(e) => {
+                // best effort: the document alone, so that its own names still resolve
+                match Parser::new(Lexer::new(path, Source::Text(self.documents[path].clone())))
+                    .and_then(|mut parser| parser.parse_main_program())
+                {
+                    Ok(main_program) => {
+                        let program = Program {
+                            functions: Vec::new(),
+                            main_program,
+                            linked: Vec::new(),
+                            externs: Vec::new(),
+                        };
+                        self.compiled.insert(path.to_path_buf(), program);
+                    }
+                    Err(_) => {
+                        self.compiled.remove(path);
+                    }
+                }
+                vec![error_diagnostic(e.as_ref(), path)]
+            }
+        };
+        // the warnings of an imported file are for when it is open
+        reported
+            .iter()
+            .filter(|d| d.is_error() || same_file(&d.span.start.file_name, path))
+            .map(|d| self.lsp_diagnostic(d, path))
+            .collect()
+    }
+
+    fn lsp_diagnostic(&self, d: &Diagnostic, path: &Path) -> lsp_types::Diagnostic {
+        let pos = &d.span.start;
+        // one found in an imported file is shown at the start of the document
+        let (range, message) = if same_file(&pos.file_name, path) {
+            (self.name_range(pos), d.message.clone())
+        } else {
+            let file = pos.file_name.display();
+            (Range::default(), format!("{}:{}:{}: {}", file, pos.line, pos.col, d.message))
+        };
+        lsp_types::Diagnostic {
+            range,
+            severity: Some(match d.severity {
+                Severity::Error => DiagnosticSeverity::ERROR,
+                Severity::Warning => DiagnosticSeverity::WARNING,
+            }),
+            code: Some(NumberOrString::String(d.code.to_string())),
+            source: Some("mpl".to_string()),
+            message,
+            ..Default::default()
+        }
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let at = params.text_document_position_params;
+        let (symbol, _) = self.symbol_at(&at.text_document.uri, at.position)?;
+        let uri = Url::from_file_path(absolute(&symbol.definition.file_name)).ok()?;
+        let range = self.name_range(&symbol.definition);
+        Some(GotoDefinitionResponse::Scalar(Location { uri, range }))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let at = params.text_document_position_params;
+        let (symbol, program) = self.symbol_at(&at.text_document.uri, at.position)?;
+        let text = match symbol.kind {
+            SymbolKind::Variable => format!(
+                "```mpl\n{} {} {}\n```\nin `{}`",
+                grammar::KW_LOCAL,
+                symbol.ty,
+                symbol.name,
+                symbol.scope.as_deref().unwrap_or_default()
+            ),
+            SymbolKind::Function => {
+                let mut functions = program
+                    .functions
+                    .iter()
+                    .chain(&program.main_program.functions)
+                    .chain(&program.main_program.main);
+                let f = functions.find(|f| f.name == symbol.name)?;
+                // as written, without `pub`: the loader makes public every function of a file
+                // without any
+                let name = f.name.rsplit("::").next().unwrap_or(&f.name);
+                let signature = if f.name == grammar::KW_MAIN {
+                    format!("{}()", grammar::KW_MAIN)
+                } else if f.export {
+                    format!("{} {} {}()", grammar::KW_EXPORT, grammar::KW_FN, name)
+                } else {
+                    format!("{} {}()", grammar::KW_FN, name)
+                };
+                let mut text = format!("```mpl\n{}\n```\n`{}`", signature, symbol.ty);
+                if let Some(comment) = self.doc_comment(&f.pos.file_name, name) {
+                    text.push_str(&format!("\n\n{}", comment));
+                }
+                text
+            }
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        })
+    }
+
+    // The symbol named under the cursor: a variable of the function the cursor is in, else a
+    // function (one of the document first)
+    fn symbol_at(&self, uri: &Url, at: lsp_types::Position) -> Option<(Symbol, &Program)> {
+        let path = uri.to_file_path().ok()?;
+        let program = self.compiled.get(&path)?;
+        let line_text = self.documents.get(&path)?.lines().nth(at.line as usize)?;
+        let word = word_at(line_text, at.character as usize)?;
+        let line = at.line as usize + 1;
+        let enclosing = program
+            .functions
+            .iter()
+            .chain(&program.main_program.functions)
+            .chain(&program.main_program.main)
+            .filter(|f| same_file(&f.pos.file_name, &path) && f.pos.line <= line)
+            .max_by_key(|f| f.pos.line);
+        let index = SymbolIndex::build(program);
+        let variable = index.symbols.iter().find(|s| {
+            s.kind == SymbolKind::Variable && s.name == word && s.scope.as_ref() == enclosing.map(|f| &f.name)
+        });
+        let mangled = format!("::{}", word);
+        let mut functions = index
+            .symbols
+            .iter()
+            .filter(|s| s.kind == SymbolKind::Function && (s.name == word || s.name.ends_with(&mangled)));
+        let function = functions.clone().find(|s| same_file(&s.definition.file_name, &path));
+        let symbol = variable.or(function).or_else(|| functions.next())?;
+        Some((symbol.clone(), program))
+    }
+
+    // The `///` comment of the function `name` of `file` (the loader does not keep them)
+    fn doc_comment(&self, file: &Path, name: &str) -> Option<String> {
+        let text = self.text(file);
+        let parse = |main: bool| -> Result<Vec<Function>, ParseError> {
+            let mut parser = Parser::new(Lexer::new(file, Source::Text(text.clone())).with_trivia())?;
+            Ok(if main {
+                let program = parser.parse_main_program()?;
+                program.functions.into_iter().chain(program.main).collect()
+            } else {
+                parser.parse_library()?.functions
+            })
+        };
+        let functions = parse(true).or_else(|_| parse(false)).ok()?;
+        functions.into_iter().find(|f| f.name == name)?.doc
+    }
+
+    // Text of a file: the document if it is open, else what is on the disk
+    fn text(&self, file: &Path) -> String {
+        match self.documents.get(&absolute(file)) {
+            Some(text) => text.clone(),
+            None => fs::read_to_string(file).unwrap_or_default(),
+        }
+    }
+
+    // Range of the name starting at `pos`
+    fn name_range(&self, pos: &Position) -> Range {
+        let text = self.text(&pos.file_name);
+        let line: Vec<char> = text.lines().nth(pos.line.saturating_sub(1)).unwrap_or_default().chars().collect();
+        let start = (pos.col.saturating_sub(1)).min(line.len());
+        let mut end = start;
+        while end < line.len() && is_name_char(line[end]) {
+            end += 1;
+        }
+        // not on a name: the character there
+        let end = if start == end { (end + 1).min(line.len()) } else { end };
+        let utf16 = |chars: &[char]| chars.iter().map(|c| c.len_utf16() as u32).sum::<u32>();
+        let line_no = pos.line.saturating_sub(1) as u32;
+        Range::new(
+            lsp_types::Position::new(line_no, utf16(&line[..start])),
+            lsp_types::Position::new(line_no, utf16(&line[..end])),
+        )
+    }
+}
+
+// Diagnostic of an error of the load (reading or parsing a file, resolving the imports)
+fn error_diagnostic(e: &(dyn Error + 'static), path: &Path) -> Diagnostic {
+    if let Some(e) = e.downcast_ref::<ParseError>() {
+        e.diagnostic()
+    } else if let Some(e) = e.downcast_ref::<LexError>() {
+        e.diagnostic()
+    } else if let Some(d) = e.downcast_ref::<Diagnostic>() {
+        d.clone()
+    } else {
+        let pos = Position {
+            file_name: path.to_path_buf(),
+            line: 1,
+            col: 1,
+        };
+        // a file that cannot be read or is not a wasm library
+        Diagnostic::error(&messages::TOKEN_ERROR, messages::READ_ERROR.code, e.to_string(), &pos)
+    }
+}
+
+// The name the cursor (a UTF-16 offset in `line`) is on or right after
+fn word_at(line: &str, character: usize) -> Option<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut units = 0;
+    let mut at = chars.len();
+    for (i, c) in chars.iter().enumerate() {
+        if units >= character {
+            at = i;
+            break;
+        }
+        units += c.len_utf16();
+    }
+    let mut start = at;
+    while start > 0 && is_name_char(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = at;
+    while end < chars.len() && is_name_char(chars[end]) {
+        end += 1;
+    }
+    (start < end).then(|| chars[start..end].iter().collect())
+}
+
+fn is_name_char(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    a == b || absolute(a) == absolute(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{
+        DidChangeTextDocumentParams, DidOpenTextDocumentParams, TextDocumentContentChangeEvent, TextDocumentIdentifier,
+        TextDocumentItem, TextDocumentPositionParams, VersionedTextDocumentIdentifier,
+    };
+
+    // The uri of an open document (not on the disk: the server reads it from the editor)
+    fn document() -> Url {
+        Url::from_file_path(std::env::temp_dir().join(format!("mpl-lsp-{}.mpl", std::process::id()))).unwrap()
+    }
+
+    fn open(server: &mut Server, text: &str) -> Vec<lsp_types::Diagnostic> {
+        let params = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(document(), "mpl".to_string(), 1, text.to_string()),
+        };
+        published(server.notification(Notification::new(DidOpenTextDocument::METHOD.to_string(), params)))
+    }
+
+    fn change(server: &mut Server, text: &str) -> Vec<lsp_types::Diagnostic> {
+        let params = DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier::new(document(), 2),
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_string(),
+            }],
+        };
+        published(server.notification(Notification::new(DidChangeTextDocument::METHOD.to_string(), params)))
+    }
+
+    // The diagnostics of the one notification published for the document
+    fn published(notifications: Vec<Notification>) -> Vec<lsp_types::Diagnostic> {
+        let [notification] = <[Notification; 1]>::try_from(notifications).expect("one notification");
+        assert_eq!(notification.method, PublishDiagnostics::METHOD);
+        let params: PublishDiagnosticsParams = serde_json::from_value(notification.params).unwrap();
+        assert_eq!(params.uri, document());
+        params.diagnostics
+    }
+
+    // The answer to `method` at line and character of the document
+    fn ask(server: &mut Server, method: &str, line: u32, character: u32) -> serde_json::Value {
+        let at = TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(document()),
+            lsp_types::Position::new(line, character),
+        );
+        let response = server.request(Request::new(1.into(), method.to_string(), at));
+        assert!(response.error.is_none(), "{:?}", response.error);
+        response.result.unwrap()
+    }
+
+    const GREET: &str = "fn greet() {\n    println(\"hi\")\n}\nmain() {\n    local int i\n    greet()\n    let i = 1\n    println(to_str(i))\n}\n";
+
+    #[test]
+    fn publishes_the_errors_of_a_document_until_it_is_fixed() {
+        let mut server = Server::default();
+        let diagnostics = open(&mut server, "main() {\n    greet()\n}\n");
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        let error = &diagnostics[0];
+        assert_eq!(error.code, Some(NumberOrString::String(messages::UNKNOWN_FUNCTION.code.to_string())));
+        assert_eq!(error.severity, Some(DiagnosticSeverity::ERROR));
+        assert_eq!(error.range.start.line, 1);
+        assert_eq!(change(&mut server, GREET), Vec::new());
+    }
+
+    #[test]
+    fn goes_to_the_definition_of_a_function() {
+        let mut server = Server::default();
+        open(&mut server, GREET);
+        let location: Location = serde_json::from_value(ask(&mut server, GotoDefinition::METHOD, 5, 6)).unwrap();
+        assert_eq!(location.uri, document());
+        assert_eq!(location.range, Range::new(lsp_types::Position::new(0, 3), lsp_types::Position::new(0, 8)));
+    }
+
+    #[test]
+    fn hover_shows_the_declaration_of_a_variable() {
+        let mut server = Server::default();
+        open(&mut server, GREET);
+        let hover: Hover = serde_json::from_value(ask(&mut server, HoverRequest::METHOD, 6, 8)).unwrap();
+        let HoverContents::Markup(markup) = hover.contents else { panic!("markdown") };
+        assert_eq!(markup.value, "```mpl\nlocal int i\n```\nin `main`");
+    }
+}
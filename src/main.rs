@@ -5,29 +5,40 @@
 // Main entry point for MPL CLI
 // All comments are in English per requirement.
 
-mod codegen;
-mod grammar;
-mod lexer;
-mod parser;
-mod runner;
-
-use clap::{Arg, ArgAction, ArgGroup, Command};
-use codegen::CodeGenerator;
-use lexer::Lexer;
-use parser::{Parser, Program};
+use clap::{Arg, ArgAction, Command};
+use mpl::codegen::{CodeGenerator, CompileOptions, MemoryLimits};
+use mpl::coverage::Coverage;
+use mpl::csource;
+use mpl::debugger::{Breakpoint, Debugger};
+use mpl::doc::{self, DocFormat, FileDoc};
+use mpl::formatter;
+use mpl::ir;
+use mpl::jsglue;
+use mpl::jssource;
+use mpl::lint::{self, Levels, Rule};
+use mpl::lexer::Source;
+use mpl::manifest::{Manifest, Target};
+use mpl::messages;
+use mpl::meta::{self, BuildInfo};
+use mpl::modules::{self, LoadedProgram};
+use mpl::optimize;
+use mpl::parser::Program;
+use mpl::profiler::Profiler;
+use mpl::runner;
+use mpl::stats::{self, ModuleStats, Timings};
+use mpl::symbols::SymbolIndex;
+use mpl::trace::Tracer;
+use rayon::prelude::*;
 use std::{
     fs,
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
     process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
 };
 use wasmprinter::{Config, PrintFmtWrite};
 
-fn resolve_rel(base_file: &Path, rel: &str) -> PathBuf {
-    // Resolve a relative path against the base file directory.
-    let base_dir = base_file.parent().unwrap_or_else(|| Path::new("."));
-    base_dir.join(rel)
-}
-
 fn file_stem_string(p: &Path) -> String {
     // Return file stem as String; fallback to "main" if none.
     match p.file_stem() {
@@ -36,217 +47,1659 @@ fn file_stem_string(p: &Path) -> String {
     }
 }
 
+// INPUT "-" reads the source from stdin; "-" as -o/-a/--dep-file output writes to stdout.
+const STDIO: &str = "-";
+
+fn is_stdio(p: &Path) -> bool {
+    p == Path::new(STDIO)
+}
+
+fn derived_base(src_file: &Path) -> PathBuf {
+    // Path the default output names derive from: stdin.* when the source is stdin.
+    if is_stdio(src_file) {
+        PathBuf::from("stdin.mpl")
+    } else {
+        src_file.to_path_buf()
+    }
+}
+
+fn write_output(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    // Write a compiler output to its file, or to stdout for "-".
+    if is_stdio(path) {
+        let mut out = io::stdout().lock();
+        out.write_all(bytes)?;
+        out.flush()
+    } else {
+        fs::write(path, bytes)
+    }
+}
+
+fn include_dirs(matches: &clap::ArgMatches) -> Vec<PathBuf> {
+    // The -I directories, in command line order.
+    matches
+        .get_many::<String>("include")
+        .map(|dirs| dirs.map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+fn load_program(
+    src_file: &Path,
+    lib_paths: &[PathBuf],
+    include_dirs: &[PathBuf],
+    library: bool,
+) -> Result<LoadedProgram, Box<dyn std::error::Error>> {
+    // Read the main program (stdin for "-"), or a library for --lib, then load it with everything it imports.
+    // The sources are read as they are lexed, never whole in memory.
+    let search_path = modules::search_path(include_dirs);
+    let (src_file, src_text) = if is_stdio(src_file) {
+        // imports are then relative to the working directory
+        (Path::new("<stdin>"), Source::Reader(Box::new(io::stdin().lock())))
+    } else {
+        let src_text = Source::open(src_file)
+            .map_err(|e| format!("cannot read '{}': {}", src_file.display(), e))?;
+        (src_file, src_text)
+    };
+    if library {
+        modules::load_library(src_file, src_text, lib_paths, &search_path)
+    } else {
+        modules::load_program(src_file, src_text, lib_paths, &search_path)
+    }
+}
+
+fn link_path(src_file: &Path, loaded: &LoadedProgram) -> Vec<PathBuf> {
+    // Where the runner finds the wasm libraries of a program: the directory of the program,
+    // then those of the libraries found elsewhere (-I, MPLPATH).
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let wasm_libs = loaded.libraries.iter().filter(|p| p.extension().is_some_and(|e| e == "wasm"));
+    for file in std::iter::once(src_file).chain(wasm_libs.map(PathBuf::as_path)) {
+        let dir = match file.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+fn input_arg() -> Arg {
+    // The program and the libraries linked with it, for compile, run, check and lint.
+    Arg::new("input")
+        .value_name("INPUT")
+        .help("<source.mpl> [<library.mpl>...]: the program (- reads it from stdin), then libraries linked with it")
+        .num_args(1..)
+        .required(true)
+}
+
+fn inputs(matches: &clap::ArgMatches) -> (PathBuf, Vec<PathBuf>) {
+    // The program, then the extra libraries.
+    let mut inputs = matches.get_many::<String>("input").unwrap().map(PathBuf::from);
+    let src_file = inputs.next().unwrap();
+    (src_file, inputs.collect())
+}
+
+fn include_arg() -> Arg {
+    // -I, for compile, run, check, lint and `mpl test`
+    Arg::new("include")
+        .short('I')
+        .value_name("DIR")
+        .help("Also look for imports in DIR (repeatable; searched before the directories of MPLPATH)")
+        .action(ArgAction::Append)
+}
+
+fn lint_args() -> Vec<Arg> {
+    // --allow and --deny, for compile, run, check and lint.
+    let rules = || Rule::ALL.map(Rule::name);
+    vec![
+        Arg::new("allow")
+            .long("allow")
+            .value_name("RULE")
+            .help("Do not report the warnings of RULE (repeatable)")
+            .value_parser(rules())
+            .action(ArgAction::Append),
+        Arg::new("deny")
+            .long("deny")
+            .value_name("RULE")
+            .help("Make the warnings of RULE errors; `warnings` makes them all errors (repeatable)")
+            .value_parser(["warnings"].into_iter().chain(rules()).collect::<Vec<_>>())
+            .action(ArgAction::Append),
+    ]
+}
+
+fn lint_levels(matches: &clap::ArgMatches) -> Result<Levels, Box<dyn std::error::Error>> {
+    // Warning levels taken from --allow and --deny.
+    let mut levels = Levels::default();
+    for name in matches.get_many::<String>("allow").into_iter().flatten() {
+        levels.allow(name.parse()?);
+    }
+    for name in matches.get_many::<String>("deny").into_iter().flatten() {
+        if name == "warnings" {
+            levels.deny_warnings();
+        } else {
+            levels.deny(name.parse()?);
+        }
+    }
+    Ok(levels)
+}
+
+fn run_args() -> Vec<Arg> {
+    // Options of a run, shared by run, run-wasm and `mpl test`.
+    vec![
+        Arg::new("seed")
+            .long("seed")
+            .value_name("N")
+            .help("Seed of random()/random_int(): the same seed gives the same run")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("engine")
+            .long("engine")
+            .value_name("ENGINE")
+            .help("Engine running the program: wasmi (default, interpreter) or wasmtime (JIT, needs the \"wasmtime\" feature)")
+            .value_parser(["wasmi", "wasmtime"]),
+        Arg::new("fuel")
+            .long("fuel")
+            .value_name("N")
+            .help("Stop the program after about N executed instructions")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("max-call-depth")
+            .long("max-call-depth")
+            .value_name("N")
+            .help(format!(
+                "Stop the program when more than N calls are in progress (a runaway recursion); default {}, on both engines",
+                runner::DEFAULT_CALL_DEPTH
+            ))
+            .value_parser(clap::value_parser!(u32).range(1..)),
+        Arg::new("timeout")
+            .long("timeout")
+            .value_name("SECONDS")
+            .help("Stop the program after SECONDS of running")
+            .value_parser(|s: &str| runner::parse_timeout(s).map_err(|e| e.to_string())),
+        Arg::new("max-memory")
+            .long("max-memory")
+            .value_name("SIZE")
+            .help("Cap the program memory when running: bytes, or with a K, M or G suffix")
+            .value_parser(|s: &str| runner::parse_memory_size(s).map_err(|e| e.to_string())),
+    ]
+}
+
+fn debug_args() -> Vec<Arg> {
+    // The instrumented runs of mpl run: the interactive debugger, the statement trace.
+    vec![
+        Arg::new("debug")
+            .long("debug")
+            .help("Run under the debugger: stop before statements, show the locals (commands on stdin, help lists them)")
+            .action(ArgAction::SetTrue),
+        Arg::new("break")
+            .long("break")
+            .value_name("[FILE:]LINE")
+            .help("With --debug, stop at this line instead of the first statement (repeatable)")
+            .action(ArgAction::Append)
+            .value_parser(|s: &str| s.parse::<Breakpoint>())
+            .requires("debug"),
+        Arg::new("trace")
+            .long("trace")
+            .help("Print each statement run, with its file and line, and the values assigned (on stderr)")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("debug"),
+        Arg::new("profile-functions")
+            .long("profile-functions")
+            .help("Print on stderr, after the run, the calls and the time spent in each function (the program is compiled without optimization: -O is ignored)")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["debug", "trace"]),
+        Arg::new("coverage")
+            .long("coverage")
+            .value_name("FILE")
+            .help("After the run, print on stderr the lines that had a statement run, or write them to FILE as an lcov tracefile (the program is compiled without optimization: -O is ignored)")
+            .num_args(0..=1)
+            .conflicts_with_all(["debug", "trace", "profile-functions"]),
+    ]
+}
+
+fn output_args() -> Vec<Arg> {
+    // What becomes of the output of a run, for run and run-wasm.
+    vec![
+        Arg::new("flush")
+            .long("flush")
+            .value_name("MODE")
+            .help("When program output reaches stdout: always, line (default) or block")
+            .value_parser(["always", "line", "block"]),
+        Arg::new("dump-memory")
+            .long("dump-memory")
+            .value_name("RANGE|FILE")
+            .help("After running, print heap_ptr, the heap blocks and a hex dump of linear memory (of RANGE: START..END or START+LEN) on stderr, or write them to FILE")
+            // --dump-memory, --dump-memory 0x400+256 or --dump-memory mem.txt
+            .num_args(0..=1),
+        Arg::new("profile")
+            .long("profile")
+            .help("After running, print on stderr the wall time, the fuel consumed, the bytes allocated and the calls of each host function")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn program_args_arg() -> Arg {
+    // Everything after `--` is handed to the program (arg_count() / arg(i)).
+    Arg::new("args")
+        .value_name("ARGS")
+        .help("Arguments passed to the program")
+        .num_args(0..)
+        .last(true)
+}
+
+fn report_args() -> Vec<Arg> {
+    // --timings and --stats, for compile, run and run-wasm.
+    vec![
+        Arg::new("timings")
+            .long("timings")
+            .help("Print on stderr the time spent lexing, parsing, generating the code and (run) instantiating and running")
+            .action(ArgAction::SetTrue),
+        Arg::new("stats")
+            .long("stats")
+            .help("Print on stderr what the wasm contains: size, functions, instructions, data bytes")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn opt_level_arg() -> Arg {
+    // -O, for compile and run.
+    Arg::new("opt-level")
+        .short('O')
+        .value_name("LEVEL")
+        .help("Optimization level: 0 none, 1 inlining of small functions, constant folding and dead code, 2 also propagation, common subexpressions and unused functions")
+        .value_parser(["0", "1", "2"])
+        .default_value("0")
+}
+
+fn wasm_features_arg() -> Arg {
+    // --wasm-features, for compile and run.
+    Arg::new("wasm-features")
+        .long("wasm-features")
+        .value_name("LIST")
+        .help("Wasm proposals the module may use: bulk-memory (memory.copy, memory.fill), or mvp for the hosts lacking it")
+        .default_value("bulk-memory")
+}
+
+fn passive_data_arg() -> Arg {
+    // --passive-data, for compile and run.
+    Arg::new("passive-data")
+        .long("passive-data")
+        .help("Put the texts of 1 KiB or more in passive data segments, written to memory the first time they are used (programs only, needs bulk-memory)")
+        .action(ArgAction::SetTrue)
+}
+
+fn float_format_arg() -> Arg {
+    // --float-format, for compile and run.
+    Arg::new("float-format")
+        .long("float-format")
+        .value_name("FORMAT")
+        .help("How to_str writes a float: digits (at most 15 significant digits, 0.1 + 0.2 is 0.3) or shortest (the shortest text reading back as the same float, 0.30000000000000004)")
+        .value_parser(["digits", "shortest"])
+        .default_value("digits")
+}
+
+fn memory_args() -> Vec<Arg> {
+    // Memory declared by the module, for compile and run.
+    vec![
+        Arg::new("memory-min")
+            .long("memory-min")
+            .value_name("PAGES")
+            .help("Initial memory of the module in 64 KiB pages (default 1); the constant data must fit")
+            .value_parser(clap::value_parser!(u32).range(1..=65536)),
+        Arg::new("memory-max")
+            .long("memory-max")
+            .value_name("PAGES")
+            .help("Maximum memory of the module in 64 KiB pages (default: no maximum)")
+            .value_parser(clap::value_parser!(u32).range(1..=65536)),
+    ]
+}
+
+fn lib_arg() -> Arg {
+    // --lib, for compile and check.
+    Arg::new("lib")
+        .long("lib")
+        .help("A library (no main), compiled to a wasm module exporting its public functions, linked by the programs that import it")
+        .action(ArgAction::SetTrue)
+}
+
+fn source_files(src_file: &Path, loaded: &LoadedProgram) -> Vec<PathBuf> {
+    // Every source file a compilation reads: the main file and all the libraries.
+    let mut files = Vec::new();
+    if !is_stdio(src_file) {
+        files.push(src_file.to_path_buf());
+    }
+    files.extend(loaded.libraries.iter().cloned());
+    files
+}
+
+fn compile_options(matches: &clap::ArgMatches) -> Result<CompileOptions, Box<dyn std::error::Error>> {
+    // -O, for compile and run.
+    let options = CompileOptions {
+        opt_level: matches.get_one::<String>("opt-level").unwrap().parse()?,
+        features: matches.get_one::<String>("wasm-features").unwrap().parse()?,
+        passive_data: matches.get_flag("passive-data"),
+        float_format: matches.get_one::<String>("float-format").unwrap().parse()?,
+    };
+    options.check()?;
+    Ok(options)
+}
+
+fn build_info(src_file: &Path, loaded: &LoadedProgram, memory: MemoryLimits, strip: bool) -> BuildInfo {
+    // The "mpl.meta" section of a compiled module: the sources read (hashed as they were
+    // lexed, not read again) and the options used.
+    let mut info = BuildInfo::new();
+    let skip = usize::from(is_stdio(src_file));
+    for (source, hash) in loaded.hashes.iter().skip(skip) {
+        info = info.source(source, *hash);
+    }
+    info.option("memory-min", memory.min_pages)
+        .option("memory-max", memory.max_pages.map_or("none".to_string(), |m| m.to_string()))
+        .option("strip", strip)
+}
+
+fn embedded_sources(sources: &[PathBuf]) -> io::Result<Vec<(PathBuf, String)>> {
+    // --embed-source: the text of every MPL source (not the wasm libraries).
+    sources
+        .iter()
+        .filter(|p| p.extension().is_none_or(|e| e != "wasm"))
+        .map(|p| Ok((p.clone(), fs::read_to_string(p)?)))
+        .collect()
+}
+
+// Numbers the temporary files of wasm_opt: --out-dir compiles several files at once
+static WASM_OPT_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn wasm_opt(wasm: &[u8], log: &mut dyn Write) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // --optimize: run Binaryen's wasm-opt ($WASM_OPT, else wasm-opt from PATH) on the module,
+    // keeping its names and custom sections, and report the size change to `log`.
+    let program = std::env::var_os("WASM_OPT").unwrap_or_else(|| "wasm-opt".into());
+    let dir = std::env::temp_dir();
+    let run = WASM_OPT_RUNS.fetch_add(1, Ordering::Relaxed);
+    let input = dir.join(format!("mpl-{}-{}-in.wasm", process::id(), run));
+    let output = dir.join(format!("mpl-{}-{}-out.wasm", process::id(), run));
+    fs::write(&input, wasm)?;
+    let status = process::Command::new(&program)
+        .arg(&input)
+        .args(["-O", "-g", "--enable-multivalue", "-o"])
+        .arg(&output)
+        .status();
+    let optimized = match status {
+        Ok(s) if s.success() => fs::read(&output).map_err(|e| e.to_string()),
+        Ok(s) => Err(format!("{} failed ({})", program.to_string_lossy(), s)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(format!(
+            "--optimize needs Binaryen's wasm-opt: '{}' was not found (install binaryen, or set WASM_OPT)",
+            program.to_string_lossy()
+        )),
+        Err(e) => Err(format!("cannot run {}: {}", program.to_string_lossy(), e)),
+    };
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&output);
+    let optimized = optimized?;
+    let delta = optimized.len() as i64 - wasm.len() as i64;
+    writeln!(
+        log,
+        "wasm-opt: {} -> {} bytes ({:+}, {:+.1}%)",
+        wasm.len(),
+        optimized.len(),
+        delta,
+        delta as f64 * 100.0 / wasm.len() as f64
+    )?;
+    Ok(optimized)
+}
+
+fn check_deterministic(first: &[u8], second: &[u8]) -> Result<(), String> {
+    // --verify-deterministic: the two compilations of the same sources must be the same bytes.
+    let (h1, h2) = (meta::hash(first), meta::hash(second));
+    if h1 != h2 {
+        let at = first.iter().zip(second).position(|(a, b)| a != b).unwrap_or(first.len().min(second.len()));
+        return Err(format!(
+            "the build is not deterministic: fnv1a64={:016x} ({} bytes) then fnv1a64={:016x} ({} bytes), first difference at byte {:#x}",
+            h1,
+            first.len(),
+            h2,
+            second.len(),
+            at
+        ));
+    }
+    eprintln!("deterministic: two compilations gave {} bytes, fnv1a64={:016x}", first.len(), h1);
+    Ok(())
+}
+
+fn print_info(wasm_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl info`: the build metadata of a module compiled by mpl.
+    let wasm = fs::read(wasm_path).map_err(|e| format!("cannot read '{}': {}", wasm_path.display(), e))?;
+    match meta::read(&wasm).map_err(|e| format!("{}: {}", wasm_path.display(), e))? {
+        Some(text) => {
+            print!("{}", text);
+            Ok(())
+        }
+        None => Err(format!(
+            "'{}' has no {} section (it was not compiled by mpl, or by an older version)",
+            wasm_path.display(),
+            meta::META_SECTION
+        )
+        .into()),
+    }
+}
+
+fn document(path: &Path, format: DocFormat) -> Result<String, Box<dyn std::error::Error>> {
+    // `mpl doc`: the reference of a project (its mpl.toml), or of a program, and of what it imports.
+    let (title, entry, libraries, import_paths) = if path.extension().is_some_and(|e| e == "mpl") {
+        (file_stem_string(path), path.to_path_buf(), Vec::new(), Vec::new())
+    } else {
+        let manifest = Manifest::load(path)?;
+        (manifest.name, manifest.entry, manifest.libraries, manifest.import_paths)
+    };
+    let loaded = load_program(&entry, &libraries, &import_paths, false)?;
+    let mut files = Vec::new();
+    for (i, file) in source_files(&entry, &loaded).iter().enumerate() {
+        // a wasm library has no source to document
+        if file.extension().is_some_and(|e| e == "wasm") {
+            continue;
+        }
+        files.push(FileDoc::read(file, i == 0)?);
+    }
+    let base = entry.parent().unwrap_or_else(|| Path::new(""));
+    Ok(doc::render(&title, &files, base, format))
+}
+
+fn format_files(files: &[PathBuf], check: bool) -> Result<bool, Box<dyn std::error::Error>> {
+    // `mpl fmt`: rewrite each file in the canonical layout, or with `check` only report the
+    // first line that would change. false if a file is not formatted (check).
+    let mut formatted = true;
+    for file in files {
+        let text = if is_stdio(file) {
+            io::read_to_string(io::stdin())?
+        } else {
+            fs::read_to_string(file).map_err(|e| format!("cannot read '{}': {}", file.display(), e))?
+        };
+        let output = formatter::format_source(file, &text)?;
+        if check {
+            if output != text {
+                let line = text.lines().zip(output.lines()).take_while(|(a, b)| a == b).count() + 1;
+                println!("{}:{}: not formatted", file.display(), line);
+                formatted = false;
+            }
+        } else if is_stdio(file) {
+            io::stdout().write_all(output.as_bytes())?;
+        } else if output != text {
+            fs::write(file, output)?;
+        }
+    }
+    Ok(formatted)
+}
+
+fn lint_program(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl lint`: the warnings of a program and of what it imports.
+    let (src_file, lib_paths) = inputs(matches);
+    let loaded = load_program(&src_file, &lib_paths, &include_dirs(matches), false)?;
+    report_warnings(&loaded.program, &lint_levels(matches)?, &mut io::stderr())
+}
+
+fn report_warnings(
+    program: &Program,
+    levels: &Levels,
+    log: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Print the warnings of a program on `log` (stderr); an error if some of them are denied.
+    let warnings = lint::lint(program, levels);
+    if warnings.is_empty() {
+        return Ok(());
+    }
+    for w in &warnings {
+        write!(log, "{}", w)?;
+    }
+    writeln!(log, "{} warning(s)", warnings.len())?;
+    match warnings.iter().filter(|w| w.is_error()).count() {
+        0 => Ok(()),
+        denied => Err(format!("{} warning(s) denied (--deny)", denied).into()),
+    }
+}
+
+fn serve_lsp() -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl lsp`: the language server, if this mpl has it.
+    #[cfg(feature = "lsp")]
+    return mpl::lsp::serve();
+    #[cfg(not(feature = "lsp"))]
+    Err("this mpl was built without the language server (cargo build --features lsp)".into())
+}
+
+fn make_escape(p: &Path) -> String {
+    // A path as written in a Makefile rule.
+    p.to_string_lossy()
+        .replace('$', "$$")
+        .replace('#', "\\#")
+        .replace(' ', "\\ ")
+}
+
+fn dep_file(targets: &[PathBuf], sources: &[PathBuf]) -> String {
+    // Makefile rule `targets: sources`, plus an empty rule per source so that
+    // make does not fail when a library is deleted.
+    let list = |paths: &[PathBuf]| paths.iter().map(|p| make_escape(p)).collect::<Vec<_>>().join(" ");
+    let mut out = format!("{}: {}\n", list(targets), list(sources));
+    for source in sources {
+        out.push_str(&format!("\n{}:\n", make_escape(source)));
+    }
+    out
+}
+
+fn write_js(wasm_out: &Path, memory: MemoryLimits, page_title: Option<&str>) -> io::Result<()> {
+    // Browser loader <wasm>.js next to the wasm, and with a title the page <wasm>.html using it.
+    let wasm_file = wasm_out.file_name().unwrap_or_default().to_string_lossy();
+    let js_out = wasm_out.with_extension("js");
+    fs::write(&js_out, jsglue::loader_js(&wasm_file, memory))?;
+    if let Some(title) = page_title {
+        let js_file = js_out.file_name().unwrap_or_default().to_string_lossy();
+        fs::write(wasm_out.with_extension("html"), jsglue::page_html(title, &js_file))?;
+    }
+    Ok(())
+}
+
+fn write_node_package(dir: &Path, wasm_out: &Path, wasm: &[u8], memory: MemoryLimits) -> io::Result<()> {
+    // Node.js package in dir: its own copy of the wasm and the JavaScript around it.
+    fs::create_dir_all(dir)?;
+    let wasm_file = wasm_out.file_name().unwrap_or_default().to_string_lossy();
+    fs::write(dir.join(&*wasm_file), wasm)?;
+    for (name, contents) in jsglue::node_package(&file_stem_string(wasm_out), &wasm_file, memory) {
+        fs::write(dir.join(name), contents)?;
+    }
+    Ok(())
+}
+
+fn build_project(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl build`: compile the project described by an mpl.toml into its output directory.
+    let manifest = Manifest::load(path)?;
+    let loaded = load_program(&manifest.entry, &manifest.libraries, &manifest.import_paths, false)?;
+    report_warnings(&loaded.program, &Levels::default(), &mut io::stderr())?;
+    let info = build_info(&manifest.entry, &loaded, manifest.memory, manifest.strip)
+        .option("opt-level", manifest.options.opt_level)
+        .option("wasm-features", manifest.options.features)
+        .option("passive-data", manifest.options.passive_data)
+        .option("target", manifest.target);
+    let mut generator = CodeGenerator::new()
+        .with_memory(manifest.memory)
+        .with_strip(manifest.strip)
+        .with_options(manifest.options)
+        .with_meta(&info);
+    let wasm = generator.generate_wasm(manifest.name.clone(), &loaded.program)?;
+
+    fs::create_dir_all(&manifest.out_dir)?;
+    let wasm_out = manifest.out_dir.join(format!("{}.wasm", manifest.name));
+    match manifest.target {
+        Target::Runner => fs::write(&wasm_out, &wasm)?,
+        Target::Js => {
+            fs::write(&wasm_out, &wasm)?;
+            write_js(&wasm_out, manifest.memory, Some(&manifest.name))?;
+        }
+        // the output directory is the package
+        Target::Node => write_node_package(&manifest.out_dir, &wasm_out, &wasm, manifest.memory)?,
+    }
+    Ok(())
+}
+
 fn build_cli() -> Command {
     Command::new("mpl")
         .about("MPL compiler/runner")
         .version("0.1.0")
-        // Clear, English usage with mutually exclusive modes (help is auto by clap).
         .override_usage(
-            "mpl (-c | -r | -rw) [OPTIONS] <INPUT>\n\
-             mpl -c  <source.mpl> [-o <wasm_name>] [-a [wat_name]]\n\
-             mpl -r  <source.mpl>\n\
-             mpl -rw <wasm_name>",
+            "mpl compile <source.mpl> [<library.mpl>...] [-o <wasm_name>] [-a [wat_name]]\n\
+             mpl compile <source.mpl>... --out-dir <dir>\n\
+             mpl run <source.mpl> [<library.mpl>...] [-- <args>...]\n\
+             mpl run-wasm <wasm_name> [-- <args>...]\n\
+             mpl check <source.mpl> [<library.mpl>...]\n\
+             mpl test [DIR]\n\
+             mpl build [PROJECT]\n\
+             mpl info <wasm_name>\n\
+             mpl fmt [--check] <source.mpl>...\n\
+             mpl doc [PROJECT] [--format markdown|html] [-o <file>]\n\
+             mpl lint [--deny warnings] <source.mpl> [<library.mpl>...]\n\
+             mpl lsp",
         )
-        // Modes (mutually exclusive). We also add explicit conflicts for clarity.
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
-            Arg::new("compile")
-                .short('c')
-                .long("compile")
-                .help("Compile the source to WebAssembly (WASM); optional WAT via -a")
-                .action(ArgAction::SetTrue)
-                .conflicts_with_all(["run", "runwasm"]),
+            Arg::new("lang")
+                .long("lang")
+                .value_name("LANG")
+                .help("Language of the error messages: en (default) or fr; error codes do not change")
+                .value_parser(["en", "fr"])
+                .default_value("en")
+                .global(true),
         )
-        .arg(
-            Arg::new("run")
-                .short('r')
-                .long("run")
-                .help("Compile the source and run it without writing files to disk")
-                .action(ArgAction::SetTrue)
-                .conflicts_with_all(["compile", "runwasm"]),
+        // The modes of the first versions stay as hidden aliases: mpl -c, -r, --rw
+        .subcommand(
+            Command::new("compile")
+                .about("Compile a program to WebAssembly (WASM), and optionally its WAT")
+                .short_flag_alias('c')
+                .long_flag_alias("compile")
+                .arg(input_arg())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("WASM_OUT")
+                        .help("Force the output name for the WebAssembly file; - for stdout"),
+                )
+                .arg(
+                    Arg::new("wat")
+                        .short('a')
+                        .long("wat")
+                        .value_name("WAT_OUT")
+                        .help("Also produce a WAT file; if no value is provided, defaults to <source>.wat; - for stdout")
+                        // Allow -a with optional value: -a or -a out.wat
+                        .num_args(0..=1),
+                )
+                .arg(
+                    Arg::new("emit")
+                        .long("emit")
+                        .value_name("KIND")
+                        .help("What is produced (comma separated): wasm, symbols (<source>.symbols.json), ast-json (<source>.ast.json), ir (<source>.ir, the typed IR of the numeric expressions, after -O), c (<source>.c, after -O), js-src (<source>.mjs, after -O)")
+                        .value_delimiter(',')
+                        .value_parser(["wasm", "symbols", "ast-json", "ir", "c", "js-src"])
+                        .default_value("wasm"),
+                )
+                .arg(include_arg())
+                .args(lint_args())
+                .arg(
+                    Arg::new("dep-file")
+                        .long("dep-file")
+                        .value_name("FILE")
+                        .help("Also write a Makefile-style dependency file listing every source file read; - for stdout"),
+                )
+                .arg(lib_arg().conflicts_with_all(["emit-js", "emit-node"]))
+                .arg(
+                    Arg::new("out-dir")
+                        .long("out-dir")
+                        .value_name("DIR")
+                        .help("Compile each INPUT on its own (a library if it has no main; patterns like src/*.mpl are expanded) to DIR/<source>.wasm")
+                        .conflicts_with_all(["output", "dep-file"]),
+                )
+                .arg(
+                    Arg::new("strip")
+                        .long("strip")
+                        .help("Leave the name section (function, local and data names) out of the wasm, for release builds")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(opt_level_arg())
+                .arg(wasm_features_arg())
+                .arg(passive_data_arg())
+                .arg(float_format_arg())
+                .arg(
+                    Arg::new("optimize")
+                        .long("optimize")
+                        .help("Run Binaryen's wasm-opt on the wasm ($WASM_OPT, else wasm-opt from PATH) and report the size change")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(report_args())
+                .arg(
+                    Arg::new("verify-deterministic")
+                        .long("verify-deterministic")
+                        .help("Compile the sources a second time and fail if the two wasm differ")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("embed-source")
+                        .long("embed-source")
+                        .help("Store the sources in the wasm so that runtime errors of mpl run-wasm show where they happened")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("lib"),
+                )
+                .arg(
+                    Arg::new("debug")
+                        .long("debug")
+                        .help("Also write a source map <wasm_name>.map, for the browser devtools to step through the MPL lines")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("optimize"),
+                )
+                .arg(
+                    Arg::new("emit-js")
+                        .long("emit-js")
+                        .help("Also write a JavaScript loader <wasm_name>.js to run the program in a browser")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("html")
+                        .long("html")
+                        .help("With --emit-js, also write a page <wasm_name>.html showing the program output")
+                        .action(ArgAction::SetTrue)
+                        .requires("emit-js"),
+                )
+                .arg(
+                    Arg::new("emit-node")
+                        .long("emit-node")
+                        .value_name("DIR")
+                        .help("Also write a Node.js package (wasm, index.js with run(), cli.js, package.json) to DIR; defaults to <wasm_name>-node")
+                        .num_args(0..=1),
+                )
+                .args(memory_args()),
         )
-        .arg(
-            Arg::new("runwasm")
-                .long("rw")
-                .value_name("WASM")
-                .help("Run an existing WebAssembly binary file")
-                .num_args(1)
-                .conflicts_with_all(["compile", "run"]),
+        .subcommand(
+            Command::new("run")
+                .about("Compile a program and run it without writing files to disk")
+                .short_flag_alias('r')
+                .long_flag_alias("run")
+                .arg(input_arg())
+                .arg(include_arg())
+                .args(lint_args())
+                .arg(opt_level_arg())
+                .arg(wasm_features_arg())
+                .arg(passive_data_arg())
+                .arg(float_format_arg())
+                .args(memory_args())
+                .args(run_args())
+                .args(debug_args())
+                .args(output_args())
+                .args(report_args())
+                .arg(program_args_arg()),
         )
-        // Options
-        .arg(
-            Arg::new("output")
-                .short('o')
-                .long("output")
-                .value_name("WASM_OUT")
-                .help("Force the output name for the WebAssembly file (used with -c)"),
+        .subcommand(
+            Command::new("run-wasm")
+                .about("Run an existing WebAssembly binary file")
+                .long_flag_alias("rw")
+                .arg(
+                    Arg::new("wasm")
+                        .value_name("WASM")
+                        .help("A wasm compiled by mpl (mpl compile or mpl build), or its WAT (.wat)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("invoke")
+                        .long("invoke")
+                        .value_names(["FUNCTION", "ARGS"])
+                        .help("Call the exported FUNCTION instead of main, with ARGS parsed by the types of its parameters, and print what it returns")
+                        .num_args(1..)
+                        .allow_negative_numbers(true),
+                )
+                .args(run_args())
+                .args(output_args())
+                .args(report_args())
+                .arg(program_args_arg()),
         )
-        .arg(
-            Arg::new("wat")
-                .short('a')
-                .long("wat")
-                .value_name("WAT_OUT")
-                .help("Also produce a WAT file; if no value is provided, defaults to <source>.wat")
-                // Allow -a with optional value: -a or -a out.wat
-                .num_args(0..=1),
+        .subcommand(
+            Command::new("check")
+                .about("Compile a program in memory and report its errors and warnings, writing nothing")
+                .arg(input_arg())
+                .arg(include_arg())
+                .args(lint_args())
+                .arg(lib_arg()),
         )
-        // Positional that may be required depending on the mode.
-        .arg(
-            Arg::new("input")
-                .value_name("INPUT")
-                .help("Input file: <source.mpl> for -c/-r; omitted for -rw")
-                .required(false),
+        .subcommand(
+            Command::new("test")
+                .about("Compile and run every <name>.mpl of DIR that has a <name>.expected file, and compare the output")
+                .arg(
+                    Arg::new("dir")
+                        .value_name("DIR")
+                        .help("Directory searched (with its subdirectories) for tests")
+                        .default_value("."),
+                )
+                .arg(include_arg())
+                .args(run_args()),
+        )
+        .subcommand(
+            Command::new("build")
+                .about("Build the project described by an mpl.toml (entry point, import paths, output directory, target)")
+                .arg(
+                    Arg::new("project")
+                        .value_name("PROJECT")
+                        .help("The mpl.toml, or the directory containing it")
+                        .default_value("."),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Print how a wasm was built: compiler version, sources and their hashes, options")
+                .arg(
+                    Arg::new("wasm")
+                        .value_name("WASM")
+                        .help("A wasm compiled by mpl (mpl compile or mpl build), or its WAT (.wat)")
+                        .required(true),
+                ),
         )
-        // Require that exactly one mode is chosen among compile/run/runwasm.
-        // ArgGroup(required) enforces "at least one"; conflicts enforce "only one".
-        .group(
-            ArgGroup::new("mode")
-                .args(["compile", "run", "runwasm"])
-                .required(true),
+        .subcommand(
+            Command::new("fmt")
+                .about("Rewrite source files in the canonical layout (indentation, line breaks, spacing)")
+                .arg(
+                    Arg::new("files")
+                        .value_name("FILE")
+                        .help("Source files to format; - reads stdin and writes the result to stdout")
+                        .num_args(1..)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("check")
+                        .long("check")
+                        .help("Write nothing: list the files that are not formatted, and fail if there are any")
+                        .action(ArgAction::SetTrue),
+                ),
         )
+        .subcommand(
+            Command::new("doc")
+                .about("Write the reference of a project from the /// comments above its functions")
+                .arg(
+                    Arg::new("project")
+                        .value_name("PROJECT")
+                        .help("The mpl.toml, the directory containing it, or a program <source.mpl>")
+                        .default_value("."),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Markdown or an HTML page")
+                        .value_parser(["markdown", "html"])
+                        .default_value("markdown"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the reference (default: stdout)"),
+                ),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about("Warn about code that compiles but is probably wrong (unused, overwritten, truncated)")
+                .arg(input_arg())
+                .arg(include_arg())
+                .args(lint_args()),
+        )
+        .subcommand(Command::new("lsp").about(
+            "Run the language server on stdin/stdout for editors: diagnostics, go to definition, hover (needs the \"lsp\" feature)",
+        ))
         .after_help(
             "EXAMPLES:
-  mpl -c main.mpl                 Compile to main.wasm
-  mpl -c main.mpl -o out.wasm     Compile to out.wasm
-  mpl -c main.mpl -a              Also emit main.wat
-  mpl -c main.mpl -a dump.wat     Also emit dump.wat
-  mpl -r main.mpl                 Compile in-memory and run (no files written)
-  mpl -rw program.wasm            Run an existing WASM binary
-
-RULES:
-  -c, -r, -rw are mutually exclusive (pick exactly one).",
+  mpl compile main.mpl            Compile to main.wasm
+  mpl compile main.mpl -o out.wasm
+                                  Compile to out.wasm
+  mpl compile main.mpl -a         Also emit main.wat
+  mpl compile main.mpl -a dump.wat
+                                  Also emit dump.wat
+  mpl compile main.mpl l1.mpl l2.mpl
+                                  Link l1.mpl and l2.mpl as libraries (no import needed)
+  mpl compile main.wat -o main.wasm
+                                  Assemble main.wat (e.g. the WAT of -a, edited by hand)
+  mpl compile main.mpl --emit=symbols
+                                  Write the symbol index main.symbols.json (no wasm)
+  mpl compile main.mpl --emit=wasm,ast-json
+                                  Also write the parsed program main.ast.json
+  mpl compile main.mpl -O 2 --emit=ir
+                                  Write main.ir, the numeric expressions as typed IR, after the optimizations
+  mpl compile main.mpl --emit=c && cc -O2 -fwrapv main.c -lm
+                                  Write main.c, the program in C, and build it
+  mpl compile main.mpl --emit=js-src && node main.mjs
+                                  Write main.mjs, the program in JavaScript, and run it
+  mpl compile main.mpl --emit-js --html
+                                  Also write main.js and main.html to run it in a browser
+  mpl compile main.mpl --emit-node
+                                  Also write the Node.js package main-node/ (node main-node/cli.js)
+  mpl compile main.mpl -I ~/mpl/std
+                                  Also look for imports in ~/mpl/std (and in $MPLPATH)
+  mpl compile src/*.mpl --out-dir build/
+                                  Compile each file on its own to build/<name>.wasm, in parallel
+  mpl compile --lib mylib.mpl     Compile the library mylib.mpl on its own to mylib.wasm
+                                  (programs link it with import \"mylib.wasm\")
+  mpl compile main.mpl --dep-file main.d
+                                  Also write main.d (make rule: main.wasm and the sources it reads)
+  mpl run main.mpl                Compile in-memory and run (no files written)
+  cat main.mpl | mpl compile - -o -
+                                  Compile stdin, write the wasm to stdout
+  mpl compile main.mpl -o out.wasm -a -
+                                  Write out.wasm and print the WAT
+  mpl run-wasm program.wasm       Run an existing WASM binary
+  mpl run-wasm program.wat        Assemble program.wat and run it
+  mpl run-wasm program.wasm --invoke square 7
+                                  Call the export square with 7 instead of main, print what it returns
+  mpl check main.mpl              Report the errors and warnings of main.mpl (no files written)
+  mpl compile main.mpl -O2        Compile with every optimization
+  mpl compile main.mpl -O2 --optimize
+                                  Then shrink main.wasm with wasm-opt (Binaryen)
+  mpl compile main.mpl --verify-deterministic
+                                  Compile twice and check that both builds are the same bytes
+  mpl compile main.mpl --embed-source
+                                  Keep the sources in main.wasm: errors of mpl run-wasm main.wasm show the line
+  mpl compile main.mpl --debug    Also write main.wasm.map, the source map of main.wasm
+  mpl run main.mpl --debug --break 12
+                                  Run under the debugger, stopping at line 12 of main.mpl
+  mpl run main.mpl --trace        Run, printing each statement and the values assigned
+  mpl run main.mpl --profile-functions
+                                  Run, then print the calls and the time of each function
+  mpl run main.mpl --coverage lcov.info
+                                  Run, then write the lines run (lcov) to lcov.info
+  mpl run main.mpl --timings --stats
+                                  Also print the time of each phase and the module statistics
+  mpl run main.mpl --profile      Also print the fuel, allocation and host calls of the run
+  mpl run main.mpl -- a b         Run with program arguments \"a\" and \"b\"
+  mpl compile main.mpl --lang=fr  Report compile errors in French
+  mpl run main.mpl --seed 42      Run with reproducible random numbers
+  mpl compile main.mpl --memory-min 4 --memory-max 16
+                                  Module memory: 4 pages at start, at most 16 (64 KiB each)
+  mpl run main.mpl --engine wasmtime
+                                  Run with the wasmtime JIT (built with --features wasmtime)
+  mpl run main.mpl --timeout 5    Stop the program if it runs for more than 5 seconds
+  mpl run main.mpl --fuel 1000000 Stop the program after about a million instructions
+  mpl run main.mpl --max-call-depth 100000
+                                  Let a deep recursion have up to 100000 calls in progress
+  mpl run main.mpl --max-memory 1M
+                                  Stop the program if it needs more than 1 MiB
+  mpl test tests                  Run tests/**/<name>.mpl and compare with <name>.expected
+  mpl test tests --timeout 2      Same, failing any test that runs for more than 2 seconds
+  mpl build                       Build the project of ./mpl.toml (outputs in its out-dir, build/ by default)
+  mpl info main.wasm              Print the compiler version, sources and options main.wasm was built with
+  mpl fmt main.mpl lib/*.mpl      Format the sources in place
+  mpl fmt --check main.mpl        Fail if main.mpl is not formatted (for CI)
+  mpl doc --format html -o doc.html
+                                  Write the reference of the project of ./mpl.toml as a page
+  mpl lint main.mpl --deny warnings
+                                  Print the warnings of main.mpl and fail if there are any
+  mpl lint main.mpl --allow unused-function
+                                  Same without the unused-function rule
+  mpl compile main.mpl --deny implicit-narrowing
+                                  Fail to compile where a float is silently truncated to an int
+  mpl run main.mpl --dump-memory mem.txt
+                                  Run, then dump the final linear memory
+  mpl run main.mpl --dump-memory 0x400+256
+                                  Run, then print the heap blocks and 256 bytes from 0x400
+
+The modes of earlier versions still work: mpl -c is mpl compile, mpl -r is mpl run,
+mpl --rw is mpl run-wasm.",
         )
 }
 
+fn exit_with(code: i32) -> ! {
+    // Flush the program output before leaving with its exit code.
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
+
+fn end_output_line() {
+    // An error after a `print` without nl starts on a line of its own when both go to the terminal.
+    let _ = io::stdout().flush();
+    if runner::stdout_line_open() && io::stdout().is_terminal() && io::stderr().is_terminal() {
+        eprintln!();
+    }
+}
+
+fn run_options(matches: &clap::ArgMatches) -> runner::RunOptions {
+    // Runner settings taken from the command line.
+    runner::RunOptions {
+        args: matches
+            .get_many::<String>("args")
+            .map(|args| args.cloned().collect())
+            .unwrap_or_default(),
+        flush: matches
+            .get_one::<String>("flush")
+            .and_then(|f| f.parse().ok())
+            .unwrap_or_default(),
+        profile: matches.get_flag("profile"),
+        interruptible: true,
+        ..run_settings(matches)
+    }
+}
+
+fn run_settings(matches: &clap::ArgMatches) -> runner::RunOptions {
+    // The options of run_args(); program arguments and output left to their defaults.
+    runner::RunOptions {
+        seed: matches.get_one::<u64>("seed").copied(),
+        max_memory: matches.get_one::<u64>("max-memory").copied(),
+        engine: matches
+            .get_one::<String>("engine")
+            .and_then(|e| e.parse().ok())
+            .unwrap_or_default(),
+        fuel: matches.get_one::<u64>("fuel").copied(),
+        max_call_depth: matches.get_one::<u32>("max-call-depth").map(|&n| n as usize),
+        timeout: matches.get_one::<std::time::Duration>("timeout").copied(),
+        ..Default::default()
+    }
+}
+
+fn memory_limits(matches: &clap::ArgMatches) -> Result<MemoryLimits, Box<dyn std::error::Error>> {
+    // Memory declared by the compiled module.
+    let limits = MemoryLimits {
+        min_pages: matches.get_one::<u32>("memory-min").copied().unwrap_or(1),
+        max_pages: matches.get_one::<u32>("memory-max").copied(),
+    };
+    if let Some(max) = limits.max_pages
+        && max < limits.min_pages
+    {
+        return Err(format!(
+            "--memory-max ({} pages) is smaller than --memory-min ({} pages)",
+            max, limits.min_pages
+        )
+        .into());
+    }
+    Ok(limits)
+}
+
+fn interruptible<T>(outcome: anyhow::Result<T>) -> Result<T, Box<dyn std::error::Error>> {
+    // A program stopped by Ctrl+C leaves with its own exit code.
+    match outcome {
+        Err(e) if e.is::<runner::Interrupted>() => {
+            end_output_line();
+            eprintln!("{}", e);
+            exit_with(runner::INTERRUPTED_EXIT_CODE)
+        }
+        outcome => Ok(outcome?),
+    }
+}
+
+fn finish_run(
+    matches: &clap::ArgMatches,
+    outcome: runner::RunOutcome,
+    mut timings: Timings,
+    wasm: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Post-run options, then leave with the program exit code.
+    if matches.contains_id("dump-memory") {
+        match matches.get_one::<String>("dump-memory") {
+            Some(file) if runner::parse_memory_range(file).is_none() => outcome.dump_memory(file)?,
+            dump => {
+                let range = dump.and_then(|range| runner::parse_memory_range(range));
+                outcome.write_memory_dump(&mut io::stderr().lock(), range)?;
+            }
+        }
+    }
+    if let Some(profile) = &outcome.profile {
+        eprintln!("{}", profile);
+    }
+    timings.record("instantiate", outcome.instantiate);
+    timings.record("execute", outcome.execute);
+    report(matches, &timings, wasm, &mut io::stderr())?;
+    exit_with(outcome.exit_code)
+}
+
+fn load_timed(
+    matches: &clap::ArgMatches,
+    timings: &mut Timings,
+    src_file: &Path,
+    lib_paths: &[PathBuf],
+    library: bool,
+    log: &mut dyn Write,
+) -> Result<LoadedProgram, Box<dyn std::error::Error>> {
+    // load_program, timing the lexer and the parser (the rest of the load) for --timings.
+    if matches.get_flag("timings") {
+        stats::measure_lexing();
+    }
+    let start = Instant::now();
+    let loaded = load_program(src_file, lib_paths, &include_dirs(matches), library)?;
+    let lexing = stats::lexing_time();
+    timings.record("lex", lexing);
+    timings.record("parse", start.elapsed().saturating_sub(lexing));
+    report_warnings(&loaded.program, &lint_levels(matches)?, log)?;
+    Ok(loaded)
+}
+
+fn report(
+    matches: &clap::ArgMatches,
+    timings: &Timings,
+    wasm: &[u8],
+    log: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // --timings and --stats, on `log` (stderr): stdout may be the wasm or the program output.
+    if matches.get_flag("timings") {
+        writeln!(log, "{}", timings)?;
+    }
+    if matches.get_flag("stats") {
+        let stats = ModuleStats::of(wasm).map_err(|e| format!("not a wasm module: {}", e))?;
+        writeln!(log, "{}", stats)?;
+    }
+    Ok(())
+}
+
+fn find_tests(dir: &Path, tests: &mut Vec<PathBuf>) -> io::Result<()> {
+    // Collect the <name>.mpl files that have a <name>.expected next to them.
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_tests(&path, tests)?;
+        } else if path.extension().is_some_and(|e| e == "mpl")
+            && path.with_extension("expected").is_file()
+        {
+            tests.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn line_diff(expected: &str, actual: &str) -> Vec<String> {
+    // Line diff (longest common subsequence): "- " expected only, "+ " actual only.
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+fn run_test(src_file: &Path, options: &runner::RunOptions, matches: &clap::ArgMatches) -> Result<(), String> {
+    // Compile and run one test in memory; Err holds the report of a failure.
+    let expected = fs::read_to_string(src_file.with_extension("expected"))
+        .map_err(|e| format!("cannot read the expected output: {}", e))?;
+    let loaded = load_program(src_file, &[], &include_dirs(matches), false).map_err(|e| e.to_string())?;
+    let mut generator = CodeGenerator::new();
+    let wasm = generator
+        .generate_wasm(file_stem_string(src_file), &loaded.program)
+        .map_err(|e| e.to_string())?;
+    let options = runner::RunOptions {
+        link_path: link_path(src_file, &loaded),
+        ..options.clone()
+    };
+    let (outcome, output) = runner::run_wasm_bytes_with_output(&wasm, &options);
+
+    // Line endings and trailing newlines do not count.
+    let expected = expected.replace("\r\n", "\n");
+    let (expected, actual) = (expected.trim_end_matches('\n'), output.trim_end_matches('\n'));
+    let mut report = Vec::new();
+    if expected != actual {
+        report = line_diff(expected, actual);
+    }
+    if let Err(e) = outcome {
+        report.push(format!("runtime error: {}", e));
+    }
+    if report.is_empty() {
+        Ok(())
+    } else {
+        Err(report.join("\n"))
+    }
+}
+
+fn run_tests(
+    dir: &Path,
+    options: &runner::RunOptions,
+    matches: &clap::ArgMatches,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    // `mpl test`: run every test of `dir`, print PASS/FAIL with diffs and a summary.
+    let mut tests = Vec::new();
+    find_tests(dir, &mut tests)
+        .map_err(|e| format!("cannot read the tests of '{}': {}", dir.display(), e))?;
+    tests.sort();
+    let mut failed = 0;
+    for test in &tests {
+        match run_test(test, options, matches) {
+            Ok(()) => println!("PASS {}", test.display()),
+            Err(report) => {
+                failed += 1;
+                println!("FAIL {}", test.display());
+                for line in report.lines() {
+                    println!("    {}", line);
+                }
+            }
+        }
+    }
+    println!(
+        "{} test(s): {} passed, {} failed",
+        tests.len(),
+        tests.len() - failed,
+        failed
+    );
+    Ok(failed == 0)
+}
+
 fn main() {
     if let Err(e) = real_main() {
+        end_output_line();
         // Use Display, not Debug
         eprintln!("{e}");
         std::process::exit(1);
     }
 }
 
-fn real_main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = build_cli().get_matches();
+fn compile(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl compile`: compile to WASM (and optionally WAT), write files, do not run.
+    if let Some(out_dir) = matches.get_one::<String>("out-dir") {
+        return compile_all(matches, Path::new(out_dir));
+    }
+    let (src_file, lib_paths) = inputs(matches);
+    let library = matches.get_flag("lib");
+    compile_file(matches, &src_file, &lib_paths, library, &derived_base(&src_file), &mut io::stderr())
+}
 
-    let compile_mode = matches.get_flag("compile");
-    let run_mode = matches.get_flag("run");
-    let runwasm_arg = matches.get_one::<String>("runwasm").cloned();
-
-    let input_path: Option<PathBuf> = matches.get_one::<String>("input").map(|s| PathBuf::from(s));
-
-    // Validate mode-specific requirements
-    if compile_mode || run_mode {
-        if input_path.is_none() {
-            eprintln!(
-                "Error: missing <source.mpl>.\n\nUSAGE:\n  mpl -c <source.mpl> [-o <wasm_name>] [-a [wat_name]]\n  mpl -r <source.mpl>"
-            );
-            process::exit(2);
-        }
-    }
-    if runwasm_arg.is_some() && input_path.is_some() {
-        eprintln!("Error: -rw does not use <INPUT> positional.\n\nUSAGE:\n  mpl -rw <wasm_name>");
-        process::exit(2);
-    }
-
-    // Dispatch per mode
-    if compile_mode {
-        // --- Compile to WASM (and optionally WAT), write files, do not run.
-        let src_file = input_path.unwrap();
-        let src_text = fs::read_to_string(&src_file)?;
-        let lex = Lexer::new(&src_file, src_text);
-        let mut parser = Parser::new(lex)?;
-        let main_program = parser.parse_main_program()?;
-        let mut lib_functions = Vec::new();
-
-        // Parse imports
-        for import in &main_program.imports {
-            let import_src_file = resolve_rel(&src_file, import);
-            let import_src = fs::read_to_string(&import_src_file)?;
-            let lex = Lexer::new(import_src_file, import_src);
-            let mut p = Parser::new(lex)?;
-            let mut functions = p.parse_library()?;
-            lib_functions.append(&mut functions);
-        }
-        let program = Program {
-            main_program,
-            functions: lib_functions,
-        };
+fn compile_file(
+    matches: &clap::ArgMatches,
+    src_file: &Path,
+    lib_paths: &[PathBuf],
+    library: bool,
+    base: &Path,
+    log: &mut dyn Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Compile one program (or library) to the outputs named after `base`; the diagnostics
+    // and the reports go to `log`.
+    if src_file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wat")) {
+        return assemble(matches, src_file, lib_paths, base);
+    }
+    let mut timings = Timings::new();
+    let loaded = load_timed(matches, &mut timings, src_file, lib_paths, library, log)?;
+    let program = &loaded.program;
+    let emits: Vec<&String> = matches.get_many::<String>("emit").unwrap_or_default().collect();
+
+    // Symbol index (JSON) for editors: <source>.symbols.json
+    let mut outputs = Vec::new();
+    if emits.iter().any(|e| *e == "symbols") {
+        let index = SymbolIndex::build(program);
+        let symbols_out = base.with_extension("symbols.json");
+        fs::write(&symbols_out, index.to_json())?;
+        outputs.push(symbols_out);
+    }
+    // The parsed program (JSON) for external tools: <source>.ast.json
+    if emits.iter().any(|e| *e == "ast-json") {
+        let ast_out = base.with_extension("ast.json");
+        fs::write(&ast_out, program.to_json()?)?;
+        outputs.push(ast_out);
+    }
+    // The numeric expressions as typed IR, as text: <source>.ir; the program in C: <source>.c,
+    // in JavaScript: <source>.mjs
+    if emits.iter().any(|e| *e == "ir" || *e == "c" || *e == "js-src") {
+        let options = compile_options(matches)?;
+        let mut optimized = program.clone();
+        optimize::optimize(&mut optimized, options.opt_level);
+        if emits.iter().any(|e| *e == "ir") {
+            let ir_out = base.with_extension("ir");
+            fs::write(&ir_out, ir::print_program(&optimized)?)?;
+            outputs.push(ir_out);
+        }
+        if emits.iter().any(|e| *e == "c") {
+            if let Some(linked) = optimized.linked.first() {
+                return Err(format!("--emit=c cannot use the wasm library {}: import its MPL source instead", linked.module).into());
+            }
+            let c_out = base.with_extension("c");
+            let c_file = c_out.file_name().unwrap_or_default().to_string_lossy();
+            fs::write(&c_out, csource::program_c(&optimized, &c_file, options.float_format)?)?;
+            outputs.push(c_out);
+        }
+        if emits.iter().any(|e| *e == "js-src") {
+            if let Some(linked) = optimized.linked.first() {
+                return Err(format!("--emit=js-src cannot use the wasm library {}: import its MPL source instead", linked.module).into());
+            }
+            let js_out = base.with_extension("mjs");
+            let js_file = js_out.file_name().unwrap_or_default().to_string_lossy();
+            fs::write(&js_out, jssource::program_js(&optimized, &js_file, options.float_format)?)?;
+            outputs.push(js_out);
+        }
+    }
+    let write_dep_file = |outputs: &[PathBuf]| -> io::Result<()> {
+        match matches.get_one::<String>("dep-file") {
+            Some(dep) => write_output(Path::new(dep), dep_file(outputs, &source_files(src_file, &loaded)).as_bytes()),
+            None => Ok(()),
+        }
+    };
+    if !emits.iter().any(|e| *e == "wasm") {
+        write_dep_file(&outputs)?;
+        if matches.get_flag("emit-js") || matches.contains_id("emit-node") {
+            return Err("--emit-js and --emit-node need the wasm output (--emit=wasm)".into());
+        }
+        return Ok(());
+    }
 
-        // Generate WASM bytes
-        let prog_name = file_stem_string(&src_file);
-        let mut generator = CodeGenerator::new();
-        let wasm = generator.generate_wasm(prog_name, &program)?;
+    // Determine WASM and WAT output paths; at most one of them can be stdout.
+    let wasm_out = if let Some(o) = matches.get_one::<String>("output") {
+        PathBuf::from(o)
+    } else {
+        base.with_extension("wasm")
+    };
+    // If a value is provided to -a, use it; else default to <source>.wat
+    let wat_out = matches.contains_id("wat").then(|| match matches.get_one::<String>("wat") {
+        Some(name) => PathBuf::from(name),
+        None => base.with_extension("wat"),
+    });
+    if is_stdio(&wasm_out) && wat_out.as_deref().is_some_and(is_stdio) {
+        return Err("-o - and -a - cannot both write to stdout".into());
+    }
+    if is_stdio(&wasm_out) && (matches.get_flag("emit-js") || matches.contains_id("emit-node")) {
+        return Err("--emit-js and --emit-node need a wasm file name (not -o -)".into());
+    }
+    // --debug: the map next to the wasm, named in it relative to it
+    let debug = matches.get_flag("debug");
+    if is_stdio(&wasm_out) && debug {
+        return Err("--debug needs a wasm file name (not -o -)".into());
+    }
+    let map_out = debug.then(|| {
+        let mut name = wasm_out.clone().into_os_string();
+        name.push(".map");
+        PathBuf::from(name)
+    });
 
-        // Determine WASM output path
-        let wasm_out = if let Some(o) = matches.get_one::<String>("output") {
-            PathBuf::from(o)
+    // Generate WASM bytes
+    let prog_name = file_stem_string(base);
+    let memory = memory_limits(matches)?;
+    let strip = matches.get_flag("strip");
+    let sources = source_files(src_file, &loaded);
+    let embed_source = matches.get_flag("embed-source");
+    let options = compile_options(matches)?;
+    let info = build_info(src_file, &loaded, memory, strip)
+        .option("opt-level", options.opt_level)
+        .option("wasm-features", options.features)
+        .option("passive-data", options.passive_data)
+        .option("lib", library)
+        .option("embed-source", embed_source)
+        .option("debug", debug);
+    let embedded = if embed_source { embedded_sources(&sources)? } else { Vec::new() };
+    let map_url = map_out.as_deref().map(|map| map.file_name().unwrap_or_default().to_string_lossy().into_owned());
+    let generator = || {
+        CodeGenerator::new()
+            .with_memory(memory)
+            .with_strip(strip)
+            .with_library(library)
+            .with_options(options)
+            .with_meta(&info)
+            .with_sources(embedded.clone())
+            .with_source_map(map_url.clone())
+    };
+    let generate = |program: &Program| generator().generate_wasm(prog_name.clone(), program);
+    let mut codegen = generator();
+    let mut wasm = timings.time("codegen", || codegen.generate_wasm(prog_name.clone(), program))?;
+    if matches.get_flag("verify-deterministic") {
+        // from the files again (stdin can only be read once: then the same program)
+        let again = if is_stdio(src_file) {
+            None
         } else {
-            src_file.with_extension("wasm")
+            Some(load_program(src_file, lib_paths, &include_dirs(matches), library)?)
         };
-        fs::write(&wasm_out, &wasm)?;
+        check_deterministic(&wasm, &generate(again.as_ref().map_or(program, |l| &l.program))?)?;
+    }
+    if matches.get_flag("optimize") {
+        wasm = timings.time("wasm-opt", || wasm_opt(&wasm, log))?;
+    }
+    write_output(&wasm_out, &wasm)?;
+    if let Some(map_out) = map_out {
+        let wasm_file = wasm_out.file_name().unwrap_or_default().to_string_lossy();
+        fs::write(&map_out, codegen.source_map(&wasm, &wasm_file, &embedded_sources(&sources)?)?)?;
+        outputs.push(map_out);
+    }
 
-        // Optionally produce WAT
-        if matches.contains_id("wat") {
-            // If a value is provided to -a, use it; else default to <source>.wat
-            let wat_out = if let Some(name) = matches.get_one::<String>("wat") {
-                PathBuf::from(name)
-            } else {
-                src_file.with_extension("wat")
-            };
-            let mut cfg = Config::new();
-            cfg.print_offsets(true).name_unnamed(true); // commentaires ";; offset: 0x..."
+    // Optionally the browser loader (and a page using it) next to the wasm
+    if matches.get_flag("emit-js") {
+        let title = matches.get_flag("html").then(|| file_stem_string(base));
+        write_js(&wasm_out, memory, title.as_deref())?;
+    }
+
+    // Optionally a Node.js package: its own copy of the wasm and the JavaScript around it
+    if matches.contains_id("emit-node") {
+        let dir = match matches.get_one::<String>("emit-node") {
+            Some(d) => PathBuf::from(d),
+            None => {
+                let stem = file_stem_string(&wasm_out);
+                wasm_out.with_file_name(format!("{}-node", stem))
+            }
+        };
+        write_node_package(&dir, &wasm_out, &wasm, memory)?;
+    }
+
+    // Optionally produce WAT
+    if let Some(wat_out) = wat_out {
+        let mut cfg = Config::new();
+        cfg.print_offsets(true).name_unnamed(true); // commentaires ";; offset: 0x..."
+
+        let mut out = String::new();
+        let mut sink = PrintFmtWrite(&mut out); // <-- pas de ::new
+        cfg.print(&wasm, &mut sink).unwrap();
+        //let wat = wasmprinter::print_bytes(&wasm).expect("WAT print failed");
+        write_output(&wat_out, out.as_bytes())?;
+        outputs.push(wat_out);
+    }
+
+    // Dependency file: what make has to rebuild when a source changes
+    outputs.insert(0, wasm_out);
+    outputs.retain(|p| !is_stdio(p));
+    write_dep_file(&outputs)?;
+    report(matches, &timings, &wasm, log)?;
 
-            let mut out = String::new();
-            let mut sink = PrintFmtWrite(&mut out); // <-- pas de ::new
-            cfg.print(&wasm, &mut sink).unwrap();
-            //let wat = wasmprinter::print_bytes(&wasm).expect("WAT print failed");
-            fs::write(&wat_out, out)?;
+    Ok(())
+}
+
+fn expand_inputs(matches: &clap::ArgMatches) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    // The INPUT files of `mpl compile --out-dir`: patterns (*, ?, [...]) are expanded here,
+    // for the shells that leave them as they are.
+    let mut files = Vec::new();
+    for input in matches.get_many::<String>("input").unwrap() {
+        if is_stdio(Path::new(input)) {
+            return Err("--out-dir compiles files: the source cannot be stdin (-)".into());
+        }
+        if !input.contains(['*', '?', '[']) {
+            files.push(PathBuf::from(input));
+            continue;
+        }
+        let mut matched: Vec<PathBuf> = glob::glob(input)
+            .map_err(|e| format!("bad pattern '{}': {}", input, e))?
+            .collect::<Result<_, _>>()?;
+        if matched.is_empty() {
+            return Err(format!("no file matches '{}'", input).into());
         }
+        matched.sort();
+        files.append(&mut matched);
+    }
+    Ok(files)
+}
 
-        // Optional: print program debug (as in your original main)
-        // println!("{:?}", program);
+fn compile_all(matches: &clap::ArgMatches, out_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl compile --out-dir`: compile each input on its own (in parallel), a program if it has
+    // a main, else a library, to <out_dir>/<source>.wasm. Each file's diagnostics are printed
+    // together, in the order of the inputs, then a summary.
+    if matches.get_one::<String>("wat").is_some() || matches.get_one::<String>("emit-node").is_some() {
+        return Err("with --out-dir the outputs are named after their sources: use -a and --emit-node without a name".into());
+    }
+    let files = expand_inputs(matches)?;
+    let mut bases: Vec<PathBuf> = Vec::new();
+    for file in &files {
+        let base = out_dir.join(file.file_name().unwrap_or_default());
+        if let Some(i) = bases.iter().position(|b| *b == base) {
+            return Err(format!(
+                "'{}' and '{}' would both be compiled to '{}'",
+                files[i].display(),
+                file.display(),
+                base.with_extension("wasm").display()
+            )
+            .into());
+        }
+        bases.push(base);
+    }
+    fs::create_dir_all(out_dir).map_err(|e| format!("cannot create '{}': {}", out_dir.display(), e))?;
 
-        Ok(())
-    } else if run_mode {
-        // --- Compile in-memory and run without writing files.
-        let src_file = input_path.unwrap();
-        let src_text = fs::read_to_string(&src_file)?;
-        let lex = Lexer::new(&src_file, src_text);
-        let mut parser = Parser::new(lex)?;
-        let main_program = parser.parse_main_program()?;
-        let mut lib_functions = Vec::new();
-
-        // Parse imports
-        for import in &main_program.imports {
-            let import_src_file = resolve_rel(&src_file, import);
-            let import_src = fs::read_to_string(&import_src_file)?;
-            let lex = Lexer::new(import_src_file, import_src);
-            let mut p = Parser::new(lex)?;
-            let mut functions = p.parse_library()?;
-            lib_functions.append(&mut functions);
-        }
-        let program = Program {
-            main_program,
-            functions: lib_functions,
-        };
+    let results: Vec<(Vec<u8>, Result<(), String>)> = files
+        .par_iter()
+        .zip(&bases)
+        .map(|(file, base)| {
+            let mut log = Vec::new();
+            let result = modules::has_main(file)
+                .and_then(|main| {
+                    let library = matches.get_flag("lib") || !main;
+                    compile_file(matches, file, &[], library, base, &mut log)
+                })
+                .map_err(|e| e.to_string());
+            (log, result)
+        })
+        .collect();
 
-        // Generate WASM bytes
-        let prog_name = file_stem_string(&src_file);
-        let mut generator = CodeGenerator::new();
-        let wasm = generator.generate_wasm(prog_name, &program)?;
+    let mut failed = 0;
+    for (log, result) in results {
+        io::stderr().write_all(&log)?;
+        if let Err(e) = result {
+            failed += 1;
+            eprintln!("{}", e);
+        }
+    }
+    eprintln!(
+        "{} file(s): {} compiled, {} failed",
+        files.len(),
+        files.len() - failed,
+        failed
+    );
+    exit_with(if failed == 0 { 0 } else { 1 })
+}
 
-        // Run directly from memory (no disk write).
-        // NOTE: ensure runner exposes `run_wasm_bytes(&[u8]) -> Result<(), E>`.
-        runner::run_wasm_bytes(&wasm)?;
+fn run(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl run`: compile in memory and run without writing files.
+    let (src_file, lib_paths) = inputs(matches);
+    let mut timings = Timings::new();
+    let loaded = load_timed(matches, &mut timings, &src_file, &lib_paths, false, &mut io::stderr())?;
 
-        Ok(())
-    } else if let Some(wasm_path) = runwasm_arg {
-        // --- Run an existing WASM file from disk.
-        runner::run_wasm_file(&wasm_path)?;
-        Ok(())
+    // Generate WASM bytes; --debug, --trace, --profile-functions and --coverage instrument
+    // the code, calling the host functions they give to the runner
+    let prog_name = file_stem_string(&derived_base(&src_file));
+    let profiler = matches.get_flag("profile-functions").then(Profiler::new);
+    let coverage = matches.contains_id("coverage").then(Coverage::new);
+    let instrumentation = if matches.get_flag("debug") {
+        let breakpoints = matches.get_many::<Breakpoint>("break").into_iter().flatten().cloned();
+        let debugger = Debugger::new(&src_file, breakpoints.collect());
+        Some((debugger.hooks(), debugger.host_functions()))
+    } else if matches.get_flag("trace") {
+        let tracer = Tracer::new();
+        Some((tracer.hooks(), tracer.host_functions()))
+    } else if let Some(profiler) = &profiler {
+        Some((profiler.hooks(), profiler.host_functions()))
     } else {
-        // Should not happen due to ArgGroup(required=true), but keep a safe fallback.
-        eprintln!("Error: one mode must be selected (-c | -r | -rw).");
-        process::exit(2);
+        coverage.as_ref().map(|coverage| (coverage.hooks(), coverage.host_functions()))
+    };
+    let (generator, host_functions) = match instrumentation {
+        Some((hooks, functions)) => (CodeGenerator::with_hooks(hooks), functions),
+        None => (CodeGenerator::new(), Vec::new()),
+    };
+    let mut options = compile_options(matches)?;
+    // the inlined functions would have no calls and the removed code no lines counted
+    if profiler.is_some() || coverage.is_some() {
+        options.opt_level = optimize::OptLevel::O0;
+    }
+    let mut generator = generator.with_memory(memory_limits(matches)?).with_options(options);
+    let wasm = timings.time("codegen", || generator.generate_wasm(prog_name, &loaded.program))?;
+
+    // Run directly from memory (no disk write), exit with the code returned by main.
+    let options = runner::RunOptions {
+        link_path: link_path(&src_file, &loaded),
+        host: host_functions.into_iter().collect(),
+        ..run_options(matches)
+    };
+    let outcome = runner::run_wasm_bytes(&wasm, &options);
+    // the calls and the lines run before a trap are shown as well
+    if let Some(profiler) = profiler {
+        eprintln!("{}", profiler);
+    }
+    if let Some(coverage) = coverage {
+        match matches.get_one::<String>("coverage") {
+            Some(file) => {
+                let mut out = io::BufWriter::new(fs::File::create(file)?);
+                coverage.write_lcov(&mut out)?;
+                out.flush()?;
+            }
+            None => eprintln!("{}", coverage),
+        }
+    }
+    finish_run(matches, interruptible(outcome)?, timings, &wasm)
+}
+
+fn assemble(matches: &clap::ArgMatches, src_file: &Path, lib_paths: &[PathBuf], base: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl compile file.wat`: the WAT (e.g. that of -a, edited by hand) assembled and checked,
+    // to <file>.wasm or -o.
+    if !lib_paths.is_empty() {
+        return Err("a .wat input is assembled as it is: it cannot be linked with libraries".into());
+    }
+    let wasm = runner::read_module(src_file)?;
+    wasmparser::validate(&wasm).map_err(|e| format!("{}: {}", src_file.display(), e))?;
+    let wasm_out = match matches.get_one::<String>("output") {
+        Some(o) => PathBuf::from(o),
+        None => base.with_extension("wasm"),
+    };
+    write_output(&wasm_out, &wasm)?;
+    Ok(())
+}
+
+fn run_wasm(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl run-wasm`: run an existing WASM file from disk.
+    let wasm_path = matches.get_one::<String>("wasm").unwrap();
+    let invoke = matches.get_many::<String>("invoke").map(|values| {
+        let mut values = values.cloned();
+        runner::Invoke {
+            name: values.next().unwrap_or_default(),
+            args: values.collect(),
+        }
+    });
+    let options = runner::RunOptions {
+        invoke,
+        ..run_options(matches)
+    };
+    let outcome = interruptible(runner::run_wasm_file(wasm_path, &options))?;
+    for value in &outcome.results {
+        println!("{}", value);
+    }
+    let wasm = if matches.get_flag("stats") { runner::read_module(wasm_path)? } else { Vec::new() };
+    finish_run(matches, outcome, Timings::new(), &wasm)
+}
+
+fn check(matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    // `mpl check`: compile in memory, reporting the errors and the warnings; nothing is written.
+    let (src_file, lib_paths) = inputs(matches);
+    let library = matches.get_flag("lib");
+    let loaded = load_program(&src_file, &lib_paths, &include_dirs(matches), library)?;
+    report_warnings(&loaded.program, &lint_levels(matches)?, &mut io::stderr())?;
+    let prog_name = file_stem_string(&derived_base(&src_file));
+    CodeGenerator::new().with_library(library).generate_wasm(prog_name, &loaded.program)?;
+    Ok(())
+}
+
+fn real_main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = build_cli().get_matches();
+    // a subcommand is required, and --lang is global
+    let Some((command, matches)) = matches.subcommand() else {
+        unreachable!()
+    };
+    if let Some(lang) = matches.get_one::<String>("lang") {
+        messages::set_lang(lang.parse()?);
+    }
+    match command {
+        "compile" => compile(matches),
+        "run" => {
+            runner::handle_interrupts()?;
+            run(matches)
+        }
+        "run-wasm" => {
+            runner::handle_interrupts()?;
+            run_wasm(matches)
+        }
+        "check" => check(matches),
+        "test" => {
+            // Run the tests of a directory (same seed for every run unless --seed is given).
+            let mut options = run_settings(matches);
+            options.seed = Some(options.seed.unwrap_or(0));
+            let dir = PathBuf::from(matches.get_one::<String>("dir").unwrap());
+            let passed = run_tests(&dir, &options, matches)?;
+            exit_with(if passed { 0 } else { 1 })
+        }
+        "build" => build_project(Path::new(matches.get_one::<String>("project").unwrap())),
+        "info" => print_info(Path::new(matches.get_one::<String>("wasm").unwrap())),
+        "doc" => {
+            let format: DocFormat = matches.get_one::<String>("format").unwrap().parse()?;
+            let reference = document(Path::new(matches.get_one::<String>("project").unwrap()), format)?;
+            match matches.get_one::<String>("output") {
+                Some(out) => fs::write(out, reference)?,
+                None => print!("{}", reference),
+            }
+            Ok(())
+        }
+        "lsp" => serve_lsp(),
+        "lint" => lint_program(matches),
+        "fmt" => {
+            let files: Vec<PathBuf> = matches.get_many::<String>("files").unwrap().map(PathBuf::from).collect();
+            let formatted = format_files(&files, matches.get_flag("check"))?;
+            exit_with(if formatted { 0 } else { 1 })
+        }
+        _ => unreachable!("subcommand {} without a handler", command),
     }
 }
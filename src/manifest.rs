@@ -0,0 +1,239 @@
+// My Programming Language
+// Project manifest: the mpl.toml read by `mpl build` (and `mpl doc`).
+//
+// [package]
+// name = "hello"              # output names (default: the directory name)
+// version = "0.1.0"
+//
+// [build]
+// entry = "main.mpl"          # main program (default: main.mpl)
+// libraries = ["extra.mpl"]   # linked without being imported
+// import-paths = ["lib"]      # searched for imports, like -I
+// out-dir = "build"           # where the outputs go (default: build)
+// target = "runner"           # runner (wasm only), js (wasm + browser loader), node (Node.js package)
+// memory-min = 1              # module memory, in 64 KiB pages
+// memory-max = 16
+// strip = false               # leave the name section out
+// opt-level = 2               # -O (default: 0)
+// wasm-features = "mvp"       # --wasm-features (default: bulk-memory)
+// passive-data = true         # --passive-data (default: false)
+// float-format = "shortest"   # --float-format (default: digits)
+//
+// Paths are relative to the directory of mpl.toml. There is no wasi target: a module imports
+// its runtime (env, str, math) from the host running it, which a WASI runtime does not provide.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use toml::{Table, Value};
+
+use crate::codegen::{CompileOptions, FloatFormat, MemoryLimits, WasmFeatures};
+
+pub const MANIFEST_FILE: &str = "mpl.toml";
+
+/// What `mpl build` produces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    #[default]
+    Runner, // the wasm, for `mpl run-wasm`
+    Js,     // the wasm, a browser loader and a page
+    Node,   // a Node.js package
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "runner" => Ok(Self::Runner),
+            "js" => Ok(Self::Js),
+            "node" => Ok(Self::Node),
+            _ => Err(format!("unknown target \"{}\" (expected runner, js or node)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Runner => "runner",
+            Self::Js => "js",
+            Self::Node => "node",
+        })
+    }
+}
+
+/// A parsed mpl.toml, its paths resolved against the project directory.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub version: Option<String>,
+    pub entry: PathBuf,
+    pub libraries: Vec<PathBuf>,
+    pub import_paths: Vec<PathBuf>,
+    pub out_dir: PathBuf,
+    pub target: Target,
+    pub memory: MemoryLimits,
+    pub strip: bool,
+    pub options: CompileOptions,
+}
+
+// Keys of a table, unknown ones rejected (a typo would otherwise be silently ignored)
+struct Section<'a> {
+    name: &'static str,
+    table: Option<&'a Table>,
+}
+
+impl<'a> Section<'a> {
+    fn new(root: &'a Table, name: &'static str, known: &[&str]) -> Result<Self, String> {
+        let table = match root.get(name) {
+            None => None,
+            Some(Value::Table(t)) => Some(t),
+            Some(_) => return Err(format!("[{}] must be a table", name)),
+        };
+        if let Some(key) = table.into_iter().flat_map(|t| t.keys()).find(|k| !known.contains(&k.as_str())) {
+            return Err(format!("unknown key '{}' in [{}]", key, name));
+        }
+        Ok(Self { name, table })
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        self.table.and_then(|t| t.get(key))
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str) -> String {
+        format!("{}.{} must be {}", self.name, key, expected)
+    }
+
+    fn string(&self, key: &str) -> Result<Option<String>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(self.wrong_type(key, "a string")),
+        }
+    }
+
+    fn strings(&self, key: &str) -> Result<Vec<String>, String> {
+        match self.get(key) {
+            None => Ok(Vec::new()),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|v| v.as_str().map(str::to_string).ok_or_else(|| self.wrong_type(key, "an array of strings")))
+                .collect(),
+            Some(_) => Err(self.wrong_type(key, "an array of strings")),
+        }
+    }
+
+    fn pages(&self, key: &str) -> Result<Option<u32>, String> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) => u32::try_from(*n)
+                .map(Some)
+                .map_err(|_| self.wrong_type(key, "a number of pages")),
+            Some(_) => Err(self.wrong_type(key, "a number of pages")),
+        }
+    }
+
+    fn bool(&self, key: &str) -> Result<bool, String> {
+        match self.get(key) {
+            None => Ok(false),
+            Some(Value::Boolean(b)) => Ok(*b),
+            Some(_) => Err(self.wrong_type(key, "true or false")),
+        }
+    }
+}
+
+impl Manifest {
+    /// Read `path`: an mpl.toml, or a directory containing one.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = if path.is_dir() {
+            path.join(MANIFEST_FILE)
+        } else {
+            path.to_path_buf()
+        };
+        let text = fs::read_to_string(&file)
+            .map_err(|e| format!("cannot read '{}': {}", file.display(), e))?;
+        let dir = match file.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Self::parse(&text, &dir).map_err(|e| format!("{}: {}", file.display(), e).into())
+    }
+
+    /// Parse the text of an mpl.toml found in `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let root: Table = text.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        if let Some(key) = root.keys().find(|k| !["package", "build"].contains(&k.as_str())) {
+            return Err(format!("unknown section [{}]", key));
+        }
+        let package = Section::new(&root, "package", &["name", "version"])?;
+        let build = Section::new(
+            &root,
+            "build",
+            &["entry", "libraries", "import-paths", "out-dir", "target", "memory-min", "memory-max", "strip", "opt-level", "wasm-features", "passive-data", "float-format"],
+        )?;
+
+        let name = match package.string("name")? {
+            Some(name) => name,
+            None => fs::canonicalize(dir)
+                .ok()
+                .and_then(|d| d.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| "main".to_string()),
+        };
+        let target = match build.string("target")? {
+            Some(t) => t.parse()?,
+            None => Target::default(),
+        };
+        let memory = MemoryLimits {
+            min_pages: build.pages("memory-min")?.unwrap_or(1),
+            max_pages: build.pages("memory-max")?,
+        };
+        if let Some(max) = memory.max_pages
+            && max < memory.min_pages
+        {
+            return Err(format!(
+                "build.memory-max ({} pages) is smaller than build.memory-min ({} pages)",
+                max, memory.min_pages
+            ));
+        }
+        let opt_level = match build.get("opt-level") {
+            None => Default::default(),
+            Some(Value::Integer(n)) => n.to_string().parse()?,
+            Some(_) => return Err(build.wrong_type("opt-level", "0, 1 or 2")),
+        };
+        let features = match build.string("wasm-features")? {
+            Some(f) => f.parse()?,
+            None => WasmFeatures::default(),
+        };
+        let float_format = match build.string("float-format")? {
+            Some(f) => f.parse()?,
+            None => FloatFormat::default(),
+        };
+        let options = CompileOptions {
+            opt_level,
+            features,
+            passive_data: build.bool("passive-data")?,
+            float_format,
+        };
+        options.check()?;
+        let paths = |key| -> Result<Vec<PathBuf>, String> {
+            Ok(build.strings(key)?.iter().map(|p| dir.join(p)).collect())
+        };
+
+        Ok(Self {
+            name,
+            version: package.string("version")?,
+            entry: dir.join(build.string("entry")?.as_deref().unwrap_or("main.mpl")),
+            libraries: paths("libraries")?,
+            import_paths: paths("import-paths")?,
+            out_dir: dir.join(build.string("out-dir")?.as_deref().unwrap_or("build")),
+            target,
+            memory,
+            strip: build.bool("strip")?,
+            options,
+        })
+    }
+}
@@ -0,0 +1,217 @@
+// My Programming Language
+// Message catalog for diagnostics: a stable code and an English and French text per message
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Language of the diagnostics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    Fr,
+}
+
+impl std::str::FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "en" => Ok(Lang::En),
+            "fr" => Ok(Lang::Fr),
+            _ => Err(format!("unknown language '{}' (en or fr)", s)),
+        }
+    }
+}
+
+// Chosen once by the CLI, read when a diagnostic is built or displayed.
+static LANG: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_lang(lang: Lang) {
+    LANG.store(lang as u8, Ordering::Relaxed);
+}
+
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::Fr,
+        _ => Lang::En,
+    }
+}
+
+// A catalog entry. `{}` placeholders are filled in order by `format`.
+#[derive(Debug)]
+pub struct Message {
+    pub code: &'static str, // never changes between versions or languages
+    pub en: &'static str,
+    pub fr: &'static str,
+}
+
+impl Message {
+    pub fn text(&self) -> &'static str {
+        match lang() {
+            Lang::En => self.en,
+            Lang::Fr => self.fr,
+        }
+    }
+
+    // A runtime error: its code, then its text ("[E0503] execution budget exceeded: ...")
+    pub fn coded(&self, args: &[&dyn Display]) -> String {
+        format!("[{}] {}", self.code, self.format(args))
+    }
+
+    pub fn format(&self, args: &[&dyn Display]) -> String {
+        let mut out = String::new();
+        let mut args = args.iter();
+        let mut parts = self.text().split("{}").peekable();
+        while let Some(part) = parts.next() {
+            out.push_str(part);
+            if parts.peek().is_some()
+                && let Some(arg) = args.next()
+            {
+                out.push_str(&arg.to_string());
+            }
+        }
+        out
+    }
+}
+
+macro_rules! messages {
+    ($($name:ident = $code:literal, $en:literal, $fr:literal;)*) => {
+        $(pub const $name: Message = Message { code: $code, en: $en, fr: $fr };)*
+    };
+}
+
+messages! {
+    // --- error headers
+    TOKEN_ERROR = "H001", "Token error", "Erreur de lexique";
+    GRAMMAR_ERROR = "H002", "Grammar error", "Erreur de grammaire";
+    GENERATION_ERROR = "H003", "Code generation error", "Erreur de génération de code";
+    LOCATION = "H004", "in file {}\n at line {}\n col {}", "dans le fichier {}\n à la ligne {}\n colonne {}";
+    WARNING = "H005", "Warning", "Avertissement";
+    DENIED_WARNING = "H006", "Error (denied warning)", "Erreur (avertissement refusé)";
+    DID_YOU_MEAN = "H007", " (did you mean '{}'?)", " (vouliez-vous dire '{}' ?)";
+    SEMANTIC_ERROR = "H008", "Semantic error", "Erreur sémantique";
+    IN_FUNCTION = "H009", " in function {}", " dans la fonction {}";
+    IN_FUNCTIONS = "H010", " in one of the functions {}", " dans l'une des fonctions {}";
+
+    // --- lexer
+    UNTERMINATED_COMMENT = "E0101", "block comment not terminated (*/ missing)", "commentaire de bloc non terminé (*/ manquant)";
+    EXPECTED_OPENING_QUOTE = "E0102", "internal: expected opening '\"'", "interne : '\"' ouvrant attendu";
+    UNTERMINATED_STRING = "E0103", "incomplete string (\" missing)", "chaîne incomplète (\" manquant)";
+    INVALID_FLOAT = "E0104", "invalid float number format", "format de nombre décimal invalide";
+    INVALID_INTEGER = "E0105", "invalid integer format", "format d'entier invalide";
+    UNEXPECTED_CHAR = "E0106", "unexpected token: '{}' ({})", "symbole inattendu : '{}' ({})";
+    UNEXPECTED_EOF = "E0107", "unexpected end of input", "fin de fichier inattendue";
+    READ_ERROR = "E0108", "cannot read the source: {}", "impossible de lire la source : {}";
+    UNTERMINATED_CHAR = "E0109", "incomplete character (' missing)", "caractère incomplet (' manquant)";
+    CHAR_LITERAL_LENGTH = "E0110", "a character literal holds one character (a string is written \"...\")", "un caractère littéral contient un seul caractère (une chaîne s'écrit \"...\")";
+    UNKNOWN_ESCAPE = "E0111", "unknown escape '\\{}' (known: \\n \\t \\r \\0 \\\\ \\' \\\" \\u{...})", "échappement inconnu '\\{}' (connus : \\n \\t \\r \\0 \\\\ \\' \\\" \\u{...})";
+    INVALID_UNICODE_ESCAPE = "E0112", "invalid \\u{...} escape: 1 to 6 hex digits of a Unicode code point, e.g. \\u{e9}", "échappement \\u{...} invalide : 1 à 6 chiffres hexadécimaux d'un point de code Unicode, par ex. \\u{e9}";
+    CONFUSABLE_CHAR = "E0113", "'{}' ({}) looks like '{}' but is another character (typed by a word processor?): write it with the keyboard key", "'{}' ({}) ressemble à '{}' mais est un autre caractère (saisi par un traitement de texte ?) : l'écrire avec la touche du clavier";
+    MIXED_SCRIPT_IDENT = "E0114", "'{}' mixes alphabets: '{}' ({}) looks like the Latin letter '{}' but is not it", "'{}' mélange des alphabets : '{}' ({}) ressemble à la lettre latine '{}' mais n'est pas elle";
+    COMBINING_MARK = "E0115", "'{}' has a separate accent ({}): write the accented letter as one character (é, not e followed by an accent)", "'{}' a un accent séparé ({}) : écrire la lettre accentuée en un seul caractère (é, et non e suivi d'un accent)";
+    INTEGER_OUT_OF_RANGE = "E0116", "integer {} is too large: an int is at most {} (write {}.0 for a float)", "l'entier {} est trop grand : un int vaut au plus {} (écrire {}.0 pour un décimal)";
+
+    // --- parser
+    UNEXPECTED_TOKEN = "E0201", "Expected {}, found {}", "{} attendu, trouvé {}";
+    VARIABLE_NOT_DECLARED = "E0202", "Variable '{}' not declared{}", "Variable '{}' non déclarée{}";
+    WRONG_ARG_COUNT = "E0203", "{}() takes {} argument(s), found {}", "{}() prend {} argument(s), trouvé {}";
+    STRING_ORDER = "E0204", "strings cannot be compared with `{}` (only == and != are supported)", "les chaînes ne peuvent pas être comparées avec `{}` (seuls == et != sont acceptés)";
+    IMPORT_CYCLE = "E0205", "import cycle: {}", "cycle d'imports : {}";
+    UNKNOWN_MODULE = "E0206", "unknown module '{}' (expected `import \"...\" as {}`)", "module inconnu '{}' (`import \"...\" as {}` attendu)";
+    PRIVATE_FUNCTION = "E0207", "function '{}' is private to {} (declare it with `pub fn` to call it from another file)", "la fonction '{}' est privée à {} (la déclarer avec `pub fn` pour l'appeler depuis un autre fichier)";
+    CALL_IN_EXPRESSION = "E0208", "'{}(' cannot be used in an expression: functions are called by the statement `{}()`", "'{}(' ne peut pas être utilisé dans une expression : les fonctions sont appelées par l'instruction `{}()`";
+    EXPRESSION_TOO_DEEP = "E0209", "expression too deeply nested (more than {} levels of parentheses, calls or operators)", "expression trop imbriquée (plus de {} niveaux de parenthèses, d'appels ou d'opérateurs)";
+    FUNCTION_IN_MODULE = "E0210", "unknown function '{}': it is defined in module '{}' (call it as {}.{}())", "fonction inconnue '{}' : elle est définie dans le module '{}' (l'appeler par {}.{}())";
+    MAIN_CALL = "E0211", "main() can only be called from the functions of its own file", "main() ne peut être appelée que par les fonctions de son propre fichier";
+    LIBRARY_CALLS_PROGRAM = "E0212", "'{}' is a function of the main program {}: the library {} cannot call it (a library only calls its own functions and those of other libraries)", "'{}' est une fonction du programme principal {} : la bibliothèque {} ne peut pas l'appeler (une bibliothèque n'appelle que ses propres fonctions et celles d'autres bibliothèques)";
+    FN_IN_EXPRESSION = "E0213", "'{}' is a fn variable: it cannot be used as a number (call it with `call_indirect {}()`)", "'{}' est une variable fn : elle ne peut pas servir de nombre (l'appeler par `call_indirect {}()`)";
+    NOT_A_FN_VARIABLE = "E0214", "'{}' is not a fn variable: it is declared `local {} {}`", "'{}' n'est pas une variable fn : elle est déclarée `local {} {}`";
+    DUPLICATE_ENUM = "E0215", "enum '{}' is declared twice (first declaration: {})", "l'enum '{}' est déclarée deux fois (première déclaration : {})";
+    DUPLICATE_ENUM_VALUE = "E0216", "'{}' appears twice in enum '{}'", "'{}' apparaît deux fois dans l'enum '{}'";
+    UNKNOWN_ENUM_VALUE = "E0217", "enum '{}' has no value '{}' (its values: {})", "l'enum '{}' n'a pas de valeur '{}' (ses valeurs : {})";
+    DUPLICATE_CASE = "E0218", "case {} appears twice in this match (first: {})", "le cas {} apparaît deux fois dans ce match (le premier : {})";
+    MATCH_ON_FLOAT = "E0219", "`match` works on ints (integers, chars and enum values), not on floats", "`match` porte sur des entiers (nombres entiers, caractères et valeurs d'enum), pas sur des décimaux";
+    MAP_IN_EXPRESSION = "E0220", "'{}' is a map: it cannot be used as a number (read the value of a key with `{}[key]`)", "'{}' est une map : elle ne peut pas servir de nombre (lire la valeur d'une clé par `{}[clé]`)";
+    NOT_A_MAP = "E0221", "'{}' is not a map: it is declared `local {} {}`", "'{}' n'est pas une map : elle est déclarée `local {} {}`";
+    MAP_ASSIGNMENT = "E0222", "'{}' is a map: a key is given a value with `let {}[key] = value`", "'{}' est une map : une clé reçoit une valeur par `let {}[clé] = valeur`";
+    BUILDER_IN_EXPRESSION = "E0223", "'{}' is a builder: it cannot be used as a number (len({}) gives the length of its text)", "'{}' est un builder : il ne peut pas servir de nombre (len({}) donne la longueur de son texte)";
+    NOT_A_BUILDER = "E0224", "'{}' is not a builder: it is declared `local {} {}`", "'{}' n'est pas un builder : elle est déclarée `local {} {}`";
+    BUILDER_ASSIGNMENT = "E0225", "'{}' is a builder: text is added to it with `append({}, ...)`", "'{}' est un builder : du texte lui est ajouté par `append({}, ...)`";
+    NOT_A_STRING = "E0226", "'{}' is not a string: it is declared `local {} {}` (only a builder can be used as a string)", "'{}' n'est pas une chaîne : elle est déclarée `local {} {}` (seul un builder peut servir de chaîne)";
+    STATEMENTS_ON_ONE_LINE = "E0227", "a new statement starts on the line of the previous one: put it on its own line or separate the two with `;`", "une nouvelle instruction commence sur la ligne de la précédente : la mettre sur sa propre ligne ou séparer les deux par `;`";
+    MISSING_OPERATOR = "E0228", "expected an operator or the end of the statement, found {} (is an operator missing before it?)", "opérateur ou fin de l'instruction attendu, trouvé {} (manque-t-il un opérateur avant ?)";
+    ASSIGNMENT_WITHOUT_LET = "E0229", "a value is given to '{}' by the statement `let {} = ...`", "une valeur est donnée à '{}' par l'instruction `let {} = ...`";
+
+    // --- code generation
+    UNKNOWN_VARIABLE = "E0301", "unknown variable '{}'{}", "variable inconnue '{}'{}";
+    UNKNOWN_FUNCTION = "E0302", "unknown function '{}'{}", "fonction inconnue '{}'{}";
+    NUMERIC_ONLY = "E0303", "only numeric expressions are supported in `{}`", "seules les expressions numériques sont acceptées dans `{}`";
+    DATA_TOO_LARGE = "E0304", "the constant data ({} bytes) does not fit in the initial memory ({} page(s) of 64 KiB, see --memory-min)", "les données constantes ({} octets) ne tiennent pas dans la mémoire initiale ({} page(s) de 64 Kio, voir --memory-min)";
+    DUPLICATE_FUNCTION = "E0305", "function '{}' is defined twice (first definition: {})", "la fonction '{}' est définie deux fois (première définition : {})";
+    DUPLICATE_VARIABLE = "E0306", "variable '{}' is declared twice in '{}' (first declaration: {})", "la variable '{}' est déclarée deux fois dans '{}' (première déclaration : {})";
+    EXPORT_NAME_TAKEN = "E0307", "cannot export '{}': the module already exports this name", "impossible d'exporter '{}' : le module exporte déjà ce nom";
+    EXTERN_FN_REF = "E0309", "extern fn '{}' takes arguments: it cannot be given to a fn variable (those hold functions without parameters)", "la fonction extern '{}' prend des arguments : elle ne peut pas être donnée à une variable fn (celles-ci contiennent des fonctions sans paramètres)";
+    EXTERN_NAME_TAKEN = "E0308", "extern fn '{}' cannot be imported as {}: the runtime imports this name", "la fonction extern '{}' ne peut pas être importée comme {} : le runtime importe déjà ce nom";
+
+    // --- runtime
+    EXIT_CODE_OUT_OF_RANGE = "E0501", "main returned {}: an exit code is between 0 and 255", "main a renvoyé {} : un code de sortie est compris entre 0 et 255";
+    OUT_OF_MEMORY = "E0502", "out of memory: the program needs more than its maximum of {} bytes (--max-memory, -c --memory-max)", "mémoire épuisée : le programme a besoin de plus que son maximum de {} octets (--max-memory, -c --memory-max)";
+    FUEL_EXHAUSTED = "E0503", "execution budget exceeded: the program used up its {} units of fuel (--fuel)", "budget d'exécution dépassé : le programme a consommé ses {} unités de carburant (--fuel)";
+    TIMED_OUT = "E0504", "execution budget exceeded: the program ran for more than {} s (--timeout)", "budget d'exécution dépassé : le programme a tourné plus de {} s (--timeout)";
+    STACK_OVERFLOW = "E0505", "stack overflow: likely infinite recursion{} (more than {} calls in progress, see --max-call-depth)", "débordement de pile : récursion probablement infinie{} (plus de {} appels en cours, voir --max-call-depth)";
+    CALL_DEPTH_TOO_DEEP = "E0506", "--max-call-depth {} is too deep for wasmtime (at most {} calls; the wasmi engine allows more)", "--max-call-depth {} est trop profond pour wasmtime (au plus {} appels ; le moteur wasmi en permet plus)";
+    INTERRUPTED = "E0507", "interrupted", "interrompu";
+    RUN_STOPPED = "E0508", "stopped: the run timed out or was interrupted", "arrêté : l'exécution a dépassé son temps ou a été interrompue";
+    RUN_LOST = "E0509", "the run stopped unexpectedly", "l'exécution s'est arrêtée de façon inattendue";
+
+    // --- lint (warnings)
+    UNUSED_VARIABLE = "W0401", "variable '{}' is declared but never used", "la variable '{}' est déclarée mais jamais utilisée";
+    UNUSED_FUNCTION = "W0402", "function '{}' is never called", "la fonction '{}' n'est jamais appelée";
+    SHADOWED_LITERAL = "W0403", "the value {} given to '{}' is replaced before it is read", "la valeur {} donnée à '{}' est remplacée avant d'être lue";
+    UNREAD_VARIABLE = "W0404", "variable '{}' is assigned but its value is never read", "la variable '{}' reçoit des valeurs qui ne sont jamais lues";
+    FLOAT_DIVISION = "W0405", "division of floats computed in int (the result goes to an int): the operands are truncated before dividing", "division de décimaux calculée en entier (le résultat va dans un int) : les opérandes sont tronqués avant la division";
+    IMPLICIT_NARROWING = "W0406", "the floats of this expression are truncated to int ({} takes an int)", "les décimaux de cette expression sont tronqués en entier ({} attend un int)";
+    CALL_KEYWORD = "W0407", "`call` is no longer needed: write {}", "`call` n'est plus nécessaire : écrire {}";
+}
+
+// French wording of what the parser expected (grammar symbols stay as they are).
+const EXPECTED_FR: &[(&str, &str)] = &[
+    ("a path string after `import`", "un chemin entre guillemets après `import`"),
+    ("a valid function name after `fn`", "un nom de fonction valide après `fn`"),
+    ("a valid function name after `call`", "un nom de fonction valide après `call`"),
+    ("a valid function name after `.`", "un nom de fonction valide après `.`"),
+    ("a module name after `as`", "un nom de module après `as`"),
+    ("a valid variable name after `for`", "un nom de variable valide après `for`"),
+    ("a valid variable name after `let`", "un nom de variable valide après `let`"),
+    ("a valid variable name after `local type`", "un nom de variable valide après `local type`"),
+    ("an instruction", "une instruction"),
+    ("a string expression", "une expression de chaîne"),
+    ("an expression", "une expression"),
+    ("`==` or `!=` after a string", "`==` ou `!=` après une chaîne"),
+    ("a type (int, float, char, fn, map or builder)", "un type (int, float, char, fn, map ou builder)"),
+    ("a builder variable", "une variable builder"),
+    ("a map variable", "une variable map"),
+    ("a fn variable after `call_indirect`", "une variable fn après `call_indirect`"),
+    ("a function reference (&name) or a fn variable", "une référence de fonction (&nom) ou une variable fn"),
+    ("a function name after `&`", "un nom de fonction après `&`"),
+    ("an enum name after `enum`", "un nom d'enum après `enum`"),
+    ("an enum value name", "un nom de valeur d'enum"),
+    ("a parameter type (int or float)", "un type de paramètre (int ou float)"),
+    ("a parameter name", "un nom de paramètre"),
+    ("a module name string", "un nom de module entre guillemets"),
+    ("a function name string", "un nom de fonction entre guillemets"),
+    ("an int, a char or an enum value after `case`", "un entier, un caractère ou une valeur d'enum après `case`"),
+    ("end of file", "la fin du fichier"),
+];
+
+// `expected` description of a grammar error in the current language
+pub fn expected(en: &'static str) -> &'static str {
+    match lang() {
+        Lang::En => en,
+        Lang::Fr => EXPECTED_FR
+            .iter()
+            .find(|(e, _)| *e == en)
+            .map_or(en, |(_, fr)| fr),
+    }
+}
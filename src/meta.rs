@@ -0,0 +1,166 @@
+// My Programming Language
+// Build metadata: the "mpl.meta" custom section written by the compiler (its version, the
+// sources with their hashes, the build options) and read back by `mpl info`.
+//
+// The section is UTF-8 text, one `key: value` line per entry:
+//
+// compiler: mpl 0.1.0
+// source: main.mpl fnv1a64=9f0c2d4e6a8b1c3d
+// option: strip=false
+//
+// With -c --embed-source the sources go in an "mpl.source" section (uncompressed: a u32 LE
+// count, then per file its path and text, each a u32 LE length and the UTF-8 bytes), and the
+// module keeps the position of the running statement in the exported i64 global "mpl.pos":
+// file index + 1 in bits 48-63, line in bits 24-47, column in bits 0-23 (0 before the first
+// statement). The runner turns it into a source snippet when the program traps.
+
+use std::error::Error;
+use std::fmt::{Display, Write};
+use std::path::Path;
+
+pub const META_SECTION: &str = "mpl.meta";
+pub const SOURCE_SECTION: &str = "mpl.source";
+pub const POS_GLOBAL: &str = "mpl.pos";
+
+const FIELD: u64 = (1 << 24) - 1; // line and column fields of mpl.pos
+
+/// What went into a module, written to its "mpl.meta" section.
+#[derive(Clone, Debug)]
+pub struct BuildInfo {
+    pub compiler: String,
+    pub sources: Vec<(String, u64)>, // path as given, FNV-1a hash of the contents
+    pub options: Vec<(String, String)>,
+}
+
+impl Default for BuildInfo {
+    fn default() -> Self {
+        Self {
+            compiler: format!("mpl {}", env!("CARGO_PKG_VERSION")),
+            sources: Vec::new(),
+            options: Vec::new(),
+        }
+    }
+}
+
+impl BuildInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A source read, with the `hash` of its bytes
+    pub fn source(mut self, path: &Path, hash: u64) -> Self {
+        self.sources.push((path.to_string_lossy().into_owned(), hash));
+        self
+    }
+
+    pub fn option(mut self, key: &str, value: impl Display) -> Self {
+        self.options.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Contents of the section
+    pub fn to_text(&self) -> String {
+        let mut out = format!("compiler: {}\n", self.compiler);
+        for (path, hash) in &self.sources {
+            let _ = writeln!(out, "source: {} fnv1a64={:016x}", path, hash);
+        }
+        for (key, value) in &self.options {
+            let _ = writeln!(out, "option: {}={}", key, value);
+        }
+        out
+    }
+}
+
+// FNV-1a, 64 bits: tells two versions of a source apart (not a cryptographic hash)
+pub const HASH_START: u64 = 0xcbf2_9ce4_8422_2325;
+
+pub fn hash(bytes: &[u8]) -> u64 {
+    hash_more(HASH_START, bytes)
+}
+
+/// The hash `h` of some bytes, continued with `bytes` (a source hashed as it is read)
+pub fn hash_more(h: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(h, |h, &b| (h ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+/// Text of the "mpl.meta" section of a wasm module, None if it has none
+pub fn read(wasm: &[u8]) -> Result<Option<String>, Box<dyn Error>> {
+    let engine = wasmi::Engine::default();
+    let module = wasmi::Module::new(&engine, wasm).map_err(|e| format!("not a wasm module: {}", e))?;
+    let section = module.custom_sections().find(|s| s.name() == META_SECTION);
+    match section {
+        Some(s) => Ok(Some(
+            String::from_utf8(s.data().to_vec()).map_err(|_| format!("the {} section is not UTF-8 text", META_SECTION))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Value of mpl.pos for a statement at `line`:`col` of the `file`-th embedded source
+pub fn encode_pos(file: usize, line: usize, col: usize) -> i64 {
+    let field = |n: usize| (n as u64).min(FIELD);
+    ((((file as u64) + 1) << 48) | (field(line) << 24) | field(col)) as i64
+}
+
+/// (file index, line, column) of a mpl.pos value, None before the first statement
+pub fn decode_pos(pos: i64) -> Option<(usize, usize, usize)> {
+    let pos = pos as u64;
+    let file = (pos >> 48) as usize;
+    (file > 0).then(|| (file - 1, ((pos >> 24) & FIELD) as usize, (pos & FIELD) as usize))
+}
+
+/// Contents of the mpl.source section
+pub fn encode_sources(files: &[(String, String)]) -> Vec<u8> {
+    let mut out = (files.len() as u32).to_le_bytes().to_vec();
+    for (path, text) in files {
+        for bytes in [path.as_bytes(), text.as_bytes()] {
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(bytes);
+        }
+    }
+    out
+}
+
+/// (path, text) of the files of a mpl.source section, None if it is malformed
+pub fn decode_sources(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    let next = |data: &mut &[u8]| -> Option<String> {
+        let (len, rest) = data.split_first_chunk::<4>()?;
+        let len = u32::from_le_bytes(*len) as usize;
+        let text = String::from_utf8(rest.get(..len)?.to_vec()).ok()?;
+        *data = &rest[len..];
+        Some(text)
+    };
+    let (count, rest) = data.split_first_chunk::<4>()?;
+    data = rest;
+    (0..u32::from_le_bytes(*count))
+        .map(|_| Some((next(&mut data)?, next(&mut data)?)))
+        .collect()
+}
+
+/// Contents of the custom section `name` of a wasm binary (whatever the engine running it)
+pub fn custom_section<'a>(wasm: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    // LEB128 u32 at the start of `bytes`, and what follows it
+    fn leb(bytes: &[u8]) -> Option<(usize, &[u8])> {
+        let mut value = 0usize;
+        for (i, &b) in bytes.iter().enumerate().take(5) {
+            value |= ((b & 0x7f) as usize) << (7 * i);
+            if b & 0x80 == 0 {
+                return Some((value, &bytes[i + 1..]));
+            }
+        }
+        None
+    }
+    let mut rest = wasm.get(8..)?; // magic and version
+    while let Some((&id, after)) = rest.split_first() {
+        let (size, after) = leb(after)?;
+        let section = after.get(..size)?;
+        rest = &after[size..];
+        if id == 0 {
+            let (len, contents) = leb(section)?;
+            if contents.get(..len)? == name.as_bytes() {
+                return Some(&contents[len..]);
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,651 @@
+// My Programming Language
+// Module loading: follows the imports of the main file and of every library, depth first.
+// Each file is loaded once (however many files import it), an import cycle is an error,
+// and the libraries come in a deterministic order: every library after the ones it imports,
+// otherwise in the order the imports are written.
+// An import not found next to the importing file is searched in the -I directories, then in MPLPATH.
+// `import "math.mpl" as math` puts the functions of math.mpl in the `math` namespace: they are
+// called as `math.square()` (and by their own name inside math.mpl), and can share names
+// with functions of other files.
+// Only `pub fn` functions can be called from another file; a file without any `pub fn`
+// (written before `pub` existed) exports all its functions.
+// `export fn` is a `pub fn` that the wasm module also exports, under its source name.
+// `extern fn name()` declares a function of the host (imported from env, see runner::HostRegistry):
+// it is called like the functions of its file, and from other files if it is a `pub extern fn`.
+// Calls are resolved once every file is loaded (the order of the definitions does not matter,
+// within a file or across files); a call that resolves nowhere is an error here, not in codegen.
+// The functions of the main file can also call `main()`.
+// Calls go one way: the main file calls the libraries, a library calls its own functions and
+// those of other libraries, never those of the main file (they would not exist in a library
+// compiled on its own).
+// `import "lib.wasm"` links a library compiled on its own (mpl compile --lib): its exported
+// functions are called like those of a source file, and the runner loads lib.wasm from where
+// the program is (the module it is imported from is its path relative to the main file, else
+// its file name).
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::diagnostic::did_you_mean;
+use crate::grammar::{self, Token};
+use crate::lexer::{Lexer, Position, Source};
+use crate::messages;
+use crate::meta;
+use crate::parser::{
+    Expr, ExternFunction, FnExpr, Function, Import, Library, LinkedFunction, MainProgram, ParseError, Parser,
+    Program, Stadment,
+};
+use crate::stats;
+use rayon::prelude::*;
+
+// Resolve an import path against the directory of the importing file.
+pub fn resolve_rel(base_file: &Path, rel: &str) -> PathBuf {
+    let base_dir = base_file.parent().unwrap_or_else(|| Path::new("."));
+    base_dir.join(rel)
+}
+
+/// Directories searched for imports: the `include_dirs` (-I), then those of MPLPATH.
+pub fn search_path(include_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = include_dirs.to_vec();
+    if let Some(mplpath) = env::var_os("MPLPATH") {
+        dirs.extend(env::split_paths(&mplpath).filter(|d| !d.as_os_str().is_empty()));
+    }
+    dirs
+}
+
+/// A parsed program with the library files it was built from.
+pub struct LoadedProgram {
+    pub program: Program,
+    pub libraries: Vec<PathBuf>, // in load order, imported ones first
+    pub hashes: Vec<(PathBuf, u64)>, // every file read, the main one first: meta::hash of its bytes as read
+}
+
+// Same file whatever the path used to reach it
+fn file_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Functions of a file, those it exports marked `public`
+fn exported(mut functions: Vec<Function>, mut externs: Vec<ExternFunction>) -> (Vec<Function>, Vec<ExternFunction>) {
+    if !functions.iter().any(|f| f.public) && !externs.iter().any(|e| e.public) {
+        functions.iter_mut().for_each(|f| f.public = true);
+        externs.iter_mut().for_each(|e| e.public = true);
+    }
+    (functions, externs)
+}
+
+// A loaded file and its namespace
+struct File {
+    path: PathBuf,
+    functions: Vec<Function>,
+    externs: Vec<ExternFunction>,
+    linked: Vec<String>,             // wasm library: its exported functions
+    hash: u64,                       // meta::hash of the bytes read
+    aliases: HashMap<String, usize>, // `import ... as alias` written in the file -> imported file
+    global: bool,                    // imported without `as` somewhere: its functions are called by their name
+    prefix: Option<String>,          // first alias it was imported as
+}
+
+struct Loader<'a> {
+    search_path: &'a [PathBuf],
+    loaded: HashMap<PathBuf, usize>, // key -> index in `files`
+    files: Vec<File>,                // the main program first
+    stack: Vec<(PathBuf, PathBuf)>,  // (key, path as written) of the files being loaded
+    order: Vec<usize>,               // libraries, imported ones first
+    parsed: HashMap<PathBuf, Result<(Library, u64), ParseError>>, // parsed ahead, by path as written
+}
+
+// A source library parsed with the hash of its source, None if it cannot be read
+// (load_library reports it)
+type Parsed = Option<Result<(Library, u64), ParseError>>;
+
+fn parse_library(path: &Path) -> Parsed {
+    let src = Source::open(path).ok()?;
+    Some(parse_source(path, src, Parser::parse_library))
+}
+
+// Parse a whole source with `parse`, hashing it as it is read
+fn parse_source<T>(
+    path: &Path,
+    src: impl Into<Source>,
+    parse: fn(&mut Parser) -> Result<T, ParseError>,
+) -> Result<(T, u64), ParseError> {
+    let mut parser = Parser::new(Lexer::new(path, src))?;
+    let parsed = parse(&mut parser)?;
+    Ok((parsed, parser.source_hash()))
+}
+
+impl Loader<'_> {
+    // The imported file: next to the importer if it is there, else the first one of the search path.
+    fn resolve_import(&self, importer: &Path, rel: &str) -> PathBuf {
+        let local = resolve_rel(importer, rel);
+        if local.exists() {
+            return local;
+        }
+        self.search_path
+            .iter()
+            .map(|dir| dir.join(rel))
+            .find(|p| p.exists())
+            .unwrap_or(local)
+    }
+
+    // Parse the source files `imports` lead to, a wave at a time (the imports of the files of
+    // one wave make the next one), the files of a wave in parallel. The files are then loaded
+    // depth first as before, each taking its parse from `parsed`: the result and the first
+    // error reported are the same as those of a sequential load. For --timings, the lexing of
+    // the workers counts for its share of the time of their wave: lex and parse still add up to
+    // the time of the load.
+    fn parse_ahead(&mut self, mut wave: Vec<PathBuf>) {
+        let mut seen: HashSet<PathBuf> = wave.iter().cloned().collect();
+        while !wave.is_empty() {
+            let start = Instant::now();
+            let parsed: Vec<(PathBuf, Parsed, Duration, Duration)> = wave
+                .into_par_iter()
+                .filter(|path| path.extension().is_none_or(|e| e != "wasm"))
+                .map(|path| {
+                    let start = Instant::now();
+                    let (library, lexing) = stats::time_lexing(|| parse_library(&path));
+                    (path, library, lexing, start.elapsed())
+                })
+                .collect();
+            let lexing: Duration = parsed.iter().map(|(_, _, lexing, _)| *lexing).sum();
+            let busy: Duration = parsed.iter().map(|(_, _, _, busy)| *busy).sum();
+            if !busy.is_zero() {
+                stats::add_lexing(start.elapsed().mul_f64(lexing.as_secs_f64() / busy.as_secs_f64()));
+            }
+            wave = Vec::new();
+            for (path, library, ..) in parsed {
+                let Some(library) = library else { continue };
+                if let Ok((library, _)) = &library {
+                    for import in &library.imports {
+                        let next = self.resolve_import(&path, &import.path);
+                        if seen.insert(next.clone()) {
+                            wave.push(next);
+                        }
+                    }
+                }
+                self.parsed.insert(path, library);
+            }
+        }
+    }
+
+    fn load_imports(&mut self, importer: usize, imports: &[Import]) -> Result<(), Box<dyn Error>> {
+        for import in imports {
+            let path = self.resolve_import(&self.files[importer].path, &import.path);
+            let file = self.load_library(&path, Some(&import.pos))?;
+            match &import.alias {
+                Some(alias) => {
+                    self.files[importer].aliases.insert(alias.clone(), file);
+                    self.files[file].prefix.get_or_insert_with(|| alias.clone());
+                }
+                None => self.files[file].global = true,
+            }
+        }
+        Ok(())
+    }
+
+    // Index of the file in `files`, loaded with its imports if it was not already
+    fn load_library(&mut self, path: &Path, import_pos: Option<&Position>) -> Result<usize, Box<dyn Error>> {
+        let key = file_key(path);
+        if let Some(start) = self.stack.iter().position(|(k, _)| *k == key) {
+            let cycle: Vec<String> = self.stack[start..]
+                .iter()
+                .map(|(_, p)| p.display().to_string())
+                .chain(std::iter::once(path.display().to_string()))
+                .collect();
+            let pos = import_pos.cloned().unwrap_or_else(|| Position::new(path.to_path_buf()));
+            return Err(ParseError::semantic(&messages::IMPORT_CYCLE, &[&cycle.join(" -> ")], &pos).into());
+        }
+        if let Some(&file) = self.loaded.get(&key) {
+            return Ok(file);
+        }
+
+        if path.extension().is_some_and(|e| e == "wasm") {
+            let (linked, hash) = library_exports(path)?;
+            let file = self.files.len();
+            self.files.push(File {
+                path: path.to_path_buf(),
+                functions: Vec::new(),
+                externs: Vec::new(),
+                linked,
+                hash,
+                aliases: HashMap::new(),
+                global: false,
+                prefix: None,
+            });
+            self.loaded.insert(key, file);
+            self.order.push(file);
+            return Ok(file);
+        }
+
+        let (library, hash) = match self.parsed.remove(path) {
+            Some(library) => library?,
+            None => self.read_library(path, import_pos)?,
+        };
+
+        let file = self.files.len();
+        let (functions, externs) = exported(library.functions, library.externs);
+        self.files.push(File {
+            path: path.to_path_buf(),
+            functions,
+            externs,
+            linked: Vec::new(),
+            hash,
+            aliases: HashMap::new(),
+            global: false,
+            prefix: None,
+        });
+        self.loaded.insert(key.clone(), file);
+        self.stack.push((key, path.to_path_buf()));
+        self.load_imports(file, &library.imports)?;
+        self.stack.pop();
+        self.order.push(file);
+        Ok(file)
+    }
+
+    // Parse a source library that was not parsed ahead (it could not be read then)
+    fn read_library(&self, path: &Path, import_pos: Option<&Position>) -> Result<(Library, u64), Box<dyn Error>> {
+        let src = Source::open(path).map_err(|e| {
+            let searched: Vec<String> = self.search_path.iter().map(|d| d.display().to_string()).collect();
+            match import_pos {
+                Some(_) if !searched.is_empty() => format!(
+                    "cannot read '{}': {} (also searched in: {})",
+                    path.display(),
+                    e,
+                    searched.join(", ")
+                ),
+                _ => format!("cannot read '{}': {}", path.display(), e),
+            }
+        })?;
+        Ok(parse_source(path, src, Parser::parse_library)?)
+    }
+}
+
+// Function names once every file is loaded: those of a file only imported with `as`
+// are mangled as "prefix::name" (prefixes made unique), the others keep their name.
+struct Namespaces {
+    paths: Vec<PathBuf>,
+    prefixes: Vec<Option<String>>,      // by file, None for the global namespace
+    defined: Vec<HashSet<String>>,      // function names written in each file
+    public: Vec<HashSet<String>>,       // those callable from other files
+    aliases: Vec<HashMap<String, usize>>,
+    global: HashMap<String, usize>,     // global namespace: name -> file defining it
+    main: bool,                         // the main file has a main() (not a library)
+}
+
+impl Namespaces {
+    fn new(files: &[File], main: bool) -> Self {
+        let mut used = HashSet::new();
+        let prefixes = files
+            .iter()
+            .map(|f| {
+                let alias = f.prefix.as_ref().filter(|_| !f.global)?;
+                let prefix = (1..)
+                    .map(|n| if n == 1 { alias.clone() } else { format!("{}{}", alias, n) })
+                    .find(|p| !used.contains(p))?;
+                used.insert(prefix.clone());
+                Some(prefix)
+            })
+            .collect();
+        let names = |public_only: bool| -> Vec<HashSet<String>> {
+            files
+                .iter()
+                .map(|f| {
+                    f.functions
+                        .iter()
+                        .filter(|func| func.public || !public_only)
+                        .map(|func| func.name.clone())
+                        .chain(f.externs.iter().filter(|e| e.public || !public_only).map(|e| e.name.clone()))
+                        .chain(f.linked.iter().cloned())
+                        .collect()
+                })
+                .collect()
+        };
+        let mut global = HashMap::new();
+        for (file, f) in files.iter().enumerate().filter(|(_, f)| f.global) {
+            let externs = f.externs.iter().map(|e| &e.name);
+            for name in f.functions.iter().map(|func| &func.name).chain(externs).chain(&f.linked) {
+                global.entry(name.clone()).or_insert(file);
+            }
+        }
+        Self {
+            paths: files.iter().map(|f| f.path.clone()).collect(),
+            prefixes,
+            defined: names(false),
+            public: names(true),
+            aliases: files.iter().map(|f| f.aliases.clone()).collect(),
+            global,
+            main,
+        }
+    }
+
+    // `name`, defined in `target`, can be called from `file`
+    fn check_visible(&self, file: usize, target: usize, name: &str, pos: &Position) -> Result<(), ParseError> {
+        if target == file || self.public[target].contains(name) {
+            return Ok(());
+        }
+        let owner = self.paths[target].display().to_string();
+        Err(ParseError::semantic(&messages::PRIVATE_FUNCTION, &[&name, &owner], pos))
+    }
+
+    // `name`, defined in `target`, is not a function of the main program called from a library
+    fn check_direction(&self, file: usize, target: usize, name: &str, pos: &Position) -> Result<(), ParseError> {
+        if target != 0 || file == 0 {
+            return Ok(());
+        }
+        let program = self.paths[0].display().to_string();
+        let library = self.paths[file].display().to_string();
+        Err(ParseError::semantic(&messages::LIBRARY_CALLS_PROGRAM, &[&name, &program, &library], pos))
+    }
+
+    // The functions `file` calls without a module name: its own, and the public ones of the
+    // global namespace
+    fn callable(&self, file: usize) -> impl Iterator<Item = &str> {
+        let global = self
+            .global
+            .iter()
+            .filter(move |&(name, &target)| self.public[target].contains(name))
+            .map(|(name, _)| name.as_str());
+        self.defined[file].iter().map(String::as_str).chain(global)
+    }
+
+    fn mangle(&self, file: usize, name: &str) -> String {
+        match &self.prefixes[file] {
+            Some(prefix) => format!("{}::{}", prefix, name),
+            None => name.to_string(),
+        }
+    }
+
+    // Mangled name of the function called as `module.name` (or `name`) from `file`.
+    // Every file is loaded, so a function can be called before (or after) its definition,
+    // and a name that does not resolve here is unknown.
+    fn resolve(&self, file: usize, name: &str, module: Option<&str>, pos: &Position) -> Result<String, ParseError> {
+        let Some(module) = module else {
+            // the file's own function first, then the global namespace
+            if self.defined[file].contains(name) {
+                return Ok(self.mangle(file, name));
+            }
+            if let Some(&target) = self.global.get(name) {
+                self.check_direction(file, target, name, pos)?;
+                self.check_visible(file, target, name, pos)?;
+                return Ok(name.to_string());
+            }
+            if name == grammar::KW_MAIN && self.main {
+                if file != 0 {
+                    return Err(ParseError::semantic(&messages::MAIN_CALL, &[], pos));
+                }
+                return Ok(name.to_string());
+            }
+            // defined in a module this file imports with `as`: to be called through it
+            let mut aliases: Vec<(&String, &usize)> = self.aliases[file].iter().collect();
+            aliases.sort();
+            if let Some((alias, _)) = aliases.iter().find(|(_, target)| self.defined[**target].contains(name)) {
+                return Err(ParseError::semantic(
+                    &messages::FUNCTION_IN_MODULE,
+                    &[&name, alias, alias, &name],
+                    pos,
+                ));
+            }
+            let hint = did_you_mean(name, self.callable(file));
+            return Err(ParseError::semantic(&messages::UNKNOWN_FUNCTION, &[&name, &hint], pos));
+        };
+        let target = *self.aliases[file]
+            .get(module)
+            .ok_or_else(|| ParseError::semantic(&messages::UNKNOWN_MODULE, &[&module, &module], pos))?;
+        if !self.defined[target].contains(name) {
+            let qualified = format!("{}.{}", module, name);
+            let functions: Vec<String> = self.public[target].iter().map(|f| format!("{}.{}", module, f)).collect();
+            let hint = did_you_mean(&qualified, functions.iter().map(String::as_str));
+            return Err(ParseError::semantic(&messages::UNKNOWN_FUNCTION, &[&qualified, &hint], pos));
+        }
+        self.check_visible(file, target, name, pos)?;
+        Ok(self.mangle(target, name))
+    }
+
+    fn resolve_calls(&self, file: usize, body: &mut [Stadment]) -> Result<(), ParseError> {
+        for st in body {
+            match st {
+                Stadment::Call { name, module, pos, .. } => *name = self.resolve(file, name, module.as_deref(), pos)?,
+                // `let f = &name`: the same names as a call
+                Stadment::Assignment {
+                    expr: Expr::Fn(FnExpr::Ref { name, module, pos }),
+                    ..
+                } => *name = self.resolve(file, name, module.as_deref(), pos)?,
+                Stadment::ForLoop { body, .. } => self.resolve_calls(file, body)?,
+                Stadment::Match { arms, default, .. } => {
+                    for arm in arms {
+                        self.resolve_calls(file, &mut arm.body)?;
+                    }
+                    if let Some(default) = default {
+                        self.resolve_calls(file, default)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_functions(&self, file: usize, functions: &mut [Function]) -> Result<(), ParseError> {
+        for function in functions {
+            self.resolve_calls(file, &mut function.body)?;
+            function.name = self.mangle(file, &function.name);
+        }
+        Ok(())
+    }
+}
+
+// Functions () -> () exported by a wasm library
+fn library_exports(path: &Path) -> Result<(Vec<String>, u64), Box<dyn Error>> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot read '{}': {}", path.display(), e))?;
+    let engine = wasmi::Engine::default();
+    let module = wasmi::Module::new(&engine, &bytes[..])
+        .map_err(|e| format!("'{}' is not a wasm library: {}", path.display(), e))?;
+    let exports = module
+        .exports()
+        .filter(|e| e.ty().func().is_some_and(|f| f.params().is_empty() && f.results().is_empty()))
+        .map(|e| e.name().to_string())
+        .collect();
+    Ok((exports, meta::hash(&bytes)))
+}
+
+/// Parse the main program `src` (read from `src_file`), everything it imports,
+/// then the libraries given on the command line and their imports.
+/// Imports missing next to the importing file are looked for in `search_path` (see search_path()).
+/// Calls are resolved against the namespaces of `import ... as`: `Stadment::Call::name`
+/// becomes the (mangled) name of the called function.
+pub fn load_program(
+    src_file: &Path,
+    src: impl Into<Source>,
+    lib_paths: &[PathBuf],
+    search_path: &[PathBuf],
+) -> Result<LoadedProgram, Box<dyn Error>> {
+    let (main_program, hash) = parse_source(src_file, src, Parser::parse_main_program)?;
+    load(src_file, main_program, hash, lib_paths, search_path)
+}
+
+/// Whether the source `path` has a main function: a program, else a library.
+/// Only the tokens are read: the errors of the file are left to its load.
+pub fn has_main(path: &Path) -> Result<bool, Box<dyn Error>> {
+    let src = Source::open(path).map_err(|e| format!("cannot read '{}': {}", path.display(), e))?;
+    for token in Lexer::new(path, src) {
+        match token {
+            Ok((Token::Main, _)) => return Ok(true),
+            Ok(_) => {}
+            Err(_) => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+/// Same as load_program() for a library compiled on its own (mpl compile --lib): `main_program.main` is None.
+pub fn load_library(
+    src_file: &Path,
+    src: impl Into<Source>,
+    lib_paths: &[PathBuf],
+    search_path: &[PathBuf],
+) -> Result<LoadedProgram, Box<dyn Error>> {
+    let (library, hash) = parse_source(src_file, src, Parser::parse_library)?;
+    let main_program = MainProgram {
+        imports: library.imports,
+        functions: library.functions,
+        externs: library.externs,
+        main: None,
+    };
+    load(src_file, main_program, hash, lib_paths, search_path)
+}
+
+fn load(
+    src_file: &Path,
+    mut main_program: MainProgram,
+    hash: u64,
+    lib_paths: &[PathBuf],
+    search_path: &[PathBuf],
+) -> Result<LoadedProgram, Box<dyn Error>> {
+    let mut loader = Loader {
+        search_path,
+        loaded: HashMap::new(),
+        files: Vec::new(),
+        stack: vec![(file_key(src_file), src_file.to_path_buf())],
+        order: Vec::new(),
+        parsed: HashMap::new(),
+    };
+    let (functions, externs) = exported(main_program.functions.drain(..).collect(), main_program.externs.clone());
+    loader.files.push(File {
+        path: src_file.to_path_buf(),
+        functions,
+        externs,
+        linked: Vec::new(),
+        hash,
+        aliases: HashMap::new(),
+        global: true,
+        prefix: None,
+    });
+    loader.loaded.insert(file_key(src_file), 0);
+    let imports = main_program.imports.iter().map(|i| loader.resolve_import(src_file, &i.path));
+    let ahead = imports.chain(lib_paths.iter().cloned()).collect();
+    loader.parse_ahead(ahead);
+    loader.load_imports(0, &main_program.imports)?;
+    // Extra libraries (paths relative to the working directory)
+    for lib in lib_paths {
+        let file = loader.load_library(lib, None)?;
+        loader.files[file].global = true;
+    }
+
+    // Every file is loaded before any call is resolved
+    let namespaces = Namespaces::new(&loader.files, main_program.main.is_some());
+    if let Some(main) = &mut main_program.main {
+        namespaces.resolve_calls(0, &mut main.body)?;
+    }
+    for (file, f) in loader.files.iter_mut().enumerate() {
+        namespaces.resolve_functions(file, &mut f.functions)?;
+    }
+    main_program.functions = std::mem::take(&mut loader.files[0].functions);
+    let mut externs = Vec::new();
+    for (file, f) in loader.files.iter_mut().enumerate() {
+        externs.extend(f.externs.drain(..).map(|e| ExternFunction {
+            name: namespaces.mangle(file, &e.name),
+            ..e
+        }));
+    }
+    let mut functions = Vec::new();
+    let mut linked = Vec::new();
+    let mut libraries = Vec::new();
+    let mut hashes = vec![(src_file.to_path_buf(), loader.files[0].hash)];
+    let main_dir = src_file.parent().unwrap_or_else(|| Path::new(""));
+    for &file in &loader.order {
+        let f = &mut loader.files[file];
+        functions.append(&mut f.functions);
+        let module = match f.path.strip_prefix(main_dir) {
+            Ok(rel) => rel.to_string_lossy().replace('\\', "/"),
+            Err(_) => f.path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        };
+        linked.extend(f.linked.iter().map(|field| LinkedFunction {
+            name: namespaces.mangle(file, field),
+            module: module.clone(),
+            field: field.clone(),
+        }));
+        libraries.push(f.path.clone());
+        hashes.push((f.path.clone(), f.hash));
+    }
+
+    Ok(LoadedProgram {
+        program: Program {
+            main_program,
+            functions,
+            linked,
+            externs,
+        },
+        libraries,
+        hashes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn a_streamed_source_is_hashed_as_it_is_read() {
+        // larger than what the lexer keeps in memory, so its start is dropped before the end
+        let mut text = "// a comment line to make the source long\n".repeat(4000);
+        text.push_str("main() {\n    println(\"hash\")\n}\n");
+        let src = Source::Reader(Box::new(Cursor::new(text.clone().into_bytes())));
+        let loaded = load_program(Path::new("long.mpl"), src, &[], &[]).expect("the program loads");
+        assert_eq!(loaded.hashes, vec![(PathBuf::from("long.mpl"), meta::hash(text.as_bytes()))]);
+    }
+
+    // `main` loaded as main.mpl of a directory holding `files` (name, text)
+    fn load_project(name: &str, files: &[(&str, &str)], main: &str) -> Result<LoadedProgram, Box<dyn Error>> {
+        let dir = env::temp_dir().join(format!("mpl-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, text) in files {
+            fs::write(dir.join(file), text).unwrap();
+        }
+        let loaded = load_program(&dir.join("main.mpl"), main, &[], &[]);
+        fs::remove_dir_all(&dir).unwrap();
+        loaded
+    }
+
+    // The header and the code of the error loading a program
+    fn error_codes(loaded: Result<LoadedProgram, Box<dyn Error>>) -> (&'static str, &'static str) {
+        let Err(e) = loaded else { panic!("the program is rejected") };
+        let diagnostic = e.downcast_ref::<ParseError>().expect("a ParseError").diagnostic();
+        (diagnostic.kind.code, diagnostic.code)
+    }
+
+    const LIB: &str = "pub fn hello() {\n    helper()\n}\nfn helper() {\n    println(\"hello\")\n}\n";
+
+    #[test]
+    fn an_import_cycle_is_a_semantic_error() {
+        let files = [("a.mpl", "import \"b.mpl\"\npub fn a() {\n}\n"), ("b.mpl", "import \"a.mpl\"\npub fn b() {\n}\n")];
+        let loaded = load_project("cycle", &files, "import \"a.mpl\"\nmain() {\n    a()\n}\n");
+        assert_eq!(error_codes(loaded), (messages::SEMANTIC_ERROR.code, messages::IMPORT_CYCLE.code));
+    }
+
+    #[test]
+    fn the_public_functions_of_an_import_are_called() {
+        let loaded = load_project("public", &[("lib.mpl", LIB)], "import \"lib.mpl\"\nmain() {\n    hello()\n}\n");
+        assert!(loaded.is_ok(), "{:?}", loaded.err());
+    }
+
+    #[test]
+    fn a_private_function_is_not_called_from_another_file() {
+        let loaded = load_project("private", &[("lib.mpl", LIB)], "import \"lib.mpl\"\nmain() {\n    helper()\n}\n");
+        assert_eq!(error_codes(loaded), (messages::SEMANTIC_ERROR.code, messages::PRIVATE_FUNCTION.code));
+    }
+
+    #[test]
+    fn the_functions_of_an_aliased_import_are_called_through_the_alias() {
+        let main = |call: &str| format!("import \"lib.mpl\" as l\nmain() {{\n    {}()\n}}\n", call);
+        let loaded = load_project("alias", &[("lib.mpl", LIB)], &main("l.hello"));
+        assert!(loaded.is_ok(), "{:?}", loaded.err());
+        let loaded = load_project("unaliased", &[("lib.mpl", LIB)], &main("hello"));
+        assert_eq!(error_codes(loaded), (messages::SEMANTIC_ERROR.code, messages::FUNCTION_IN_MODULE.code));
+    }
+}
@@ -0,0 +1,1033 @@
+// My Programming Language
+// AST optimization passes, run by the code generator before emitting (see CompileOptions).
+//
+// -O0  nothing: the code follows the source
+// -O1  inlining of the small functions, constant folding, merging of adjacent string literals,
+//      removal of the statements after a `return`; the emitted instructions then go through the
+//      peephole pass (peephole.rs)
+// -O2  -O1, then constant and copy propagation inside a function, reuse of the subexpressions
+//      computed twice in a run of statements, and removal of the functions that cannot be called
+//
+// An operator is computed in the type of where its value goes (`let f = 7 / 2` stores 3.5 in
+// a float), so folding follows the same typing as codegen: a folded constant keeps the
+// inferred type of the expression it replaces and gives the same value in its context.
+// Whatever traps at run time (division by zero, float out of the int range) is not folded.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::codegen::{Ty, infer_type};
+use crate::lexer::Position;
+use crate::grammar::MathFn;
+use crate::parser::{BinOp, Expr, FnExpr, Function, NumExpr, Program, Stadment, StrExpr, Variable};
+use crate::visit::{
+    MutVisitor, Visitor, walk_body, walk_body_mut, walk_num_expr, walk_num_expr_mut, walk_stadment, walk_stadment_mut,
+};
+
+/// How much the program is optimized (-O).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::O0),
+            "1" => Ok(Self::O1),
+            "2" => Ok(Self::O2),
+            _ => Err(format!("unknown optimization level '{}' (0, 1 or 2)", s)),
+        }
+    }
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::O0 => "0",
+            Self::O1 => "1",
+            Self::O2 => "2",
+        })
+    }
+}
+
+/// Optimize `prog` in place at `level`.
+pub fn optimize(prog: &mut Program, level: OptLevel) {
+    if level == OptLevel::O0 {
+        return;
+    }
+    inline_small_functions(prog);
+    if level >= OptLevel::O2 {
+        remove_unused_functions(prog);
+    }
+    let functions = prog
+        .functions
+        .iter_mut()
+        .chain(&mut prog.main_program.functions)
+        .chain(&mut prog.main_program.main);
+    for f in functions {
+        if level >= OptLevel::O2 {
+            propagate(&mut f.body, &mut HashMap::new());
+            reuse_subexpressions(&mut f.body, &mut f.variables, &mut 0);
+        }
+        simplify(&mut f.body);
+    }
+}
+
+// --- constant folding
+
+#[derive(Clone, Copy)]
+enum Value {
+    Int(i32),
+    Float(f64),
+}
+
+// Value of a constant expression computed as `target`, None if it is not constant or traps
+fn eval(e: &NumExpr, target: Ty) -> Option<Value> {
+    match (e, target) {
+        (NumExpr::Int(i), Ty::I32) => Some(Value::Int(*i)),
+        (NumExpr::Int(i), Ty::F64) => Some(Value::Float(*i as f64)),
+        (NumExpr::Float(r), Ty::F64) => Some(Value::Float(*r)),
+        (NumExpr::Char(c), Ty::I32) => Some(Value::Int(*c as i32)),
+        (NumExpr::Char(c), Ty::F64) => Some(Value::Float(*c as u32 as f64)),
+        // i32.trunc_f64_s traps on NaN and out of range
+        (NumExpr::Float(r), Ty::I32) => {
+            let t = r.trunc();
+            (t >= i32::MIN as f64 && t <= i32::MAX as f64).then_some(Value::Int(t as i32))
+        }
+        (NumExpr::Neg(inner), _) => match eval(inner, target)? {
+            Value::Int(i) => Some(Value::Int(0i32.wrapping_sub(i))),
+            Value::Float(r) => Some(Value::Float(-r)),
+        },
+        (NumExpr::Binary { op, left, right }, _) => match (eval(left, target)?, eval(right, target)?) {
+            (Value::Int(a), Value::Int(b)) => match op {
+                BinOp::Add => Some(Value::Int(a.wrapping_add(b))),
+                BinOp::Sub => Some(Value::Int(a.wrapping_sub(b))),
+                BinOp::Mul => Some(Value::Int(a.wrapping_mul(b))),
+                // i32.div_s traps on 0 and on MIN / -1
+                BinOp::Div => a.checked_div(b).map(Value::Int),
+            },
+            (Value::Float(a), Value::Float(b)) => Some(Value::Float(match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => a / b,
+            })),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Constant of type `ty` giving `value` when computed as the target type `value` is in
+fn constant(value: Value, ty: Ty) -> Option<NumExpr> {
+    match (value, ty) {
+        (Value::Int(i), Ty::I32) => Some(NumExpr::Int(i)),
+        (Value::Float(r), Ty::F64) => Some(NumExpr::Float(r)),
+        (Value::Int(i), Ty::F64) => Some(NumExpr::Float(i as f64)),
+        // an int expression in a float context: only if an int gives the same float
+        (Value::Float(r), Ty::I32) => {
+            let exact = r.fract() == 0.0 && r >= i32::MIN as f64 && r <= i32::MAX as f64;
+            (exact && !(r == 0.0 && r.is_sign_negative())).then_some(NumExpr::Int(r as i32))
+        }
+        // a fn or map variable is never given a number; a char keeps its literal
+        (_, Ty::Char | Ty::Fn | Ty::Map | Ty::Builder) => None,
+    }
+}
+
+fn fold(e: &mut NumExpr, target: Ty) {
+    if let NumExpr::Math { func, .. } = *e {
+        // the argument types of codegen's gen_math
+        let ty = match func {
+            MathFn::Sqrt | MathFn::Pow | MathFn::Sin | MathFn::Cos | MathFn::Tan | MathFn::Log | MathFn::Exp => Ty::F64,
+            _ => infer_type(e),
+        };
+        if let NumExpr::Math { args, .. } = e {
+            args.iter_mut().for_each(|a| fold(a, ty));
+        }
+        return;
+    }
+    match e {
+        NumExpr::Int(_) | NumExpr::Float(_) | NumExpr::Char(_) => return,
+        NumExpr::Neg(inner) => fold(inner, target),
+        NumExpr::Ord(inner) | NumExpr::Chr(inner) => {
+            fold(inner, Ty::I32);
+            return;
+        }
+        NumExpr::CharAt { s, index } => {
+            fold_str(s);
+            fold(index, Ty::I32);
+            return;
+        }
+        NumExpr::Binary { left, right, .. } => {
+            fold(left, target);
+            fold(right, target);
+        }
+        NumExpr::RandomInt { lo, hi } => {
+            fold(lo, Ty::I32);
+            fold(hi, Ty::I32);
+        }
+        NumExpr::Len(s) | NumExpr::ToInt(s) | NumExpr::ToFloat(s) => fold_str(s),
+        NumExpr::StrEq { left, right, .. } => {
+            fold_str(left);
+            fold_str(right);
+        }
+        NumExpr::MapGet { key, .. } | NumExpr::MapHas { key, .. } => {
+            fold_str(key);
+            return;
+        }
+        NumExpr::Var { .. } | NumExpr::ArgCount | NumExpr::Random | NumExpr::Math { .. } => return,
+    }
+    if let Some(c) = eval(e, target).and_then(|v| constant(v, infer_type(e))) {
+        *e = c;
+    }
+}
+
+fn fold_str(e: &mut StrExpr) {
+    match e {
+        StrExpr::NumToStr(n) => fold(n, infer_type(n)),
+        StrExpr::Arg(n) => fold(n, Ty::I32),
+        StrExpr::ToFixed { n, decimals } => {
+            fold(n, Ty::F64);
+            fold(decimals, Ty::I32);
+        }
+        StrExpr::Thousands { n, decimals } => {
+            fold(n, Ty::F64);
+            if let Some(decimals) = decimals {
+                fold(decimals, Ty::I32);
+            }
+        }
+        StrExpr::DecimalComma(s) => fold_str(s),
+        StrExpr::Substr { s, start, len } => {
+            fold_str(s);
+            fold(start, Ty::I32);
+            fold(len, Ty::I32);
+        }
+        StrExpr::CharAt { s, index } => {
+            fold_str(s);
+            fold(index, Ty::I32);
+        }
+        StrExpr::Repeat { s, count } => {
+            fold_str(s);
+            fold(count, Ty::I32);
+        }
+        StrExpr::Join { sep, parts } => {
+            fold_str(sep);
+            parts.iter_mut().for_each(fold_str);
+        }
+        StrExpr::Str(_) | StrExpr::Nl | StrExpr::Builder { .. } => {}
+    }
+}
+
+fn fold_expr(e: &mut Expr, target: Ty) {
+    match e {
+        Expr::Num(n) => fold(n, target),
+        Expr::Str(s) => fold_str(s),
+        Expr::Fn(_) => {}
+    }
+}
+
+// --- statements
+
+// Folding, string literals merged, nothing after a `return`
+fn simplify(body: &mut Vec<Stadment>) {
+    if let Some(ret) = body.iter().position(|st| matches!(st, Stadment::Return { .. })) {
+        body.truncate(ret + 1);
+    }
+    for st in body.iter_mut() {
+        match st {
+            Stadment::Print { items, .. }
+            | Stadment::Println { items, .. }
+            | Stadment::EPrint { items, .. }
+            | Stadment::EPrintln { items, .. }
+            | Stadment::Append { items, .. } => {
+                items.iter_mut().for_each(fold_str);
+                merge_literals(items);
+            }
+            Stadment::Assignment { var, expr, .. } => fold_expr(expr, var.ty),
+            Stadment::ForLoop {
+                var,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                fold_expr(start, var.ty);
+                fold_expr(end, var.ty);
+                if let Some(step) = step {
+                    fold_expr(step, var.ty);
+                }
+                simplify(body);
+            }
+            Stadment::Return { expr, .. } => fold_expr(expr, Ty::I32),
+            Stadment::MapSet { key, value, .. } => {
+                fold_str(key);
+                fold(value, Ty::I32);
+            }
+            Stadment::MapDelete { key, .. } => fold_str(key),
+            Stadment::Match { value, arms, default, .. } => {
+                fold(value, Ty::I32);
+                arms.iter_mut().for_each(|arm| simplify(&mut arm.body));
+                if let Some(default) = default {
+                    simplify(default);
+                }
+            }
+            Stadment::DumpHeap { region, .. } => {
+                if let Some((start, len)) = region {
+                    fold(start, Ty::I32);
+                    fold(len, Ty::I32);
+                }
+            }
+            // the arguments of an extern fn are computed in the types of its parameters,
+            // not known here
+            Stadment::Call { .. } | Stadment::CallIndirect { .. } | Stadment::Clear { .. } | Stadment::Flush => {}
+        }
+    }
+}
+
+// "a", "b", nl -> "ab\n"; to_str of an int constant is its text
+fn merge_literals(items: &mut Vec<StrExpr>) {
+    let mut merged: Vec<StrExpr> = Vec::with_capacity(items.len());
+    for item in items.drain(..) {
+        let text = match &item {
+            StrExpr::Str(s) => Some(s.clone()),
+            StrExpr::Nl => Some("\n".to_string()),
+            StrExpr::NumToStr(n) => match **n {
+                NumExpr::Int(i) => Some(i.to_string()),
+                _ => None,
+            },
+            _ => None,
+        };
+        match (text, merged.last_mut()) {
+            (Some(text), Some(StrExpr::Str(last))) => last.push_str(&text),
+            (Some(text), _) => merged.push(StrExpr::Str(text)),
+            (None, _) => merged.push(item),
+        }
+    }
+    *items = merged;
+}
+
+// --- constant and copy propagation
+
+// What a variable is known to hold: a constant of its type, or the value of another variable
+type Facts = HashMap<String, NumExpr>;
+
+// Forget `name` and the copies of it
+fn kill(facts: &mut Facts, name: &str) {
+    facts.remove(name);
+    facts.retain(|_, v| !matches!(v, NumExpr::Var { var, .. } if var.name == name));
+}
+
+// Variables assigned by statements (loop variables included)
+struct Assigned<'a>(&'a mut HashSet<String>);
+
+impl Visitor for Assigned<'_> {
+    fn visit_stadment(&mut self, st: &Stadment) {
+        if let Stadment::Assignment { var, .. } | Stadment::ForLoop { var, .. } = st {
+            self.0.insert(var.name.clone());
+        }
+        walk_stadment(self, st);
+    }
+}
+
+fn propagate(body: &mut [Stadment], facts: &mut Facts) {
+    for st in body.iter_mut() {
+        match st {
+            Stadment::Print { items, .. }
+            | Stadment::Println { items, .. }
+            | Stadment::EPrint { items, .. }
+            | Stadment::EPrintln { items, .. }
+            | Stadment::Append { items, .. } => {
+                items.iter_mut().for_each(|s| Substitute(facts).visit_str_expr_mut(s));
+            }
+            Stadment::Assignment { var, expr, .. } => {
+                Substitute(facts).visit_expr_mut(expr);
+                fold_expr(expr, var.ty);
+                kill(facts, &var.name);
+                let known = match expr {
+                    Expr::Num(src @ NumExpr::Var { .. }) => match src {
+                        NumExpr::Var { var: v, .. } if v.ty == var.ty && v.name != var.name => Some(src.clone()),
+                        _ => None,
+                    },
+                    Expr::Num(n) => eval(n, var.ty).and_then(|v| constant(v, var.ty)),
+                    Expr::Str(_) | Expr::Fn(_) => None,
+                };
+                if let Some(known) = known {
+                    facts.insert(var.name.clone(), known);
+                }
+            }
+            Stadment::ForLoop {
+                var,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                // start, end and step are computed once, before the loop
+                Substitute(facts).visit_expr_mut(start);
+                Substitute(facts).visit_expr_mut(end);
+                if let Some(step) = step {
+                    Substitute(facts).visit_expr_mut(step);
+                }
+                let mut changed = HashSet::from([var.name.clone()]);
+                walk_body(&mut Assigned(&mut changed), body);
+                changed.iter().for_each(|name| kill(facts, name));
+                // facts found in the body only hold until the end of an iteration
+                propagate(body, &mut facts.clone());
+            }
+            Stadment::Return { expr, .. } => Substitute(facts).visit_expr_mut(expr),
+            Stadment::MapSet { key, value, .. } => {
+                Substitute(facts).visit_str_expr_mut(key);
+                Substitute(facts).visit_num_expr_mut(value);
+                fold_str(key);
+                fold(value, Ty::I32);
+            }
+            Stadment::MapDelete { key, .. } => {
+                Substitute(facts).visit_str_expr_mut(key);
+                fold_str(key);
+            }
+            Stadment::Match { value, arms, default, .. } => {
+                Substitute(facts).visit_num_expr_mut(value);
+                // each case starts from what is known before the match, and what one of them
+                // assigns is unknown after it
+                let mut changed = HashSet::new();
+                let bodies = arms.iter_mut().map(|arm| &mut arm.body).chain(default.as_mut());
+                for body in bodies {
+                    walk_body(&mut Assigned(&mut changed), body);
+                    propagate(body, &mut facts.clone());
+                }
+                changed.iter().for_each(|name| kill(facts, name));
+            }
+            Stadment::DumpHeap { region, .. } => {
+                if let Some((start, len)) = region {
+                    Substitute(facts).visit_num_expr_mut(start);
+                    Substitute(facts).visit_num_expr_mut(len);
+                }
+            }
+            Stadment::Call { args, .. } => args.iter_mut().for_each(|arg| Substitute(facts).visit_num_expr_mut(arg)),
+            Stadment::CallIndirect { .. } | Stadment::Clear { .. } | Stadment::Flush => {}
+        }
+    }
+}
+
+// Replaces the variables with a known value by it
+struct Substitute<'a>(&'a Facts);
+
+impl MutVisitor for Substitute<'_> {
+    fn visit_num_expr_mut(&mut self, e: &mut NumExpr) {
+        if let NumExpr::Var { var, pos } = e {
+            if let Some(known) = self.0.get(&var.name) {
+                *e = match known {
+                    // keep the position of the use
+                    NumExpr::Var { var, .. } => NumExpr::Var {
+                        var: var.clone(),
+                        pos: pos.clone(),
+                    },
+                    c => c.clone(),
+                };
+            }
+            return;
+        }
+        walk_num_expr_mut(self, e);
+    }
+}
+
+// --- common subexpressions
+
+// An operation computed twice in a basic block (a run of statements without loop nor match)
+// with the same values is computed once into a local, `cse.N` (no name of the source has a
+// dot), assigned right before the statement of its first use; the others read the local.
+// Only what cannot trap is moved: no int division, no float truncated to an int.
+fn reuse_subexpressions(body: &mut Vec<Stadment>, variables: &mut Vec<Variable>, temps: &mut usize) {
+    let mut start = 0;
+    while start < body.len() {
+        let end = body[start..]
+            .iter()
+            .position(|st| matches!(st, Stadment::ForLoop { .. } | Stadment::Match { .. }))
+            .map_or(body.len(), |i| start + i);
+        let inserted = reuse_in_block(body, start..end, variables, temps);
+        start = end + inserted;
+        match body.get_mut(start) {
+            Some(Stadment::ForLoop { body, .. }) => reuse_subexpressions(body, variables, temps),
+            Some(Stadment::Match { arms, default, .. }) => {
+                for body in arms.iter_mut().map(|arm| &mut arm.body).chain(default.as_mut()) {
+                    reuse_subexpressions(body, variables, temps);
+                }
+            }
+            _ => {}
+        }
+        start += 1;
+    }
+}
+
+// An operation of a basic block: its text, computed in `ty`, with the values of the variables
+// it reads (their number of assignments so far)
+#[derive(Default)]
+struct Block {
+    assignments: HashMap<String, usize>,
+    all: HashMap<String, usize>, // uses of each operation
+    top: HashMap<String, usize>, // uses outside the operations used twice
+    temps: HashMap<String, Variable>,
+}
+
+impl Block {
+    fn id(&self, e: &NumExpr, ty: Ty) -> Option<String> {
+        let NumExpr::Binary { .. } = e else {
+            return None;
+        };
+        let mut reads = Vec::new();
+        let text = pure_text(e, ty, &mut reads)?;
+        if reads.is_empty() {
+            return None; // a constant, folded
+        }
+        let values: Vec<String> = reads
+            .iter()
+            .map(|name| self.assignments.get(*name).copied().unwrap_or(0).to_string())
+            .collect();
+        Some(format!("{:?} {} @{}", ty, text, values.join(",")))
+    }
+
+    fn count_all(&mut self, e: &NumExpr, ty: Ty) {
+        if let Some(id) = self.id(e, ty) {
+            *self.all.entry(id).or_default() += 1;
+        }
+        for_operands(e, ty, |operand, ty| self.count_all(operand, ty));
+    }
+
+    fn count_top(&mut self, e: &NumExpr, ty: Ty) {
+        if let Some(id) = self.id(e, ty) {
+            *self.top.entry(id.clone()).or_default() += 1;
+            if self.all[&id] > 1 {
+                return;
+            }
+        }
+        for_operands(e, ty, |operand, ty| self.count_top(operand, ty));
+    }
+
+    // Replace the operations used twice by their local, adding the assignments of the new
+    // locals to `defs`
+    fn replace(&mut self, e: &mut NumExpr, ty: Ty, pos: &Position, defs: &mut Vec<Stadment>, new: &mut NewTemps) {
+        if let Some(id) = self.id(e, ty) {
+            if self.top[&id] > 1 {
+                let var = match self.temps.get(&id) {
+                    Some(var) => var.clone(),
+                    None => {
+                        *new.count += 1;
+                        let var = Variable {
+                            name: format!("cse.{}", new.count),
+                            ty: if ty == Ty::F64 { Ty::F64 } else { Ty::I32 },
+                            pos: pos.clone(),
+                        };
+                        new.variables.push(var.clone());
+                        defs.push(Stadment::Assignment {
+                            var: var.clone(),
+                            expr: Expr::Num(e.clone()),
+                            pos: pos.clone(),
+                        });
+                        self.temps.insert(id, var.clone());
+                        var
+                    }
+                };
+                *e = NumExpr::Var { var, pos: pos.clone() };
+                return;
+            }
+            if self.all[&id] > 1 {
+                return;
+            }
+        }
+        match e {
+            NumExpr::Binary { left, right, .. } => {
+                self.replace(left, ty, pos, defs, new);
+                self.replace(right, ty, pos, defs, new);
+            }
+            NumExpr::Neg(inner) => self.replace(inner, neg_type(ty), pos, defs, new),
+            _ => {}
+        }
+    }
+
+    fn assigned(&mut self, st: &Stadment) {
+        if let Stadment::Assignment { var, .. } = st {
+            *self.assignments.entry(var.name.clone()).or_default() += 1;
+        }
+    }
+}
+
+// The locals made for a function
+struct NewTemps<'a> {
+    variables: &'a mut Vec<Variable>,
+    count: &'a mut usize,
+}
+
+// The operations of the statements `range` of `body` reused; returns the number of statements
+// inserted (the assignments of the new locals)
+fn reuse_in_block(
+    body: &mut Vec<Stadment>,
+    range: std::ops::Range<usize>,
+    variables: &mut Vec<Variable>,
+    temps: &mut usize,
+) -> usize {
+    let mut block = Block::default();
+    for st in &mut body[range.clone()] {
+        for (e, ty) in roots(st) {
+            block.count_all(e, ty);
+        }
+        block.assigned(st);
+    }
+    block.assignments.clear();
+    for st in &mut body[range.clone()] {
+        for (e, ty) in roots(st) {
+            block.count_top(e, ty);
+        }
+        block.assigned(st);
+    }
+    block.assignments.clear();
+    let mut new = NewTemps { variables, count: temps };
+    let mut rewritten = Vec::new();
+    for mut st in body.drain(range.clone()) {
+        let mut defs = Vec::new();
+        if let Some(pos) = st.pos().cloned() {
+            for (e, ty) in roots(&mut st) {
+                block.replace(e, ty, &pos, &mut defs, &mut new);
+            }
+        }
+        block.assigned(&st);
+        rewritten.extend(defs);
+        rewritten.push(st);
+    }
+    let inserted = rewritten.len() - range.len();
+    body.splice(range.start..range.start, rewritten);
+    inserted
+}
+
+// The expressions of a statement computed as numbers, with the type they are computed in
+fn roots(st: &mut Stadment) -> Vec<(&mut NumExpr, Ty)> {
+    fn numbers(items: &mut [StrExpr]) -> Vec<(&mut NumExpr, Ty)> {
+        items
+            .iter_mut()
+            .filter_map(|item| match item {
+                StrExpr::NumToStr(n) => {
+                    let ty = infer_type(n);
+                    Some((&mut **n, ty))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+    match st {
+        Stadment::Print { items, .. }
+        | Stadment::Println { items, .. }
+        | Stadment::EPrint { items, .. }
+        | Stadment::EPrintln { items, .. }
+        | Stadment::Append { items, .. } => numbers(items),
+        Stadment::Assignment {
+            var,
+            expr: Expr::Num(e),
+            ..
+        } => vec![(e, var.ty)],
+        Stadment::Return { expr: Expr::Num(e), .. } => vec![(e, Ty::I32)],
+        Stadment::MapSet { value, .. } => vec![(value, Ty::I32)],
+        _ => Vec::new(),
+    }
+}
+
+// The type the operand of a `-` is computed in
+fn neg_type(ty: Ty) -> Ty {
+    if ty == Ty::F64 { Ty::F64 } else { Ty::I32 }
+}
+
+// Call `f` on the operands of an operation, with the type they are computed in
+fn for_operands(e: &NumExpr, ty: Ty, mut f: impl FnMut(&NumExpr, Ty)) {
+    match e {
+        NumExpr::Binary { left, right, .. } => {
+            f(left, ty);
+            f(right, ty);
+        }
+        NumExpr::Neg(inner) => f(inner, neg_type(ty)),
+        _ => {}
+    }
+}
+
+// The text of an expression computed in `ty` made of operators, variables and constants that
+// cannot trap, with the variables it reads; None for anything else
+fn pure_text<'a>(e: &'a NumExpr, ty: Ty, reads: &mut Vec<&'a str>) -> Option<String> {
+    let float = ty == Ty::F64;
+    match e {
+        NumExpr::Int(i) => Some(i.to_string()),
+        NumExpr::Float(r) if float => Some(format!("{:?}", r)),
+        NumExpr::Char(c) => Some((*c as u32).to_string()),
+        NumExpr::Var { var, .. } if float || var.ty != Ty::F64 => {
+            reads.push(&var.name);
+            Some(var.name.clone())
+        }
+        NumExpr::Neg(inner) => Some(format!("-({})", pure_text(inner, neg_type(ty), reads)?)),
+        NumExpr::Binary { op, left, right } if float || *op != BinOp::Div => Some(format!(
+            "({} {:?} {})",
+            pure_text(left, ty, reads)?,
+            op,
+            pure_text(right, ty, reads)?
+        )),
+        _ => None,
+    }
+}
+
+// --- inlining
+
+// Most statements (those of the loops and the cases counted) of a function inlined at its calls
+const INLINE_MAX_STATEMENTS: usize = 8;
+
+// Replace the calls of the small functions by their bodies, which saves the call and lets the
+// other passes work across it. The locals of the function become locals of the caller, named
+// `function.local` (no name of the source has a dot), and are reset to 0 where the body starts
+// as they would be by a call. Main, the recursive functions and those with fn, map or builder
+// locals keep their calls.
+fn inline_small_functions(prog: &mut Program) {
+    let functions: HashMap<String, Function> = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .map(|f| (f.name.clone(), f.clone()))
+        .collect();
+    let recursive = recursive_functions(&functions);
+    let mut inliner = Inliner {
+        functions,
+        recursive,
+        inlined: HashMap::new(),
+    };
+    let all = prog
+        .functions
+        .iter_mut()
+        .chain(&mut prog.main_program.functions)
+        .chain(&mut prog.main_program.main);
+    for f in all {
+        inliner.inline_calls(&mut f.body, &mut f.variables);
+    }
+}
+
+// Names of the functions a function calls
+struct Callees(Vec<String>);
+
+impl Visitor for Callees {
+    fn visit_stadment(&mut self, st: &Stadment) {
+        if let Stadment::Call { name, .. } = st {
+            self.0.push(name.clone());
+        }
+        walk_stadment(self, st);
+    }
+}
+
+// The functions calling themselves, directly or through others
+fn recursive_functions(functions: &HashMap<String, Function>) -> HashSet<String> {
+    let callees: HashMap<&str, Vec<String>> = functions
+        .iter()
+        .map(|(name, f)| {
+            let mut callees = Callees(Vec::new());
+            callees.visit_function(f);
+            (name.as_str(), callees.0)
+        })
+        .collect();
+    let reaches_itself = |start: &str| {
+        let mut seen = HashSet::new();
+        let mut pending: Vec<&str> = vec![start];
+        while let Some(name) = pending.pop() {
+            for callee in callees.get(name).into_iter().flatten() {
+                if callee == start {
+                    return true;
+                }
+                if seen.insert(callee.as_str()) {
+                    pending.push(callee);
+                }
+            }
+        }
+        false
+    };
+    functions.keys().filter(|name| reaches_itself(name)).cloned().collect()
+}
+
+struct Inliner {
+    functions: HashMap<String, Function>,
+    recursive: HashSet<String>,
+    inlined: HashMap<String, Option<Function>>, // the functions as inlined (their own calls inlined), None if not
+}
+
+impl Inliner {
+    // The function `name` with its calls inlined, if it is inlined at its calls
+    fn inlinable(&mut self, name: &str) -> Option<Function> {
+        if let Some(known) = self.inlined.get(name) {
+            return known.clone();
+        }
+        let candidate = self.functions.get(name).filter(|f| {
+            !self.recursive.contains(name) && f.variables.iter().all(|v| matches!(v.ty, Ty::I32 | Ty::F64 | Ty::Char))
+        });
+        // not recursive: inlining its calls comes back here for other functions only
+        let inlined = candidate.cloned().and_then(|mut f| {
+            self.inline_calls(&mut f.body, &mut f.variables);
+            (count_statements(&f.body) <= INLINE_MAX_STATEMENTS).then_some(f)
+        });
+        self.inlined.insert(name.to_string(), inlined.clone());
+        inlined
+    }
+
+    // Inline the calls of `body`, adding the locals of the inlined functions to `variables`
+    fn inline_calls(&mut self, body: &mut Vec<Stadment>, variables: &mut Vec<Variable>) {
+        for st in std::mem::take(body) {
+            match st {
+                Stadment::Call { ref name, ref args, .. } if args.is_empty() => match self.inlinable(name) {
+                    Some(callee) => body.extend(inline(&callee, variables)),
+                    None => body.push(st),
+                },
+                Stadment::ForLoop {
+                    var,
+                    start,
+                    end,
+                    step,
+                    body: mut loop_body,
+                    pos,
+                } => {
+                    self.inline_calls(&mut loop_body, variables);
+                    body.push(Stadment::ForLoop {
+                        var,
+                        start,
+                        end,
+                        step,
+                        body: loop_body,
+                        pos,
+                    });
+                }
+                Stadment::Match {
+                    value,
+                    mut arms,
+                    mut default,
+                    pos,
+                } => {
+                    for arm in &mut arms {
+                        self.inline_calls(&mut arm.body, variables);
+                    }
+                    if let Some(default) = &mut default {
+                        self.inline_calls(default, variables);
+                    }
+                    body.push(Stadment::Match { value, arms, default, pos });
+                }
+                st => body.push(st),
+            }
+        }
+    }
+}
+
+// The body of `callee` at a call, its locals renamed and added to `variables`
+fn inline(callee: &Function, variables: &mut Vec<Variable>) -> Vec<Stadment> {
+    let mut renamed = Rename {
+        prefix: format!("{}.", callee.name),
+    };
+    let mut body = callee.body.clone();
+    walk_body_mut(&mut renamed, &mut body);
+    let mut resets = Vec::new();
+    for local in &callee.variables {
+        let var = Variable {
+            name: renamed.name(&local.name),
+            ..local.clone()
+        };
+        if !variables.iter().any(|v| v.name == var.name) {
+            variables.push(var.clone());
+        }
+        if !assigned_first(&body, &var.name) {
+            let zero = match var.ty {
+                Ty::F64 => NumExpr::Float(0.0),
+                Ty::Char => NumExpr::Char('\0'),
+                _ => NumExpr::Int(0),
+            };
+            let pos = var.pos.clone();
+            resets.push(Stadment::Assignment { var, expr: Expr::Num(zero), pos });
+        }
+    }
+    resets.extend(body);
+    resets
+}
+
+// Gives the variables of an inlined body the names of the caller's locals
+struct Rename {
+    prefix: String,
+}
+
+impl Rename {
+    fn name(&self, local: &str) -> String {
+        format!("{}{}", self.prefix, local)
+    }
+}
+
+impl MutVisitor for Rename {
+    fn visit_stadment_mut(&mut self, st: &mut Stadment) {
+        if let Stadment::Assignment { var, .. } | Stadment::ForLoop { var, .. } = st {
+            var.name = self.name(&var.name);
+        }
+        walk_stadment_mut(self, st);
+    }
+
+    fn visit_num_expr_mut(&mut self, e: &mut NumExpr) {
+        if let NumExpr::Var { var, .. } = e {
+            var.name = self.name(&var.name);
+        }
+        walk_num_expr_mut(self, e);
+    }
+}
+
+// Whether the first statement of `body` using `name` gives it a value without reading it:
+// its 0 is then never seen
+fn assigned_first(body: &[Stadment], name: &str) -> bool {
+    for st in body {
+        let mut reads = Reads(name, false);
+        reads.visit_stadment(st);
+        if let Stadment::Assignment { var, .. } = st
+            && var.name == name
+        {
+            return !reads.1;
+        }
+        if reads.1 || matches!(st, Stadment::ForLoop { var, .. } if var.name == name) {
+            return false;
+        }
+    }
+    true
+}
+
+// Whether an expression reads the variable
+struct Reads<'a>(&'a str, bool);
+
+impl Visitor for Reads<'_> {
+    fn visit_num_expr(&mut self, e: &NumExpr) {
+        if let NumExpr::Var { var, .. } = e
+            && var.name == self.0
+        {
+            self.1 = true;
+        }
+        walk_num_expr(self, e);
+    }
+}
+
+// Statements of a body, those of the loops and the cases included
+fn count_statements(body: &[Stadment]) -> usize {
+    body.iter()
+        .map(|st| {
+            1 + match st {
+                Stadment::ForLoop { body, .. } => count_statements(body),
+                Stadment::Match { arms, default, .. } => {
+                    arms.iter().map(|arm| count_statements(&arm.body)).sum::<usize>()
+                        + default.as_deref().map_or(0, count_statements)
+                }
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+// --- unused functions
+
+// Keep what main, the exported functions and (for a library) the public ones can call, a
+// function given to a fn variable counting as called
+fn remove_unused_functions(prog: &mut Program) {
+    struct Calls<'a>(&'a mut Vec<String>);
+    impl Visitor for Calls<'_> {
+        fn visit_expr(&mut self, e: &Expr) {
+            if let Expr::Fn(FnExpr::Ref { name, .. }) = e {
+                self.0.push(name.clone());
+            }
+        }
+
+        fn visit_stadment(&mut self, st: &Stadment) {
+            if let Stadment::Call { name, .. } = st {
+                self.0.push(name.clone());
+            }
+            walk_stadment(self, st);
+        }
+    }
+    let library = prog.main_program.main.is_none();
+    let all: HashMap<&str, &Function> = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main)
+        .map(|f| (f.name.as_str(), f))
+        .collect();
+    let exported = prog.functions.iter().filter(|f| f.export);
+    let roots = prog.main_program.functions.iter().filter(|f| f.export || (library && f.public));
+    let mut pending: Vec<String> = exported
+        .chain(roots)
+        .chain(&prog.main_program.main)
+        .map(|f| f.name.clone())
+        .collect();
+    let mut used: HashSet<String> = HashSet::new();
+    while let Some(name) = pending.pop() {
+        if let Some(f) = all.get(name.as_str())
+            && used.insert(name)
+        {
+            Calls(&mut pending).visit_function(f);
+        }
+    }
+    prog.functions.retain(|f| used.contains(&f.name));
+    prog.main_program.functions.retain(|f| used.contains(&f.name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::{CodeGenerator, CompileOptions};
+    use crate::runner::RunOptions;
+    use crate::testing;
+
+    // The program of `text` after the passes of `level`
+    fn optimized(text: &str, level: OptLevel) -> Program {
+        let mut prog = testing::program(text);
+        optimize(&mut prog, level);
+        prog
+    }
+
+    // The value given by the first statement of main
+    fn first_value(prog: &Program) -> &NumExpr {
+        match &prog.main_program.main.as_ref().expect("a main").body[0] {
+            Stadment::Assignment { expr: Expr::Num(value), .. } => value,
+            other => panic!("not an assignment: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn o1_folds_constant_expressions() {
+        let text = "main() {\n    local int i\n    let i = 2 * 3 + 1\n    return i\n}\n";
+        assert!(matches!(first_value(&optimized(text, OptLevel::O0)), NumExpr::Binary { .. }));
+        assert!(matches!(first_value(&optimized(text, OptLevel::O1)), NumExpr::Int(7)));
+    }
+
+    #[test]
+    fn what_traps_is_not_folded() {
+        let text = "main() {\n    local int i\n    let i = 1 / 0\n    return i\n}\n";
+        assert!(matches!(first_value(&optimized(text, OptLevel::O2)), NumExpr::Binary { .. }));
+    }
+
+    #[test]
+    fn o2_removes_the_functions_that_cannot_be_called() {
+        let text = "fn used() {\n    println(\"used\")\n}\nfn unused() {\n    println(\"unused\")\n}\nmain() {\n    used()\n}\n";
+        let names = |prog: Program| -> Vec<String> { prog.main_program.functions.into_iter().map(|f| f.name).collect() };
+        assert!(names(optimized(text, OptLevel::O1)).contains(&"unused".to_string()));
+        assert!(!names(optimized(text, OptLevel::O2)).contains(&"unused".to_string()));
+    }
+
+    #[test]
+    fn every_level_prints_the_same() {
+        let text = "fn twice() {\n    println(to_str(2.0 * 21.0))\n}\nmain() {\n    local int i\n    local int n\n    local float x\n    let x = 0.5\n    for i = 1 to 4\n        let n = n + i * i + (i * i) / 2\n        let x = x * 1.5 + (3.0 - 1.0)\n    next\n    twice()\n    println(to_str(n), \" \", to_str(x), \" \", \"a\", \"b\")\n    return n - 40\n}\n";
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let options = CompileOptions {
+                opt_level: level,
+                ..CompileOptions::default()
+            };
+            let wasm = testing::compile_with(CodeGenerator::new().with_options(options), text);
+            let (outcome, stdout) = testing::run_wasm(&wasm, &RunOptions::default());
+            assert_eq!(outcome.map(|o| o.exit_code).ok(), Some(4), "-O{}", level);
+            assert_eq!(stdout, "42\n44 18.78125 ab\n", "-O{}", level);
+        }
+    }
+}
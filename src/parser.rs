@@ -4,11 +4,15 @@
 
 use std::path::PathBuf;
 
+use serde::Serialize;
+
 use crate::codegen::Ty;
-use crate::grammar::{self, Token};
-use crate::lexer::{LexError, Lexer, Position};
+use crate::diagnostic::{Diagnostic, did_you_mean};
+use crate::grammar::{self, MathFn, Token};
+use crate::lexer::{LexError, Lexer, Position, Span, Trivia, TriviaKind};
+use crate::messages::{self, Message};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum BinOp {
     Add,
     Sub,
@@ -16,16 +20,56 @@ pub enum BinOp {
     Div,
 }
 
-#[derive(Debug, Clone)]
+// How operators of the same binding power group: `a - b - c` is `(a - b) - c` (left)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+// Infix operators of numeric expressions: (token, operator, binding power, associativity).
+// A higher power binds tighter; the prefix signs bind tighter than all of them.
+// A new operator is a line here (and its BinOp), not a new parse function.
+pub const BINARY_OPERATORS: [(Token, BinOp, u8, Assoc); 4] = [
+    (Token::Plus, BinOp::Add, 10, Assoc::Left),
+    (Token::Minus, BinOp::Sub, 10, Assoc::Left),
+    (Token::Star, BinOp::Mul, 20, Assoc::Left),
+    (Token::Slash, BinOp::Div, 20, Assoc::Left),
+];
+
+fn binary_operator(token: &Token) -> Option<(BinOp, u8, Assoc)> {
+    BINARY_OPERATORS
+        .iter()
+        .find(|(t, ..)| t == token)
+        .map(|&(_, op, power, assoc)| (op, power, assoc))
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub enum Expr {
     Num(NumExpr),
     Str(StrExpr),
+    Fn(FnExpr), // the value of a `local fn` variable
+}
+
+// A function reference: `&name` (`&module.name`), or the value of another fn variable
+#[derive(Debug, Clone, Serialize)]
+pub enum FnExpr {
+    Ref {
+        name: String,           // once the program is loaded, the mangled name (see modules.rs)
+        module: Option<String>, // `math` in `&math.square`
+        pos: Position,
+    },
+    Var {
+        var: Variable,
+        pos: Position,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum NumExpr {
     Int(i32),
     Float(f64),
+    Char(char), // 'a': its code point, typed char
     Binary {
         op: BinOp,
         left: Box<NumExpr>,
@@ -36,21 +80,108 @@ pub enum NumExpr {
         pos: Position,
     },
     Neg(Box<NumExpr>),
+    ArgCount,
+    Random, // float in [0, 1)
+    RandomInt {
+        lo: Box<NumExpr>,
+        hi: Box<NumExpr>,
+    }, // int in [lo, hi]
+    Len(Box<StrExpr>), // number of characters
+    ToInt(Box<StrExpr>),   // traps if the string is not an integer
+    ToFloat(Box<StrExpr>), // traps if the string is not a number
+    StrEq {
+        left: Box<StrExpr>,
+        right: Box<StrExpr>,
+        negated: bool, // `!=`
+    }, // 1 if equal, 0 otherwise
+    Math {
+        func: MathFn,
+        args: Vec<NumExpr>,
+    },
+    MapGet {
+        var: Variable, // a `local map` variable
+        key: Box<StrExpr>,
+        pos: Position,
+    }, // the value of the key, 0 if the map does not have it
+    MapHas {
+        var: Variable,
+        key: Box<StrExpr>,
+        pos: Position,
+    }, // 1 if the map has the key, 0 otherwise
+    Ord(Box<NumExpr>), // ord(c): the code point of a char, as an int
+    Chr(Box<NumExpr>), // chr(n): the char of a code point
+    CharAt {
+        s: Box<StrExpr>,
+        index: Box<NumExpr>,
+    }, // char_at(s, i) used as a number: the char, not a one-character string
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum StrExpr {
     Str(String),
     NumToStr(Box<NumExpr>),
+    ToFixed {
+        n: Box<NumExpr>,
+        decimals: Box<NumExpr>,
+    }, // to_str(n, decimals): n as a float, with that many digits after the dot
     Nl,
+    Arg(Box<NumExpr>),
+    Substr {
+        s: Box<StrExpr>,
+        start: Box<NumExpr>,
+        len: Box<NumExpr>,
+    }, // characters [start, start + len)
+    CharAt {
+        s: Box<StrExpr>,
+        index: Box<NumExpr>,
+    }, // one-character string
+    Repeat {
+        s: Box<StrExpr>,
+        count: Box<NumExpr>,
+    },
+    Join {
+        sep: Box<StrExpr>,
+        parts: Vec<StrExpr>,
+    },
+    Thousands {
+        n: Box<NumExpr>,
+        decimals: Option<Box<NumExpr>>,
+    }, // format_thousands(n [, decimals]): the digits before the dot grouped by three
+    DecimalComma(Box<StrExpr>), // decimal_comma(s): every '.' becomes ','
+    Builder {
+        var: Variable, // a `local builder` variable: its text so far
+        pos: Position,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum Stadment {
-    Print(Vec<StrExpr>),
-    Println(Vec<StrExpr>),
+    Print {
+        items: Vec<StrExpr>,
+        pos: Position,
+    },
+    Println {
+        items: Vec<StrExpr>,
+        pos: Position,
+    },
+    EPrint {
+        items: Vec<StrExpr>, // on stderr
+        pos: Position,
+    },
+    EPrintln {
+        items: Vec<StrExpr>,
+        pos: Position,
+    },
     Call {
-        name: String,
+        name: String,           // once the program is loaded, the mangled name (see modules.rs)
+        module: Option<String>, // `math` in `math.square()`
+        args: Vec<NumExpr>,     // only an extern fn takes arguments
+        keyword: bool,          // written `call f()`, as in earlier versions
+        pos: Position,
+    },
+    CallIndirect {
+        var: Variable, // a `local fn` variable
         pos: Position,
     },
     Assignment {
@@ -66,44 +197,210 @@ pub enum Stadment {
         body: Vec<Stadment>,
         pos: Position,
     },
+    Return {
+        expr: Expr,
+        pos: Position,
+    },
+    MapSet {
+        var: Variable, // a `local map` variable
+        key: StrExpr,
+        value: NumExpr, // an int
+        pos: Position,
+    },
+    MapDelete {
+        var: Variable,
+        key: StrExpr,
+        pos: Position,
+    },
+    Append {
+        var: Variable, // a `local builder` variable
+        items: Vec<StrExpr>,
+        pos: Position,
+    },
+    Clear {
+        var: Variable,
+        pos: Position,
+    },
+    Match {
+        value: NumExpr, // an int
+        arms: Vec<MatchArm>,
+        default: Option<Vec<Stadment>>,
+        pos: Position,
+    },
+    Flush,
+    // dump_heap() or dump_heap(start, len): the heap and a hex dump of memory, on stderr
+    DumpHeap {
+        region: Option<(NumExpr, NumExpr)>, // ints; None: the whole heap
+        pos: Position,
+    },
 }
 
-#[derive(Debug)]
+// `case 1, 2: ...` of a match: the values it is taken for (enum values are their number)
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchArm {
+    pub values: Vec<i32>,
+    pub body: Vec<Stadment>,
+    pub pos: Position,
+}
+
+impl Stadment {
+    // Where the statement starts (None for flush())
+    pub fn pos(&self) -> Option<&Position> {
+        match self {
+            Self::Print { pos, .. }
+            | Self::Println { pos, .. }
+            | Self::EPrint { pos, .. }
+            | Self::EPrintln { pos, .. }
+            | Self::Call { pos, .. }
+            | Self::CallIndirect { pos, .. }
+            | Self::Assignment { pos, .. }
+            | Self::ForLoop { pos, .. }
+            | Self::Return { pos, .. }
+            | Self::MapSet { pos, .. }
+            | Self::MapDelete { pos, .. }
+            | Self::Append { pos, .. }
+            | Self::Clear { pos, .. }
+            | Self::Match { pos, .. }
+            | Self::DumpHeap { pos, .. } => Some(pos),
+            Self::Flush => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Program {
     pub functions: Vec<Function>,
     pub main_program: MainProgram,
+    pub linked: Vec<LinkedFunction>, // functions of the wasm libraries it imports
+    pub externs: Vec<ExternFunction>, // host functions declared by its files (mangled names)
 }
 
-#[derive(Debug)]
+impl Program {
+    /// The AST as JSON (--emit=ast-json), for tools written in other languages.
+    ///
+    /// The encoding follows the types of this file field by field: a struct is an object, an
+    /// enum variant is `"Flush"` without data and `{"Variant": data}` with, every Position is
+    /// `{"file_name", "line", "col"}`. Fields come in declaration order, so the same program
+    /// always gives the same text; a change to these types is a change to the format.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct MainProgram {
-    pub imports: Vec<String>,
+    pub imports: Vec<Import>,
     pub functions: Vec<Function>,
-    pub main: Function,
+    pub externs: Vec<ExternFunction>,
+    pub main: Option<Function>, // None for a library compiled on its own (mpl compile --lib)
+}
+
+// Function of a library compiled with --lib, imported as `field` from the wasm module `module`
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedFunction {
+    pub name: String, // name it is called by (mangled, see modules.rs)
+    pub module: String,
+    pub field: String,
+}
+
+// `extern fn name(int x, float y)`: a function of the host, imported as `field` from the module
+// `module` (env.name, or as `#[import("module", "field")]` says); the runner provides it from its
+// HostRegistry
+#[derive(Debug, Clone, Serialize)]
+pub struct ExternFunction {
+    pub name: String, // name it is called by
+    pub module: String,
+    pub field: String,
+    pub params: Vec<Variable>, // int (i32) or float (f64)
+    pub public: bool,          // `pub extern fn`: callable from other files, as a `pub fn`
+    pub pos: Position,       // where it is declared
+    pub doc: Option<String>, // its `///` comments
+}
+
+#[derive(Debug)]
+pub struct Library {
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    pub externs: Vec<ExternFunction>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Import {
+    pub path: String,          // as written, relative to the importing file
+    pub alias: Option<String>, // `import "math.mpl" as math`: functions called as `math.name`
+    pub pos: Position,         // where the import is written
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Variable {
     pub name: String,
     pub ty: Ty,
+    pub pos: Position, // where the variable is declared
 }
 
 pub fn find_variable_index(variables: &[Variable], name: &str) -> Option<usize> {
     variables.iter().position(|v| v.name == name)
 }
 
-pub fn get_variable(variables: &Vec<Variable>, name: &str) -> Variable {
+// An error unless `var` holds a number (it is not a fn, map or builder variable)
+fn check_number(var: &Variable, pos: &Position) -> Result<(), ParseError> {
+    let message = match var.ty {
+        Ty::Fn => &messages::FN_IN_EXPRESSION,
+        Ty::Map => &messages::MAP_IN_EXPRESSION,
+        Ty::Builder => &messages::BUILDER_IN_EXPRESSION,
+        Ty::I32 | Ty::F64 | Ty::Char => return Ok(()),
+    };
+    Err(ParseError::semantic(message, &[&var.name, &var.name], pos))
+}
+
+// The declared variable `name` of type `ty`, else the error reported at `pos`: `message` if it
+// has another type
+fn get_typed(
+    variables: &[Variable],
+    name: &str,
+    ty: Ty,
+    message: &Message,
+    pos: &Position,
+) -> Result<Variable, ParseError> {
+    let var = get_variable(variables, name, pos)?;
+    if var.ty != ty {
+        return Err(ParseError::semantic(message, &[&name, &var.ty.name(), &name], pos));
+    }
+    Ok(var)
+}
+
+fn get_map(variables: &[Variable], name: &str, pos: &Position) -> Result<Variable, ParseError> {
+    get_typed(variables, name, Ty::Map, &messages::NOT_A_MAP, pos)
+}
+
+fn get_builder(variables: &[Variable], name: &str, pos: &Position) -> Result<Variable, ParseError> {
+    get_typed(variables, name, Ty::Builder, &messages::NOT_A_BUILDER, pos)
+}
+
+// Error for the undeclared variable `name`, with the declared one it may be a typo of
+fn not_declared(variables: &[Variable], name: &str, pos: &Position) -> ParseError {
+    let hint = did_you_mean(name, variables.iter().map(|v| v.name.as_str()));
+    ParseError::semantic(&messages::VARIABLE_NOT_DECLARED, &[&name, &hint], pos)
+}
+
+// The declared variable `name`, else the error reported at `pos`
+pub fn get_variable(variables: &[Variable], name: &str, pos: &Position) -> Result<Variable, ParseError> {
     variables
         .iter()
         .find(|v| v.name == name)
-        .expect(&format!("Variable '{}' not declared", name))
-        .clone()
+        .cloned()
+        .ok_or_else(|| not_declared(variables, name, pos))
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Function {
     pub name: String,
+    pub public: bool, // `pub fn`: callable from other files (see modules.rs)
+    pub export: bool, // `export fn`: exported from the wasm module (and public)
     pub body: Vec<Stadment>,
     pub variables: Vec<Variable>,
+    pub pos: Position,       // where the function is defined
+    pub doc: Option<String>, // its `///` comments (parsed from a lexer with_trivia)
 }
 
 #[derive(Debug)]
@@ -112,14 +409,78 @@ pub enum ParseError {
     Unexpected {
         found: Token,
         expected: &'static str,
+        span: Box<Span>, // of the token found
+    },
+    Syntax {
         pos: Position,
+        code: &'static str, // stable message code (see messages.rs)
+        msg: String,
+    },
+    Semantic {
+        pos: Position,
+        code: &'static str,
+        msg: String,
     },
     Generator {
         pos: Position,
+        code: &'static str,
         msg: String,
     },
 }
 
+impl ParseError {
+    // Syntax error built from a catalog message: the text cannot be read as a program
+    pub fn syntax(m: &Message, args: &[&dyn std::fmt::Display], pos: &Position) -> Self {
+        Self::Syntax {
+            pos: pos.clone(),
+            code: m.code,
+            msg: m.format(args),
+        }
+    }
+
+    // Semantic error built from a catalog message: the program reads, but does not make sense
+    // (types, declarations, imports, calls)
+    pub fn semantic(m: &Message, args: &[&dyn std::fmt::Display], pos: &Position) -> Self {
+        Self::Semantic {
+            pos: pos.clone(),
+            code: m.code,
+            msg: m.format(args),
+        }
+    }
+
+    // Code generation error built from a catalog message
+    pub fn generator(m: &Message, args: &[&dyn std::fmt::Display], pos: &Position) -> Self {
+        Self::Generator {
+            pos: pos.clone(),
+            code: m.code,
+            msg: m.format(args),
+        }
+    }
+
+    // The error as the compiler reports it
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Self::Lex(e) => e.diagnostic(),
+            Self::Unexpected {
+                found,
+                expected,
+                span,
+            } => Diagnostic::error(
+                &messages::GRAMMAR_ERROR,
+                messages::UNEXPECTED_TOKEN.code,
+                messages::UNEXPECTED_TOKEN.format(&[&messages::expected(expected), &format!("{:?}", found)]),
+                &span.start,
+            )
+            .with_span((**span).clone()),
+            Self::Syntax { pos, code, msg } => Diagnostic::error(&messages::GRAMMAR_ERROR, code, msg.clone(), pos),
+            Self::Semantic { pos, code, msg } => Diagnostic::error(&messages::SEMANTIC_ERROR, code, msg.clone(), pos),
+            Self::Generator { pos, code, msg } => {
+                Diagnostic::error(&messages::GENERATION_ERROR, code, msg.clone(), pos)
+            }
+        }
+    }
+}
+
 impl From<LexError> for ParseError {
     fn from(e: LexError) -> Self {
         Self::Lex(e)
@@ -128,58 +489,182 @@ impl From<LexError> for ParseError {
 
 impl std::fmt::Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Lex(e) => write!(f, "{}", e),
-            Self::Unexpected {
-                found,
-                expected,
-                pos,
-            } => write!(
-                f,
-                " Grammar error : Expected {}, found {:?}\n in file {}\n at line {}\n col {}\n",
-                expected,
-                found,
-                pos.file_name.to_string_lossy(),
-                pos.line,
-                pos.col,
-            ),
-            Self::Generator { pos, msg } => write!(
-                f,
-                " Code generation error : {}\n in file {}\n at line {}\n col {}\n",
-                msg,
-                pos.file_name.to_string_lossy(),
-                pos.line,
-                pos.col,
-            ),
-        }
+        self.diagnostic().fmt(f)
     }
 }
 
 impl std::error::Error for ParseError {}
 
+// Deepest nesting of expressions (parentheses, function arguments, operators) the parser accepts
+const MAX_NESTING: usize = 128;
+
+/// Module the `extern fn` are imported from.
+pub const EXTERN_MODULE: &str = "env";
+
 pub struct Parser {
     lx: Lexer,     // lexer
     token: Token,  // current token
-    pos: Position, // current position
+    pos: Position, // where the current token starts (the positions of the AST and the errors)
+    end: Position, // just after the current token
+    prev_end: Position, // just after the token before the current one
+    stadment_end: Option<usize>, // line of the statement the current token follows, unless a `;` ended it
+    comments: Vec<Trivia>, // comments right before the current token (lexer with_trivia)
+    peeked: Option<(Token, Span, Vec<Trivia>)>, // token after the current one, once peek() read it
+    depth: usize,  // expressions being parsed, one inside the other (see nested())
+    enums: Vec<EnumDecl>, // the enums declared so far in the file
+    externs: Vec<ExternFunction>, // the `extern fn` of the file, in order
+}
+
+// `enum Color { Red, Green, Blue }`: C-like, Color.Red is the int 0, Color.Green 1...
+// An enum is known from its declaration to the end of its file; its values are replaced by
+// their number as they are parsed.
+struct EnumDecl {
+    name: String,
+    variants: Vec<String>,
+    pos: Position,
+}
+
+// Tokens a statement or a declaration starts with
+fn starts_stadment(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Call
+            | Token::CallIndirect
+            | Token::Print
+            | Token::Println
+            | Token::EPrint
+            | Token::EPrintln
+            | Token::Let
+            | Token::For
+            | Token::Match
+            | Token::Delete
+            | Token::Append
+            | Token::Clear
+            | Token::Flush
+            | Token::DumpHeap
+            | Token::Local
+            | Token::Return
+    )
+}
+
+// file:line:col, for the messages pointing at a first declaration
+fn at(pos: &Position) -> String {
+    format!("{}:{}:{}", pos.file_name.display(), pos.line, pos.col)
 }
 
 impl Parser {
     pub fn new(lx: Lexer) -> Result<Self, ParseError> {
         let token = Token::Eof;
         let pos = Position::new(PathBuf::new());
-        Ok(Self { lx, token, pos })
+        Ok(Self {
+            lx,
+            token,
+            end: pos.clone(),
+            prev_end: pos.clone(),
+            stadment_end: None,
+            pos,
+            comments: Vec::new(),
+            peeked: None,
+            depth: 0,
+            enums: Vec::new(),
+            externs: Vec::new(),
+        })
+    }
+
+    // The next token of the lexer, with the comments before it
+    fn read(&mut self) -> Result<(Token, Span, Vec<Trivia>), ParseError> {
+        let (token, span) = self.lx.next_spanned()?;
+        Ok((token, span, self.lx.take_trivia()))
     }
 
     // Move one token forward
     fn next_token(&mut self) -> Result<(), ParseError> {
-        (self.token, self.pos) = self.lx.next_token()?;
+        let span;
+        (self.token, span, self.comments) = match self.peeked.take() {
+            Some(next) => next,
+            None => self.read()?,
+        };
+        self.prev_end = std::mem::replace(&mut self.end, span.end);
+        self.pos = span.start;
+        self.stadment_end = None;
         Ok(())
     }
 
-    // library ::= [ functions ]
-    pub fn parse_library(&mut self) -> Result<Vec<Function>, ParseError> {
-        self.next_token()?; // Get the first token
-        Ok(self.parse_functions()?)
+    // Move one token forward, returning the token left behind and where it starts
+    pub(crate) fn advance(&mut self) -> Result<(Token, Position), ParseError> {
+        let token = std::mem::replace(&mut self.token, Token::Eof);
+        let pos = self.pos.clone();
+        self.next_token()?;
+        Ok((token, pos))
+    }
+
+    // The token after the current one, without moving (one token of lookahead)
+    pub(crate) fn peek(&mut self) -> Result<&Token, ParseError> {
+        let next = match self.peeked.take() {
+            Some(next) => next,
+            None => self.read()?,
+        };
+        Ok(&self.peeked.insert(next).0)
+    }
+
+    // Parse an expression inside another one: the parser recurses, so the nesting is bounded
+    // (a generated `((((...))))` would otherwise overflow the stack)
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        if self.depth >= MAX_NESTING {
+            return Err(ParseError::syntax(&messages::EXPRESSION_TOO_DEEP, &[&MAX_NESTING], &self.pos));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    // The `///` comments on the lines right above the current token, without the `///`
+    fn doc_comment(&self) -> Option<String> {
+        let mut line = self.pos.line;
+        let mut lines = Vec::new();
+        for comment in self.comments.iter().rev() {
+            let text = comment.text.strip_prefix("///").filter(|t| !t.starts_with('/'));
+            match text {
+                Some(text) if comment.kind == TriviaKind::Line && comment.span.start.line + 1 == line => {
+                    lines.push(text.strip_prefix(' ').unwrap_or(text));
+                    line = comment.span.start.line;
+                }
+                _ => break,
+            }
+        }
+        lines.reverse();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    // Error for the current token when `expected` was
+    pub(crate) fn unexpected(&self, expected: &'static str) -> ParseError {
+        ParseError::Unexpected {
+            found: self.token.clone(),
+            expected,
+            span: Box::new(Span {
+                start: self.pos.clone(),
+                end: self.end.clone(),
+            }),
+        }
+    }
+
+    /// meta::hash of the source parsed (see Lexer::source_hash)
+    pub fn source_hash(&mut self) -> u64 {
+        self.lx.source_hash()
+    }
+
+    // library ::= [ imports ]
+    //             [ functions ]
+    pub fn parse_library(&mut self) -> Result<Library, ParseError> {
+        let imports = self.parse_imports()?;
+        let functions = self.parse_functions()?;
+        let externs = std::mem::take(&mut self.externs);
+        Ok(Library {
+            imports,
+            functions,
+            externs,
+        })
     }
 
     // main_program ::= [ imports ]
@@ -192,39 +677,182 @@ impl Parser {
         Ok(MainProgram {
             imports,
             functions,
-            main,
+            externs: std::mem::take(&mut self.externs),
+            main: Some(main),
         })
     }
 
-    // imports ::= { "IMPORT" str }
-    pub fn parse_imports(&mut self) -> Result<Vec<String>, ParseError> {
-        let mut paths = Vec::new();
+    // imports ::= { "IMPORT" str [ AS ident ] }
+    pub fn parse_imports(&mut self) -> Result<Vec<Import>, ParseError> {
+        let mut imports = Vec::new();
         self.next_token()?; // Get the first token
         while matches!(self.token, Token::Import) {
+            let pos = self.pos.clone();
             self.next_token()?; // get the string after the keyword IMPORT
-            let (import_name, pos) =
+            let (path, _) =
                 crate::expect!(self,Token::Str(s) => s, "a path string after `import`")?;
-            paths.push(import_name);
+            let alias = if matches!(self.token, Token::As) {
+                self.next_token()?;
+                let (alias, _) =
+                    crate::expect!(self, Token::Ident(s) => s, "a module name after `as`")?;
+                Some(alias)
+            } else {
+                None
+            };
+            imports.push(Import { path, alias, pos });
         }
-        Ok(paths)
+        Ok(imports)
     }
 
-    // functions ::= { function }
+    // functions ::= { function | extern_function | enum }
+    // The `extern fn` are kept until the end of the file (parse_library, parse_main_program).
     pub fn parse_functions(&mut self) -> Result<Vec<Function>, ParseError> {
         let mut functions = Vec::new();
-        while matches!(self.token, Token::Fn) {
-            functions.push(self.parse_function()?);
+        loop {
+            let extern_function = match self.token {
+                Token::Extern | Token::Hash => true,
+                Token::Pub => matches!(self.peek()?, Token::Extern),
+                _ => false,
+            };
+            if extern_function {
+                self.parse_extern_function()?;
+                continue;
+            }
+            match self.token {
+                Token::Fn | Token::Pub | Token::Export => functions.push(self.parse_function()?),
+                Token::Enum => self.parse_enum()?,
+                _ => return Ok(functions),
+            }
+        }
+    }
+
+    // extern_function ::= [ '#' '[' IMPORT '(' str [ ',' str ] ')' ']' ]
+    //                     [ PUB ] EXTERN FN ident '(' [ param { ',' param } ] ')'
+    // param ::= ( INT_TYPE | FLOAT_TYPE ) ident
+    // The attribute gives the module and the name it is imported as: env and its own name by default.
+    fn parse_extern_function(&mut self) -> Result<(), ParseError> {
+        let doc = self.doc_comment();
+        let (module, field) = if matches!(self.token, Token::Hash) {
+            self.parse_import_attribute()?
+        } else {
+            (None, None)
+        };
+        let public = matches!(self.token, Token::Pub);
+        if public {
+            self.next_token()?;
+        }
+        crate::expect!(self, Token::Extern, grammar::KW_EXTERN)?;
+        crate::expect!(self, Token::Fn, grammar::KW_FN)?;
+        let (name, pos) =
+            crate::expect!(self, Token::Ident(s) => s, "a valid function name after `fn`")?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let mut params = Vec::new();
+        while !matches!(self.token, Token::RParen) {
+            if !params.is_empty() {
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+            }
+            let ty = match self.token {
+                Token::IntType => Ty::I32,
+                Token::FloatType => Ty::F64,
+                _ => return Err(self.unexpected("a parameter type (int or float)")),
+            };
+            self.next_token()?;
+            let (param, param_pos) = crate::expect!(self, Token::Ident(s) => s, "a parameter name")?;
+            params.push(Variable { name: param, ty, pos: param_pos });
+        }
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        self.externs.push(ExternFunction {
+            module: module.unwrap_or_else(|| EXTERN_MODULE.to_string()),
+            field: field.unwrap_or_else(|| name.clone()),
+            name,
+            params,
+            public,
+            pos,
+            doc,
+        });
+        Ok(())
+    }
+
+    // `#[import("module")]` or `#[import("module", "name")]` before an extern fn
+    fn parse_import_attribute(&mut self) -> Result<(Option<String>, Option<String>), ParseError> {
+        crate::expect!(self, Token::Hash, grammar::HASH)?;
+        crate::expect!(self, Token::LBracket, grammar::LBRACKET)?;
+        crate::expect!(self, Token::Import, grammar::KW_IMPORT)?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let (module, _) = crate::expect!(self, Token::Str(s) => s, "a module name string")?;
+        let field = if matches!(self.token, Token::Comma) {
+            self.next_token()?;
+            Some(crate::expect!(self, Token::Str(s) => s, "a function name string")?.0)
+        } else {
+            None
+        };
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        crate::expect!(self, Token::RBracket, grammar::RBRACKET)?;
+        Ok((Some(module), field))
+    }
+
+    // enum ::= ENUM ident '{' ident { ',' ident } [ ',' ] '}'
+    fn parse_enum(&mut self) -> Result<(), ParseError> {
+        crate::expect!(self, Token::Enum, grammar::KW_ENUM)?;
+        let (name, pos) = crate::expect!(self, Token::Ident(s) => s, "an enum name after `enum`")?;
+        if let Some(first) = self.enums.iter().find(|e| e.name == name) {
+            return Err(ParseError::semantic(&messages::DUPLICATE_ENUM, &[&name, &at(&first.pos)], &pos));
+        }
+        crate::expect!(self, Token::LBrace, grammar::LBRACE)?;
+        let mut variants: Vec<String> = Vec::new();
+        loop {
+            let (variant, variant_pos) =
+                crate::expect!(self, Token::Ident(s) => s, "an enum value name")?;
+            if variants.contains(&variant) {
+                return Err(ParseError::semantic(&messages::DUPLICATE_ENUM_VALUE, &[&variant, &name], &variant_pos));
+            }
+            variants.push(variant);
+            if !matches!(self.token, Token::Comma) {
+                break;
+            }
+            self.next_token()?;
+            if matches!(self.token, Token::RBrace) {
+                break;
+            }
         }
-        Ok(functions)
+        crate::expect!(self, Token::RBrace, grammar::RBRACE)?;
+        self.enums.push(EnumDecl { name, variants, pos });
+        Ok(())
     }
 
-    // function ::= FN ident '(' ')' '{'
-    //                           [ { variable_declaration } ]
-    //                           [ { stadment } ]
-    //                       '}'
+    // The number of `name.variant`, `name` being an enum of the file; the current token is
+    // the `.` after `name`
+    fn parse_enum_value(&mut self, name: &str, pos: &Position) -> Result<i32, ParseError> {
+        crate::expect!(self, Token::Dot, grammar::DOT)?;
+        let (variant, _) = crate::expect!(self, Token::Ident(s) => s, "an enum value name")?;
+        let decl = self.enums.iter().find(|e| e.name == name).expect("parse_enum_value: a declared enum");
+        match decl.variants.iter().position(|v| *v == variant) {
+            Some(i) => Ok(i as i32),
+            None => Err(ParseError::semantic(
+                &messages::UNKNOWN_ENUM_VALUE,
+                &[&name, &variant, &decl.variants.join(", ")],
+                pos,
+            )),
+        }
+    }
+
+    fn is_enum(&self, name: &str) -> bool {
+        self.enums.iter().any(|e| e.name == name)
+    }
+
+    // function ::= [ PUB | EXPORT ] FN ident '(' ')' '{'
+    //                                            [ { variable_declaration } ]
+    //                                            [ { stadment } ]
+    //                                        '}'
     pub fn parse_function(&mut self) -> Result<Function, ParseError> {
         let mut body = Vec::new();
         let mut variables = Vec::new();
+        let doc = self.doc_comment();
+        let export = matches!(self.token, Token::Export);
+        let public = export || matches!(self.token, Token::Pub);
+        if public {
+            self.next_token()?;
+        }
         crate::expect!(self, Token::Fn, grammar::KW_FN)?;
         let (name, pos) =
             crate::expect!(self,Token::Ident(s) => s, "a valid function name after `fn`")?;
@@ -240,24 +868,81 @@ impl Parser {
         crate::expect!(self, Token::RBrace, grammar::RBRACE)?;
         Ok(Function {
             name,
+            public,
+            export,
             body,
             variables,
+            pos,
+            doc,
         })
     }
 
-    //stadment ::= call_function | print | assignment
+    //stadment ::= call_function | call_indirect | print | assignment | for_loop | match | delete
+    //           | append | clear | flush | dump_heap
+    // Statements are written one per line, or separated by `;` on a line:
+    //   let x = 1; let y = 2
     pub fn parse_stadment(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        self.check_new_stadment()?;
+        let stadment = self.parse_instruction(variables)?;
+        self.end_stadment()?;
+        Ok(stadment)
+    }
+
+    fn parse_instruction(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        if self.at_call()? {
+            return self.parse_call_function(variables);
+        }
+        if let Token::Ident(name) = &self.token {
+            let name = name.clone();
+            if matches!(self.peek()?, Token::Equal | Token::LBracket) {
+                return Err(ParseError::syntax(&messages::ASSIGNMENT_WITHOUT_LET, &[&name, &name], &self.pos));
+            }
+        }
         match &self.token {
-            Token::Call => self.parse_call_function(),
-            Token::Print => self.parse_print(variables, false),
-            Token::Println => self.parse_print(variables, true),
+            Token::Call => self.parse_call_function(variables),
+            Token::CallIndirect => self.parse_call_indirect(variables),
+            Token::Print | Token::Println | Token::EPrint | Token::EPrintln => self.parse_print(variables),
             Token::Let => self.parse_assignment(variables),
             Token::For => self.parse_for_loop(variables),
-            _ => Err(ParseError::Unexpected {
-                found: self.token.clone(),
-                expected: "an instruction",
-                pos: self.pos.clone(),
-            }),
+            Token::Match => self.parse_match(variables),
+            Token::Delete => self.parse_delete(variables),
+            Token::Append => self.parse_append(variables),
+            Token::Clear => self.parse_clear(variables),
+            Token::Flush => self.parse_flush(),
+            Token::DumpHeap => self.parse_dump_heap(variables),
+            _ => Err(self.unexpected("an instruction")),
+        }
+    }
+
+    // A statement (or a declaration) starting on the line where the previous one ended:
+    // `let x = 1 let y = 2` misses a `;`, `let x = a b` an operator between a and b
+    fn check_new_stadment(&mut self) -> Result<(), ParseError> {
+        if self.stadment_end != Some(self.pos.line) {
+            return Ok(());
+        }
+        if starts_stadment(&self.token) || self.at_call()? {
+            return Err(ParseError::syntax(&messages::STATEMENTS_ON_ONE_LINE, &[], &self.pos));
+        }
+        let found = format!("{:?}", self.token);
+        Err(ParseError::syntax(&messages::MISSING_OPERATOR, &[&found], &self.pos))
+    }
+
+    // A call without `call`: a function name followed by `(`, or a module name by `.`
+    fn at_call(&mut self) -> Result<bool, ParseError> {
+        Ok(match self.token {
+            Token::Ident(_) => matches!(self.peek()?, Token::LParen | Token::Dot),
+            Token::Main => matches!(self.peek()?, Token::LParen),
+            _ => false,
+        })
+    }
+
+    // After a statement or a declaration: its optional `;`
+    fn end_stadment(&mut self) -> Result<(), ParseError> {
+        if matches!(self.token, Token::Semicolon) {
+            self.next_token()
+        } else {
+            self.stadment_end = Some(self.prev_end.line);
+            Ok(())
         }
     }
 
@@ -281,45 +966,236 @@ impl Parser {
             body.push(self.parse_stadment(variables)?);
         }
         crate::expect!(self, Token::Next, grammar::KW_NEXT)?;
-        let var = get_variable(variables, &var_name);
+        let var = get_variable(variables, &var_name, &pos)?;
+        check_number(&var, &pos)?;
         Ok(Stadment::ForLoop {var,start,end,step,body,pos})
     }   
 
+    // match ::= MATCH expr '{'
+    //               { CASE case_value { ',' case_value } ':' { stadment } }
+    //               [ DEFAULT ':' { stadment } ]
+    //           '}'
+    // The int (or char) `expr` is compared with the values of each case; the first case holding it runs,
+    // else the default (no fallthrough: a case ends where the next one starts).
+    pub fn parse_match(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        let pos = crate::expect!(self, Token::Match, grammar::KW_MATCH)?;
+        let value = self.parse_num_expr(variables)?;
+        if !matches!(crate::codegen::infer_type(&value), Ty::I32 | Ty::Char) {
+            return Err(ParseError::semantic(&messages::MATCH_ON_FLOAT, &[], &pos));
+        }
+        crate::expect!(self, Token::LBrace, grammar::LBRACE)?;
+        let mut arms: Vec<MatchArm> = Vec::new();
+        let mut seen: Vec<(i32, Position)> = Vec::new();
+        while matches!(self.token, Token::Case) {
+            let arm_pos = crate::expect!(self, Token::Case, grammar::KW_CASE)?;
+            let mut values = Vec::new();
+            loop {
+                let value_pos = self.pos.clone();
+                let value = self.parse_case_value()?;
+                if let Some((_, first)) = seen.iter().find(|(v, _)| *v == value) {
+                    return Err(ParseError::semantic(&messages::DUPLICATE_CASE, &[&value, &at(first)], &value_pos));
+                }
+                seen.push((value, value_pos));
+                values.push(value);
+                if !matches!(self.token, Token::Comma) {
+                    break;
+                }
+                self.next_token()?;
+            }
+            crate::expect!(self, Token::Colon, grammar::COLON)?;
+            let body = self.parse_case_body(variables)?;
+            arms.push(MatchArm { values, body, pos: arm_pos });
+        }
+        let default = if matches!(self.token, Token::Default) {
+            self.next_token()?;
+            crate::expect!(self, Token::Colon, grammar::COLON)?;
+            Some(self.parse_case_body(variables)?)
+        } else {
+            None
+        };
+        crate::expect!(self, Token::RBrace, grammar::RBRACE)?;
+        Ok(Stadment::Match { value, arms, default, pos })
+    }
+
+    // case_value ::= [ '-' ] INT  |  CHAR  |  ident '.' ident
+    fn parse_case_value(&mut self) -> Result<i32, ParseError> {
+        match self.token.clone() {
+            Token::Integer(n) => {
+                self.next_token()?;
+                Ok(n)
+            }
+            Token::Char(c) => {
+                self.next_token()?;
+                Ok(c as i32)
+            }
+            Token::Minus => {
+                self.next_token()?;
+                let (n, _) = crate::expect!(self, Token::Integer(n) => n, "an int, a char or an enum value after `case`")?;
+                Ok(n.wrapping_neg())
+            }
+            Token::Ident(name) if self.is_enum(&name) => {
+                let pos = self.pos.clone();
+                self.next_token()?;
+                self.parse_enum_value(&name, &pos)
+            }
+            _ => Err(self.unexpected("an int, a char or an enum value after `case`")),
+        }
+    }
+
+    // The statements of a case, up to the next case, the default or the end of the match
+    fn parse_case_body(&mut self, variables: &Vec<Variable>) -> Result<Vec<Stadment>, ParseError> {
+        let mut body = Vec::new();
+        while !matches!(self.token, Token::Case | Token::Default | Token::RBrace) {
+            body.push(self.parse_stadment(variables)?);
+        }
+        Ok(body)
+    }
+
     // main_function ::=  MAIN '(' ')' '{'
     //                        [ { variable_declaration } ]
     //                        [ { stadment } ]
+    //                        [ return ]
     //                    '}'
     //                    EOF
     pub fn parse_main_function(&mut self) -> Result<Function, ParseError> {
         let mut body = Vec::new();
         let mut variables = Vec::new();
-        crate::expect!(self, Token::Main, grammar::KW_MAIN)?;
+        let doc = self.doc_comment();
+        let pos = crate::expect!(self, Token::Main, grammar::KW_MAIN)?;
         crate::expect!(self, Token::LParen, grammar::LPAREN)?;
         crate::expect!(self, Token::RParen, grammar::RPAREN)?;
         crate::expect!(self, Token::LBrace, grammar::LBRACE)?;
         while matches!(self.token, Token::Local) {
             variables.push(self.parse_variable_declaration()?);
         }
-        while !matches!(self.token, Token::RBrace) {
+        while !matches!(self.token, Token::RBrace | Token::Return) {
             body.push(self.parse_stadment(&variables)?);
         }
+        if matches!(self.token, Token::Return) {
+            body.push(self.parse_return(&variables)?); // exit code, only as the last instruction
+        }
         crate::expect!(self, Token::RBrace, grammar::RBRACE)?;
         crate::expect!(self, Token::Eof, grammar::EOF)?;
         Ok(Function {
             name: grammar::KW_MAIN.to_string(),
+            public: true, // exported to the host
+            export: false, // always exported, as "main"
             body,
             variables,
+            pos,
+            doc,
         })
     }
 
-    // call_function ::=  CALL ident '(' ')'
-    pub fn parse_call_function(&mut self) -> Result<Stadment, ParseError> {
-        crate::expect!(self, Token::Call, grammar::KW_CALL)?;
-        let (name, pos) =
+    // call_function ::=  [ CALL ] [ ident '.' ] ident '(' [ expr { ',' expr } ] ')'  |  [ CALL ] MAIN '(' ')'
+    // (the arguments are those of an extern fn; `call` is that of earlier versions, still accepted)
+    pub fn parse_call_function(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        let keyword = matches!(self.token, Token::Call);
+        if keyword {
+            self.next_token()?;
+        }
+        if matches!(self.token, Token::Main) {
+            let pos = crate::expect!(self, Token::Main, grammar::KW_MAIN)?;
+            crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+            crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+            let name = grammar::KW_MAIN.to_string();
+            return Ok(Stadment::Call { name, module: None, args: Vec::new(), keyword, pos });
+        }
+        let (mut name, pos) =
             crate::expect!(self,Token::Ident(s) => s, "a valid function name after `call`")?;
+        let mut module = None;
+        if matches!(self.token, Token::Dot) {
+            self.next_token()?;
+            let (fn_name, _) =
+                crate::expect!(self,Token::Ident(s) => s, "a valid function name after `.`")?;
+            module = Some(std::mem::replace(&mut name, fn_name));
+        }
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let mut args = Vec::new();
+        while !matches!(self.token, Token::RParen) {
+            if !args.is_empty() {
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+            }
+            args.push(self.parse_num_expr(variables)?);
+        }
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        Ok(Stadment::Call { name, module, args, keyword, pos })
+    }
+
+    // call_indirect ::=  CALL_INDIRECT ident '(' ')'
+    // Calls the function a `local fn` variable refers to.
+    pub fn parse_call_indirect(&mut self, variables: &[Variable]) -> Result<Stadment, ParseError> {
+        crate::expect!(self, Token::CallIndirect, grammar::KW_CALL_INDIRECT)?;
+        let (name, pos) =
+            crate::expect!(self, Token::Ident(s) => s, "a fn variable after `call_indirect`")?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        let var = get_variable(variables, &name, &pos)?;
+        if var.ty != Ty::Fn {
+            return Err(ParseError::semantic(&messages::NOT_A_FN_VARIABLE, &[&name, &var.ty.name(), &name], &pos));
+        }
+        Ok(Stadment::CallIndirect { var, pos })
+    }
+
+    // fn_expr ::=  '&' [ ident '.' ] ident  |  '&' MAIN  |  ident
+    // What a `local fn` variable is given: a function, or the value of another fn variable.
+    fn parse_fn_expr(&mut self, variables: &[Variable]) -> Result<FnExpr, ParseError> {
+        if !matches!(self.token, Token::Amp) {
+            let (name, pos) =
+                crate::expect!(self, Token::Ident(s) => s, "a function reference (&name) or a fn variable")?;
+            let var = get_variable(variables, &name, &pos)?;
+            if var.ty != Ty::Fn {
+                return Err(ParseError::semantic(&messages::NOT_A_FN_VARIABLE, &[&name, &var.ty.name(), &name], &pos));
+            }
+            return Ok(FnExpr::Var { var, pos });
+        }
+        self.next_token()?;
+        if matches!(self.token, Token::Main) {
+            let pos = crate::expect!(self, Token::Main, grammar::KW_MAIN)?;
+            return Ok(FnExpr::Ref { name: grammar::KW_MAIN.to_string(), module: None, pos });
+        }
+        let (mut name, pos) =
+            crate::expect!(self, Token::Ident(s) => s, "a function name after `&`")?;
+        let mut module = None;
+        if matches!(self.token, Token::Dot) {
+            self.next_token()?;
+            let (fn_name, _) =
+                crate::expect!(self, Token::Ident(s) => s, "a valid function name after `.`")?;
+            module = Some(std::mem::replace(&mut name, fn_name));
+        }
+        Ok(FnExpr::Ref { name, module, pos })
+    }
+
+    // flush ::= FLUSH '(' ')'
+    pub fn parse_flush(&mut self) -> Result<Stadment, ParseError> {
+        crate::expect!(self, Token::Flush, grammar::KW_FLUSH)?;
         crate::expect!(self, Token::LParen, grammar::LPAREN)?;
         crate::expect!(self, Token::RParen, grammar::RPAREN)?;
-        Ok(Stadment::Call { name, pos })
+        Ok(Stadment::Flush)
+    }
+
+    // dump_heap ::= DUMP_HEAP '(' [ expr ',' expr ] ')'
+    pub fn parse_dump_heap(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        let pos = crate::expect!(self, Token::DumpHeap, grammar::KW_DUMP_HEAP)?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let mut region = None;
+        if !matches!(self.token, Token::RParen) {
+            let start = self.parse_num_expr(variables)?;
+            crate::expect!(self, Token::Comma, grammar::COMMA)?;
+            let len = self.parse_num_expr(variables)?;
+            region = Some((start, len));
+        }
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        Ok(Stadment::DumpHeap { region, pos })
+    }
+
+    // return ::= RETURN expr
+    pub fn parse_return(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        self.check_new_stadment()?;
+        let pos = crate::expect!(self, Token::Return, grammar::KW_RETURN)?;
+        let expr = self.parse_expr(variables)?;
+        self.end_stadment()?;
+        Ok(Stadment::Return { expr, pos })
     }
 
     pub fn parse_expr(&mut self, variables: &Vec<Variable>) -> Result<Expr, ParseError> {
@@ -327,31 +1203,98 @@ impl Parser {
         Ok(Expr::Num(num_expr))
     }
 
-    // assignment ::=  LET ident '=' expr
+    // assignment ::=  LET ident '=' ( expr | fn_expr )  |  LET ident '[' str_expr ']' '=' expr
+    // A `local fn` variable is given a fn_expr, a key of a `local map` an int, the others an
+    // expression.
     pub fn parse_assignment(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
         crate::expect!(self, Token::Let, grammar::KW_LET)?;
         let (var_name, pos) =
             crate::expect!(self, Token::Ident(s) => s, "a valid variable name after `let`")?;
+        if matches!(self.token, Token::LBracket) {
+            let var = get_map(variables, &var_name, &pos)?;
+            let key = self.parse_map_key(variables)?;
+            crate::expect!(self, Token::Equal, grammar::EQUAL)?;
+            let value = self.parse_num_expr(variables)?;
+            return Ok(Stadment::MapSet { var, key, value, pos });
+        }
         crate::expect!(self, Token::Equal, grammar::EQUAL)?;
         // check if the variable exists
         let var_index =
-            find_variable_index(variables, &var_name).ok_or_else(|| ParseError::Generator {
-                pos: pos.clone(),
-                msg: format!("Variable '{}' not declared", var_name),
-            })?;
+            find_variable_index(variables, &var_name).ok_or_else(|| not_declared(variables, &var_name, &pos))?;
         let var = variables[var_index].clone();
-        let expr = self.parse_expr(variables)?;
+        let message = match var.ty {
+            Ty::Map => Some(&messages::MAP_ASSIGNMENT),
+            Ty::Builder => Some(&messages::BUILDER_ASSIGNMENT),
+            Ty::I32 | Ty::F64 | Ty::Char | Ty::Fn => None,
+        };
+        if let Some(message) = message {
+            return Err(ParseError::semantic(message, &[&var_name, &var_name], &pos));
+        }
+        let expr = if var.ty == Ty::Fn {
+            Expr::Fn(self.parse_fn_expr(variables)?)
+        } else {
+            self.parse_expr(variables)?
+        };
         Ok(Stadment::Assignment { var, expr, pos })
     }
 
-    // print ::=  (PRINT | PRINTLN) '(' str_expr [',' str_expr] ')'
-    
-    pub fn parse_print(&mut self,variables: &Vec<Variable>,nl: bool) -> Result<Stadment, ParseError> {
-        if nl {
-            crate::expect!(self, Token::Println, grammar::KW_PRINTLN)?;
-        } else {
-            crate::expect!(self, Token::Print, grammar::KW_PRINT)?;
+    // '[' str_expr ']': the key of a map
+    fn parse_map_key(&mut self, variables: &Vec<Variable>) -> Result<StrExpr, ParseError> {
+        crate::expect!(self, Token::LBracket, grammar::LBRACKET)?;
+        let key = self.parse_str_expr(variables)?;
+        crate::expect!(self, Token::RBracket, grammar::RBRACKET)?;
+        Ok(key)
+    }
+
+    // ident ',' str_expr: the map and the key of has() and delete()
+    fn parse_map_and_key(&mut self, variables: &Vec<Variable>) -> Result<(Variable, StrExpr, Position), ParseError> {
+        let (name, pos) = crate::expect!(self, Token::Ident(s) => s, "a map variable")?;
+        let var = get_map(variables, &name, &pos)?;
+        crate::expect!(self, Token::Comma, grammar::COMMA)?;
+        let key = self.parse_str_expr(variables)?;
+        Ok((var, key, pos))
+    }
+
+    // delete ::= DELETE '(' ident ',' str_expr ')'
+    pub fn parse_delete(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        crate::expect!(self, Token::Delete, grammar::KW_DELETE)?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let (var, key, pos) = self.parse_map_and_key(variables)?;
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        Ok(Stadment::MapDelete { var, key, pos })
+    }
+
+    // append ::= APPEND '(' ident { ',' str_expr } ')'
+    pub fn parse_append(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        crate::expect!(self, Token::Append, grammar::KW_APPEND)?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let (name, pos) = crate::expect!(self, Token::Ident(s) => s, "a builder variable")?;
+        let var = get_builder(variables, &name, &pos)?;
+        let mut items = Vec::new();
+        while matches!(self.token, Token::Comma) {
+            self.next_token()?;
+            items.push(self.parse_str_expr(variables)?);
         }
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        Ok(Stadment::Append { var, items, pos })
+    }
+
+    // clear ::= CLEAR '(' ident ')': the builder is empty again, its memory is kept
+    pub fn parse_clear(&mut self, variables: &[Variable]) -> Result<Stadment, ParseError> {
+        crate::expect!(self, Token::Clear, grammar::KW_CLEAR)?;
+        crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+        let (name, pos) = crate::expect!(self, Token::Ident(s) => s, "a builder variable")?;
+        let var = get_builder(variables, &name, &pos)?;
+        crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+        Ok(Stadment::Clear { var, pos })
+    }
+
+    // print ::=  (PRINT | PRINTLN | EPRINT | EPRINTLN) '(' str_expr [',' str_expr] ')'
+    // eprint and eprintln write on stderr
+    pub fn parse_print(&mut self, variables: &Vec<Variable>) -> Result<Stadment, ParseError> {
+        let statement = self.token.clone();
+        let pos = self.pos.clone();
+        self.next_token()?;
         crate::expect!(self, Token::LParen, grammar::LPAREN)?;
         let mut str_expr: Vec<StrExpr> = Vec::new();
         str_expr.push(self.parse_str_expr(variables)?);
@@ -360,86 +1303,186 @@ impl Parser {
             str_expr.push(self.parse_str_expr(variables)?);
         }
         crate::expect!(self, Token::RParen, grammar::RPAREN)?;
-        if nl {
-            Ok(Stadment::Println(str_expr))
-        } else {
-            Ok(Stadment::Print(str_expr))
-        }
+        Ok(match statement {
+            Token::Println => Stadment::Println { items: str_expr, pos },
+            Token::EPrint => Stadment::EPrint { items: str_expr, pos },
+            Token::EPrintln => Stadment::EPrintln { items: str_expr, pos },
+            _ => Stadment::Print { items: str_expr, pos },
+        })
     }
 
-    // str_expr ::= str | to_str(num_expr) | NL
+    // str_expr ::= str | char | chr(num_expr) | to_str(num_expr) | NL | arg(num_expr)
+    //            | substr(str_expr, num_expr, num_expr) | char_at(str_expr, num_expr)
+    //            | repeat(str_expr, num_expr) | join(str_expr, str_expr { ',' str_expr }) | ident
     fn parse_str_expr(&mut self, variables: &Vec<Variable>) -> Result<StrExpr, ParseError> {
+        self.nested(|p| p.parse_str_operand(variables))
+    }
+
+    fn parse_str_operand(&mut self, variables: &Vec<Variable>) -> Result<StrExpr, ParseError> {
         let tok = self.token.clone();
         match tok {
             Token::Str(s) => {
                 self.next_token()?;
                 Ok(StrExpr::Str(s))
             }
+            Token::Char(c) => {
+                self.next_token()?;
+                Ok(StrExpr::Str(c.to_string()))
+            }
+            // chr(n) is the string of its character
+            Token::Chr => Ok(StrExpr::NumToStr(Box::new(self.parse_primary(variables)?))),
             Token::ToStr => {
                 self.next_token()?;
                 crate::expect!(self, Token::LParen, grammar::LPAREN)?;
                 let inner = self.parse_num_expr(variables)?;
+                let decimals = if matches!(self.token, Token::Comma) {
+                    self.next_token()?;
+                    Some(self.parse_num_expr(variables)?)
+                } else {
+                    None
+                };
                 crate::expect!(self, Token::RParen, grammar::RPAREN)?;
-                Ok(StrExpr::NumToStr(Box::new(inner)))
+                Ok(match decimals {
+                    Some(decimals) => StrExpr::ToFixed { n: Box::new(inner), decimals: Box::new(decimals) },
+                    None => StrExpr::NumToStr(Box::new(inner)),
+                })
             }
             Token::Nl => {
                 self.next_token()?;
                 Ok(StrExpr::Nl)
             }
-            _ => Err(ParseError::Unexpected {
-                found: self.token.clone(),
-                expected: "a string or to_str(num)",
-                pos: self.pos.clone(),
-            }),
+            Token::Arg => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let index = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::Arg(Box::new(index)))
+            }
+            Token::Substr => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let s = self.parse_str_expr(variables)?;
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+                let start = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+                let len = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::Substr {
+                    s: Box::new(s),
+                    start: Box::new(start),
+                    len: Box::new(len),
+                })
+            }
+            Token::CharAt => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let s = self.parse_str_expr(variables)?;
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+                let index = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::CharAt {
+                    s: Box::new(s),
+                    index: Box::new(index),
+                })
+            }
+            Token::Repeat => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let s = self.parse_str_expr(variables)?;
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+                let count = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::Repeat {
+                    s: Box::new(s),
+                    count: Box::new(count),
+                })
+            }
+            Token::FormatThousands => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let n = self.parse_num_expr(variables)?;
+                let decimals = if matches!(self.token, Token::Comma) {
+                    self.next_token()?;
+                    Some(Box::new(self.parse_num_expr(variables)?))
+                } else {
+                    None
+                };
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::Thousands { n: Box::new(n), decimals })
+            }
+            Token::DecimalComma => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let s = self.parse_str_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::DecimalComma(Box::new(s)))
+            }
+            Token::Join => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let sep = self.parse_str_expr(variables)?;
+                let mut parts = Vec::new();
+                loop {
+                    crate::expect!(self, Token::Comma, grammar::COMMA)?;
+                    parts.push(self.parse_str_expr(variables)?);
+                    if !matches!(self.token, Token::Comma) {
+                        break;
+                    }
+                }
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(StrExpr::Join {
+                    sep: Box::new(sep),
+                    parts,
+                })
+            }
+            Token::Ident(name) => {
+                let pos = self.pos.clone();
+                self.next_token()?;
+                // a char is the string of its character
+                if let Some(var) = variables.iter().find(|v| v.name == name && v.ty == Ty::Char) {
+                    let var = var.clone();
+                    return Ok(StrExpr::NumToStr(Box::new(NumExpr::Var { var, pos })));
+                }
+                let var = get_typed(variables, &name, Ty::Builder, &messages::NOT_A_STRING, &pos)?;
+                Ok(StrExpr::Builder { var, pos })
+            }
+            _ => Err(self.unexpected("a string expression")),
         }
     }
 
-    // expr ::= additive
+    // expr ::= unary { binary_op unary }
+    // grouped by the binding powers of BINARY_OPERATORS
     fn parse_num_expr(&mut self, variables: &Vec<Variable>) -> Result<NumExpr, ParseError> {
-        self.parse_additive(variables)
+        self.nested(|p| p.parse_binary(variables, 0))
     }
 
-    // additive ::= multiplicative { ('+' | '-') multiplicative }
-    fn parse_additive(&mut self, variables: &Vec<Variable>) -> Result<NumExpr, ParseError> {
-        let mut node = self.parse_multiplicative(variables)?;
-        loop {
-            let op = match &self.token {
-                Token::Plus => {
-                    self.next_token()?;
-                    BinOp::Add
-                }
-                Token::Minus => {
-                    self.next_token()?;
-                    BinOp::Sub
-                }
-                _ => break,
-            };
-            let rhs = self.parse_multiplicative(variables)?;
-            node = NumExpr::Binary {
-                op,
-                left: Box::new(node),
-                right: Box::new(rhs),
-            };
-        }
-        Ok(node)
+    // Precedence climbing: an operand, then the operators binding at least as tight as
+    // `min_power`, each with its right operand (which takes the tighter ones)
+    fn parse_binary(&mut self, variables: &Vec<Variable>, min_power: u8) -> Result<NumExpr, ParseError> {
+        let depth = self.depth;
+        let result = self.parse_operations(variables, min_power);
+        self.depth = depth;
+        result
     }
 
-    // multiplicative ::= unary { ('*' | '/') unary }
-    fn parse_multiplicative(&mut self, variables: &Vec<Variable>) -> Result<NumExpr, ParseError> {
+    // The operands and operators of parse_binary. Each operator puts what is on its left one
+    // level deeper: a flat `1 + 1 + ... + 1` is as deep as it is long, and counts as nesting
+    fn parse_operations(&mut self, variables: &Vec<Variable>, min_power: u8) -> Result<NumExpr, ParseError> {
         let mut node = self.parse_unary(variables)?;
-        loop {
-            let op = match &self.token {
-                Token::Star => {
-                    self.next_token()?;
-                    BinOp::Mul
-                }
-                Token::Slash => {
-                    self.next_token()?;
-                    BinOp::Div
-                }
-                _ => break,
+        while let Some((op, power, assoc)) = binary_operator(&self.token) {
+            if power < min_power {
+                break;
+            }
+            if self.depth >= MAX_NESTING {
+                return Err(ParseError::syntax(&messages::EXPRESSION_TOO_DEEP, &[&MAX_NESTING], &self.pos));
+            }
+            self.depth += 1;
+            self.next_token()?;
+            let right_power = match assoc {
+                Assoc::Left => power + 1,
+                Assoc::Right => power,
             };
-            let rhs = self.parse_unary(variables)?;
+            let rhs = self.nested(|p| p.parse_binary(variables, right_power))?;
             node = NumExpr::Binary {
                 op,
                 left: Box::new(node),
@@ -474,7 +1517,14 @@ impl Parser {
         }
     }
 
-    // primary ::= INT | FLOAT |'(' expr ')' | ident
+    // primary ::= INT | FLOAT | CHAR |'(' expr ')' | ident | ident '.' ident (enum value) | ARG_COUNT '(' ')'
+    //           | RANDOM '(' ')' | RANDOM_INT '(' expr ',' expr ')' | LEN '(' str_expr ')'
+    //           | TO_INT '(' str_expr ')' | TO_FLOAT '(' str_expr ')' | ORD '(' expr ')' | CHR '(' expr ')'
+    //           | CHAR_AT '(' str_expr ',' expr ')'
+    //           | str_expr ('==' | '!=') str_expr
+    // A char compared with `==` or `!=` is compared as a string: 'a' == char_at(s, 0).
+    //           | math_fn '(' expr { ',' expr } ')'
+    //           | ident '[' str_expr ']' | HAS '(' ident ',' str_expr ')'
     fn parse_primary(&mut self, variables: &Vec<Variable>) -> Result<NumExpr, ParseError> {
         let tok = self.token.clone();
         match tok {
@@ -486,28 +1536,186 @@ impl Parser {
                 self.next_token()?;
                 Ok(NumExpr::Float(n))
             }
+            Token::Char(_) if self.peek_comparison()? => self.parse_str_comparison(variables),
+            Token::Char(c) => {
+                self.next_token()?;
+                Ok(NumExpr::Char(c))
+            }
+            Token::Ord | Token::Chr => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let inner = Box::new(self.parse_num_expr(variables)?);
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                if matches!(tok, Token::Ord) {
+                    Ok(NumExpr::Ord(inner))
+                } else {
+                    Ok(NumExpr::Chr(inner))
+                }
+            }
+            Token::CharAt => {
+                let left = self.parse_str_expr(variables)?;
+                match left {
+                    // a char, unless compared as a string
+                    StrExpr::CharAt { s, index } if !self.at_comparison() => Ok(NumExpr::CharAt { s, index }),
+                    left => self.finish_str_comparison(left, variables),
+                }
+            }
             Token::LParen => {
                 self.next_token()?;
                 let e = self.parse_num_expr(variables)?;
                 crate::expect!(self, Token::RParen, grammar::RPAREN)?;
                 Ok(e)
             }
-            Token::Ident(ref var_name) => {
+            Token::Math(func) => {
+                let pos = self.pos.clone();
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let mut args = vec![self.parse_num_expr(variables)?];
+                while matches!(self.token, Token::Comma) {
+                    self.next_token()?;
+                    args.push(self.parse_num_expr(variables)?);
+                }
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                if args.len() != func.arity() {
+                    return Err(ParseError::semantic(
+                        &messages::WRONG_ARG_COUNT,
+                        &[&func.name(), &func.arity(), &args.len()],
+                        &pos,
+                    ));
+                }
+                Ok(NumExpr::Math { func, args })
+            }
+            Token::ArgCount => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(NumExpr::ArgCount)
+            }
+            Token::Str(_)
+            | Token::ToStr
+            | Token::Nl
+            | Token::Arg
+            | Token::Substr
+            | Token::FormatThousands
+            | Token::DecimalComma => self.parse_str_comparison(variables),
+            Token::Len => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let s = self.parse_str_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(NumExpr::Len(Box::new(s)))
+            }
+            Token::ToInt | Token::ToFloat => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let s = Box::new(self.parse_str_expr(variables)?);
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                if matches!(tok, Token::ToInt) {
+                    Ok(NumExpr::ToInt(s))
+                } else {
+                    Ok(NumExpr::ToFloat(s))
+                }
+            }
+            Token::Random => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(NumExpr::Random)
+            }
+            Token::Has => {
                 self.next_token()?;
-                let var = get_variable(variables, var_name);
-                Ok(NumExpr::Var {
-                    var,
-                    pos: self.pos.clone(),
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let (var, key, pos) = self.parse_map_and_key(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(NumExpr::MapHas { var, key: Box::new(key), pos })
+            }
+            Token::RandomInt => {
+                self.next_token()?;
+                crate::expect!(self, Token::LParen, grammar::LPAREN)?;
+                let lo = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::Comma, grammar::COMMA)?;
+                let hi = self.parse_num_expr(variables)?;
+                crate::expect!(self, Token::RParen, grammar::RPAREN)?;
+                Ok(NumExpr::RandomInt {
+                    lo: Box::new(lo),
+                    hi: Box::new(hi),
                 })
             }
-            _ => Err(ParseError::Unexpected {
-                found: self.token.clone(),
-                expected: "an expression",
-                pos: self.pos.clone(),
-            }),
+            Token::Ident(ref var_name) => {
+                let pos = self.pos.clone();
+                // `name(` is a call, not a variable
+                if matches!(self.peek()?, Token::LParen) {
+                    return Err(ParseError::syntax(&messages::CALL_IN_EXPRESSION, &[var_name, var_name], &pos));
+                }
+                let is_char = variables.iter().any(|v| v.name == *var_name && v.ty == Ty::Char);
+                if is_char && self.peek_comparison()? {
+                    return self.parse_str_comparison(variables);
+                }
+                self.next_token()?;
+                if matches!(self.token, Token::Dot) && self.is_enum(var_name) {
+                    return Ok(NumExpr::Int(self.parse_enum_value(var_name, &pos)?));
+                }
+                let var = get_variable(variables, var_name, &pos)?;
+                if var.ty == Ty::Map && matches!(self.token, Token::LBracket) {
+                    let key = Box::new(self.parse_map_key(variables)?);
+                    return Ok(NumExpr::MapGet { var, key, pos });
+                }
+                check_number(&var, &pos)?;
+                Ok(NumExpr::Var { var, pos })
+            }
+            _ => Err(self.unexpected("an expression")),
         }
     }
-    // type ::= INT | FLOAT
+    // str_comparison ::= str_expr ('==' | '!=') str_expr
+    // Strings have no order: `<`, `<=`, `>` and `>=` are rejected.
+    fn parse_str_comparison(&mut self, variables: &Vec<Variable>) -> Result<NumExpr, ParseError> {
+        let left = self.parse_str_expr(variables)?;
+        self.finish_str_comparison(left, variables)
+    }
+
+    // The comparison of `left`, already parsed, with the str_expr after the operator
+    fn finish_str_comparison(&mut self, left: StrExpr, variables: &Vec<Variable>) -> Result<NumExpr, ParseError> {
+        let negated = match self.token {
+            Token::EqEq => false,
+            Token::NotEq => true,
+            Token::Less => return Err(self.str_order_error(grammar::LESS)),
+            Token::LessEq => return Err(self.str_order_error(grammar::LESS_EQ)),
+            Token::Greater => return Err(self.str_order_error(grammar::GREATER)),
+            Token::GreaterEq => return Err(self.str_order_error(grammar::GREATER_EQ)),
+            _ => {
+                return Err(self.unexpected("`==` or `!=` after a string"));
+            }
+        };
+        self.next_token()?;
+        let right = self.parse_str_expr(variables)?;
+        Ok(NumExpr::StrEq {
+            left: Box::new(left),
+            right: Box::new(right),
+            negated,
+        })
+    }
+
+    // Whether the current token is a comparison operator
+    fn at_comparison(&self) -> bool {
+        matches!(
+            self.token,
+            Token::EqEq | Token::NotEq | Token::Less | Token::LessEq | Token::Greater | Token::GreaterEq
+        )
+    }
+
+    // Whether the token after the current one is a comparison operator
+    fn peek_comparison(&mut self) -> Result<bool, ParseError> {
+        Ok(matches!(
+            self.peek()?,
+            Token::EqEq | Token::NotEq | Token::Less | Token::LessEq | Token::Greater | Token::GreaterEq
+        ))
+    }
+
+    fn str_order_error(&self, op: &str) -> ParseError {
+        ParseError::semantic(&messages::STRING_ORDER, &[&op], &self.pos)
+    }
+
+    // type ::= INT | FLOAT | CHAR | FN | MAP | BUILDER
     fn parse_type(&mut self) -> Result<Ty, ParseError> {
         match self.token {
             Token::IntType => {
@@ -518,20 +1726,85 @@ impl Parser {
                 self.next_token()?;
                 Ok(Ty::F64)
             }
-            _ => Err(ParseError::Unexpected {
-                found: self.token.clone(),
-                expected: "a type (int or float)",
-                pos: self.pos.clone(),
-            }),
+            Token::CharType => {
+                self.next_token()?;
+                Ok(Ty::Char)
+            }
+            Token::MapType => {
+                self.next_token()?;
+                Ok(Ty::Map)
+            }
+            Token::BuilderType => {
+                self.next_token()?;
+                Ok(Ty::Builder)
+            }
+            Token::Fn => {
+                self.next_token()?;
+                Ok(Ty::Fn)
+            }
+            _ => Err(self.unexpected("a type (int, float, char, fn, map or builder)")),
         }
     }
 
     // variable_declaration ::= LOCAL type ident
     fn parse_variable_declaration(&mut self) -> Result<Variable, ParseError> {
+        self.check_new_stadment()?;
         crate::expect!(self, Token::Local, grammar::KW_LOCAL)?;
         let ty = self.parse_type()?;
         let (name, pos) =
             crate::expect!(self,Token::Ident(s) => s, "a valid variable name after `local type`")?;
-        Ok(Variable { name, ty })
+        self.end_stadment()?;
+        Ok(Variable { name, ty, pos })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::lexer::Source;
+
+    fn parse(text: String) -> Result<MainProgram, ParseError> {
+        Parser::new(Lexer::new(Path::new("test.mpl"), Source::Text(text)))?.parse_main_program()
+    }
+
+    // `main` assigning the sum of `terms` ones, as one flat expression
+    fn parse_sum(terms: usize) -> Result<MainProgram, ParseError> {
+        let sum = vec!["1"; terms].join(" + ");
+        parse(format!("main() {{\n    local int i\n    let i = {}\n    return i\n}}\n", sum))
+    }
+
+    // The header and code of the error of `main` with `body`
+    fn error_of(body: &str) -> (&'static str, &'static str) {
+        let e = parse(format!("main() {{\n    local int i\n{}\n    return 0\n}}\n", body)).expect_err("an error");
+        let diagnostic = e.diagnostic();
+        (diagnostic.kind.code, diagnostic.code)
+    }
+
+    #[test]
+    fn syntax_errors_are_grammar_errors() {
+        let grammar = messages::GRAMMAR_ERROR.code;
+        assert_eq!(error_of("    let i = 1 let i = 2"), (grammar, messages::STATEMENTS_ON_ONE_LINE.code));
+        assert_eq!(error_of("    let i = 1 2"), (grammar, messages::MISSING_OPERATOR.code));
+        assert_eq!(parse_sum(2000).expect_err("too deep").diagnostic().kind.code, grammar);
+    }
+
+    #[test]
+    fn semantic_errors_are_not_code_generation_errors() {
+        let semantic = messages::SEMANTIC_ERROR.code;
+        assert_eq!(error_of("    let j = 1"), (semantic, messages::VARIABLE_NOT_DECLARED.code));
+        assert_eq!(error_of("    match 1.5\n    end"), (semantic, messages::MATCH_ON_FLOAT.code));
+    }
+
+    #[test]
+    fn a_long_flat_expression_is_too_deep() {
+        let e = parse_sum(2000).expect_err("2000 terms are too deep");
+        assert_eq!(e.diagnostic().code, messages::EXPRESSION_TOO_DEEP.code);
+    }
+
+    #[test]
+    fn a_flat_expression_within_the_limit_parses() {
+        parse_sum(100).expect("the sum parses");
     }
 }
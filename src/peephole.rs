@@ -0,0 +1,115 @@
+// My Programming Language
+// Peephole pass on the emitted instructions of a function body (-O1 and above).
+//
+// codegen emits each expression the simple way (`i32.const 0; <x>; i32.sub` for -x, an int
+// converted to a float before a float operation...). Once a body is complete, neighbouring
+// instructions with a known result are rewritten until nothing changes:
+//
+// i32.const a; i32.const b; i32.add/sub/mul  ->  i32.const (a op b), wrapping like wasm
+// f64.const a; f64.const b; f64.add/sub/mul/div  ->  f64.const (a op b)
+// f64.const a; f64.neg                    ->  f64.const -a
+// i32.const n; f64.convert_i32_s           ->  f64.const n
+// f64.convert_i32_s; i32.trunc_f64_s       ->  nothing (every i32 is an exact f64)
+// i32.const/f64.const/local.get; drop      ->  nothing
+// local.set x; local.get x                 ->  local.tee x
+//
+// Only straight-line neighbours are rewritten: no pattern contains a block, a branch or a call.
+//
+// The statement starts recorded for the source map (-c --debug) follow the rewrites: a start on
+// a removed instruction moves to the instruction replacing it, else to the next one.
+
+use wasm_encoder::reencode::{Reencode, RoundtripReencoder};
+use wasm_encoder::{Encode, Ieee64, Instruction};
+use wasmparser::{BinaryReader, OperatorsReader};
+
+/// The instructions of `body` (a function body without its locals) with the patterns rewritten.
+/// `marks`, offsets of instructions in `body` in increasing order, become offsets in the result.
+pub fn optimize_body(body: &[u8], marks: &mut [u32]) -> Vec<u8> {
+    let Some(mut code) = decode(body, marks) else {
+        return body.to_vec();
+    };
+    while rewrite(&mut code) {}
+    let mut out = Vec::with_capacity(body.len());
+    let mut next = marks.iter_mut();
+    for (instruction, count) in &code {
+        for mark in next.by_ref().take(*count as usize) {
+            *mark = out.len() as u32;
+        }
+        instruction.encode(&mut out);
+    }
+    for mark in next {
+        *mark = out.len() as u32;
+    }
+    out
+}
+
+// The instructions, each with the number of marks on it
+fn decode<'a>(body: &'a [u8], marks: &[u32]) -> Option<Vec<(Instruction<'a>, u32)>> {
+    let mut reader = OperatorsReader::new(BinaryReader::new(body, 0));
+    let mut code = Vec::new();
+    let mut marks = marks.iter().peekable();
+    while !reader.eof() {
+        let offset = reader.original_position() as u32;
+        let mut count = 0;
+        while marks.next_if(|&&mark| mark <= offset).is_some() {
+            count += 1;
+        }
+        let op = reader.read().ok()?;
+        code.push((RoundtripReencoder.instruction(op).ok()?, count));
+    }
+    Some(code)
+}
+
+fn f64_of(x: &Ieee64) -> f64 {
+    f64::from(*x)
+}
+
+// One pass over the code; true if something was rewritten
+fn rewrite(code: &mut Vec<(Instruction<'_>, u32)>) -> bool {
+    use Instruction::*;
+    let mut out: Vec<Instruction<'_>> = Vec::with_capacity(code.len());
+    let mut counts: Vec<u32> = Vec::with_capacity(code.len());
+    let mut moved = 0; // marks of removed instructions, for the next one
+    let mut changed = false;
+    for (ins, count) in code.drain(..) {
+        let n = out.len();
+        let replaced: Option<(usize, Vec<Instruction<'_>>)> = match (&out[n.saturating_sub(2)..], &ins) {
+            ([I32Const(a), I32Const(b)], I32Add) => Some((2, vec![I32Const(a.wrapping_add(*b))])),
+            ([I32Const(a), I32Const(b)], I32Sub) => Some((2, vec![I32Const(a.wrapping_sub(*b))])),
+            ([I32Const(a), I32Const(b)], I32Mul) => Some((2, vec![I32Const(a.wrapping_mul(*b))])),
+            ([F64Const(a), F64Const(b)], F64Add | F64Sub | F64Mul | F64Div) => {
+                let (a, b) = (f64_of(a), f64_of(b));
+                let r = match ins {
+                    F64Add => a + b,
+                    F64Sub => a - b,
+                    F64Mul => a * b,
+                    _ => a / b,
+                };
+                Some((2, vec![F64Const(r.into())]))
+            }
+            ([.., F64Const(a)], F64Neg) => Some((1, vec![F64Const((-f64_of(a)).into())])),
+            ([.., I32Const(a)], F64ConvertI32S) => Some((1, vec![F64Const((*a as f64).into())])),
+            ([.., F64ConvertI32S], I32TruncF64S) => Some((1, vec![])),
+            ([.., I32Const(_) | F64Const(_) | LocalGet(_)], Drop) => Some((1, vec![])),
+            ([.., LocalSet(x)], LocalGet(y)) if x == y => Some((1, vec![LocalTee(*x)])),
+            _ => None,
+        };
+        match replaced {
+            Some((removed, with)) => {
+                moved += counts.drain(n - removed..).sum::<u32>() + count;
+                out.truncate(n - removed);
+                for ins in with {
+                    out.push(ins);
+                    counts.push(std::mem::take(&mut moved));
+                }
+                changed = true;
+            }
+            None => {
+                out.push(ins);
+                counts.push(count + std::mem::take(&mut moved));
+            }
+        }
+    }
+    *code = out.into_iter().zip(counts).collect();
+    changed
+}
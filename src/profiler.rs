@@ -0,0 +1,188 @@
+// My Programming Language
+// Function profile of `mpl run --profile-functions`: how many times each MPL function was
+// called and the time spent in it, printed as a table on stderr at the end of the run:
+//
+//   functions:
+//     function                  calls    total ms     self ms
+//     fib                         177       0.842       0.842
+//     main                          1       0.901       0.059
+//
+// The code generator calls the host when a function starts and returns (see CodegenHooks):
+//
+//   i32.const <function>; call prof.enter     ;; first thing of the body
+//   i32.const <function>; call prof.exit      ;; before each return and the end of the body
+//
+// total is the time from enter to exit, that of recursive calls counted once; self leaves
+// out the time of the functions it called. The time of the host calls themselves is included.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use wasm_encoder::ValType;
+
+use crate::codegen::CodegenHooks;
+use crate::runner::{HostFunction, HostType, HostValue};
+
+#[derive(Default)]
+struct FunctionStats {
+    calls: u64,
+    total: Duration,
+    own: Duration, // self time
+}
+
+// A running call: function, when it started, time spent in the functions it called
+struct Frame {
+    function: u32,
+    started: Instant,
+    callees: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    names: HashMap<u32, String>, // MPL name of the instrumented functions
+    stats: HashMap<u32, FunctionStats>,
+    stack: Vec<Frame>,
+}
+
+impl State {
+    fn enter(&mut self, function: u32) {
+        self.stats.entry(function).or_default().calls += 1;
+        self.stack.push(Frame {
+            function,
+            started: Instant::now(),
+            callees: Duration::ZERO,
+        });
+    }
+
+    fn exit(&mut self, function: u32) -> Result<(), String> {
+        let frame = self
+            .stack
+            .pop()
+            .filter(|f| f.function == function)
+            .ok_or_else(|| format!("prof.exit({}) does not match the running function", function))?;
+        let spent = frame.started.elapsed();
+        let recursive = self.stack.iter().any(|f| f.function == function);
+        let stats = self.stats.entry(function).or_default();
+        if !recursive {
+            stats.total += spent;
+        }
+        stats.own += spent.saturating_sub(frame.callees);
+        if let Some(caller) = self.stack.last_mut() {
+            caller.callees += spent;
+        }
+        Ok(())
+    }
+}
+
+/// The function profile of a run: the instrumentation given to the code generator and the
+/// host functions given to the runner share it; its Display is the table.
+#[derive(Clone, Default)]
+pub struct Profiler {
+    state: Arc<Mutex<State>>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The calls to prof.enter and prof.exit at the start and the returns of every function.
+    pub fn hooks(&self) -> CodegenHooks {
+        let state = Arc::clone(&self.state);
+        CodegenHooks::new()
+            .import("prof", "enter", &[ValType::I32], &[])
+            .import("prof", "exit", &[ValType::I32], &[])
+            .on_function_enter(move |ctx, instr| {
+                state.lock().unwrap().names.insert(ctx.fn_id, ctx.function.name.clone());
+                instr.i32_const(ctx.fn_id as i32);
+                instr.call(ctx.func_index("prof.enter").expect("prof imports declared"));
+            })
+            .on_function_exit(|ctx, instr| {
+                instr.i32_const(ctx.fn_id as i32);
+                instr.call(ctx.func_index("prof.exit").expect("prof imports declared"));
+            })
+    }
+
+    /// The prof.* functions of the instrumented module.
+    pub fn host_functions(&self) -> Vec<HostFunction> {
+        use HostType::I32;
+        let enter = Arc::clone(&self.state);
+        let exit = Arc::clone(&self.state);
+        vec![
+            HostFunction::new("prof", "enter", &[I32], &[], move |args| {
+                enter.lock().unwrap().enter(function(args));
+                Ok(Vec::new())
+            }),
+            HostFunction::new("prof", "exit", &[I32], &[], move |args| {
+                exit.lock().unwrap().exit(function(args))?;
+                Ok(Vec::new())
+            }),
+        ]
+    }
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        let mut rows: Vec<(&str, &FunctionStats)> = state
+            .stats
+            .iter()
+            .map(|(id, stats)| (state.names.get(id).map_or("?", String::as_str), stats))
+            .collect();
+        rows.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(f, "functions:")?;
+        write!(f, "  {:<20} {:>10} {:>11} {:>11}", "function", "calls", "total ms", "self ms")?;
+        for (name, stats) in rows {
+            write!(
+                f,
+                "\n  {:<20} {:>10} {:>11.3} {:>11.3}",
+                name,
+                stats.calls,
+                ms(stats.total),
+                ms(stats.own)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn function(args: &[HostValue]) -> u32 {
+    match args.first() {
+        Some(HostValue::I32(v)) => *v as u32,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::CodeGenerator;
+    use crate::runner::RunOptions;
+    use crate::testing;
+
+    #[test]
+    fn counts_the_calls_of_each_function() {
+        let text = "fn down() {\n    local int n\n    let n = n - 1\n}\nmain() {\n    local int i\n    for i = 1 to 5\n        down()\n    next\n}\n";
+        let profiler = Profiler::new();
+        let wasm = testing::compile_with(CodeGenerator::with_hooks(profiler.hooks()), text);
+        let options = RunOptions {
+            host: profiler.host_functions().into_iter().collect(),
+            ..RunOptions::default()
+        };
+        let (outcome, _) = testing::run_wasm(&wasm, &options);
+        outcome.expect("the program runs");
+        let table = profiler.to_string();
+        let calls = |name: &str| -> Option<u64> {
+            let row = table.lines().find(|row| row.split_whitespace().next() == Some(name))?;
+            row.split_whitespace().nth(1)?.parse().ok()
+        };
+        assert!(table.starts_with("functions:\n  function"), "{}", table);
+        assert_eq!(calls("down"), Some(5), "{}", table);
+        assert_eq!(calls("main"), Some(1), "{}", table);
+    }
+}
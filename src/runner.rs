@@ -1,188 +1,2022 @@
 // runner.rs (wasmi 0.51.x)
-// Provides: env.memory, env.log, str.to_str, str.concat
-// Uses exported mutable global 'heap_ptr' as a bump allocator.
+// Provides: env.memory, env.log, env.flush, env.args_count, env.args_get, env.dump_heap, env.random, env.random_int,
+//           math.pow/sin/cos/tan/log/exp, str.to_str, str.concat, str.len, str.substr, str.char_at, str.eq,
+//           str.code_at, str.char_text,
+//           str.parse_i32/parse_f64, and the HostFunctions of RunOptions (debugger, extern fn...)
+// They are all registered in a HostRegistry (see builtins()), which the engines turn into imports.
+// str.to_str and str.concat are only imported by modules built before they were emitted
+// into the module itself (runtime.rs); they are kept so those modules still run with mpl run-wasm.
+// Uses exported mutable global 'heap_ptr' as a bump allocator, never below 'data_end'.
+// The engine is behind `WasmHost`: wasmi (interpreter, default) or wasmtime (JIT, feature "wasmtime").
+// The engine-independent parts of the host functions (results, trap messages) are shared below.
+// Wasm libraries (mpl compile --lib, imported from modules named "<name>.wasm") are linked by the wasmi
+// engine: instantiated after the program, their data placed on top of the heap (see link_libraries()).
 
 use anyhow::{Result, anyhow};
 use std::{
-    fs,
-    path::Path,
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
-use wasmi::{Caller, Engine, Linker, Memory, MemoryType, Module, Store, TypedFunc, Val};
+use wasmi::{
+    AsContextMut, Caller, Config, Engine, Func, Global, Instance, Linker, Memory, MemoryType, Module, Mutability,
+    ResumableCall, Store, TrapCode, Val,
+};
+
+use crate::codegen::{FloatFormat, LIBRARY_SECTION};
+use crate::{messages, meta, runtime};
 
+#[cfg(feature = "wasmtime")]
+mod wasmtime_host;
+
+/// `x` rounded up to a multiple of `align` (a power of two); None past u32::MAX.
 #[inline]
-fn align_up(x: u32, align: u32) -> u32 {
-    (x + (align - 1)) & !(align - 1)
+fn align_up(x: u32, align: u32) -> Option<u32> {
+    x.checked_add(align - 1).map(|x| x & !(align - 1))
+}
+
+/// Read a guest string; strings are UTF-8 and indexed by character, not by byte.
+fn read_str(mem: &mut dyn HostMemory, ptr: i32, len: i32) -> Result<String, String> {
+    utf8(mem.read(ptr, len)?, ptr)
+}
+
+fn utf8(bytes: Vec<u8>, ptr: i32) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|_| format!("string at 0x{:x} is not valid UTF-8", ptr))
+}
+
+/// Byte offsets of the characters [start, start + count) of `s`; None if out of range.
+fn char_range(s: &str, start: i32, count: i32) -> Option<(usize, usize)> {
+    let start = usize::try_from(start).ok()?;
+    let count = usize::try_from(count).ok()?;
+    let mut offsets = s.char_indices().map(|(i, _)| i).chain(std::iter::once(s.len()));
+    let from = offsets.nth(start)?;
+    let to = if count == 0 { from } else { offsets.nth(count - 1)? };
+    Some((from, to))
+}
+
+/// str.substr on the string `s` stored at `ptr`: the (ptr, len) of the slice.
+fn substr_of(s: &str, ptr: i32, start: i32, count: i32) -> Result<(i32, i32), String> {
+    let (from, to) = char_range(s, start, count).ok_or_else(|| {
+        format!(
+            "substr(_, {}, {}) out of range: the string has {} character(s)",
+            start,
+            count,
+            s.chars().count()
+        )
+    })?;
+    Ok((ptr + from as i32, (to - from) as i32))
+}
+
+/// str.char_at on the string `s` stored at `ptr`: the (ptr, len) of the character.
+fn char_at_of(s: &str, ptr: i32, i: i32) -> Result<(i32, i32), String> {
+    let (from, to) = char_range(s, i, 1).ok_or_else(|| {
+        format!(
+            "char_at(_, {}) out of range: the string has {} character(s)",
+            i,
+            s.chars().count()
+        )
+    })?;
+    Ok((ptr + from as i32, (to - from) as i32))
+}
+
+/// str.code_at: the code point of character `i` of `s`.
+fn code_at_of(s: &str, i: i32) -> Result<i32, String> {
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| s.chars().nth(i))
+        .map(|c| c as i32)
+        .ok_or_else(|| format!("char_at(_, {}) out of range: the string has {} character(s)", i, s.chars().count()))
+}
+
+/// str.char_text: the character of the char `c`, when it is a code point.
+fn char_of(c: i32) -> Result<char, String> {
+    u32::try_from(c)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("chr({}): not a Unicode code point (0 to 0x10FFFF, except 0xD800 to 0xDFFF)", c))
+}
+
+// Surrounding whitespace is ignored; anything else that is not a number traps.
+fn parse_int(s: &str) -> Result<i32, String> {
+    s.trim()
+        .parse::<i32>()
+        .map_err(|_| format!("to_int(\"{}\"): not a valid integer", s))
+}
+
+fn parse_float(s: &str) -> Result<f64, String> {
+    s.trim()
+        .parse::<f64>()
+        .map_err(|_| format!("to_float(\"{}\"): not a valid number", s))
+}
+
+/// env.args_get: the bytes of argument `i`.
+fn arg_bytes(args: &[String], i: i32) -> Result<&[u8], String> {
+    usize::try_from(i)
+        .ok()
+        .and_then(|i| args.get(i))
+        .map(|arg| arg.as_bytes())
+        .ok_or_else(|| format!("arg({}) out of range: the program has {} argument(s)", i, args.len()))
+}
+
+fn output_error(e: io::Error) -> String {
+    format!("cannot write output: {}", e)
+}
+
+// Host view of the guest heap, known once the module is instantiated.
+#[derive(Clone, Copy)]
+struct Heap<G> {
+    heap_ptr: G,           // exported bump pointer
+    data_end: u32,         // end of the constant data; nothing is allocated below it
+    start: u32,            // first block, once the libraries have their data (dump_heap)
+    free_list: Option<G>,  // exported head of the free blocks; absent from older modules
+}
+
+type HeapCell<G = wasmi::Global> = Arc<Mutex<Option<Heap<G>>>>;
+
+impl Heap<Global> {
+    fn layout(&self, ctx: impl wasmi::AsContext) -> HeapLayout {
+        let value = |global: &Global| match global.get(&ctx) {
+            Val::I32(v) => v as u32,
+            _ => 0,
+        };
+        HeapLayout {
+            start: self.start,
+            end: value(&self.heap_ptr),
+            free_list: self.free_list.as_ref().map(value),
+        }
+    }
+}
+
+fn below_data_end(ptr: u32, data_end: u32) -> String {
+    format!(
+        "heap_ptr (0x{:x}) is below data_end (0x{:x}): allocating would overwrite constant data",
+        ptr, data_end
+    )
+}
+
+/// Check the heap exported by a freshly instantiated module.
+fn check_heap(heap_start: u32, data_end: u32) -> Result<()> {
+    if heap_start < data_end {
+        return Err(anyhow!(
+            "invalid module: heap_ptr (0x{:x}) starts below data_end (0x{:x})",
+            heap_start,
+            data_end
+        ));
+    }
+    Ok(())
+}
+
+/// Memory of the module in pages: the minimum it declares, and its maximum capped by --max-memory.
+fn memory_pages(min_pages: u64, declared_max: Option<u64>, max_memory: Option<u64>) -> Result<(u32, Option<u32>)> {
+    let cap = max_memory.map(|bytes| bytes.div_ceil(PAGE_SIZE).min(65536));
+    if let Some(cap) = cap
+        && cap < min_pages
+    {
+        return Err(anyhow!(
+            "--max-memory is below the {} bytes of initial memory the module needs",
+            min_pages * PAGE_SIZE
+        ));
+    }
+    let max_pages = match (declared_max, cap) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+    .map(|p| p as u32);
+    Ok((min_pages as u32, max_pages))
+}
+
+/// What alloc_bytes needs of a running module, whatever the engine: its memory and the
+/// 'heap_ptr' global (which the module can set to anything).
+trait HeapAccess {
+    /// 'heap_ptr' and 'data_end'
+    fn heap_ptr(&mut self) -> Result<(u32, u32), String>;
+    fn set_heap_ptr(&mut self, ptr: u32) -> Result<(), String>;
+    /// The pages of the memory, and its maximum if it has one
+    fn pages(&mut self) -> (u64, Option<u64>);
+    /// Add `pages` pages to the memory; false past its maximum
+    fn grow(&mut self, pages: u64) -> bool;
+    fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), String>;
+}
+
+/// Copy `parts` one after the other into a new heap block at 'heap_ptr' and bump it;
+/// returns (ptr, len) of the payload. Traps if 'heap_ptr' was moved below 'data_end', and with
+/// out of memory if the block does not fit (also when 'heap_ptr' is near the end of the
+/// address space).
+fn alloc_bytes(memory: &mut impl HeapAccess, parts: &[&[u8]]) -> Result<(i32, i32), String> {
+    let (ptr, data_end) = memory.heap_ptr()?;
+    if ptr < data_end {
+        return Err(below_data_end(ptr, data_end));
+    }
+
+    let (current, max) = memory.pages();
+    let no_room = || out_of_memory(max.unwrap_or(current));
+    let total = parts
+        .iter()
+        .try_fold(0u32, |sum, part| sum.checked_add(u32::try_from(part.len()).ok()?))
+        .ok_or_else(no_room)?;
+    let payload = ptr.checked_add(HEADER).ok_or_else(no_room)?;
+    let next = payload.checked_add(total).and_then(|end| align_up(end, 16)).ok_or_else(no_room)?;
+    let pages = (next as u64).div_ceil(PAGE_SIZE);
+    if pages > current && !memory.grow(pages - current) {
+        return Err(no_room());
+    }
+
+    memory.write(ptr, &block_header(ptr, next))?;
+    let mut end = payload;
+    for part in parts {
+        memory.write(end, part)?;
+        end += part.len() as u32; // at most next
+    }
+    memory.set_heap_ptr(next)?;
+    Ok((payload as i32, total as i32))
+}
+
+const HEADER: u32 = runtime::HEADER as u32;
+
+// Header of a used block from `ptr` to `next`, as rt.alloc writes it: [size][next = 0]
+fn block_header(ptr: u32, next: u32) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&(next - ptr - HEADER).to_le_bytes());
+    header
+}
+
+fn random_int_in(rng: &mut Rng, lo: i32, hi: i32) -> Result<i32, String> {
+    if lo > hi {
+        return Err(format!(
+            "random_int({}, {}): the lower bound is greater than the upper bound",
+            lo, hi
+        ));
+    }
+    let span = (hi as i64 - lo as i64 + 1) as u64;
+    let n = rng.next_u64() % span;
+    Ok((lo as i64 + n as i64) as i32)
+}
+
+// The exit code of a run: main returned a status the process can exit with. Outside 0..=255,
+// it would be cut to its low byte (256 would exit with 0, a success), so it is an error instead.
+fn main_exit_code(code: i32) -> Result<i32> {
+    if (0..=255).contains(&code) {
+        Ok(code)
+    } else {
+        Err(anyhow!(messages::EXIT_CODE_OUT_OF_RANGE.coded(&[&code])))
+    }
+}
+
+fn out_of_memory(max_pages: u64) -> String {
+    messages::OUT_OF_MEMORY.coded(&[&(max_pages * PAGE_SIZE)])
+}
+
+const PAGE_SIZE: u64 = 65536;
+
+fn fuel_exhausted(fuel: u64) -> String {
+    messages::FUEL_EXHAUSTED.coded(&[&fuel])
+}
+
+/// Calls in progress at most when --max-call-depth is not given, whatever the engine.
+pub const DEFAULT_CALL_DEPTH: usize = 1000;
+
+// The call stack overflowed: a recursion that does not stop, in `function` (the one running,
+// else the recursive ones of the module)
+fn stack_overflow(wasm_bytes: &[u8], function: Option<String>, max_call_depth: usize) -> String {
+    let functions = match function {
+        Some(function) => vec![function],
+        None => recursive_functions(wasm_bytes),
+    };
+    // o::f, the mangled name of a function of a module, is called as o.f()
+    let names: Vec<String> = functions.iter().map(|f| format!("'{}'", f.replace("::", "."))).collect();
+    let place = match names.as_slice() {
+        [] => String::new(),
+        [name] => messages::IN_FUNCTION.format(&[name]),
+        names => messages::IN_FUNCTIONS.format(&[&names.join(", ")]),
+    };
+    messages::STACK_OVERFLOW.coded(&[&place, &max_call_depth])
+}
+
+// The functions of the module that call themselves, directly or through others, by the
+// names of its name section (none in a module built with --strip)
+fn recursive_functions(wasm_bytes: &[u8]) -> Vec<String> {
+    let mut imported = 0;
+    let mut calls: Vec<Vec<u32>> = Vec::new(); // by defined function
+    let mut names = HashMap::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm_bytes) {
+        match payload {
+            Ok(wasmparser::Payload::ImportSection(imports)) => {
+                imported += imports
+                    .into_iter()
+                    .filter(|i| matches!(i.as_ref().map(|i| i.ty), Ok(wasmparser::TypeRef::Func(_))))
+                    .count() as u32;
+            }
+            Ok(wasmparser::Payload::CodeSectionEntry(body)) => {
+                let mut callees = Vec::new();
+                if let Ok(mut operators) = body.get_operators_reader() {
+                    while let Ok(op) = operators.read() {
+                        if let wasmparser::Operator::Call { function_index } = op {
+                            callees.push(function_index);
+                        }
+                    }
+                }
+                calls.push(callees);
+            }
+            Ok(wasmparser::Payload::CustomSection(section)) => {
+                if let wasmparser::KnownCustom::Name(reader) = section.as_known() {
+                    for subsection in reader.into_iter().flatten() {
+                        if let wasmparser::Name::Function(map) = subsection {
+                            names.extend(map.into_iter().flatten().map(|n| (n.index, n.name.to_string())));
+                        }
+                    }
+                }
+            }
+            Err(_) => break,
+            _ => {}
+        }
+    }
+    // the defined functions reaching themselves
+    let reaches_itself = |start: u32| {
+        let mut seen = HashSet::new();
+        let mut todo = vec![start];
+        while let Some(f) = todo.pop() {
+            let callees = f.checked_sub(imported).and_then(|i| calls.get(i as usize));
+            for &callee in callees.into_iter().flatten() {
+                if callee == start {
+                    return true;
+                }
+                if seen.insert(callee) {
+                    todo.push(callee);
+                }
+            }
+        }
+        false
+    };
+    (imported..imported + calls.len() as u32)
+        .filter(|&f| reaches_itself(f))
+        .filter_map(|f| names.get(&f).cloned())
+        .collect()
+}
+
+fn timed_out(timeout: Duration) -> String {
+    messages::TIMED_OUT.coded(&[&timeout.as_secs_f64()])
+}
+
+/// Parse a --timeout value: seconds, possibly with a fractional part.
+pub fn parse_timeout(s: &str) -> Result<Duration> {
+    let secs: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid timeout '{}' (seconds)", s))?;
+    if secs.is_nan() || secs <= 0.0 {
+        return Err(anyhow!("the timeout must be a positive number of seconds"));
+    }
+    Duration::try_from_secs_f64(secs).map_err(|_| anyhow!("timeout '{}' is too large", s))
+}
+
+/// When the program output buffered by env.log reaches stdout.
+/// Whatever the mode, it is flushed by the flush() builtin and at program end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    Always, // after every print
+    #[default]
+    Line, // after every print containing a newline
+    Block, // only when the buffer is full
+}
+
+impl std::str::FromStr for FlushMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(FlushMode::Always),
+            "line" => Ok(FlushMode::Line),
+            "block" => Ok(FlushMode::Block),
+            _ => Err(anyhow!("unknown flush mode '{}' (always, line or block)", s)),
+        }
+    }
+}
+
+/// Where the program output goes: env.log writes to it, env.flush and the end of the run flush it.
+pub trait OutputSink: Send {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Program output on stdout, buffered and flushed according to the FlushMode.
+pub struct StdoutSink {
+    out: io::BufWriter<io::Stdout>,
+    mode: FlushMode,
+}
+
+impl StdoutSink {
+    pub fn new(mode: FlushMode) -> Self {
+        StdoutSink {
+            out: io::BufWriter::new(io::stdout()),
+            mode,
+        }
+    }
+}
+
+// Whether the program output on stdout ends in the middle of a line (print without nl)
+static STDOUT_LINE_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Whether what the programs printed on stdout does not end with a newline, so that a message
+/// written on the same terminal would follow it on its last line.
+pub fn stdout_line_open() -> bool {
+    STDOUT_LINE_OPEN.load(Ordering::Relaxed)
+}
+
+impl OutputSink for StdoutSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        if let Some(&last) = bytes.last() {
+            STDOUT_LINE_OPEN.store(last != b'\n', Ordering::Relaxed);
+        }
+        match self.mode {
+            FlushMode::Always => self.out.flush(),
+            FlushMode::Line if bytes.contains(&b'\n') => self.out.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Program output kept in memory, shared with whoever reads it after the run.
+#[derive(Clone, Default)]
+pub struct CaptureSink(Arc<Mutex<Vec<u8>>>);
+
+impl CaptureSink {
+    /// What the program printed so far (invalid UTF-8 is replaced).
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
 }
 
-/// Read a slice from guest memory.
-fn read_slice(mem: &Memory, caller: &mut Caller<'_, ()>, ptr: u32, len: u32) -> Vec<u8> {
-    let mut buf = vec![0u8; len as usize];
-    // wasmi 0.51: Memory::read takes &Caller (or &mut Caller); both work.
-    mem.read(&*caller, ptr as usize, &mut buf)
-        .expect("mem read");
-    buf
+impl OutputSink for CaptureSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Pseudo-random generator behind env.random (splitmix64): small, fast and reproducible from a seed.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: Option<u64>) -> Self {
+        // Without a seed, every run is different.
+        let state = seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)
+        });
+        Rng { state }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1): the 53 high bits as the mantissa
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Most decimals of to_str(x, decimals), as JavaScript's toFixed
+const MAX_DECIMALS: i32 = 100;
+
+// to_str(x, decimals)
+fn fixed_text(x: f64, decimals: i32) -> Result<String, String> {
+    if !(0..=MAX_DECIMALS).contains(&decimals) {
+        return Err(format!(
+            "to_str(_, {}): the number of decimals must be between 0 and {}",
+            decimals, MAX_DECIMALS
+        ));
+    }
+    Ok(format!("{:.*}", decimals as usize, x))
+}
+
+// The digits of a number's text before the dot grouped by three: 1234567.5 -> 1 234 567.5
+fn group_thousands(text: &str) -> String {
+    let (sign, unsigned) = text.strip_prefix('-').map_or(("", text), |rest| ("-", rest));
+    let (int, fraction) = unsigned.split_at(unsigned.find('.').unwrap_or(unsigned.len()));
+    if !int.bytes().all(|b| b.is_ascii_digit()) {
+        return text.to_string(); // NaN, inf
+    }
+    let mut grouped = String::from(sign);
+    for (i, digit) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            grouped.push(' ');
+        }
+        grouped.push(digit);
+    }
+    grouped + fraction
 }
 
-/// Write a slice into guest memory.
-fn write_slice(mem: &Memory, caller: &mut Caller<'_, ()>, ptr: u32, data: &[u8]) {
-    mem.write(&mut *caller, ptr as usize, data)
-        .expect("mem write");
+// Host implementation of a one-argument math.* import
+type UnaryMathFn = fn(f64) -> f64;
+
+// math.sin/cos/tan/log/exp(x: f64) -> f64 (log is the natural logarithm)
+const UNARY_MATH: [(&str, UnaryMathFn); 5] = [
+    ("sin", f64::sin),
+    ("cos", f64::cos),
+    ("tan", f64::tan),
+    ("log", f64::ln),
+    ("exp", f64::exp),
+];
+
+/// The engine running the modules.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EngineKind {
+    #[default]
+    Wasmi, // interpreter: portable, always available
+    Wasmtime, // JIT compiler: faster on long computations, needs the "wasmtime" feature
+}
+
+impl std::str::FromStr for EngineKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wasmi" => Ok(EngineKind::Wasmi),
+            "wasmtime" => Ok(EngineKind::Wasmtime),
+            _ => Err(anyhow!("unknown engine '{}' (wasmi or wasmtime)", s)),
+        }
+    }
+}
+
+/// A WebAssembly engine providing the host functions of the MPL modules.
+pub trait WasmHost {
+    /// Instantiate the module and run its `main`; the program output goes to `output`.
+    fn run(&self, wasm_bytes: &[u8], options: &RunOptions, output: Box<dyn OutputSink>) -> Result<RunOutcome>;
+}
+
+/// The engine of the given kind; fails if mpl was built without it.
+pub fn host(kind: EngineKind) -> Result<Box<dyn WasmHost>> {
+    match kind {
+        EngineKind::Wasmi => Ok(Box::new(WasmiHost)),
+        #[cfg(feature = "wasmtime")]
+        EngineKind::Wasmtime => Ok(Box::new(wasmtime_host::WasmtimeHost)),
+        #[cfg(not(feature = "wasmtime"))]
+        EngineKind::Wasmtime => Err(anyhow!(
+            "this mpl was built without the wasmtime engine (cargo build --features wasmtime)"
+        )),
+    }
+}
+
+/// Type of a value passed to or returned by a `HostFunction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostType {
+    I32,
+    I64,
+    F64,
+}
+
+/// A value passed to or returned by a `HostFunction`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostValue {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+}
+
+pub type HostCallback =
+    Arc<dyn Fn(&mut dyn HostMemory, &[HostValue]) -> Result<Vec<HostValue>, String> + Send + Sync>;
+
+/// The linear memory of the module, as a host function sees it during a call.
+pub trait HostMemory {
+    /// The whole memory.
+    fn data(&self) -> &[u8];
+
+    /// Copy `parts` one after the other into a new heap block; the (ptr, len) of the payload.
+    fn alloc(&mut self, parts: &[&[u8]]) -> Result<(i32, i32), String>;
+
+    /// Where the heap blocks are now.
+    fn heap(&mut self) -> HeapLayout;
+
+    /// The `len` bytes at `ptr`; an error if they are not all in memory.
+    fn read(&self, ptr: i32, len: i32) -> Result<Vec<u8>, String> {
+        let (start, count) = (ptr as u32 as usize, len as u32 as usize);
+        self.data()
+            .get(start..start.saturating_add(count))
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| format!("out of bounds memory access: {} byte(s) at 0x{:x}", count, start))
+    }
+}
+
+/// A function the module imports from the host. The built-in ones are in every run; those of
+/// RunOptions::host add to them or replace them, for the tools instrumenting the code (see
+/// CodegenHooks::import) and for the `extern fn` of the programs. The program output is
+/// flushed before each call of those; an error stops the program.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub module: String,
+    pub name: String,
+    pub params: Vec<HostType>,
+    pub results: Vec<HostType>,
+    pub call: HostCallback,
+    flush: bool, // the output is flushed before each call (all but the built-in functions)
+}
+
+impl HostFunction {
+    /// A function of the arguments only.
+    pub fn new(
+        module: &str,
+        name: &str,
+        params: &[HostType],
+        results: &[HostType],
+        call: impl Fn(&[HostValue]) -> Result<Vec<HostValue>, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self::with_memory(module, name, params, results, move |_, args| call(args))
+    }
+
+    /// A function that also reads or allocates in the memory of the module (strings...).
+    pub fn with_memory(
+        module: &str,
+        name: &str,
+        params: &[HostType],
+        results: &[HostType],
+        call: impl Fn(&mut dyn HostMemory, &[HostValue]) -> Result<Vec<HostValue>, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            module: module.to_string(),
+            name: name.to_string(),
+            params: params.to_vec(),
+            results: results.to_vec(),
+            call: Arc::new(call),
+            flush: true,
+        }
+    }
+
+    // A built-in function: the output is only flushed by those writing it
+    fn builtin(
+        module: &str,
+        name: &str,
+        params: &[HostType],
+        results: &[HostType],
+        call: impl Fn(&mut dyn HostMemory, &[HostValue]) -> Result<Vec<HostValue>, String> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            flush: false,
+            ..Self::with_memory(module, name, params, results, call)
+        }
+    }
 }
 
-/// Run a WebAssembly module given as bytes.
-pub fn run_wasm_bytes(wasm_bytes: &[u8]) -> Result<()> {
-    let engine = Engine::default();
+impl std::fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HostFunction({}.{})", self.module, self.name)
+    }
+}
+
+/// The host functions of a run, by module and name. Registering a function under the name of
+/// another one replaces it:
+///
+/// ```ignore
+/// let mut host = HostRegistry::new();
+/// host.register("env", "beep", &[], &[], |_, _| {
+///     eprint!("\x07");
+///     Ok(Vec::new())
+/// });
+/// let options = RunOptions { host, ..RunOptions::default() };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HostRegistry {
+    functions: Vec<HostFunction>,
+}
+
+impl HostRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the function `module.name` (see HostFunction::with_memory).
+    pub fn register(
+        &mut self,
+        module: &str,
+        name: &str,
+        params: &[HostType],
+        results: &[HostType],
+        call: impl Fn(&mut dyn HostMemory, &[HostValue]) -> Result<Vec<HostValue>, String> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.add(HostFunction::with_memory(module, name, params, results, call))
+    }
+
+    /// Register a function, replacing the one of the same module and name.
+    pub fn add(&mut self, function: HostFunction) -> &mut Self {
+        match self.functions.iter_mut().find(|f| f.module == function.module && f.name == function.name) {
+            Some(f) => *f = function,
+            None => self.functions.push(function),
+        }
+        self
+    }
+
+    pub fn get(&self, module: &str, name: &str) -> Option<&HostFunction> {
+        self.functions.iter().find(|f| f.module == module && f.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &HostFunction> {
+        self.functions.iter()
+    }
+}
+
+impl Extend<HostFunction> for HostRegistry {
+    fn extend<I: IntoIterator<Item = HostFunction>>(&mut self, functions: I) {
+        for function in functions {
+            self.add(function);
+        }
+    }
+}
+
+impl FromIterator<HostFunction> for HostRegistry {
+    fn from_iter<I: IntoIterator<Item = HostFunction>>(functions: I) -> Self {
+        let mut registry = Self::new();
+        registry.extend(functions);
+        registry
+    }
+}
+
+fn int(args: &[HostValue], i: usize) -> i32 {
+    match args.get(i) {
+        Some(HostValue::I32(v)) => *v,
+        _ => 0,
+    }
+}
+
+fn float(args: &[HostValue], i: usize) -> f64 {
+    match args.get(i) {
+        Some(HostValue::F64(v)) => *v,
+        _ => 0.0,
+    }
+}
+
+// A string result: (ptr, len)
+fn slice((ptr, len): (i32, i32)) -> Vec<HostValue> {
+    vec![HostValue::I32(ptr), HostValue::I32(len)]
+}
+
+// The functions every run provides; the program output goes to `output`
+fn builtins(options: &RunOptions, output: &Arc<Mutex<Box<dyn OutputSink>>>) -> HostRegistry {
+    use HostType::{F64, I32};
+    use HostValue as V;
+    let mut registry = HostRegistry::new();
+
+    // env.log(ptr: i32, len: i32) -> ()
+    let out = Arc::clone(output);
+    registry.add(HostFunction::builtin("env", "log", &[I32, I32], &[], move |mem, args| {
+        let bytes = mem.read(int(args, 0), int(args, 1))?;
+        out.lock().unwrap().write(&bytes).map_err(output_error)?;
+        Ok(Vec::new())
+    }));
+
+    // env.log_err(ptr: i32, len: i32) -> (), on stderr after what the program printed
+    let out = Arc::clone(output);
+    registry.add(HostFunction::builtin("env", "log_err", &[I32, I32], &[], move |mem, args| {
+        out.lock().unwrap().flush().map_err(output_error)?;
+        let bytes = mem.read(int(args, 0), int(args, 1))?;
+        let mut err = io::stderr().lock();
+        err.write_all(&bytes).and_then(|_| err.flush()).map_err(output_error)?;
+        Ok(Vec::new())
+    }));
+
+    // env.flush() -> ()
+    let out = Arc::clone(output);
+    registry.add(HostFunction::builtin("env", "flush", &[], &[], move |_, _| {
+        out.lock().unwrap().flush().map_err(output_error)?;
+        Ok(Vec::new())
+    }));
+
+    // env.dump_heap(start: i32, len: i32) -> ()
+    let out = Arc::clone(output);
+    registry.add(HostFunction::builtin("env", "dump_heap", &[I32, I32], &[], move |mem, args| {
+        out.lock().unwrap().flush().map_err(output_error)?;
+        let layout = mem.heap();
+        dump_heap(mem.data(), &layout, int(args, 0), int(args, 1)).map_err(output_error)?;
+        Ok(Vec::new())
+    }));
+
+    // env.args_count() -> i32
+    // env.args_get(i: i32) -> (ptr: i32, len: i32)
+    let count = options.args.len() as i32;
+    registry.add(HostFunction::builtin("env", "args_count", &[], &[I32], move |_, _| Ok(vec![V::I32(count)])));
+    let program_args = options.args.clone();
+    registry.add(HostFunction::builtin("env", "args_get", &[I32], &[I32, I32], move |mem, args| {
+        let arg = arg_bytes(&program_args, int(args, 0))?;
+        mem.alloc(&[arg]).map(slice)
+    }));
+
+    // env.random() -> f64 in [0, 1)
+    // env.random_int(lo: i32, hi: i32) -> i32 in [lo, hi]
+    let rng = Arc::new(Mutex::new(Rng::new(options.seed)));
+    let rng_int = Arc::clone(&rng);
+    registry.add(HostFunction::builtin("env", "random", &[], &[F64], move |_, _| {
+        Ok(vec![V::F64(rng.lock().unwrap().next_f64())])
+    }));
+    registry.add(HostFunction::builtin("env", "random_int", &[I32, I32], &[I32], move |_, args| {
+        random_int_in(&mut rng_int.lock().unwrap(), int(args, 0), int(args, 1)).map(|n| vec![V::I32(n)])
+    }));
+
+    // math.pow(x: f64, y: f64) -> f64
+    // math.sin/cos/tan/log/exp(x: f64) -> f64
+    registry.add(HostFunction::builtin("math", "pow", &[F64, F64], &[F64], |_, args| {
+        Ok(vec![V::F64(float(args, 0).powf(float(args, 1)))])
+    }));
+    for (name, f) in UNARY_MATH {
+        registry.add(HostFunction::builtin("math", name, &[F64], &[F64], move |_, args| {
+            Ok(vec![V::F64(f(float(args, 0)))])
+        }));
+    }
+
+    // str.to_str_i32(n: i32) / str.to_str_f64(n: f64) -> (ptr: i32, len: i32) (older modules, see above)
+    // str.concat(s1_ptr, s1_len, s2_ptr, s2_len) -> (ptr, len)
+    registry.add(HostFunction::builtin("str", "to_str_i32", &[I32], &[I32, I32], |mem, args| {
+        mem.alloc(&[int(args, 0).to_string().as_bytes()]).map(slice)
+    }));
+    registry.add(HostFunction::builtin("str", "to_str_f64", &[F64], &[I32, I32], |mem, args| {
+        mem.alloc(&[runtime::float_text(float(args, 0), FloatFormat::Digits).as_bytes()]).map(slice)
+    }));
+
+    // str.to_str_shortest(x: f64) -> (ptr: i32, len: i32), for --float-format=shortest
+    // str.to_str_fixed(x: f64, decimals: i32) -> (ptr: i32, len: i32), for to_str(x, decimals)
+    registry.add(HostFunction::builtin("str", "to_str_shortest", &[F64], &[I32, I32], |mem, args| {
+        mem.alloc(&[runtime::float_text(float(args, 0), FloatFormat::Shortest).as_bytes()]).map(slice)
+    }));
+    registry.add(HostFunction::builtin("str", "to_str_fixed", &[F64, I32], &[I32, I32], |mem, args| {
+        let text = fixed_text(float(args, 0), int(args, 1))?;
+        mem.alloc(&[text.as_bytes()]).map(slice)
+    }));
+
+    // str.format_thousands(x: f64, decimals: i32) -> (ptr: i32, len: i32), decimals -1: as to_str(x)
+    // str.decimal_comma(ptr: i32, len: i32) -> (ptr: i32, len: i32)
+    registry.add(HostFunction::builtin("str", "format_thousands", &[F64, I32], &[I32, I32], |mem, args| {
+        let text = match int(args, 1) {
+            -1 => runtime::float_text(float(args, 0), FloatFormat::Digits),
+            decimals => fixed_text(float(args, 0), decimals)?,
+        };
+        mem.alloc(&[group_thousands(&text).as_bytes()]).map(slice)
+    }));
+    registry.add(HostFunction::builtin("str", "decimal_comma", &[I32, I32], &[I32, I32], |mem, args| {
+        let mut bytes = mem.read(int(args, 0), int(args, 1))?;
+        bytes.iter_mut().filter(|b| **b == b'.').for_each(|b| *b = b',');
+        mem.alloc(&[&bytes]).map(slice)
+    }));
+    registry.add(HostFunction::builtin("str", "concat", &[I32; 4], &[I32, I32], |mem, args| {
+        let b1 = mem.read(int(args, 0), int(args, 1))?;
+        let b2 = mem.read(int(args, 2), int(args, 3))?;
+        mem.alloc(&[&b1, &b2]).map(slice)
+    }));
+
+    // str.len(ptr: i32, len: i32) -> i32 (characters)
+    registry.add(HostFunction::builtin("str", "len", &[I32, I32], &[I32], |mem, args| {
+        Ok(vec![V::I32(read_str(mem, int(args, 0), int(args, 1))?.chars().count() as i32)])
+    }));
+
+    // str.substr(ptr: i32, len: i32, start: i32, count: i32) -> (ptr: i32, len: i32)
+    // The result points into the original string: nothing is allocated.
+    registry.add(HostFunction::builtin("str", "substr", &[I32; 4], &[I32, I32], |mem, args| {
+        let (ptr, len) = (int(args, 0), int(args, 1));
+        substr_of(&read_str(mem, ptr, len)?, ptr, int(args, 2), int(args, 3)).map(slice)
+    }));
+
+    // str.char_at(ptr: i32, len: i32, i: i32) -> (ptr: i32, len: i32)
+    registry.add(HostFunction::builtin("str", "char_at", &[I32; 3], &[I32, I32], |mem, args| {
+        let (ptr, len) = (int(args, 0), int(args, 1));
+        char_at_of(&read_str(mem, ptr, len)?, ptr, int(args, 2)).map(slice)
+    }));
+
+    // str.code_at(ptr: i32, len: i32, i: i32) -> i32 (a code point)
+    // str.char_text(c: i32) -> (ptr: i32, len: i32)
+    registry.add(HostFunction::builtin("str", "code_at", &[I32; 3], &[I32], |mem, args| {
+        code_at_of(&read_str(mem, int(args, 0), int(args, 1))?, int(args, 2)).map(|c| vec![V::I32(c)])
+    }));
+    registry.add(HostFunction::builtin("str", "char_text", &[I32], &[I32, I32], |mem, args| {
+        let c = char_of(int(args, 0))?;
+        mem.alloc(&[c.encode_utf8(&mut [0; 4]).as_bytes()]).map(slice)
+    }));
+
+    // str.eq(p1: i32, l1: i32, p2: i32, l2: i32) -> i32 (1 if equal)
+    registry.add(HostFunction::builtin("str", "eq", &[I32; 4], &[I32], |mem, args| {
+        if int(args, 1) != int(args, 3) {
+            return Ok(vec![V::I32(0)]);
+        }
+        let b1 = mem.read(int(args, 0), int(args, 1))?;
+        let b2 = mem.read(int(args, 2), int(args, 3))?;
+        Ok(vec![V::I32((b1 == b2) as i32)])
+    }));
+
+    // str.parse_i32(ptr: i32, len: i32) -> i32
+    // str.parse_f64(ptr: i32, len: i32) -> f64
+    registry.add(HostFunction::builtin("str", "parse_i32", &[I32, I32], &[I32], |mem, args| {
+        parse_int(&read_str(mem, int(args, 0), int(args, 1))?).map(|n| vec![V::I32(n)])
+    }));
+    registry.add(HostFunction::builtin("str", "parse_f64", &[I32, I32], &[F64], |mem, args| {
+        parse_float(&read_str(mem, int(args, 0), int(args, 1))?).map(|x| vec![V::F64(x)])
+    }));
+
+    registry
+}
+
+// The host functions of a run: the built-in ones, replaced or added to by those of the options
+fn host_registry(options: &RunOptions, output: &Arc<Mutex<Box<dyn OutputSink>>>) -> HostRegistry {
+    let mut registry = builtins(options, output);
+    registry.extend(options.host.iter().cloned());
+    registry
+}
+
+// An error for the first host function of `imports` (module, name) that `registry` lacks
+fn check_imports<'a>(registry: &HostRegistry, mut imports: impl Iterator<Item = (&'a str, &'a str)>) -> Result<()> {
+    match imports.find(|(module, name)| registry.get(module, name).is_none()) {
+        Some((module, name)) => Err(anyhow!(
+            "the module imports the host function '{}.{}', which this host does not provide (register it in the HostRegistry of the run)",
+            module,
+            name
+        )),
+        None => Ok(()),
+    }
+}
+
+/// How to run a module.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    pub args: Vec<String>, // program arguments, seen through arg_count() / arg(i)
+    pub flush: FlushMode,  // when the program output is written to stdout (StdoutSink)
+    pub seed: Option<u64>, // seed of random()/random_int(); None = different on every run
+    pub max_memory: Option<u64>, // cap of the linear memory in bytes (rounded up to 64 KiB pages)
+    pub engine: EngineKind, // engine running the module
+    pub fuel: Option<u64>,  // execution budget, roughly one unit per instruction
+    pub max_call_depth: Option<usize>, // calls in progress at most; None = DEFAULT_CALL_DEPTH
+    pub timeout: Option<Duration>, // wall-clock budget of the run
+    pub link_path: Vec<PathBuf>, // directories searched for the wasm libraries the module imports
+    pub host: HostRegistry, // host functions besides the built-in ones, see HostFunction
+    pub profile: bool, // measure the run (fuel, host calls, allocation): RunOutcome::profile
+    pub invoke: Option<Invoke>, // export called instead of main
+    pub interruptible: bool,    // Ctrl+C stops the run (see handle_interrupts)
+}
+
+/// An exported function to call instead of main (mpl run-wasm --invoke), with its arguments
+/// as given on the command line: they are parsed by the types of its parameters.
+#[derive(Debug, Clone)]
+pub struct Invoke {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+// The arguments of --invoke as the values of the parameters `params` (None: a type not handled)
+fn invoke_args(invoke: &Invoke, params: &[Option<HostType>]) -> Result<Vec<HostValue>> {
+    if params.len() != invoke.args.len() {
+        return Err(anyhow!(
+            "'{}' takes {} argument(s), {} given to --invoke",
+            invoke.name,
+            params.len(),
+            invoke.args.len()
+        ));
+    }
+    let parse = |arg: &String, ty: &Option<HostType>| {
+        let value = match ty {
+            Some(HostType::I32) => arg.trim().parse().ok().map(HostValue::I32),
+            Some(HostType::I64) => arg.trim().parse().ok().map(HostValue::I64),
+            Some(HostType::F64) => arg.trim().parse().ok().map(HostValue::F64),
+            None => {
+                return Err(anyhow!(
+                    "'{}' has a parameter --invoke cannot pass (only i32, i64 and f64)",
+                    invoke.name
+                ));
+            }
+        };
+        value.ok_or_else(|| anyhow!("invalid argument '{}' of '{}': expected {:?}", arg, invoke.name, ty.unwrap()))
+    };
+    invoke.args.iter().zip(params).map(|(arg, ty)| parse(arg, ty)).collect()
+}
+
+fn no_export(name: &str) -> anyhow::Error {
+    anyhow!("the module exports no function '{}'", name)
+}
+
+/// Parse a memory size: bytes, or a number followed by K, M or G (powers of 1024).
+pub fn parse_memory_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let factor: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(anyhow!("invalid memory size '{}' (unit K, M or G)", s)),
+    };
+    let n: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid memory size '{}'", s))?;
+    n.checked_mul(factor)
+        .ok_or_else(|| anyhow!("memory size '{}' is too large", s))
+}
+
+/// Where the heap blocks are: from `start` (heap_ptr once the module and its libraries are in
+/// memory) to `end` (heap_ptr now), and the first free block (None: the module has no free list).
+#[derive(Clone, Copy, Debug)]
+pub struct HeapLayout {
+    pub start: u32,
+    pub end: u32,
+    pub free_list: Option<u32>,
+}
+
+// A heap block: address of the header, payload size, on the free list
+struct Block {
+    at: u32,
+    size: u32,
+    free: bool,
+}
+
+fn read_u32(memory: &[u8], at: u32) -> Option<u32> {
+    let bytes = memory.get(at as usize..at as usize + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+// The blocks of the heap, and where the walk stopped if it met something that is not a block
+// (heap_ptr moved by hand, a header overwritten...)
+fn heap_blocks(memory: &[u8], layout: &HeapLayout, free_list: u32) -> (Vec<Block>, Option<u32>) {
+    // the free list, guarded against a loop in a damaged one
+    let mut free = Vec::new();
+    let mut b = free_list;
+    while b != 0 && free.len() < memory.len() / HEADER as usize {
+        free.push(b);
+        match read_u32(memory, b + 4) {
+            Some(next) => b = next,
+            None => break,
+        }
+    }
+
+    let mut blocks = Vec::new();
+    let mut at = layout.start;
+    while at < layout.end {
+        let next = read_u32(memory, at).and_then(|size| Some((size, at.checked_add(HEADER)?.checked_add(size)?)));
+        match next {
+            Some((size, next)) if size % 8 == 0 && next <= layout.end => {
+                blocks.push(Block { at, size, free: free.contains(&at) });
+                at = next;
+            }
+            _ => return (blocks, Some(at)),
+        }
+    }
+    (blocks, None)
+}
+
+/// Write the blocks of the heap: address and size of each payload, the start of the text of those
+/// in use, and the totals.
+pub fn write_heap<W: Write>(out: &mut W, memory: &[u8], layout: &HeapLayout) -> io::Result<()> {
+    let Some(free_list) = layout.free_list else {
+        return writeln!(
+            out,
+            "heap 0x{:08x}..0x{:08x}: {} bytes (no free list in this module: the blocks are not known)",
+            layout.start,
+            layout.end,
+            layout.end.saturating_sub(layout.start)
+        );
+    };
+    let (blocks, stop) = heap_blocks(memory, layout, free_list);
+    let total = |free: bool| {
+        let sizes = blocks.iter().filter(|b| b.free == free).map(|b| b.size);
+        (sizes.clone().count(), sizes.sum::<u32>())
+    };
+    let (used, used_bytes) = total(false);
+    let (free, free_bytes) = total(true);
+    writeln!(
+        out,
+        "heap 0x{:08x}..0x{:08x}: {} block(s) in use ({} bytes), {} free ({} bytes)",
+        layout.start, layout.end, used, used_bytes, free, free_bytes
+    )?;
+    for block in &blocks {
+        let payload = block.at + HEADER;
+        if block.free {
+            writeln!(out, "  0x{:08x} {:>8}  free", payload, block.size)?;
+        } else {
+            let text = memory.get(payload as usize..(payload + block.size.min(32)) as usize).unwrap_or_default();
+            writeln!(out, "  0x{:08x} {:>8}  used  |{}|", payload, block.size, printable(text))?;
+        }
+    }
+    if let Some(at) = stop {
+        writeln!(out, "  0x{:08x}: not a block header, the rest of the heap is not shown", at)?;
+    }
+    Ok(())
+}
+
+fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+        .collect()
+}
+
+/// Write a hex dump of `range` (clipped to the memory), 16 bytes per line at their address.
+/// Runs of all-zero lines are collapsed into a single `*` line.
+pub fn write_hex<W: Write>(out: &mut W, memory: &[u8], range: Range<u32>) -> io::Result<()> {
+    let end = (range.end as usize).min(memory.len());
+    let mut at = (range.start as usize).min(end);
+    let mut skipping = false;
+    while at < end {
+        let line = &memory[at..(at - at % 16 + 16).min(end)];
+        if line.iter().all(|&b| b == 0) {
+            if !skipping {
+                writeln!(out, "*")?;
+                skipping = true;
+            }
+        } else {
+            skipping = false;
+            write!(out, "{:08x} ", at - at % 16)?;
+            write!(out, "{}", "   ".repeat(at % 16))?;
+            for b in line {
+                write!(out, " {:02x}", b)?;
+            }
+            writeln!(out, "{}  |{}|", "   ".repeat(16 - at % 16 - line.len()), printable(line))?;
+        }
+        at += line.len();
+    }
+    Ok(())
+}
+
+/// A memory range of --dump-memory: START..END or START+LEN, in decimal or 0x hexadecimal.
+pub fn parse_memory_range(s: &str) -> Option<Range<u32>> {
+    let number = |n: &str| match n.trim().strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => n.trim().parse().ok(),
+    };
+    if let Some((start, end)) = s.split_once("..") {
+        let (start, end) = (number(start)?, number(end)?);
+        (start <= end).then_some(start..end)
+    } else {
+        let (start, len) = s.split_once('+')?;
+        let start = number(start)?;
+        Some(start..start.checked_add(number(len)?)?)
+    }
+}
+
+// env.dump_heap(start, len): heap_ptr, the blocks and a hex dump of [start, start + len), of the
+// heap when start is -1; on stderr, after what the program printed
+fn dump_heap(memory: &[u8], layout: &HeapLayout, start: i32, len: i32) -> io::Result<()> {
+    let range = match start {
+        -1 => layout.start..layout.end,
+        _ => start as u32..(start as u32).saturating_add(len.max(0) as u32),
+    };
+    let mut out = io::stderr().lock();
+    writeln!(out, "heap_ptr = 0x{:08x} ({})", layout.end, layout.end)?;
+    write_heap(&mut out, memory, layout)?;
+    write_hex(&mut out, memory, range)
+}
+
+/// What a finished run leaves behind.
+pub struct RunOutcome {
+    pub exit_code: i32,  // value returned by main (0 if main returns nothing)
+    pub memory: Vec<u8>, // final contents of linear memory
+    pub heap: HeapLayout, // end: final value of the exported 'heap_ptr' global
+    pub instantiate: Duration, // compiling and instantiating the module (and its libraries)
+    pub execute: Duration,     // running main
+    pub profile: Option<Profile>, // with RunOptions::profile
+    pub results: Vec<HostValue>,  // returned by the function of RunOptions::invoke
+}
+
+impl fmt::Display for HostValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostValue::I32(v) => write!(f, "{}", v),
+            HostValue::I64(v) => write!(f, "{}", v),
+            HostValue::F64(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// What a run cost, measured with RunOptions::profile (mpl run --profile).
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub wall: Duration,                 // instantiating and running
+    pub fuel: u64,                      // fuel consumed, roughly one unit per instruction
+    pub allocated: u64,                 // bytes taken by moving heap_ptr up
+    pub host_calls: Vec<(String, u64)>, // calls of each host import called, by name
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let calls: u64 = self.host_calls.iter().map(|(_, n)| n).sum();
+        writeln!(f, "profile:")?;
+        writeln!(f, "  wall time    {:.3} ms", self.wall.as_secs_f64() * 1000.0)?;
+        writeln!(f, "  fuel         {}", self.fuel)?;
+        writeln!(f, "  allocated    {} bytes", self.allocated)?;
+        write!(f, "  host calls   {}", calls)?;
+        for (import, n) in &self.host_calls {
+            write!(f, "\n    {:<18} {:>10}", import, n)?;
+        }
+        Ok(())
+    }
+}
+
+// Calls of each host import, counted for --profile; counts nothing otherwise
+#[derive(Clone, Default)]
+struct HostCalls(Option<Arc<Mutex<BTreeMap<String, u64>>>>);
+
+impl HostCalls {
+    fn new(enabled: bool) -> Self {
+        HostCalls(enabled.then(Default::default))
+    }
+
+    fn count(&self, import: &str) {
+        if let Some(calls) = &self.0 {
+            let mut calls = calls.lock().unwrap();
+            match calls.get_mut(import) {
+                Some(n) => *n += 1,
+                None => {
+                    calls.insert(import.to_string(), 1);
+                }
+            }
+        }
+    }
+
+    fn counts(&self) -> Vec<(String, u64)> {
+        self.0
+            .as_ref()
+            .map(|calls| calls.lock().unwrap().iter().map(|(k, n)| (k.clone(), *n)).collect())
+            .unwrap_or_default()
+    }
+}
+
+// Fuel given to a run: its budget, or all there is when only --profile counts it
+fn initial_fuel(options: &RunOptions) -> Option<u64> {
+    options.fuel.or(options.profile.then_some(u64::MAX))
+}
+
+impl RunOutcome {
+    /// Write `heap_ptr`, the heap blocks and a hex dump of `range` (None: the whole memory).
+    pub fn write_memory_dump<W: Write>(&self, out: &mut W, range: Option<Range<u32>>) -> io::Result<()> {
+        writeln!(out, "heap_ptr = 0x{:08x} ({})", self.heap.end, self.heap.end)?;
+        writeln!(out, "memory size = {} bytes", self.memory.len())?;
+        write_heap(out, &self.memory, &self.heap)?;
+        write_hex(out, &self.memory, range.unwrap_or(0..self.memory.len() as u32))
+    }
+
+    /// Write the memory dump to a file.
+    pub fn dump_memory<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        self.write_memory_dump(&mut out, None)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// Where a module built with --embed-source was when it trapped: location and source line
+fn source_context(wasm_bytes: &[u8], pos: Option<i64>) -> Option<String> {
+    let (file, line, col) = meta::decode_pos(pos?)?;
+    let files = meta::decode_sources(meta::custom_section(wasm_bytes, meta::SOURCE_SECTION)?)?;
+    let (path, text) = files.get(file)?;
+    let mut out = messages::LOCATION.format(&[path, &line, &col]);
+    if let Some(src) = text.lines().nth(line.saturating_sub(1)) {
+        let margin = " ".repeat(line.to_string().len());
+        out.push_str(&format!("\n {} | {}\n {} | {}^", line, src, margin, " ".repeat(col.saturating_sub(1))));
+    }
+    Some(out)
+}
+
+// The error of a trapped program, with the statement it was running if the module tracks it
+fn at_source(e: anyhow::Error, wasm_bytes: &[u8], pos: Option<i64>) -> anyhow::Error {
+    let context = source_context(wasm_bytes, pos);
+    if e.is::<Interrupted>() {
+        return Interrupted { location: context }.into();
+    }
+    match context {
+        Some(context) => anyhow!("{}\n {}", e, context),
+        None => e,
+    }
+}
+
+// Set by the Ctrl+C handler of handle_interrupts
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// How often a run looks for Ctrl+C, and how long a wasmi run is then given to stop at the end
+// of its slice of fuel before being abandoned
+const INTERRUPT_POLL: Duration = Duration::from_millis(50);
+const INTERRUPT_GRACE: Duration = Duration::from_millis(500);
+
+/// Stop the runs on Ctrl+C instead of killing the process: the run fails with `Interrupted`
+/// once the output printed so far is flushed. A second Ctrl+C exits at once.
+pub fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    })
+    .map_err(|e| anyhow!("cannot handle Ctrl+C: {}", e))
+}
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Exit code of a program stopped by Ctrl+C (128 + SIGINT, as the shells report it).
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// The error of a run stopped by Ctrl+C, with the statement it was running if the module
+/// tracks it (mpl compile --embed-source).
+#[derive(Debug)]
+pub struct Interrupted {
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = messages::INTERRUPTED.coded(&[]);
+        match &self.location {
+            Some(location) => write!(f, "{}\n {}", text, location),
+            None => write!(f, "{}", text),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// The output of a run on its own thread, also flushed by the thread waiting for it
+#[derive(Clone)]
+struct SharedSink(Arc<Mutex<Box<dyn OutputSink>>>);
+
+impl OutputSink for SharedSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Run a WebAssembly module given as bytes, with the engine chosen in `options`.
+pub fn run_wasm_bytes(wasm_bytes: &[u8], options: &RunOptions) -> Result<RunOutcome> {
+    let output = StdoutSink::new(options.flush);
+    host(options.engine)?.run(wasm_bytes, options, Box::new(output))
+}
+
+/// Run a module and capture its output instead of printing it.
+/// The text printed before a trap is returned with the error as well.
+pub fn run_wasm_bytes_with_output(
+    wasm_bytes: &[u8],
+    options: &RunOptions,
+) -> (Result<RunOutcome>, String) {
+    let output = CaptureSink::default();
+    let outcome = host(options.engine).and_then(|h| h.run(wasm_bytes, options, Box::new(output.clone())));
+    (outcome, output.text())
+}
+
+/// The wasmi interpreter.
+pub struct WasmiHost;
+
+impl WasmHost for WasmiHost {
+    fn run(&self, wasm_bytes: &[u8], options: &RunOptions, output: Box<dyn OutputSink>) -> Result<RunOutcome> {
+        if options.timeout.is_none() && !options.interruptible {
+            return run_wasmi(wasm_bytes, options, output, None);
+        }
+        // wasmi cannot interrupt running code: the module runs on its own thread, in slices of
+        // fuel, and is told to stop at the deadline or on Ctrl+C. It stops at the end of its slice.
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut output = SharedSink(Arc::new(Mutex::new(output)));
+        let (sender, receiver) = mpsc::channel();
+        {
+            let wasm_bytes = wasm_bytes.to_vec();
+            let options = options.clone();
+            let output = Box::new(output.clone());
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let _ = sender.send(run_wasmi(&wasm_bytes, &options, output, Some(stop)));
+            });
+        }
+        let deadline = options.timeout.map(|timeout| (Instant::now() + timeout, timeout));
+        loop {
+            match receiver.recv_timeout(INTERRUPT_POLL) {
+                Ok(outcome) => return outcome,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(anyhow!(messages::RUN_LOST.coded(&[]))),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            if options.interruptible && interrupted() {
+                stop.store(true, Ordering::Relaxed);
+                let outcome = receiver
+                    .recv_timeout(INTERRUPT_GRACE)
+                    .unwrap_or_else(|_| Err(Interrupted { location: None }.into()));
+                output.flush()?;
+                return outcome;
+            }
+            if let Some((deadline, timeout)) = deadline
+                && Instant::now() >= deadline
+            {
+                stop.store(true, Ordering::Relaxed);
+                let _ = receiver.recv_timeout(INTERRUPT_GRACE);
+                output.flush()?;
+                return Err(anyhow!(timed_out(timeout)));
+            }
+        }
+    }
+}
+
+// A wasm library is imported as a module named after its file
+fn is_library_module(module: &str) -> bool {
+    module.ends_with(".wasm")
+}
+
+fn find_library(name: &str, link_path: &[PathBuf]) -> Result<PathBuf> {
+    link_path
+        .iter()
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+        .ok_or_else(|| {
+            let dirs: Vec<String> = link_path.iter().map(|d| d.display().to_string()).collect();
+            anyhow!("cannot find the wasm library '{}' (searched in: {})", name, dirs.join(", "))
+        })
+}
+
+// Fuel given to a stoppable wasmi run at a time: it looks at its stop flag between two slices,
+// even in a loop that makes no host call
+const FUEL_SLICE: u64 = 1_000_000;
+
+// The fuel of a stoppable run: the budget (--fuel, else unlimited) is given to the store a
+// slice at a time
+struct Slices {
+    stop: Arc<AtomicBool>,
+    reserve: Mutex<u64>, // fuel of the budget not given to the store yet
+}
+
+impl Slices {
+    // Give the store its next slice, `required` at least, unless the run is told to stop or
+    // the budget is spent
+    fn refill(&self, mut ctx: impl AsContextMut, required: u64) -> Result<(), wasmi::Error> {
+        if self.stop.load(Ordering::Relaxed) {
+            return Err(wasmi::Error::new(messages::RUN_STOPPED.coded(&[])));
+        }
+        let mut ctx = ctx.as_context_mut();
+        let mut reserve = self.reserve.lock().unwrap();
+        let left = reserve.saturating_add(ctx.get_fuel()?);
+        if left < required {
+            return Err(TrapCode::OutOfFuel.into());
+        }
+        let slice = FUEL_SLICE.max(required).min(left);
+        *reserve = left - slice;
+        ctx.set_fuel(slice)
+    }
+
+    // Fuel of the budget not used yet
+    fn unused(&self, ctx: impl AsContextMut) -> u64 {
+        let in_store = ctx.as_context().get_fuel().unwrap_or_default();
+        self.reserve.lock().unwrap().saturating_add(in_store)
+    }
+}
+
+// Call `func`, in slices of fuel if the run is stoppable
+fn call_wasm(
+    mut ctx: impl AsContextMut,
+    func: &Func,
+    params: &[Val],
+    results: &mut [Val],
+    slices: Option<&Slices>,
+) -> Result<(), wasmi::Error> {
+    let Some(slices) = slices else {
+        return func.call(ctx, params, results);
+    };
+    let mut call = func.call_resumable(&mut ctx, params, results)?;
+    loop {
+        call = match call {
+            ResumableCall::Finished => return Ok(()),
+            ResumableCall::OutOfFuel(out) => {
+                slices.refill(&mut ctx, out.required_fuel())?;
+                out.resume(&mut ctx, results)?
+            }
+            ResumableCall::HostTrap(trap) => return Err(trap.into_host_error()),
+        };
+    }
+}
+
+// Called functions of the wasm libraries, (module, name) -> function once the library is instantiated
+type LibrarySlots = HashMap<(String, String), Arc<OnceLock<Func>>>;
+
+// Define the library functions `module` imports as forwarders to the (future) library instances:
+// the program is instantiated before the libraries, which import its runtime.
+fn define_library_imports(
+    store: &mut Store<()>,
+    linker: &mut Linker<()>,
+    module: &Module,
+    slots: &mut LibrarySlots,
+    slices: &Option<Arc<Slices>>,
+) -> Result<Vec<String>> {
+    let mut libraries = Vec::new();
+    for import in module.imports().filter(|i| is_library_module(i.module())) {
+        let key = (import.module().to_string(), import.name().to_string());
+        if slots.contains_key(&key) {
+            continue;
+        }
+        let slot = Arc::new(OnceLock::new());
+        let (target, slices) = (Arc::clone(&slot), slices.clone());
+        let func = Func::wrap(&mut *store, move |mut caller: Caller<'_, ()>| -> Result<(), wasmi::Error> {
+            let f: &Func = target
+                .get()
+                .ok_or_else(|| wasmi::Error::new("wasm library called before being linked"))?;
+            call_wasm(&mut caller, f, &[], &mut [], slices.as_deref())
+        });
+        linker.define(&key.0, &key.1, func)?;
+        if !libraries.contains(&key.0) {
+            libraries.push(key.0.clone());
+        }
+        slots.insert(key, slot);
+    }
+    Ok(libraries)
+}
+
+// Instantiate the wasm libraries (and theirs, transitively) after the program `instance`:
+// each one gets its constant data on top of the heap and the runtime functions of the program.
+#[allow(clippy::too_many_arguments)]
+fn link_libraries(
+    store: &mut Store<()>,
+    linker: &mut Linker<()>,
+    instance: &Instance,
+    memory: Memory,
+    heap_global: Global,
+    mut pending: Vec<String>,
+    mut slots: LibrarySlots,
+    options: &RunOptions,
+    slices: &Option<Arc<Slices>>,
+) -> Result<()> {
+    linker.allow_shadowing(true); // one rt.data_base per library
+    for rt in &runtime::ALL {
+        let name = format!("rt.{}", rt.name);
+        let func = instance
+            .get_func(&*store, &name)
+            .ok_or_else(|| anyhow!("export '{}' not found (the module links wasm libraries)", name))?;
+        linker.define("rt", rt.name, func)?;
+    }
+    let mut linked = Vec::new();
+    // a library imports the libraries it uses relative to its own directory
+    let mut link_path = options.link_path.clone();
+    while let Some(name) = pending.pop() {
+        if linked.contains(&name) {
+            continue;
+        }
+        let path = find_library(&name, &link_path)?;
+        if let Some(dir) = path.parent().filter(|d| !link_path.iter().any(|p| p == d)) {
+            link_path.push(dir.to_path_buf());
+        }
+        let module = Module::new(store.engine(), &fs::read(&path)?[..])?;
+        let data_size = module
+            .custom_sections()
+            .find(|s| s.name() == LIBRARY_SECTION)
+            .and_then(|s| <[u8; 4]>::try_from(s.data()).ok())
+            .map(u32::from_le_bytes)
+            .ok_or_else(|| anyhow!("'{}' is not an MPL library (compile it with mpl compile --lib)", path.display()))?;
+
+        // its data goes where the heap was, the heap starts after it
+        let no_room = || anyhow!("the data of '{}' does not fit in memory", name);
+        let base = match heap_global.get(&*store) {
+            Val::I32(v) => align_up(v as u32, 16).ok_or_else(no_room)?,
+            _ => return Err(anyhow!("heap_ptr must be i32")),
+        };
+        let end = base
+            .checked_add(data_size)
+            .and_then(|end| align_up(end, 16))
+            .ok_or_else(no_room)?;
+        let (pages, current) = ((end as u64).div_ceil(PAGE_SIZE), memory.size(&*store));
+        if pages > current {
+            memory
+                .grow(&mut *store, pages - current)
+                .map_err(|_| no_room())?;
+        }
+        heap_global.set(&mut *store, Val::I32(end as i32))?;
+        let data_base = Global::new(&mut *store, Val::I32(base as i32), Mutability::Const);
+        linker.define("rt", "data_base", data_base)?;
+
+        pending.extend(define_library_imports(store, linker, &module, &mut slots, slices)?);
+        let library = linker.instantiate_and_start(&mut *store, &module)?;
+        for ((module_name, field), slot) in &slots {
+            if *module_name == name {
+                let func = library
+                    .get_func(&*store, field)
+                    .ok_or_else(|| anyhow!("the wasm library '{}' has no function '{}'", name, field))?;
+                let _ = slot.set(func);
+            }
+        }
+        linked.push(name);
+    }
+    Ok(())
+}
+
+fn run_wasmi(
+    wasm_bytes: &[u8],
+    options: &RunOptions,
+    output: Box<dyn OutputSink>,
+    stop: Option<Arc<AtomicBool>>,
+) -> Result<RunOutcome> {
+    let mut config = Config::default();
+    let fuel = initial_fuel(options);
+    config.consume_fuel(fuel.is_some() || stop.is_some());
+    config.set_max_recursion_depth(options.max_call_depth.unwrap_or(DEFAULT_CALL_DEPTH));
+    let started = Instant::now();
+    let engine = Engine::new(&config);
     let module = Module::new(&engine, wasm_bytes)?;
 
     // Thread-safe cell to store the exported 'heap_ptr' Global after instantiation.
-    let heap_ptr_cell: Arc<Mutex<Option<wasmi::Global>>> = Arc::new(Mutex::new(None));
+    let heap_ptr_cell: HeapCell = Arc::new(Mutex::new(None));
 
     let mut store = Store::new(&engine, ());
+    // the start functions run on the whole budget, main and the library calls in slices
+    let budget = fuel.unwrap_or(u64::MAX);
+    if fuel.is_some() || stop.is_some() {
+        store.set_fuel(budget)?;
+    }
+    let slices = stop.map(|stop| Arc::new(Slices { stop, reserve: Mutex::new(0) }));
     let mut linker = Linker::new(&engine);
 
-    // Imported memory: env.memory
-    let memory_ty = MemoryType::new(1, None); // not a Result in 0.51
+    // Imported memory: env.memory, sized as the module declares it (mpl compile --memory-min/--memory-max).
+    // --max-memory caps it: memory.grow fails past the maximum (4 GiB at most for wasm32).
+    let (min_pages, declared_max) = module
+        .imports()
+        .find(|i| i.module() == "env" && i.name() == "memory")
+        .and_then(|i| i.ty().memory().copied())
+        .map_or((1, None), |ty| (ty.minimum(), ty.maximum()));
+    let (min_pages, max_pages) = memory_pages(min_pages, declared_max, options.max_memory)?;
+    let memory_ty = MemoryType::new(min_pages, max_pages); // not a Result in 0.51
     let memory = Memory::new(&mut store, memory_ty)?;
     linker.define("env", "memory", memory)?;
 
-    /*  Glue rust functions */
+    let output = Arc::new(Mutex::new(output));
+    let calls = HostCalls::new(options.profile);
 
-    // env.log(ptr: i32, len: i32) -> ()
-    {
-        let mem = memory;
-        linker.func_wrap(
-            "env",
-            "log",
-            move |mut caller: Caller<'_, ()>, ptr: i32, len: i32| {
-                let bytes = read_slice(&mem, &mut caller, ptr as u32, len as u32);
-                print!("{}", String::from_utf8_lossy(&bytes));
+    // The host functions, on wasmi values
+    let registry = host_registry(options, &output);
+    let imports = module.imports().filter(|i| i.ty().func().is_some() && !is_library_module(i.module()));
+    check_imports(&registry, imports.map(|i| (i.module(), i.name())))?;
+    for function in registry.iter() {
+        let wasm_type = |t: &HostType| match t {
+            HostType::I32 => wasmi::ValType::I32,
+            HostType::I64 => wasmi::ValType::I64,
+            HostType::F64 => wasmi::ValType::F64,
+        };
+        let ty = wasmi::FuncType::new(function.params.iter().map(wasm_type), function.results.iter().map(wasm_type));
+        let (output, heap_cell) = (Arc::clone(&output), Arc::clone(&heap_ptr_cell));
+        let (call, flush) = (Arc::clone(&function.call), function.flush);
+        let (calls, import) = (calls.clone(), format!("{}.{}", function.module, function.name));
+        linker.func_new(
+            &function.module,
+            &function.name,
+            ty,
+            move |mut caller: Caller<'_, ()>, params: &[Val], results: &mut [Val]| -> Result<(), wasmi::Error> {
+                calls.count(&import);
+                if flush {
+                    output.lock().unwrap().flush().map_err(|e| wasmi::Error::new(output_error(e)))?;
+                }
+                let params: Vec<HostValue> =
+                    params.iter().map(|v| host_value(v).unwrap_or(HostValue::I32(0))).collect();
+                let mut mem = WasmiMemory {
+                    caller: &mut caller,
+                    memory,
+                    heap: &heap_cell,
+                };
+                let values = call(&mut mem, &params).map_err(wasmi::Error::new)?;
+                for (result, value) in results.iter_mut().zip(values) {
+                    *result = wasm_value(value);
+                }
+                Ok(())
             },
         )?;
     }
 
-    // str.to_str_i32(n: i32) -> (ptr: i32, len: i32)
-    {
-        let mem = memory;
-        let heap_cell = Arc::clone(&heap_ptr_cell);
-        linker.func_wrap(
-            "str",
-            "to_str_i32",
-            move |mut caller: Caller<'_, ()>, n: i32| -> (i32, i32) {
-                let s = n.to_string();
-                let bytes = s.as_bytes();
-
-                let heap = {
-                    let guard = heap_cell.lock().unwrap();
-                    guard
-                        .as_ref()
-                        .cloned()
-                        .expect("heap_ptr global not set yet")
-                };
+    // Fuel runs out in the start function as well as in main.
+    let budget_error = |e: wasmi::Error| -> anyhow::Error {
+        match (e.as_trap_code(), options.fuel) {
+            (Some(TrapCode::OutOfFuel), Some(fuel)) => anyhow!(fuel_exhausted(fuel)),
+            (Some(TrapCode::StackOverflow), _) => {
+                let depth = options.max_call_depth.unwrap_or(DEFAULT_CALL_DEPTH);
+                anyhow!(stack_overflow(wasm_bytes, None, depth))
+            }
+            _ => e.into(),
+        }
+    };
 
-                let cur = match heap.get(&caller) {
-                    Val::I32(v) => v as u32,
-                    _ => panic!("heap_ptr must be i32"),
-                };
-                let ptr = cur;
+    // Instantiate and run start (if any).
+    let mut slots = LibrarySlots::new();
+    let libraries = define_library_imports(&mut store, &mut linker, &module, &mut slots, &slices)?;
+    let instance = linker
+        .instantiate_and_start(&mut store, &module)
+        .map_err(budget_error)?;
 
-                write_slice(&mem, &mut caller, ptr, bytes);
+    // Fetch exported global 'heap_ptr' and store it for host funcs.
+    let heap_global = instance
+        .get_global(&store, "heap_ptr")
+        .ok_or_else(|| anyhow!("export 'heap_ptr' not found"))?;
+    let heap_start = match heap_global.get(&store) {
+        Val::I32(v) => v as u32,
+        _ => return Err(anyhow!("heap_ptr must be i32")),
+    };
+    // 'data_end' is absent from modules built before it existed: no lower bound then.
+    let data_end = match instance.get_global(&store, "data_end").map(|g| g.get(&store)) {
+        Some(Val::I32(v)) => v as u32,
+        Some(_) => return Err(anyhow!("data_end must be i32")),
+        None => 0,
+    };
+    check_heap(heap_start, data_end)?;
+    *heap_ptr_cell.lock().unwrap() = Some(Heap {
+        heap_ptr: heap_global,
+        data_end,
+        start: heap_start,
+        free_list: instance.get_global(&store, "free_list"),
+    });
+    if !libraries.is_empty() {
+        link_libraries(&mut store, &mut linker, &instance, memory, heap_global, libraries, slots, options, &slices)?;
+        // the data of the libraries is not in heap blocks
+        if let (Some(heap), Val::I32(start)) = (heap_ptr_cell.lock().unwrap().as_mut(), heap_global.get(&store)) {
+            heap.start = start as u32;
+        }
+    }
+
+    // --invoke: the export and its arguments, checked before running
+    let invoked = match &options.invoke {
+        Some(invoke) => {
+            let func = instance.get_func(&store, &invoke.name).ok_or_else(|| no_export(&invoke.name))?;
+            let ty = func.ty(&store);
+            let params: Vec<Option<HostType>> = ty.params().iter().map(|t| host_type(*t)).collect();
+            let params: Vec<Val> = invoke_args(invoke, &params)?.into_iter().map(wasm_value).collect();
+            let results: Vec<Val> = ty.results().iter().map(|t| Val::default(*t)).collect();
+            Some((func, params, results))
+        }
+        None => None,
+    };
+
+    // main starts on a slice: wasmi cannot resume a call out of fuel before its first instruction
+    if let Some(slices) = &slices {
+        let left = store.get_fuel()?;
+        let slice = left.min(FUEL_SLICE);
+        *slices.reserve.lock().unwrap() = left - slice;
+        store.set_fuel(slice)?;
+    }
 
-                let next = align_up(ptr + bytes.len() as u32, 16);
-                heap.set(&mut caller, Val::I32(next as i32))
-                    .expect("set heap_ptr");
+    // Call exported 'main': () -> i32, or () -> () for modules built before exit codes.
+    let instantiate = started.elapsed();
+    let mut results = Vec::new();
+    let exit_code = if let Some((func, params, mut values)) = invoked {
+        call_wasm(&mut store, &func, &params, &mut values, slices.as_deref()).map(|()| {
+            results = values.iter().filter_map(host_value).collect();
+            0
+        })
+    } else {
+        let main_fn = instance.get_func(&store, "main").ok_or_else(|| no_export("main"))?;
+        let mut values: Vec<Val> = main_fn.ty(&store).results().iter().map(|t| Val::default(*t)).collect();
+        call_wasm(&mut store, &main_fn, &[], &mut values, slices.as_deref()).map(|()| match values.first() {
+            Some(Val::I32(code)) => *code,
+            _ => 0,
+        })
+    };
+    let execute = started.elapsed() - instantiate;
+    // Program end: whatever was printed reaches stdout, even if main trapped.
+    output.lock().unwrap().flush()?;
+    // The in-module allocator traps with `unreachable` when memory.grow fails.
+    let exit_code = exit_code.map_err(|e| match max_pages {
+        Some(max)
+            if e.as_trap_code() == Some(TrapCode::UnreachableCodeReached)
+                && memory.size(&store) >= max as u64 =>
+        {
+            anyhow!(out_of_memory(max as u64))
+        }
+        _ if options.interruptible && interrupted() => Interrupted { location: None }.into(),
+        _ => budget_error(e),
+    });
+    let exit_code = exit_code.and_then(main_exit_code).map_err(|e| {
+        let pos = match instance.get_global(&store, meta::POS_GLOBAL).map(|g| g.get(&store)) {
+            Some(Val::I64(p)) => Some(p),
+            _ => None,
+        };
+        at_source(e, wasm_bytes, pos)
+    })?;
 
-                (ptr as i32, bytes.len() as i32)
+    let heap = heap_ptr_cell.lock().unwrap().expect("heap_ptr global set").layout(&store);
+    let profile = options.profile.then(|| Profile {
+        wall: started.elapsed(),
+        fuel: budget
+            - match &slices {
+                Some(slices) => slices.unused(&mut store),
+                None => store.get_fuel().unwrap_or_default(),
             },
-        )?;
+        allocated: heap.end.saturating_sub(heap.start) as u64,
+        host_calls: calls.counts(),
+    });
+    Ok(RunOutcome {
+        exit_code,
+        memory: memory.data(&store).to_vec(),
+        heap,
+        instantiate,
+        execute,
+        profile,
+        results,
+    })
+}
+
+// The memory of a wasmi module during a host call
+struct WasmiMemory<'a, 'b> {
+    caller: &'a mut Caller<'b, ()>,
+    memory: Memory,
+    heap: &'a HeapCell,
+}
+
+impl HostMemory for WasmiMemory<'_, '_> {
+    fn data(&self) -> &[u8] {
+        self.memory.data(&*self.caller)
     }
 
-    // str.to_str_f64(n: f64) -> (ptr: i32, len: i32)
-    {
-        let mem = memory;
-        let heap_cell = Arc::clone(&heap_ptr_cell);
-        linker.func_wrap(
-            "str",
-            "to_str_f64",
-            move |mut caller: Caller<'_, ()>, x: f64| -> (i32, i32) {
-                let s = x.to_string();
-                let bytes = s.as_bytes();
-
-                let heap = {
-                    let g = heap_cell.lock().unwrap();
-                    g.as_ref().cloned().expect("heap_ptr not set")
-                };
-                let cur = match heap.get(&caller) {
-                    Val::I32(v) => v as u32,
-                    _ => panic!("heap_ptr i32"),
-                };
-                let ptr = cur;
+    fn alloc(&mut self, parts: &[&[u8]]) -> Result<(i32, i32), String> {
+        alloc_bytes(self, parts)
+    }
 
-                write_slice(&mem, &mut caller, ptr, bytes);
-                let next = align_up(ptr + bytes.len() as u32, 16);
-                heap.set(&mut caller, Val::I32(next as i32))
-                    .expect("set heap_ptr");
+    fn heap(&mut self) -> HeapLayout {
+        self.heap.lock().unwrap().expect("heap_ptr global not set yet").layout(&*self.caller)
+    }
+}
 
-                (ptr as i32, bytes.len() as i32)
-            },
-        )?;
+impl HeapAccess for WasmiMemory<'_, '_> {
+    fn heap_ptr(&mut self) -> Result<(u32, u32), String> {
+        let heap = self.heap.lock().unwrap().expect("heap_ptr global not set yet");
+        match heap.heap_ptr.get(&*self.caller) {
+            Val::I32(v) => Ok((v as u32, heap.data_end)),
+            _ => Err("heap_ptr must be i32".to_string()),
+        }
     }
 
-    // str.concat(s1_ptr,s1_len,s2_ptr,s2_len) -> (ptr,len)
-    {
-        let mem = memory;
-        let heap_cell = Arc::clone(&heap_ptr_cell);
-        linker.func_wrap(
-            "str",
-            "concat",
-            move |mut caller: Caller<'_, ()>, p1: i32, l1: i32, p2: i32, l2: i32| -> (i32, i32) {
-                let b1 = read_slice(&mem, &mut caller, p1 as u32, l1 as u32);
-                let b2 = read_slice(&mem, &mut caller, p2 as u32, l2 as u32);
-
-                let heap = {
-                    let guard = heap_cell.lock().unwrap();
-                    guard
-                        .as_ref()
-                        .cloned()
-                        .expect("heap_ptr global not set yet")
-                };
+    fn set_heap_ptr(&mut self, ptr: u32) -> Result<(), String> {
+        let heap = self.heap.lock().unwrap().expect("heap_ptr global not set yet");
+        heap.heap_ptr.set(&mut *self.caller, Val::I32(ptr as i32)).map_err(|e| e.to_string())
+    }
 
-                let cur = match heap.get(&caller) {
-                    Val::I32(v) => v as u32,
-                    _ => panic!("heap_ptr must be i32"),
-                };
-                let ptr = cur;
+    fn pages(&mut self) -> (u64, Option<u64>) {
+        (self.memory.size(&*self.caller), self.memory.ty(&*self.caller).maximum())
+    }
 
-                write_slice(&mem, &mut caller, ptr, &b1);
-                write_slice(&mem, &mut caller, ptr + b1.len() as u32, &b2);
+    fn grow(&mut self, pages: u64) -> bool {
+        self.memory.grow(&mut *self.caller, pages).is_ok()
+    }
 
-                let total = (b1.len() + b2.len()) as u32;
-                let next = align_up(ptr + total, 16);
-                heap.set(&mut caller, Val::I32(next as i32))
-                    .expect("set heap_ptr");
+    fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), String> {
+        self.memory.write(&mut *self.caller, ptr as usize, data).map_err(|e| e.to_string())
+    }
+}
 
-                (ptr as i32, total as i32)
-            },
-        )?;
+fn host_type(ty: wasmi::ValType) -> Option<HostType> {
+    match ty {
+        wasmi::ValType::I32 => Some(HostType::I32),
+        wasmi::ValType::I64 => Some(HostType::I64),
+        wasmi::ValType::F64 => Some(HostType::F64),
+        _ => None,
     }
+}
 
-    // Instantiate and run start (if any).
-    let instance = linker.instantiate_and_start(&mut store, &module)?;
+fn host_value(value: &Val) -> Option<HostValue> {
+    match value {
+        Val::I32(x) => Some(HostValue::I32(*x)),
+        Val::I64(x) => Some(HostValue::I64(*x)),
+        Val::F64(x) => Some(HostValue::F64(f64::from(*x))),
+        _ => None,
+    }
+}
 
-    // Fetch exported global 'heap_ptr' and store it for host funcs.
-    let heap_global = instance
-        .get_global(&store, "heap_ptr")
-        .ok_or_else(|| anyhow!("export 'heap_ptr' not found"))?;
-    *heap_ptr_cell.lock().unwrap() = Some(heap_global);
+fn wasm_value(value: HostValue) -> Val {
+    match value {
+        HostValue::I32(x) => Val::I32(x),
+        HostValue::I64(x) => Val::I64(x),
+        HostValue::F64(x) => Val::F64(x.into()),
+    }
+}
 
-    // Call exported 'main'.
-    let main_fn: TypedFunc<(), ()> = instance.get_typed_func(&store, "main")?;
-    main_fn.call(&mut store, ())?;
+/// The module in a file: a `.wat` file (the text `mpl compile -a` writes, maybe edited by hand)
+/// is assembled first.
+pub fn read_module<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wat")) {
+        Ok(wat::parse_file(path)?)
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
 
-    Ok(())
+/// Run a wasm (or WAT) file; the wasm libraries it links are also looked for in its directory.
+pub fn run_wasm_file<P: AsRef<Path>>(path: P, options: &RunOptions) -> Result<RunOutcome> {
+    let bytes = read_module(&path)?;
+    let dir = match path.as_ref().parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut options = options.clone();
+    options.link_path.insert(0, dir);
+    run_wasm_bytes(&bytes, &options)
 }
 
-pub fn run_wasm_file<P: AsRef<Path>>(path: P) -> Result<()> {
-    let bytes = fs::read(path)?;
-    run_wasm_bytes(&bytes)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::compile as wasm;
+
+    // Prints "start", then loops for a long time without calling the host
+    const SPIN: &str = "main() {\n    local int i\n    println(\"start\")\n    for i = 1 to 2000000000\n    next\n    return 0\n}\n";
+
+    fn spin_wasm() -> Vec<u8> {
+        wasm(SPIN)
+    }
+
+    fn returning(code: i32) -> Result<RunOutcome> {
+        let program = format!("main() {{\n    local int c\n    let c = {}\n    return c\n}}\n", code);
+        WasmiHost.run(&wasm(&program), &RunOptions::default(), Box::new(CaptureSink::default()))
+    }
+
+    #[test]
+    fn main_exits_with_what_it_returns() {
+        for code in [0, 1, 255] {
+            assert_eq!(returning(code).map(|outcome| outcome.exit_code).ok(), Some(code));
+        }
+    }
+
+    #[test]
+    fn main_cannot_return_an_exit_code_outside_a_byte() {
+        // 256 would otherwise exit with 0, a success
+        for code in [256, -1] {
+            let Err(e) = returning(code) else {
+                panic!("main returning {} fails", code);
+            };
+            assert!(e.to_string().starts_with(&messages::EXIT_CODE_OUT_OF_RANGE.coded(&[&code])), "{}", e);
+        }
+    }
+
+    // Keeps what is written until it is flushed
+    #[derive(Clone, Default)]
+    struct Buffered {
+        pending: Vec<u8>,
+        flushed: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl OutputSink for Buffered {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.pending.extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed.lock().unwrap().append(&mut self.pending);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_timed_out_run_flushes_what_it_printed() {
+        let output = Buffered::default();
+        let flushed = Arc::clone(&output.flushed);
+        let options = RunOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..RunOptions::default()
+        };
+        let Err(e) = WasmiHost.run(&spin_wasm(), &options, Box::new(output)) else {
+            panic!("the run times out");
+        };
+        assert_eq!(e.to_string(), timed_out(Duration::from_millis(200)));
+        assert_eq!(&flushed.lock().unwrap()[..], b"start\n");
+    }
+
+    #[test]
+    fn a_stopped_run_ends_without_host_calls() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopper = Arc::clone(&stop);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stopper.store(true, Ordering::Relaxed);
+        });
+        let outcome = run_wasmi(&spin_wasm(), &RunOptions::default(), Box::new(CaptureSink::default()), Some(stop));
+        let Err(e) = outcome else {
+            panic!("the run stops");
+        };
+        assert_eq!(e.to_string(), messages::RUN_STOPPED.coded(&[]));
+    }
+
+    #[test]
+    fn a_runaway_recursion_names_its_function() {
+        let wasm = wasm("fn f() {\n    f()\n}\nmain() {\n    f()\n}\n");
+        let Err(e) = WasmiHost.run(&wasm, &RunOptions::default(), Box::new(CaptureSink::default())) else {
+            panic!("the stack overflows");
+        };
+        let place = messages::IN_FUNCTION.format(&[&"'f'"]);
+        assert!(e.to_string().starts_with(&messages::STACK_OVERFLOW.coded(&[&place, &DEFAULT_CALL_DEPTH])), "{}", e);
+    }
 }
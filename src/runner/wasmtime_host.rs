@@ -0,0 +1,340 @@
+// runner/wasmtime_host.rs (wasmtime 41, feature "wasmtime")
+// The same host functions as the wasmi runner, on the wasmtime JIT:
+// long numeric programs run much faster than with the interpreter.
+
+use super::{
+    DEFAULT_CALL_DEPTH, Heap, HeapAccess, HeapCell, HeapLayout, HostCalls, HostMemory, HostType, HostValue, INTERRUPT_POLL, Interrupted,
+    OutputSink, Profile, RunOptions, RunOutcome, WasmHost, alloc_bytes, at_source, check_heap, check_imports,
+    fuel_exhausted, host_registry, initial_fuel, interrupted, invoke_args, is_library_module, main_exit_code, memory_pages, no_export,
+    out_of_memory, output_error, stack_overflow, timed_out,
+};
+use crate::{messages, meta};
+use anyhow::{Result, anyhow};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+use wasmtime::{
+    AsContextMut, Caller, Config, Engine, Error, Global, Linker, Memory, MemoryType, Module, Store, Trap,
+    TypedFunc, Val, WasmBacktrace,
+};
+
+// wasmtime limits the bytes of its stack, not the calls: each of the --max-call-depth calls
+// (DEFAULT_CALL_DEPTH without it) gets this much, and the stack cannot go past MAX_WASM_STACK
+// (that of the thread running the module)
+const FRAME_BYTES: usize = 256;
+const MAX_WASM_STACK: usize = 4 << 20;
+
+/// The wasmtime JIT compiler.
+pub struct WasmtimeHost;
+
+impl WasmHost for WasmtimeHost {
+    fn run(&self, wasm_bytes: &[u8], options: &RunOptions, output: Box<dyn OutputSink>) -> Result<RunOutcome> {
+        run_wasmtime(wasm_bytes, options, output)
+    }
+}
+
+// The memory of a wasmtime module during a host call
+struct WasmtimeMemory<'a, 'b> {
+    caller: &'a mut Caller<'b, ()>,
+    memory: Memory,
+    heap: &'a HeapCell<Global>,
+}
+
+impl HostMemory for WasmtimeMemory<'_, '_> {
+    fn data(&self) -> &[u8] {
+        self.memory.data(&*self.caller)
+    }
+
+    fn alloc(&mut self, parts: &[&[u8]]) -> Result<(i32, i32), String> {
+        alloc_bytes(self, parts)
+    }
+
+    fn heap(&mut self) -> HeapLayout {
+        self.heap.lock().unwrap().expect("heap_ptr global not set yet").layout(&mut *self.caller)
+    }
+}
+
+impl HeapAccess for WasmtimeMemory<'_, '_> {
+    fn heap_ptr(&mut self) -> Result<(u32, u32), String> {
+        let heap = self.heap.lock().unwrap().expect("heap_ptr global not set yet");
+        match heap.heap_ptr.get(&mut *self.caller) {
+            Val::I32(v) => Ok((v as u32, heap.data_end)),
+            _ => Err("heap_ptr must be i32".to_string()),
+        }
+    }
+
+    fn set_heap_ptr(&mut self, ptr: u32) -> Result<(), String> {
+        let heap = self.heap.lock().unwrap().expect("heap_ptr global not set yet");
+        heap.heap_ptr.set(&mut *self.caller, Val::I32(ptr as i32)).map_err(|e| e.to_string())
+    }
+
+    fn pages(&mut self) -> (u64, Option<u64>) {
+        (self.memory.size(&*self.caller), self.memory.ty(&*self.caller).maximum())
+    }
+
+    fn grow(&mut self, pages: u64) -> bool {
+        self.memory.grow(&mut *self.caller, pages).is_ok()
+    }
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), String> {
+        self.memory.write(&mut *self.caller, ptr as usize, data).map_err(|e| e.to_string())
+    }
+}
+
+impl Heap<Global> {
+    fn layout(&self, mut store: impl AsContextMut) -> HeapLayout {
+        let mut value = |global: &Global| match global.get(&mut store) {
+            Val::I32(v) => v as u32,
+            _ => 0,
+        };
+        HeapLayout {
+            start: self.start,
+            end: value(&self.heap_ptr),
+            free_list: self.free_list.as_ref().map(value),
+        }
+    }
+}
+
+fn run_wasmtime(wasm_bytes: &[u8], options: &RunOptions, output: Box<dyn OutputSink>) -> Result<RunOutcome> {
+    let mut config = Config::new();
+    let fuel = initial_fuel(options);
+    config.consume_fuel(fuel.is_some());
+    config.epoch_interruption(options.timeout.is_some() || options.interruptible);
+    let depth = options.max_call_depth.unwrap_or(DEFAULT_CALL_DEPTH);
+    let stack = depth.saturating_mul(FRAME_BYTES);
+    if stack > MAX_WASM_STACK {
+        return Err(anyhow!(messages::CALL_DEPTH_TOO_DEEP.coded(&[&depth, &(MAX_WASM_STACK / FRAME_BYTES)])));
+    }
+    config.max_wasm_stack(stack);
+    let started = Instant::now();
+    let engine = Engine::new(&config)?;
+    let module = Module::new(&engine, wasm_bytes)?;
+    if let Some(import) = module.imports().find(|i| is_library_module(i.module())) {
+        return Err(anyhow!(
+            "linking the wasm library '{}' needs the wasmi engine (--engine wasmi)",
+            import.module()
+        ));
+    }
+
+    let heap_ptr_cell: HeapCell<Global> = Arc::new(Mutex::new(None));
+
+    let mut store = Store::new(&engine, ());
+    if let Some(fuel) = fuel {
+        store.set_fuel(fuel)?;
+    }
+    // The timeout and Ctrl+C interrupt the code when the epoch moves past the deadline.
+    if options.timeout.is_some() || options.interruptible {
+        store.set_epoch_deadline(1);
+    }
+    if let Some(timeout) = options.timeout {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            engine.increment_epoch();
+        });
+    }
+    // watches for Ctrl+C as long as the run holds `running`
+    let running = Arc::new(());
+    if options.interruptible {
+        let engine = engine.clone();
+        let running = Arc::downgrade(&running);
+        thread::spawn(move || {
+            while running.strong_count() > 0 {
+                if interrupted() {
+                    engine.increment_epoch();
+                    break;
+                }
+                thread::sleep(INTERRUPT_POLL);
+            }
+        });
+    }
+    let mut linker: Linker<()> = Linker::new(&engine);
+
+    // Imported memory env.memory, sized as in the wasmi runner.
+    let (min_pages, declared_max) = module
+        .imports()
+        .find(|i| i.module() == "env" && i.name() == "memory")
+        .and_then(|i| i.ty().memory().cloned())
+        .map_or((1, None), |ty| (ty.minimum(), ty.maximum()));
+    let (min_pages, max_pages) = memory_pages(min_pages, declared_max, options.max_memory)?;
+    let memory = Memory::new(&mut store, MemoryType::new(min_pages, max_pages))?;
+    linker.define(&store, "env", "memory", memory)?;
+
+    let output = Arc::new(Mutex::new(output));
+    let calls = HostCalls::new(options.profile);
+
+    // The host functions, on wasmtime values
+    let registry = host_registry(options, &output);
+    let imports = module.imports().filter(|i| i.ty().func().is_some());
+    check_imports(&registry, imports.map(|i| (i.module(), i.name())))?;
+    for function in registry.iter() {
+        let wasm_type = |t: &HostType| match t {
+            HostType::I32 => wasmtime::ValType::I32,
+            HostType::I64 => wasmtime::ValType::I64,
+            HostType::F64 => wasmtime::ValType::F64,
+        };
+        let ty = wasmtime::FuncType::new(
+            &engine,
+            function.params.iter().map(wasm_type),
+            function.results.iter().map(wasm_type),
+        );
+        let (output, heap_cell) = (Arc::clone(&output), Arc::clone(&heap_ptr_cell));
+        let (call, flush) = (Arc::clone(&function.call), function.flush);
+        let (calls, import) = (calls.clone(), format!("{}.{}", function.module, function.name));
+        linker.func_new(
+            &function.module,
+            &function.name,
+            ty,
+            move |mut caller: Caller<'_, ()>, params: &[Val], results: &mut [Val]| -> Result<()> {
+                calls.count(&import);
+                if flush {
+                    output.lock().unwrap().flush().map_err(|e| Error::msg(output_error(e)))?;
+                }
+                let params: Vec<HostValue> =
+                    params.iter().map(|v| host_value(v).unwrap_or(HostValue::I32(0))).collect();
+                let mut mem = WasmtimeMemory {
+                    caller: &mut caller,
+                    memory,
+                    heap: &heap_cell,
+                };
+                let values = call(&mut mem, &params).map_err(Error::msg)?;
+                for (result, value) in results.iter_mut().zip(values) {
+                    *result = wasm_value(value);
+                }
+                Ok(())
+            },
+        )?;
+    }
+
+    let budget_error = |e: Error| -> Error {
+        match e.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => anyhow!(fuel_exhausted(options.fuel.unwrap_or_default())),
+            Some(Trap::Interrupt) if options.interruptible && interrupted() => Interrupted { location: None }.into(),
+            Some(Trap::Interrupt) => anyhow!(timed_out(options.timeout.unwrap_or_default())),
+            Some(Trap::StackOverflow) => {
+                // the innermost function of the backtrace, named by the name section
+                let running = e
+                    .downcast_ref::<WasmBacktrace>()
+                    .and_then(|trace| trace.frames().first()?.func_name().map(str::to_string));
+                anyhow!(stack_overflow(wasm_bytes, running, depth))
+            }
+            // the trap or host message, without the wasm backtrace wrapped around it
+            _ => anyhow!("{}", e.root_cause()),
+        }
+    };
+
+    // Instantiate (runs the start function, if any).
+    let instance = linker.instantiate(&mut store, &module).map_err(budget_error)?;
+
+    let heap_global = instance
+        .get_global(&mut store, "heap_ptr")
+        .ok_or_else(|| anyhow!("export 'heap_ptr' not found"))?;
+    let heap_start = match heap_global.get(&mut store) {
+        Val::I32(v) => v as u32,
+        _ => return Err(anyhow!("heap_ptr must be i32")),
+    };
+    let data_end = match instance.get_global(&mut store, "data_end").map(|g| g.get(&mut store)) {
+        Some(Val::I32(v)) => v as u32,
+        Some(_) => return Err(anyhow!("data_end must be i32")),
+        None => 0,
+    };
+    check_heap(heap_start, data_end)?;
+    let free_list = instance.get_global(&mut store, "free_list");
+    *heap_ptr_cell.lock().unwrap() = Some(Heap {
+        heap_ptr: heap_global,
+        data_end,
+        start: heap_start,
+        free_list,
+    });
+
+    // --invoke: the export and its arguments, checked before running
+    let invoked = match &options.invoke {
+        Some(invoke) => {
+            let func = instance.get_func(&mut store, &invoke.name).ok_or_else(|| no_export(&invoke.name))?;
+            let ty = func.ty(&store);
+            let params: Vec<Option<HostType>> = ty.params().map(|t| host_type(&t)).collect();
+            let params: Vec<Val> = invoke_args(invoke, &params)?.into_iter().map(wasm_value).collect();
+            let results: Vec<Val> = ty.results().map(|t| Val::default_for_ty(&t).unwrap_or(Val::I32(0))).collect();
+            Some((func, params, results))
+        }
+        None => None,
+    };
+
+    // main: () -> i32, or () -> () for modules built before exit codes.
+    let instantiate = started.elapsed();
+    let mut results = Vec::new();
+    let exit_code = if let Some((func, params, mut values)) = invoked {
+        func.call(&mut store, &params, &mut values).map(|()| {
+            results = values.iter().filter_map(host_value).collect();
+            0
+        })
+    } else if let Ok(main_fn) = instance.get_typed_func::<(), i32>(&mut store, "main") {
+        main_fn.call(&mut store, ())
+    } else {
+        let main_fn: TypedFunc<(), ()> = instance.get_typed_func(&mut store, "main")?;
+        main_fn.call(&mut store, ()).map(|()| 0)
+    };
+    let execute = started.elapsed() - instantiate;
+    output.lock().unwrap().flush()?;
+    let exit_code = exit_code.map_err(|e| match max_pages {
+        Some(max)
+            if e.downcast_ref::<Trap>() == Some(&Trap::UnreachableCodeReached)
+                && memory.size(&store) >= max as u64 =>
+        {
+            anyhow!(out_of_memory(max as u64))
+        }
+        _ => budget_error(e),
+    });
+    let exit_code = exit_code.and_then(main_exit_code).map_err(|e| {
+        let pos = match instance.get_global(&mut store, meta::POS_GLOBAL).map(|g| g.get(&mut store)) {
+            Some(Val::I64(p)) => Some(p),
+            _ => None,
+        };
+        at_source(e, wasm_bytes, pos)
+    })?;
+
+    let heap = heap_ptr_cell.lock().unwrap().expect("heap_ptr global set").layout(&mut store);
+    let profile = options.profile.then(|| Profile {
+        wall: started.elapsed(),
+        fuel: fuel.unwrap_or_default() - store.get_fuel().unwrap_or_default(),
+        allocated: heap.end.saturating_sub(heap.start) as u64,
+        host_calls: calls.counts(),
+    });
+    Ok(RunOutcome {
+        exit_code,
+        memory: memory.data(&store).to_vec(),
+        heap,
+        instantiate,
+        execute,
+        profile,
+        results,
+    })
+}
+
+fn host_type(ty: &wasmtime::ValType) -> Option<HostType> {
+    match ty {
+        wasmtime::ValType::I32 => Some(HostType::I32),
+        wasmtime::ValType::I64 => Some(HostType::I64),
+        wasmtime::ValType::F64 => Some(HostType::F64),
+        _ => None,
+    }
+}
+
+fn host_value(value: &Val) -> Option<HostValue> {
+    match value {
+        Val::I32(x) => Some(HostValue::I32(*x)),
+        Val::I64(x) => Some(HostValue::I64(*x)),
+        Val::F64(x) => Some(HostValue::F64(f64::from_bits(*x))),
+        _ => None,
+    }
+}
+
+fn wasm_value(value: HostValue) -> Val {
+    match value {
+        HostValue::I32(x) => Val::I32(x),
+        HostValue::I64(x) => Val::I64(x),
+        HostValue::F64(x) => Val::F64(x.to_bits()),
+    }
+}
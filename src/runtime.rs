@@ -0,0 +1,1087 @@
+// My Programming Language
+// Runtime functions emitted into every module: heap allocation, concatenation, number formatting.
+// They only rely on the exported globals and on env.memory, so a module that prints
+// strings and numbers runs in any host providing env.log and env.memory.
+// Heap blocks are [size: i32][next: i32][payload], 8-byte aligned; `next` links the free
+// blocks (first fit, split when the rest is worth it, no coalescing). New blocks are taken
+// at heap_ptr, where the host allocations (args_get) also put a block header: the blocks follow
+// each other from the start of the heap to heap_ptr, which dump_heap() walks.
+
+use wasm_encoder::{BlockType, Function, InstructionSink, MemArg, ValType};
+
+use crate::codegen::FloatFormat;
+
+// Signature and local names of a runtime function
+pub struct RuntimeFn {
+    pub name: &'static str, // known as "rt.<name>" in the function map
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+    pub locals: &'static [(&'static str, ValType)], // after the parameters
+    pub param_names: &'static [&'static str],
+}
+
+pub const ALLOC: RuntimeFn = RuntimeFn {
+    name: "alloc",
+    params: &[ValType::I32],
+    results: &[ValType::I32],
+    locals: &[
+        ("n", ValType::I32),
+        ("prev", ValType::I32),
+        ("b", ValType::I32),
+        ("rest", ValType::I32),
+        ("end", ValType::I32),
+    ],
+    param_names: &["size"],
+};
+
+pub const FREE: RuntimeFn = RuntimeFn {
+    name: "free",
+    params: &[ValType::I32],
+    results: &[],
+    locals: &[],
+    param_names: &["p"],
+};
+
+pub const CONCAT: RuntimeFn = RuntimeFn {
+    name: "concat",
+    params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+    results: &[ValType::I32, ValType::I32],
+    locals: &[("p", ValType::I32)],
+    param_names: &["p1", "l1", "p2", "l2"],
+};
+
+pub const TO_STR_I32: RuntimeFn = RuntimeFn {
+    name: "to_str_i32",
+    params: &[ValType::I32],
+    results: &[ValType::I32, ValType::I32],
+    locals: &[
+        ("v", ValType::I64),
+        ("t", ValType::I64),
+        ("len", ValType::I32),
+        ("p", ValType::I32),
+        ("i", ValType::I32),
+    ],
+    param_names: &["n"],
+};
+
+pub const TO_STR_F64: RuntimeFn = RuntimeFn {
+    name: "to_str_f64",
+    params: &[ValType::F64],
+    results: &[ValType::I32, ValType::I32],
+    locals: &[
+        ("neg", ValType::I32),
+        ("a", ValType::F64),
+        ("s", ValType::F64),
+        ("e", ValType::I32),
+        ("q", ValType::I32),
+        ("pw", ValType::F64),
+        ("m", ValType::I64),
+        ("k", ValType::I32),
+        ("len", ValType::I32),
+        ("p", ValType::I32),
+        ("j", ValType::I32),
+        ("c", ValType::I32),
+        ("off", ValType::I32),
+        ("v", ValType::F64),
+        ("r", ValType::F64),
+        ("pr", ValType::F64),
+        ("xh", ValType::F64),
+        ("xl", ValType::F64),
+        ("yh", ValType::F64),
+        ("yl", ValType::F64),
+    ],
+    param_names: &["x"],
+};
+
+// --- copies without the bulk memory proposal (--wasm-features mvp): byte loops, emitted into
+// the module before the other runtime functions. The regions never overlap where the runtime
+// and the generated code copy, so the bytes go first to last.
+
+pub const MEM_COPY: RuntimeFn = RuntimeFn {
+    name: "mem_copy",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[],
+    locals: &[],
+    param_names: &["dst", "src", "n"],
+};
+
+pub const MEM_FILL: RuntimeFn = RuntimeFn {
+    name: "mem_fill",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[],
+    locals: &[],
+    param_names: &["dst", "byte", "n"],
+};
+
+pub const MEMORY: [RuntimeFn; 2] = [MEM_COPY, MEM_FILL];
+
+// How memory is copied and filled, the operands [dst, src or byte, n] being on the stack
+#[derive(Copy, Clone, Debug)]
+pub enum MemoryOps {
+    Bulk,                          // memory.copy, memory.fill
+    Calls { copy: u32, fill: u32 }, // function indices of rt.mem_copy and rt.mem_fill
+}
+
+impl MemoryOps {
+    pub fn copy(self, i: &mut InstructionSink<'_>) {
+        match self {
+            Self::Bulk => i.memory_copy(0, 0),
+            Self::Calls { copy, .. } => i.call(copy),
+        };
+    }
+
+    pub fn fill(self, i: &mut InstructionSink<'_>) {
+        match self {
+            Self::Bulk => i.memory_fill(0),
+            Self::Calls { fill, .. } => i.call(fill),
+        };
+    }
+}
+
+// Body of rt.mem_copy or rt.mem_fill
+pub fn memory_body(f: &RuntimeFn) -> Function {
+    let (dst, src, n) = (0, 1, 2);
+    let mut func = new_function(f);
+    let mut i = func.instructions();
+    i.block(BlockType::Empty).loop_(BlockType::Empty);
+    i.local_get(n).i32_eqz().br_if(1);
+    i.local_get(dst);
+    match f.name {
+        "mem_copy" => {
+            i.local_get(src).i32_load8_u(MEM);
+            i.local_get(src).i32_const(1).i32_add().local_set(src);
+        }
+        "mem_fill" => {
+            i.local_get(src);
+        }
+        _ => unreachable!("unknown memory function {}", f.name),
+    }
+    i.i32_store8(MEM);
+    i.local_get(dst).i32_const(1).i32_add().local_set(dst);
+    i.local_get(n).i32_const(1).i32_sub().local_set(n);
+    i.br(0).end().end();
+    i.end();
+    func
+}
+
+// Every runtime function, in the order they are emitted
+pub const ALL: [RuntimeFn; 5] = [ALLOC, FREE, CONCAT, TO_STR_I32, TO_STR_F64];
+
+const MEM: MemArg = MemArg {
+    offset: 0,
+    align: 0,
+    memory_index: 0,
+};
+
+// Block header fields
+const SIZE: MemArg = MemArg {
+    offset: 0,
+    align: 2,
+    memory_index: 0,
+};
+const NEXT: MemArg = MemArg {
+    offset: 4,
+    align: 2,
+    memory_index: 0,
+};
+pub const HEADER: i32 = 8; // size and next: the runner walks the blocks (dump_heap)
+
+const PAGE_BITS: i32 = 16; // 64 KiB wasm pages
+
+// Smallest free block worth splitting off a reused one
+const MIN_SPLIT: i32 = 16;
+
+// Significant digits printed for a float
+const FLOAT_DIGITS: i32 = 15;
+
+// The constant texts of rt.to_str_f64, in the data section with the program's literals
+pub const TEXTS: [&str; 3] = ["NaN", "-inf", "-0"];
+
+/// The text of `x` in the given format: that of rt.to_str_f64 (Digits), for the hosts that
+/// format floats themselves (str.to_str_f64 of the modules built by older versions of mpl),
+/// or that of str.to_str_shortest.
+pub fn float_text(x: f64, format: FloatFormat) -> String {
+    if x.is_nan() {
+        return TEXTS[0].to_string();
+    }
+    let sign = if x.is_sign_negative() { "-" } else { "" };
+    if x.is_infinite() {
+        return format!("{}inf", sign);
+    }
+    if x == 0.0 {
+        return format!("{}0", sign);
+    }
+    // d.dddde<exponent>: the significant digits, rounded
+    let sci = match format {
+        FloatFormat::Digits => format!("{:.*e}", FLOAT_DIGITS as usize - 1, x.abs()),
+        FloatFormat::Shortest => format!("{:e}", x.abs()),
+    };
+    let (mantissa, exponent) = sci.split_once('e').unwrap_or((&sci, "0"));
+    let e: i32 = exponent.parse().unwrap_or(0);
+    let digits = mantissa.replace('.', "");
+    let digits = digits.trim_end_matches('0');
+    if e < 0 {
+        return format!("{}0.{}{}", sign, "0".repeat((-e - 1) as usize), digits);
+    }
+    let int_len = e as usize + 1;
+    if digits.len() <= int_len {
+        format!("{}{}{}", sign, digits, "0".repeat(int_len - digits.len()))
+    } else {
+        format!("{}{}.{}", sign, &digits[..int_len], &digits[int_len..])
+    }
+}
+
+// What the runtime functions refer to
+pub struct Runtime {
+    pub heap_ptr: u32, // global index
+    pub data_end: u32, // global index
+    pub free_list: u32, // global index, first free block (0 = none)
+    pub alloc: u32,    // function index of rt.alloc
+    pub memory: MemoryOps,
+    pub nan: (u32, u32),        // "NaN" in the data section
+    pub minus_inf: (u32, u32),  // "-inf"; "inf" is the same text without the sign
+    pub minus_zero: (u32, u32), // "-0"; "0" is the same text without the sign
+}
+
+fn new_function(f: &RuntimeFn) -> Function {
+    Function::new(f.locals.iter().map(|&(_, ty)| (1, ty)))
+}
+
+impl Runtime {
+    // Body of the runtime function `f`
+    pub fn body(&self, f: &RuntimeFn) -> Function {
+        match f.name {
+            "alloc" => self.alloc(),
+            "free" => self.free(),
+            "concat" => self.concat(),
+            "to_str_i32" => self.to_str_i32(),
+            "to_str_f64" => self.to_str_f64(),
+            _ => unreachable!("unknown runtime function {}", f.name),
+        }
+    }
+
+    // alloc(size) -> p: first free block large enough, else a new block at heap_ptr.
+    // Memory grows as needed; traps when the host refuses (see --max-memory).
+    fn alloc(&self) -> Function {
+        let (size, n, prev, b, rest, end) = (0, 1, 2, 3, 4, 5);
+        let mut f = new_function(&ALLOC);
+        let mut i = f.instructions();
+        i.local_get(size).i32_const(7).i32_add().i32_const(-8).i32_and().local_set(n);
+
+        // first fit in the free list; prev = block whose `next` points to b (0: the global)
+        i.global_get(self.free_list).local_set(b);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(b).i32_eqz().br_if(1);
+        i.local_get(b).i32_load(SIZE).local_get(n).i32_ge_u();
+        i.if_(BlockType::Empty);
+        // unlink b
+        i.local_get(prev).i32_eqz();
+        i.if_(BlockType::Empty);
+        i.local_get(b).i32_load(NEXT).global_set(self.free_list);
+        i.else_();
+        i.local_get(prev).local_get(b).i32_load(NEXT).i32_store(NEXT);
+        i.end();
+        // give the end of a large block back to the free list
+        i.local_get(b).i32_load(SIZE).local_get(n).i32_sub().local_tee(rest);
+        i.i32_const(HEADER + MIN_SPLIT).i32_ge_u();
+        i.if_(BlockType::Empty);
+        i.local_get(b).local_get(n).i32_add().i32_const(HEADER).i32_add().local_set(prev);
+        i.local_get(prev).local_get(rest).i32_const(HEADER).i32_sub().i32_store(SIZE);
+        i.local_get(prev).global_get(self.free_list).i32_store(NEXT);
+        i.local_get(prev).global_set(self.free_list);
+        i.local_get(b).local_get(n).i32_store(SIZE);
+        i.end();
+        i.local_get(b).i32_const(HEADER).i32_add().return_();
+        i.end();
+        i.local_get(b).local_set(prev);
+        i.local_get(b).i32_load(NEXT).local_set(b);
+        i.br(0).end().end();
+
+        // new block at heap_ptr; traps if heap_ptr was moved below the constant data
+        i.global_get(self.heap_ptr).local_tee(b);
+        i.global_get(self.data_end).i32_lt_u();
+        i.if_(BlockType::Empty).unreachable().end();
+        i.local_get(b).i32_const(HEADER).i32_add().local_get(n).i32_add().local_set(end);
+        // grow by the missing pages: ceil(end / 64K) - memory.size
+        i.local_get(end).memory_size(0).i32_const(PAGE_BITS).i32_shl().i32_gt_u();
+        i.if_(BlockType::Empty);
+        i.local_get(end).i32_const((1 << PAGE_BITS) - 1).i32_add();
+        i.i32_const(PAGE_BITS).i32_shr_u().memory_size(0).i32_sub();
+        i.memory_grow(0).i32_const(-1).i32_eq();
+        i.if_(BlockType::Empty).unreachable().end();
+        i.end();
+        i.local_get(b).local_get(n).i32_store(SIZE);
+        i.local_get(end).global_set(self.heap_ptr);
+        i.local_get(b).i32_const(HEADER).i32_add();
+        i.end();
+        f
+    }
+
+    // free(p): put the block of p at the head of the free list.
+    // Pointers below data_end (string literals) are ignored.
+    fn free(&self) -> Function {
+        let p = 0;
+        let mut f = new_function(&FREE);
+        let mut i = f.instructions();
+        i.local_get(p).global_get(self.data_end).i32_lt_u();
+        i.if_(BlockType::Empty).return_().end();
+        i.local_get(p).i32_const(HEADER).i32_sub();
+        i.global_get(self.free_list).i32_store(NEXT);
+        i.local_get(p).i32_const(HEADER).i32_sub().global_set(self.free_list);
+        i.end();
+        f
+    }
+
+    // concat(p1,l1,p2,l2) -> (p,l1+l2)
+    fn concat(&self) -> Function {
+        let (p1, l1, p2, l2, p) = (0, 1, 2, 3, 4);
+        let mut f = new_function(&CONCAT);
+        let mut i = f.instructions();
+        i.local_get(l1).local_get(l2).i32_add().call(self.alloc).local_set(p);
+        i.local_get(p).local_get(p1).local_get(l1);
+        self.memory.copy(&mut i);
+        i.local_get(p).local_get(l1).i32_add();
+        i.local_get(p2).local_get(l2);
+        self.memory.copy(&mut i);
+        i.local_get(p).local_get(l1).local_get(l2).i32_add();
+        i.end();
+        f
+    }
+
+    // to_str_i32(n) -> (p,len): decimal digits, with a leading '-' if negative
+    fn to_str_i32(&self) -> Function {
+        let (n, v, t, len, p, at) = (0, 1, 2, 3, 4, 5);
+        let mut f = new_function(&TO_STR_I32);
+        let mut i = f.instructions();
+        // v = |n| as i64 (i32::MIN has no i32 opposite)
+        i.local_get(n).i64_extend_i32_s().local_set(v);
+        i.local_get(v).i64_const(0).i64_lt_s();
+        i.if_(BlockType::Empty);
+        i.i64_const(0).local_get(v).i64_sub().local_set(v);
+        i.end();
+        // len = number of digits + 1 for the sign
+        i.local_get(n).i32_const(0).i32_lt_s().i32_const(1).i32_add().local_set(len);
+        i.local_get(v).local_set(t);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(t).i64_const(10).i64_lt_u().br_if(1);
+        i.local_get(t).i64_const(10).i64_div_u().local_set(t);
+        i.local_get(len).i32_const(1).i32_add().local_set(len);
+        i.br(0).end().end();
+        // digits from right to left
+        i.local_get(len).call(self.alloc).local_tee(p);
+        i.local_get(len).i32_add().local_set(at);
+        i.loop_(BlockType::Empty);
+        i.local_get(at).i32_const(1).i32_sub().local_tee(at);
+        i.local_get(v).i64_const(10).i64_rem_u().i32_wrap_i64();
+        i.i32_const(b'0' as i32).i32_add().i32_store8(MEM);
+        i.local_get(v).i64_const(10).i64_div_u().local_tee(v);
+        i.i64_const(0).i64_ne().br_if(0);
+        i.end();
+        i.local_get(n).i32_const(0).i32_lt_s();
+        i.if_(BlockType::Empty);
+        i.local_get(p).i32_const(b'-' as i32).i32_store8(MEM);
+        i.end();
+        i.local_get(p).local_get(len);
+        i.end();
+        f
+    }
+
+    // to_str_f64(x) -> (p,len): at most FLOAT_DIGITS significant digits, trailing zeros
+    // removed, never an exponent (1e20 is 100000000000000000000); NaN, inf, -inf, 0, -0.
+    fn to_str_f64(&self) -> Function {
+        let (x, neg, a, s, e, q, pw, m, k, len, p, j, c, off) =
+            (0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13);
+        let (v, r, pr, xh, xl, yh, yl) = (14, 15, 16, 17, 18, 19, 20);
+        let top = 10i64.pow(FLOAT_DIGITS as u32);
+        let mut f = new_function(&TO_STR_F64);
+        let mut i = f.instructions();
+
+        // special values come from the data section
+        i.local_get(x).local_get(x).f64_ne();
+        i.if_(BlockType::Empty);
+        i.i32_const(self.nan.0 as i32).i32_const(self.nan.1 as i32).return_();
+        i.end();
+        i.local_get(x).i64_reinterpret_f64().i64_const(0).i64_lt_s().local_set(neg);
+        i.local_get(x).f64_abs().local_set(a);
+        for (value, (ptr, len)) in [(f64::INFINITY, self.minus_inf), (0.0, self.minus_zero)] {
+            i.local_get(a).f64_const(value.into()).f64_eq();
+            i.if_(BlockType::Empty);
+            i.i32_const(ptr as i32).i32_const(1).local_get(neg).i32_sub().i32_add();
+            i.i32_const(len as i32 - 1).local_get(neg).i32_add();
+            i.return_().end();
+        }
+
+        // e: decimal exponent, 10^e <= a < 10^(e+1)
+        i.local_get(a).local_set(s);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(s).f64_const(10.0.into()).f64_lt().br_if(1);
+        i.local_get(s).f64_const(10.0.into()).f64_div().local_set(s);
+        i.local_get(e).i32_const(1).i32_add().local_set(e);
+        i.br(0).end().end();
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(s).f64_const(1.0.into()).f64_ge().br_if(1);
+        i.local_get(s).f64_const(10.0.into()).f64_mul().local_set(s);
+        i.local_get(e).i32_const(1).i32_sub().local_set(e);
+        i.br(0).end().end();
+
+        // m: the significant digits, a * 10^q rounded with q = FLOAT_DIGITS-1-e; again with e
+        // one more or less when the estimate was off by one
+        i.loop_(BlockType::Empty);
+        // s = a * 10^(q - |q| steps of 22): powers above 1e22 are not exact, go by steps
+        i.local_get(a).local_set(s);
+        i.i32_const(FLOAT_DIGITS - 1).local_get(e).i32_sub().local_set(q);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(q).i32_const(22).i32_le_s().br_if(1);
+        i.local_get(s).f64_const(1e22.into()).f64_mul().local_set(s);
+        i.local_get(q).i32_const(22).i32_sub().local_set(q);
+        i.br(0).end().end();
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(q).i32_const(-22).i32_ge_s().br_if(1);
+        i.local_get(s).f64_const(1e22.into()).f64_div().local_set(s);
+        i.local_get(q).i32_const(22).i32_add().local_set(q);
+        i.br(0).end().end();
+        // pw = 10^|q|, exact
+        i.f64_const(1.0.into()).local_set(pw);
+        i.local_get(q).local_set(c);
+        i.local_get(q).i32_const(0).i32_lt_s();
+        i.if_(BlockType::Empty);
+        i.i32_const(0).local_get(q).i32_sub().local_set(c);
+        i.end();
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(c).i32_eqz().br_if(1);
+        i.local_get(pw).f64_const(10.0.into()).f64_mul().local_set(pw);
+        i.local_get(c).i32_const(1).i32_sub().local_set(c);
+        i.br(0).end().end();
+        // v = s * pw or s / pw, rounded; r has the sign of what the rounding left out
+        i.local_get(q).i32_const(0).i32_lt_s();
+        i.if_(BlockType::Empty);
+        i.local_get(s).local_get(pw).f64_div().local_set(v);
+        two_product(&mut i, (v, pw, pr, r), (xh, xl, yh, yl));
+        i.local_get(s).local_get(pr).f64_sub().local_get(r).f64_sub().local_set(r);
+        i.else_();
+        two_product(&mut i, (s, pw, v, r), (xh, xl, yh, yl));
+        i.end();
+        // rounded half to even as the host does it, on the exact value: a v halfway between two
+        // integers goes the way of r
+        i.local_get(v).f64_nearest().local_set(pr);
+        i.local_get(v).local_get(pr).f64_sub().f64_abs().f64_const(0.5.into()).f64_eq();
+        i.if_(BlockType::Empty);
+        i.local_get(r).f64_const(0.0.into()).f64_gt();
+        i.if_(BlockType::Empty);
+        i.local_get(v).f64_ceil().local_set(pr);
+        i.end();
+        i.local_get(r).f64_const(0.0.into()).f64_lt();
+        i.if_(BlockType::Empty);
+        i.local_get(v).f64_floor().local_set(pr);
+        i.end();
+        i.end();
+        i.local_get(pr).i64_trunc_f64_u().local_set(m);
+        i.local_get(m).i64_const(top).i64_ge_u();
+        i.if_(BlockType::Empty);
+        i.local_get(e).i32_const(1).i32_add().local_set(e);
+        i.br(1);
+        i.end();
+        i.local_get(m).i64_const(top / 10).i64_lt_u();
+        i.if_(BlockType::Empty);
+        i.local_get(e).i32_const(1).i32_sub().local_set(e);
+        i.br(1);
+        i.end();
+        i.end();
+        // k: digits left once the trailing zeros are removed
+        i.i32_const(FLOAT_DIGITS).local_set(k);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(m).i64_const(10).i64_rem_u().i64_const(0).i64_ne().br_if(1);
+        i.local_get(m).i64_const(10).i64_div_u().local_set(m);
+        i.local_get(k).i32_const(1).i32_sub().local_set(k);
+        i.br(0).end().end();
+
+        // len: e >= 0 -> neg + (e+1) [+ '.' + k-(e+1)], e < 0 -> neg + "0." + (-e-1) zeros + k
+        i.local_get(e).i32_const(0).i32_ge_s();
+        i.if_(BlockType::Empty);
+        i.local_get(neg).local_get(e).i32_add().i32_const(1).i32_add().local_set(len);
+        i.local_get(k).local_get(e).i32_const(1).i32_add().i32_gt_s();
+        i.if_(BlockType::Empty);
+        i.local_get(len).local_get(k).i32_add().local_get(e).i32_sub().local_set(len);
+        i.end();
+        i.else_();
+        i.local_get(neg).i32_const(1).i32_add().local_get(e).i32_sub();
+        i.local_get(k).i32_add().local_set(len);
+        i.end();
+
+        // '0' everywhere, then the sign, the dot and the digits
+        i.local_get(len).call(self.alloc).local_set(p);
+        i.local_get(p).i32_const(b'0' as i32).local_get(len);
+        self.memory.fill(&mut i);
+        i.local_get(neg);
+        i.if_(BlockType::Empty);
+        i.local_get(p).i32_const(b'-' as i32).i32_store8(MEM);
+        i.end();
+        i.local_get(e).i32_const(0).i32_lt_s();
+        i.if_(BlockType::Empty);
+        i.local_get(p).local_get(neg).i32_add().i32_const(1).i32_add();
+        i.i32_const(b'.' as i32).i32_store8(MEM);
+        i.i32_const(1).local_get(e).i32_sub().local_set(off);
+        i.else_();
+        i.local_get(k).local_get(e).i32_const(1).i32_add().i32_gt_s();
+        i.if_(BlockType::Empty);
+        i.local_get(p).local_get(neg).i32_add().local_get(e).i32_add().i32_const(1).i32_add();
+        i.i32_const(b'.' as i32).i32_store8(MEM);
+        i.end();
+        i.end();
+        // digit j (0 = first) goes to p + neg + j + off; off = 1 after the dot when e >= 0
+        i.local_get(k).local_set(j);
+        i.loop_(BlockType::Empty);
+        i.local_get(j).i32_const(1).i32_sub().local_set(j);
+        i.local_get(e).i32_const(0).i32_ge_s();
+        i.if_(BlockType::Empty);
+        i.local_get(j).local_get(e).i32_gt_s().local_set(off);
+        i.end();
+        i.local_get(p).local_get(neg).i32_add().local_get(j).i32_add().local_get(off).i32_add();
+        i.local_get(m).i64_const(10).i64_rem_u().i32_wrap_i64();
+        i.i32_const(b'0' as i32).i32_add().i32_store8(MEM);
+        i.local_get(m).i64_const(10).i64_div_u().local_set(m);
+        i.local_get(j).br_if(0);
+        i.end();
+
+        i.local_get(p).local_get(len);
+        i.end();
+        f
+    }
+}
+
+// p = x * y, and err such that x * y = p + err exactly (Dekker): x and y are split in halves
+// of 26 bits whose products are exact. Wasm has no fma to do it in one instruction.
+fn two_product(i: &mut InstructionSink<'_>, (x, y, p, err): (u32, u32, u32, u32), (xh, xl, yh, yl): (u32, u32, u32, u32)) {
+    for (v, h, l) in [(x, xh, xl), (y, yh, yl)] {
+        i.f64_const(134217729.0.into()).local_get(v).f64_mul().local_tee(h);
+        i.local_get(h).local_get(v).f64_sub().f64_sub().local_set(h);
+        i.local_get(v).local_get(h).f64_sub().local_set(l);
+    }
+    i.local_get(x).local_get(y).f64_mul().local_set(p);
+    i.local_get(xh).local_get(yh).f64_mul().local_get(p).f64_sub();
+    i.local_get(xh).local_get(yl).f64_mul().f64_add();
+    i.local_get(xl).local_get(yh).f64_mul().f64_add();
+    i.local_get(xl).local_get(yl).f64_mul().f64_add().local_set(err);
+}
+
+// --- maps (`local map m`), only emitted into the modules using one
+//
+// A map is the address of a header [cap][count][used][slots], 0 for a map never set (empty).
+// `slots` is an open-addressing table of `cap` entries (a power of two) of [hash][key][len][value],
+// probed linearly; `key` is 0 for a free entry, DELETED for a removed one, else a copy of the key
+// owned by the map. `used` counts the entries not free: it stays under 3/4 of `cap`, so a probe
+// always ends on a free entry.
+
+pub const MAP_HASH: RuntimeFn = RuntimeFn {
+    name: "map_hash",
+    params: &[ValType::I32, ValType::I32],
+    results: &[ValType::I32],
+    locals: &[("h", ValType::I32), ("i", ValType::I32)],
+    param_names: &["kp", "kl"],
+};
+
+pub const MAP_FIND: RuntimeFn = RuntimeFn {
+    name: "map_find",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[ValType::I32],
+    locals: &[
+        ("h", ValType::I32),
+        ("mask", ValType::I32),
+        ("i", ValType::I32),
+        ("s", ValType::I32),
+        ("k", ValType::I32),
+        ("j", ValType::I32),
+    ],
+    param_names: &["m", "kp", "kl"],
+};
+
+pub const MAP_GROW: RuntimeFn = RuntimeFn {
+    name: "map_grow",
+    params: &[ValType::I32],
+    results: &[],
+    locals: &[
+        ("old", ValType::I32),
+        ("old_cap", ValType::I32),
+        ("cap", ValType::I32),
+        ("slots", ValType::I32),
+        ("j", ValType::I32),
+        ("s", ValType::I32),
+        ("i", ValType::I32),
+    ],
+    param_names: &["m"],
+};
+
+pub const MAP_GET: RuntimeFn = RuntimeFn {
+    name: "map_get",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[ValType::I32],
+    locals: &[("s", ValType::I32)],
+    param_names: &["m", "kp", "kl"],
+};
+
+pub const MAP_SET: RuntimeFn = RuntimeFn {
+    name: "map_set",
+    params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+    results: &[ValType::I32],
+    locals: &[
+        ("s", ValType::I32),
+        ("h", ValType::I32),
+        ("mask", ValType::I32),
+        ("i", ValType::I32),
+        ("k", ValType::I32),
+        ("kc", ValType::I32),
+    ],
+    param_names: &["m", "kp", "kl", "v"],
+};
+
+pub const MAP_DELETE: RuntimeFn = RuntimeFn {
+    name: "map_delete",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[],
+    locals: &[("s", ValType::I32)],
+    param_names: &["m", "kp", "kl"],
+};
+
+// The map functions, in the order they are emitted
+pub const MAPS: [RuntimeFn; 6] = [MAP_HASH, MAP_FIND, MAP_GROW, MAP_GET, MAP_SET, MAP_DELETE];
+
+// Map header fields
+const CAP: MemArg = field(0);
+const COUNT: MemArg = field(4);
+const USED: MemArg = field(8);
+const SLOTS: MemArg = field(12);
+const MAP_HEADER: i32 = 16;
+
+// Entry fields
+const E_HASH: MemArg = field(0);
+const E_KEY: MemArg = field(4);
+const E_LEN: MemArg = field(8);
+const E_VALUE: MemArg = field(12);
+const ENTRY_BITS: i32 = 4; // 16 bytes
+
+const DELETED: i32 = -1; // `key` of a removed entry
+const MAP_MIN_CAP: i32 = 8;
+
+// 32-bit FNV-1a
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: i32 = 0x0100_0193;
+
+const fn field(offset: u64) -> MemArg {
+    MemArg {
+        offset,
+        align: 2,
+        memory_index: 0,
+    }
+}
+
+// What the map functions refer to
+pub struct MapRuntime {
+    pub alloc: u32, // function index of rt.alloc
+    pub free: u32,  // function index of rt.free
+    pub first: u32, // function index of the first of MAPS
+    pub memory: MemoryOps,
+}
+
+impl MapRuntime {
+    fn index(&self, f: &RuntimeFn) -> u32 {
+        let at = MAPS.iter().position(|m| m.name == f.name).expect("a map function");
+        self.first + at as u32
+    }
+
+    // Body of the map function `f`
+    pub fn body(&self, f: &RuntimeFn) -> Function {
+        match f.name {
+            "map_hash" => self.hash(),
+            "map_find" => self.find(),
+            "map_grow" => self.grow(),
+            "map_get" => self.get(),
+            "map_set" => self.set(),
+            "map_delete" => self.delete(),
+            _ => unreachable!("unknown map function {}", f.name),
+        }
+    }
+
+    // map_hash(kp,kl) -> h: FNV-1a of the bytes of the key
+    fn hash(&self) -> Function {
+        let (kp, kl, h, at) = (0, 1, 2, 3);
+        let mut f = new_function(&MAP_HASH);
+        let mut i = f.instructions();
+        i.i32_const(FNV_OFFSET as i32).local_set(h);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(at).local_get(kl).i32_ge_u().br_if(1);
+        i.local_get(h).local_get(kp).local_get(at).i32_add().i32_load8_u(MEM).i32_xor();
+        i.i32_const(FNV_PRIME).i32_mul().local_set(h);
+        i.local_get(at).i32_const(1).i32_add().local_set(at);
+        i.br(0).end().end();
+        i.local_get(h);
+        i.end();
+        f
+    }
+
+    // map_find(m,kp,kl) -> s: the address of the entry of the key, 0 if the key is not there
+    fn find(&self) -> Function {
+        let (m, kp, kl, h, mask, at, s, k, j) = (0, 1, 2, 3, 4, 5, 6, 7, 8);
+        let mut f = new_function(&MAP_FIND);
+        let mut i = f.instructions();
+        i.local_get(m).i32_eqz();
+        i.if_(BlockType::Empty).i32_const(0).return_().end();
+        i.local_get(kp).local_get(kl).call(self.index(&MAP_HASH)).local_set(h);
+        i.local_get(m).i32_load(CAP).i32_const(1).i32_sub().local_tee(mask);
+        i.local_get(h).i32_and().local_set(at);
+        i.loop_(BlockType::Empty);
+        i.local_get(m).i32_load(SLOTS).local_get(at).i32_const(ENTRY_BITS).i32_shl().i32_add().local_tee(s);
+        i.i32_load(E_KEY).local_tee(k);
+        i.i32_eqz();
+        i.if_(BlockType::Empty).i32_const(0).return_().end();
+        // block $next: this entry is not the key
+        i.block(BlockType::Empty);
+        i.local_get(k).i32_const(DELETED).i32_eq().br_if(0);
+        i.local_get(s).i32_load(E_HASH).local_get(h).i32_ne().br_if(0);
+        i.local_get(s).i32_load(E_LEN).local_get(kl).i32_ne().br_if(0);
+        i.i32_const(0).local_set(j);
+        i.loop_(BlockType::Empty);
+        i.local_get(j).local_get(kl).i32_eq();
+        i.if_(BlockType::Empty).local_get(s).return_().end();
+        i.local_get(k).local_get(j).i32_add().i32_load8_u(MEM);
+        i.local_get(kp).local_get(j).i32_add().i32_load8_u(MEM);
+        i.i32_ne().br_if(1);
+        i.local_get(j).i32_const(1).i32_add().local_set(j);
+        i.br(0).end();
+        i.end();
+        i.local_get(at).i32_const(1).i32_add().local_get(mask).i32_and().local_set(at);
+        i.br(0).end();
+        i.unreachable();
+        i.end();
+        f
+    }
+
+    // map_grow(m): a new table for the entries of m, twice as large unless most of the
+    // entries in use were removed ones, without the removed ones
+    fn grow(&self) -> Function {
+        let (m, old, old_cap, cap, slots, j, s, at) = (0, 1, 2, 3, 4, 5, 6, 7);
+        let mut f = new_function(&MAP_GROW);
+        let mut i = f.instructions();
+        i.local_get(m).i32_load(SLOTS).local_set(old);
+        i.local_get(m).i32_load(CAP).local_tee(old_cap).local_set(cap);
+        i.local_get(m).i32_load(COUNT).i32_const(1).i32_add().i32_const(2).i32_mul();
+        i.local_get(old_cap).i32_gt_u();
+        i.if_(BlockType::Empty);
+        i.local_get(old_cap).i32_const(1).i32_shl().local_set(cap);
+        i.end();
+        i.local_get(cap).i32_const(ENTRY_BITS).i32_shl().call(self.alloc).local_tee(slots);
+        i.i32_const(0).local_get(cap).i32_const(ENTRY_BITS).i32_shl();
+        self.memory.fill(&mut i);
+        i.local_get(m).local_get(cap).i32_store(CAP);
+        i.local_get(m).local_get(slots).i32_store(SLOTS);
+        i.local_get(m).local_get(m).i32_load(COUNT).i32_store(USED);
+        // move the entries holding a key
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(j).local_get(old_cap).i32_ge_u().br_if(1);
+        i.local_get(old).local_get(j).i32_const(ENTRY_BITS).i32_shl().i32_add().local_tee(s);
+        i.i32_load(E_KEY).i32_const(1).i32_add().i32_const(1).i32_gt_u(); // neither 0 nor DELETED
+        i.if_(BlockType::Empty);
+        i.local_get(s).i32_load(E_HASH).local_set(at);
+        i.loop_(BlockType::Empty);
+        i.local_get(at).local_get(cap).i32_const(1).i32_sub().i32_and().local_set(at);
+        i.local_get(slots).local_get(at).i32_const(ENTRY_BITS).i32_shl().i32_add().i32_load(E_KEY);
+        i.if_(BlockType::Empty);
+        i.local_get(at).i32_const(1).i32_add().local_set(at);
+        i.br(1);
+        i.end();
+        i.end();
+        i.local_get(slots).local_get(at).i32_const(ENTRY_BITS).i32_shl().i32_add();
+        i.local_get(s).i32_const(1 << ENTRY_BITS);
+        self.memory.copy(&mut i);
+        i.end();
+        i.local_get(j).i32_const(1).i32_add().local_set(j);
+        i.br(0).end().end();
+        i.local_get(old).call(self.free);
+        i.end();
+        f
+    }
+
+    // map_get(m,kp,kl) -> v: the value of the key, 0 if the key is not there
+    fn get(&self) -> Function {
+        let (m, kp, kl, s) = (0, 1, 2, 3);
+        let mut f = new_function(&MAP_GET);
+        let mut i = f.instructions();
+        i.local_get(m).local_get(kp).local_get(kl).call(self.index(&MAP_FIND)).local_tee(s);
+        i.if_(BlockType::Result(ValType::I32));
+        i.local_get(s).i32_load(E_VALUE);
+        i.else_();
+        i.i32_const(0);
+        i.end();
+        i.end();
+        f
+    }
+
+    // map_set(m,kp,kl,v) -> m: give the key the value v, adding it (a copy of it) if it is
+    // not there; m is made on the first set of an empty map
+    fn set(&self) -> Function {
+        let (m, kp, kl, v, s, h, mask, at, k, kc) = (0, 1, 2, 3, 4, 5, 6, 7, 8, 9);
+        let mut f = new_function(&MAP_SET);
+        let mut i = f.instructions();
+        i.local_get(m).i32_eqz();
+        i.if_(BlockType::Empty);
+        i.i32_const(MAP_HEADER).call(self.alloc).local_set(m);
+        i.local_get(m).i32_const(MAP_MIN_CAP).i32_store(CAP);
+        i.local_get(m).i32_const(0).i32_store(COUNT);
+        i.local_get(m).i32_const(0).i32_store(USED);
+        i.local_get(m).i32_const(MAP_MIN_CAP << ENTRY_BITS).call(self.alloc).local_tee(s);
+        i.i32_store(SLOTS);
+        i.local_get(s).i32_const(0).i32_const(MAP_MIN_CAP << ENTRY_BITS);
+        self.memory.fill(&mut i);
+        i.end();
+        // already there: a new value
+        i.local_get(m).local_get(kp).local_get(kl).call(self.index(&MAP_FIND)).local_tee(s);
+        i.if_(BlockType::Empty);
+        i.local_get(s).local_get(v).i32_store(E_VALUE);
+        i.local_get(m).return_();
+        i.end();
+        // a new key: (used + 1) / cap must stay under 3/4
+        i.local_get(m).i32_load(USED).i32_const(1).i32_add().i32_const(4).i32_mul();
+        i.local_get(m).i32_load(CAP).i32_const(3).i32_mul().i32_gt_u();
+        i.if_(BlockType::Empty);
+        i.local_get(m).call(self.index(&MAP_GROW));
+        i.end();
+        // the first entry free or removed
+        i.local_get(kp).local_get(kl).call(self.index(&MAP_HASH)).local_set(h);
+        i.local_get(m).i32_load(CAP).i32_const(1).i32_sub().local_tee(mask);
+        i.local_get(h).i32_and().local_set(at);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(m).i32_load(SLOTS).local_get(at).i32_const(ENTRY_BITS).i32_shl().i32_add().local_tee(s);
+        i.i32_load(E_KEY).local_tee(k);
+        i.i32_eqz().br_if(1);
+        i.local_get(k).i32_const(DELETED).i32_eq().br_if(1);
+        i.local_get(at).i32_const(1).i32_add().local_get(mask).i32_and().local_set(at);
+        i.br(0).end().end();
+        i.local_get(k).i32_eqz();
+        i.if_(BlockType::Empty);
+        i.local_get(m).local_get(m).i32_load(USED).i32_const(1).i32_add().i32_store(USED);
+        i.end();
+        i.local_get(m).local_get(m).i32_load(COUNT).i32_const(1).i32_add().i32_store(COUNT);
+        i.local_get(kl).call(self.alloc).local_tee(kc);
+        i.local_get(kp).local_get(kl);
+        self.memory.copy(&mut i);
+        i.local_get(s).local_get(h).i32_store(E_HASH);
+        i.local_get(s).local_get(kc).i32_store(E_KEY);
+        i.local_get(s).local_get(kl).i32_store(E_LEN);
+        i.local_get(s).local_get(v).i32_store(E_VALUE);
+        i.local_get(m);
+        i.end();
+        f
+    }
+
+    // map_delete(m,kp,kl): remove the key if it is there
+    fn delete(&self) -> Function {
+        let (m, kp, kl, s) = (0, 1, 2, 3);
+        let mut f = new_function(&MAP_DELETE);
+        let mut i = f.instructions();
+        i.local_get(m).local_get(kp).local_get(kl).call(self.index(&MAP_FIND)).local_tee(s);
+        i.if_(BlockType::Empty);
+        i.local_get(s).i32_load(E_KEY).call(self.free);
+        i.local_get(s).i32_const(DELETED).i32_store(E_KEY);
+        i.local_get(m).local_get(m).i32_load(COUNT).i32_const(1).i32_sub().i32_store(COUNT);
+        i.end();
+        i.end();
+        f
+    }
+}
+
+// --- repeat() and string builders (`local builder b`), only emitted into the modules using them
+//
+// A builder is the address of a header [ptr][len][cap], 0 for a builder never appended to
+// (empty). Its text is the first `len` bytes of the heap block `ptr` of `cap` bytes, which
+// doubles when full: appending n bytes in a loop copies O(n) bytes in all.
+
+pub const REPEAT: RuntimeFn = RuntimeFn {
+    name: "repeat",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[ValType::I32, ValType::I32],
+    locals: &[("out", ValType::I32), ("at", ValType::I32), ("end", ValType::I32)],
+    param_names: &["p", "len", "n"],
+};
+
+pub const BUILDER_APPEND: RuntimeFn = RuntimeFn {
+    name: "builder_append",
+    params: &[ValType::I32, ValType::I32, ValType::I32],
+    results: &[ValType::I32],
+    locals: &[("len", ValType::I32), ("cap", ValType::I32), ("buf", ValType::I32)],
+    param_names: &["b", "p", "l"],
+};
+
+pub const BUILDER_TEXT: RuntimeFn = RuntimeFn {
+    name: "builder_text",
+    params: &[ValType::I32],
+    results: &[ValType::I32, ValType::I32],
+    locals: &[],
+    param_names: &["b"],
+};
+
+// The builder functions, in the order they are emitted
+pub const BUILDERS: [RuntimeFn; 3] = [REPEAT, BUILDER_APPEND, BUILDER_TEXT];
+
+// Builder header fields
+const B_PTR: MemArg = field(0);
+pub const B_LEN: MemArg = field(4); // set to 0 by clear()
+const B_CAP: MemArg = field(8);
+const BUILDER_HEADER: i32 = 12;
+const BUILDER_MIN_CAP: i32 = 16;
+
+// What the builder functions refer to
+pub struct BuilderRuntime {
+    pub alloc: u32, // function index of rt.alloc
+    pub free: u32,  // function index of rt.free
+    pub memory: MemoryOps,
+}
+
+impl BuilderRuntime {
+    // Body of the builder function `f`
+    pub fn body(&self, f: &RuntimeFn) -> Function {
+        match f.name {
+            "repeat" => self.repeat(),
+            "builder_append" => self.append(),
+            "builder_text" => self.text(),
+            _ => unreachable!("unknown builder function {}", f.name),
+        }
+    }
+
+    // repeat(p,len,n) -> (p,len): the string n times (empty if n <= 0), in one allocation
+    fn repeat(&self) -> Function {
+        let (p, len, n, out, at, end) = (0, 1, 2, 3, 4, 5);
+        let mut f = new_function(&REPEAT);
+        let mut i = f.instructions();
+        i.local_get(n).i32_const(0).i32_lt_s();
+        i.if_(BlockType::Empty).i32_const(0).local_set(n).end();
+        i.local_get(len).local_get(n).i32_mul().local_tee(end);
+        i.call(self.alloc).local_tee(out).local_tee(at);
+        i.local_get(end).i32_add().local_set(end);
+        i.block(BlockType::Empty).loop_(BlockType::Empty);
+        i.local_get(at).local_get(end).i32_eq().br_if(1);
+        i.local_get(at).local_get(p).local_get(len);
+        self.memory.copy(&mut i);
+        i.local_get(at).local_get(len).i32_add().local_set(at);
+        i.br(0).end().end();
+        i.local_get(out).local_get(end).local_get(out).i32_sub();
+        i.end();
+        f
+    }
+
+    // builder_append(b,p,l) -> b: the builder with the string added, made when b is 0. The
+    // string is copied before the old block is freed: it may be the text of the builder itself.
+    fn append(&self) -> Function {
+        let (b, p, l, len, cap, buf) = (0, 1, 2, 3, 4, 5);
+        let mut f = new_function(&BUILDER_APPEND);
+        let mut i = f.instructions();
+        i.local_get(b).i32_eqz();
+        i.if_(BlockType::Empty);
+        i.i32_const(BUILDER_HEADER).call(self.alloc).local_set(b);
+        i.local_get(b).i32_const(0).i32_store(B_PTR);
+        i.local_get(b).i32_const(0).i32_store(B_LEN);
+        i.local_get(b).i32_const(0).i32_store(B_CAP);
+        i.end();
+        i.local_get(b).i32_load(B_LEN).local_set(len);
+        i.local_get(len).local_get(l).i32_add();
+        i.local_get(b).i32_load(B_CAP).local_tee(cap);
+        i.i32_gt_u();
+        i.if_(BlockType::Empty);
+        // cap = max(2 * cap, len + l, BUILDER_MIN_CAP)
+        i.local_get(cap).i32_const(1).i32_shl().local_set(cap);
+        i.local_get(cap).local_get(len).local_get(l).i32_add().i32_lt_u();
+        i.if_(BlockType::Empty).local_get(len).local_get(l).i32_add().local_set(cap).end();
+        i.local_get(cap).i32_const(BUILDER_MIN_CAP).i32_lt_u();
+        i.if_(BlockType::Empty).i32_const(BUILDER_MIN_CAP).local_set(cap).end();
+        i.local_get(cap).call(self.alloc).local_tee(buf);
+        i.local_get(b).i32_load(B_PTR).local_get(len);
+        self.memory.copy(&mut i);
+        i.local_get(buf).local_get(len).i32_add().local_get(p).local_get(l);
+        self.memory.copy(&mut i);
+        i.local_get(b).i32_load(B_PTR);
+        i.if_(BlockType::Empty).local_get(b).i32_load(B_PTR).call(self.free).end();
+        i.local_get(b).local_get(buf).i32_store(B_PTR);
+        i.local_get(b).local_get(cap).i32_store(B_CAP);
+        i.else_();
+        i.local_get(b).i32_load(B_PTR).local_get(len).i32_add();
+        i.local_get(p).local_get(l);
+        self.memory.copy(&mut i);
+        i.end();
+        i.local_get(b).local_get(len).local_get(l).i32_add().i32_store(B_LEN);
+        i.local_get(b);
+        i.end();
+        f
+    }
+
+    // builder_text(b) -> (p,len): the text of the builder, still owned by it
+    fn text(&self) -> Function {
+        let b = 0;
+        let mut f = new_function(&BUILDER_TEXT);
+        let mut i = f.instructions();
+        i.local_get(b).i32_eqz();
+        i.if_(BlockType::Empty).i32_const(0).i32_const(0).return_().end();
+        i.local_get(b).i32_load(B_PTR).local_get(b).i32_load(B_LEN);
+        i.end();
+        f
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // What rt.to_str_f64 prints for each value, one per line
+    fn module_texts(values: &[f64]) -> Vec<String> {
+        // a float literal has a dot
+        let literal = |x: &f64| match x.to_string() {
+            text if text.contains('.') => text,
+            text => text + ".0",
+        };
+        let lines: String = values.iter().map(|x| format!("    println(to_str({}))\n", literal(x))).collect();
+        let text = format!("main() {{\n{}    return 0\n}}\n", lines);
+        testing::output(&text).lines().map(str::to_string).collect()
+    }
+
+    fn assert_same_texts(values: &[f64]) {
+        let host: Vec<String> = values.iter().map(|&x| float_text(x, FloatFormat::Digits)).collect();
+        for ((x, host), module) in values.iter().zip(&host).zip(module_texts(values)) {
+            assert_eq!(module, *host, "to_str({})", x);
+        }
+    }
+
+    #[test]
+    fn the_module_rounds_digits_as_the_host() {
+        assert_same_texts(&[
+            std::f64::consts::E,
+            -std::f64::consts::E,
+            0.1 + 0.2,
+            1.0 / 3.0,
+            // halfway between two 15-digit texts: to the even one
+            100000000000000.5,
+            100000000000001.5,
+            // rounding up to one more digit
+            999999999999999.6,
+            9.999999999999999,
+            0.000001234567890123456,
+            123456789012345678.0,
+        ]);
+    }
+
+    #[test]
+    fn the_module_rounds_digits_as_the_host_from_1e_minus_8_to_1e36() {
+        // splitmix64: the same values at each run
+        let mut state = 0u64;
+        let mut next = || {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let z = (state ^ (state >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            let z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+        let values: Vec<f64> = (0..2000)
+            .map(|_| {
+                let mantissa = 1.0 + (next() >> 11) as f64 / (1u64 << 53) as f64 * 9.0;
+                mantissa * 10f64.powi((next() % 45) as i32 - 8)
+            })
+            .collect();
+        assert_same_texts(&values);
+    }
+}
@@ -0,0 +1,147 @@
+// My Programming Language
+// Source map of a module (-c --debug): the WebAssembly flavour of the Source Map v3 format,
+// read by the browser devtools to put breakpoints on MPL lines and step through them.
+//
+// A wasm source map has a single generated line: the "column" of a mapping is the offset of an
+// instruction in the module file. Every statement start maps to its file, line and column, and
+// the module gives the name of the map in a "sourceMappingURL" custom section.
+//
+// The code generator marks each statement start with a `nop` (it emits no other one), then
+// takes the marks out of the body before the peephole pass: see take_marks().
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use wasm_encoder::{CustomSection, Encode};
+use wasmparser::{BinaryReader, BinaryReaderError, OperatorsReader, Operator, Parser, Payload};
+
+use crate::lexer::Position;
+
+pub const URL_SECTION: &str = "sourceMappingURL";
+
+/// The statement starts of a generated function: offset in its body, position in the sources.
+#[derive(Clone, Debug)]
+pub struct FunctionLines {
+    pub code_index: u32, // entry of the code section (imports not counted)
+    pub starts: Vec<(u32, Position)>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SourceMap {
+    version: u32,
+    file: String,
+    sources: Vec<String>,
+    sources_content: Vec<Option<String>>,
+    names: Vec<String>,
+    mappings: String,
+}
+
+/// The body without its `nop` marks, and the offsets where they were in that body.
+pub fn take_marks(body: &[u8]) -> (Vec<u8>, Vec<u32>) {
+    let mut reader = OperatorsReader::new(BinaryReader::new(body, 0));
+    let mut out = Vec::with_capacity(body.len());
+    let mut marks = Vec::new();
+    let mut copied = 0;
+    while !reader.eof() {
+        let offset = reader.original_position();
+        match reader.read() {
+            Ok(Operator::Nop) => {
+                out.extend_from_slice(&body[copied..offset]);
+                marks.push(out.len() as u32);
+                copied = offset + 1;
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    out.extend_from_slice(&body[copied..]);
+    (out, marks)
+}
+
+/// The custom section naming the source map of the module (the URL as a wasm string).
+pub fn url_section(url: &str) -> CustomSection<'static> {
+    let mut data = Vec::new();
+    url.encode(&mut data);
+    CustomSection {
+        name: URL_SECTION.into(),
+        data: data.into(),
+    }
+}
+
+/// The JSON source map of `wasm`, the module named `file` holding the functions of `lines`.
+/// `sources` (path, text) gives the content of the files it can.
+pub fn build(
+    wasm: &[u8],
+    file: &str,
+    lines: &[FunctionLines],
+    sources: &[(PathBuf, String)],
+) -> Result<String, BinaryReaderError> {
+    // where the instructions of each code entry start in the module
+    let mut bodies = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        if let Payload::CodeSectionEntry(body) = payload? {
+            bodies.push(body.get_operators_reader()?.original_position() as u32);
+        }
+    }
+
+    let mut files: Vec<&Path> = Vec::new();
+    let mut starts: Vec<(u32, u32, usize, usize)> = Vec::new(); // offset, file, line, column
+    for function in lines {
+        let base = bodies[function.code_index as usize];
+        for (offset, pos) in &function.starts {
+            let file = match files.iter().position(|f| *f == pos.file_name) {
+                Some(k) => k,
+                None => {
+                    files.push(&pos.file_name);
+                    files.len() - 1
+                }
+            };
+            starts.push((base + offset, file as u32, pos.line, pos.col));
+        }
+    }
+    starts.sort_by_key(|start| start.0);
+
+    // one segment per start, each field relative to the previous segment (lines and columns
+    // from 0 in the map, from 1 in MPL)
+    let mut mappings = String::new();
+    let mut previous = [0i64; 4];
+    for (k, &(offset, file, line, col)) in starts.iter().enumerate() {
+        if k > 0 {
+            mappings.push(',');
+        }
+        let fields = [offset as i64, file as i64, line as i64 - 1, col as i64 - 1];
+        for (field, before) in fields.iter().zip(&mut previous) {
+            vlq(&mut mappings, field - *before);
+            *before = *field;
+        }
+    }
+
+    let map = SourceMap {
+        version: 3,
+        file: file.to_string(),
+        sources: files.iter().map(|f| f.to_string_lossy().into_owned()).collect(),
+        sources_content: files
+            .iter()
+            .map(|f| sources.iter().find(|(path, _)| path == f).map(|(_, text)| text.clone()))
+            .collect(),
+        names: Vec::new(),
+        mappings,
+    };
+    Ok(serde_json::to_string(&map).expect("a source map is plain data"))
+}
+
+// Base64 VLQ: the sign in the lowest bit, then 5 bits per digit, lowest first, 32 = more digits
+fn vlq(out: &mut String, value: i64) {
+    const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut rest = if value < 0 { ((-value) << 1) | 1 } else { value << 1 };
+    loop {
+        let digit = rest & 31;
+        rest >>= 5;
+        if rest == 0 {
+            out.push(DIGITS[digit as usize] as char);
+            return;
+        }
+        out.push(DIGITS[(digit | 32) as usize] as char);
+    }
+}
@@ -0,0 +1,150 @@
+// My Programming Language
+// --timings and --stats: where the time of a compilation (and of the run, for mpl run and
+// run-wasm) goes, and what the generated module contains.
+//
+// The lexer runs inside the parser (one token at a time), so its time is added up in
+// `Lexer::next_token` while `measure_lexing` is on, and the parse time is the rest of the load.
+
+use std::cell::Cell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use wasmparser::{Parser, Payload};
+
+thread_local! {
+    // time spent in the lexer of this thread, None while it is not measured
+    static LEXING: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Start adding up the time spent in the lexer (from zero)
+pub fn measure_lexing() {
+    LEXING.with(|t| t.set(Some(Duration::ZERO)));
+}
+
+/// Time spent in the lexer since `measure_lexing`
+pub fn lexing_time() -> Duration {
+    LEXING.with(|t| t.get()).unwrap_or_default()
+}
+
+/// Run `f` (maybe on another thread) measuring its lexing time on its own; returns its result
+/// and that time, for the thread measuring the lexing to add up (add_lexing). Whatever this
+/// thread was measuring goes on afterwards.
+pub fn time_lexing<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let saved = LEXING.with(|t| t.replace(Some(Duration::ZERO)));
+    let result = f();
+    let spent = LEXING.with(|t| t.replace(saved)).unwrap_or_default();
+    (result, spent)
+}
+
+/// Add time spent in the lexer on other threads, if this thread measures the lexing time
+pub fn add_lexing(spent: Duration) {
+    LEXING.with(|t| {
+        if let Some(total) = t.get() {
+            t.set(Some(total + spent));
+        }
+    });
+}
+
+// Run `f`, a step of the lexer, adding its time to the lexing time if it is measured
+pub(crate) fn lexing<T>(f: impl FnOnce() -> T) -> T {
+    let Some(spent) = LEXING.with(|t| t.get()) else {
+        return f();
+    };
+    let start = Instant::now();
+    let result = f();
+    LEXING.with(|t| t.set(Some(spent + start.elapsed())));
+    result
+}
+
+/// The phases of a compilation or a run, in order, and how long each took.
+#[derive(Debug, Default)]
+pub struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, phase: &'static str, spent: Duration) {
+        self.phases.push((phase, spent));
+    }
+
+    /// Run `f` as the phase `phase`
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        writeln!(f, "timings:")?;
+        for (phase, spent) in &self.phases {
+            writeln!(f, "  {:<12} {:>10.3} ms", phase, ms(*spent))?;
+        }
+        let total: Duration = self.phases.iter().map(|(_, spent)| *spent).sum();
+        write!(f, "  {:<12} {:>10.3} ms", "total", ms(total))
+    }
+}
+
+/// What a wasm module contains.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ModuleStats {
+    pub size: usize,         // bytes of the whole module
+    pub functions: usize,    // defined in the module
+    pub imported: usize,     // imported functions
+    pub instructions: usize, // in the bodies of the defined functions
+    pub data_bytes: usize,   // constant data
+    pub data_segments: usize,
+}
+
+impl ModuleStats {
+    pub fn of(wasm: &[u8]) -> Result<Self, wasmparser::BinaryReaderError> {
+        let mut stats = ModuleStats {
+            size: wasm.len(),
+            ..Self::default()
+        };
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::ImportSection(imports) => {
+                    for import in imports {
+                        if matches!(import?.ty, wasmparser::TypeRef::Func(_)) {
+                            stats.imported += 1;
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    stats.functions += 1;
+                    let mut operators = body.get_operators_reader()?;
+                    while !operators.eof() {
+                        operators.read()?;
+                        stats.instructions += 1;
+                    }
+                }
+                Payload::DataSection(data) => {
+                    for segment in data {
+                        stats.data_bytes += segment?.data.len();
+                        stats.data_segments += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+}
+
+impl fmt::Display for ModuleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "stats:")?;
+        writeln!(f, "  module       {} bytes", self.size)?;
+        writeln!(f, "  functions    {} (+{} imported)", self.functions, self.imported)?;
+        writeln!(f, "  instructions {}", self.instructions)?;
+        write!(f, "  data         {} bytes in {} segment(s)", self.data_bytes, self.data_segments)
+    }
+}
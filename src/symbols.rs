@@ -0,0 +1,214 @@
+// My Programming Language
+// Semantic pass: index of every function and variable with its definition and references
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::grammar;
+use crate::lexer::Position;
+use crate::parser::{Expr, FnExpr, Function, NumExpr, Program, Stadment, StrExpr};
+use crate::visit::{Visitor, walk_expr, walk_num_expr, walk_stadment, walk_str_expr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Variable,
+}
+
+impl SymbolKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SymbolKind::Function => "function",
+            SymbolKind::Variable => "variable",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: String,
+    pub ty: String,            // "int", "float" or a function signature
+    pub scope: Option<String>, // enclosing function of a variable
+    pub definition: Position,
+    pub references: Vec<Position>,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolIndex {
+    pub symbols: Vec<Symbol>,
+}
+
+impl SymbolIndex {
+    // Resolve every call and variable use of the program to its symbol.
+    pub fn build(program: &Program) -> Self {
+        let mut index = SymbolIndex::default();
+        let functions: Vec<&Function> = program
+            .functions
+            .iter()
+            .chain(&program.main_program.functions)
+            .chain(&program.main_program.main)
+            .collect();
+
+        // functions first: calls may refer to functions defined later
+        let mut fn_symbols = HashMap::new();
+        for f in &functions {
+            let ty = if f.name == grammar::KW_MAIN {
+                format!("() -> {}", grammar::KW_INT_TYPE)
+            } else {
+                "() -> ()".to_string()
+            };
+            fn_symbols.insert(f.name.clone(), index.symbols.len());
+            index.symbols.push(Symbol {
+                kind: SymbolKind::Function,
+                name: f.name.clone(),
+                ty,
+                scope: None,
+                definition: f.pos.clone(),
+                references: Vec::new(),
+            });
+        }
+
+        for f in &functions {
+            let mut var_symbols = HashMap::new();
+            for var in &f.variables {
+                var_symbols.insert(var.name.clone(), index.symbols.len());
+                index.symbols.push(Symbol {
+                    kind: SymbolKind::Variable,
+                    name: var.name.clone(),
+                    ty: var.ty.name().to_string(),
+                    scope: Some(f.name.clone()),
+                    definition: var.pos.clone(),
+                    references: Vec::new(),
+                });
+            }
+            let mut scope = Scope {
+                fn_symbols: &fn_symbols,
+                var_symbols,
+                symbols: &mut index.symbols,
+            };
+            scope.visit_function(f);
+        }
+        index
+    }
+
+    // JSON encoding: { "symbols": [ { kind, name, type, scope, definition, references } ] }
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n  \"symbols\": [");
+        for (i, sym) in self.symbols.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let scope = match &sym.scope {
+                Some(s) => json_string(s),
+                None => "null".to_string(),
+            };
+            let refs: Vec<String> = sym.references.iter().map(json_position).collect();
+            let _ = write!(
+                out,
+                "\n    {{ \"kind\": {}, \"name\": {}, \"type\": {}, \"scope\": {}, \"definition\": {}, \"references\": [{}] }}",
+                json_string(sym.kind.name()),
+                json_string(&sym.name),
+                json_string(&sym.ty),
+                scope,
+                json_position(&sym.definition),
+                refs.join(", ")
+            );
+        }
+        out.push_str("\n  ]\n}\n");
+        out
+    }
+}
+
+// Names visible from a function body, and the symbols their uses are added to
+struct Scope<'a> {
+    fn_symbols: &'a HashMap<String, usize>,
+    var_symbols: HashMap<String, usize>,
+    symbols: &'a mut [Symbol],
+}
+
+impl Scope<'_> {
+    fn var_ref(&mut self, name: &str, pos: &Position) {
+        if let Some(&i) = self.var_symbols.get(name) {
+            self.symbols[i].references.push(pos.clone());
+        }
+    }
+}
+
+impl Visitor for Scope<'_> {
+    fn visit_stadment(&mut self, st: &Stadment) {
+        match st {
+            Stadment::Call { name, pos, .. } => {
+                if let Some(&i) = self.fn_symbols.get(name) {
+                    self.symbols[i].references.push(pos.clone());
+                }
+            }
+            Stadment::Assignment { var, pos, .. }
+            | Stadment::ForLoop { var, pos, .. }
+            | Stadment::CallIndirect { var, pos }
+            | Stadment::MapSet { var, pos, .. }
+            | Stadment::MapDelete { var, pos, .. }
+            | Stadment::Append { var, pos, .. }
+            | Stadment::Clear { var, pos } => self.var_ref(&var.name, pos),
+            _ => {}
+        }
+        walk_stadment(self, st);
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        match e {
+            Expr::Fn(FnExpr::Ref { name, pos, .. }) => {
+                if let Some(&i) = self.fn_symbols.get(name) {
+                    self.symbols[i].references.push(pos.clone());
+                }
+            }
+            Expr::Fn(FnExpr::Var { var, pos }) => self.var_ref(&var.name, pos),
+            _ => {}
+        }
+        walk_expr(self, e);
+    }
+
+    fn visit_num_expr(&mut self, e: &NumExpr) {
+        if let NumExpr::Var { var, pos } | NumExpr::MapGet { var, pos, .. } | NumExpr::MapHas { var, pos, .. } = e {
+            self.var_ref(&var.name, pos);
+        }
+        walk_num_expr(self, e);
+    }
+
+    fn visit_str_expr(&mut self, e: &StrExpr) {
+        if let StrExpr::Builder { var, pos } = e {
+            self.var_ref(&var.name, pos);
+        }
+        walk_str_expr(self, e);
+    }
+}
+
+// JSON string literal with the mandatory escapes
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_position(pos: &Position) -> String {
+    format!(
+        "{{ \"file\": {}, \"line\": {}, \"col\": {} }}",
+        json_string(&pos.file_name.to_string_lossy()),
+        pos.line,
+        pos.col
+    )
+}
@@ -0,0 +1,44 @@
+// My Programming Language
+// What the unit tests share: a program given as text, loaded as the main file test.mpl,
+// compiled and run with its output captured.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::codegen::CodeGenerator;
+use crate::modules;
+use crate::parser::Program;
+use crate::runner::{self, RunOptions, RunOutcome};
+
+/// `text` loaded with what it imports
+pub fn program(text: &str) -> Program {
+    modules::load_program(Path::new("test.mpl"), text, &[], &[])
+        .expect("the program loads")
+        .program
+}
+
+/// `text` compiled by `generator` (instrumented, optimized...)
+pub fn compile_with(mut generator: CodeGenerator, text: &str) -> Vec<u8> {
+    generator
+        .generate_wasm("test".to_string(), &program(text))
+        .expect("the program compiles")
+}
+
+pub fn compile(text: &str) -> Vec<u8> {
+    compile_with(CodeGenerator::new(), text)
+}
+
+/// A run of `wasm` and what it printed
+pub fn run_wasm(wasm: &[u8], options: &RunOptions) -> (Result<RunOutcome>, String) {
+    runner::run_wasm_bytes_with_output(wasm, options)
+}
+
+/// What `text` prints, run to its end
+pub fn output(text: &str) -> String {
+    let (outcome, stdout) = run_wasm(&compile(text), &RunOptions::default());
+    if let Err(e) = outcome {
+        panic!("the program runs: {}", e);
+    }
+    stdout
+}
@@ -0,0 +1,141 @@
+// My Programming Language
+// Statement trace of `mpl run --trace`: every executed statement with its place in the
+// sources, and the value an assignment stored, on stderr:
+//
+//   [main.mpl:4] let x = 1 + 2
+//   [main.mpl:4] x = 3
+//
+// The code generator calls the host around the statements (see CodegenHooks):
+//
+//   i32.const <file>; i32.const <line>; call trace.statement     ;; before each statement
+//   i32.const <name>; local.get <x>; call trace.value_i32        ;; after `let x = ...`
+//
+// trace.value_f64 for the float variables; fn, map and builder variables are not shown.
+
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use wasm_encoder::ValType;
+
+use crate::codegen::{CodegenHooks, Ty};
+use crate::debugger::SourceFiles;
+use crate::parser::{Stadment, find_variable_index};
+use crate::runner::{HostFunction, HostType, HostValue};
+
+#[derive(Default)]
+struct State {
+    files: SourceFiles, // the file numbers of trace.statement
+    names: Vec<String>, // the variable names of trace.value_*
+    at: (u32, usize),   // file and line of the running statement
+}
+
+impl State {
+    fn name_id(&mut self, name: &str) -> u32 {
+        match self.names.iter().position(|n| n == name) {
+            Some(k) => k as u32,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() as u32 - 1
+            }
+        }
+    }
+
+    fn location(&self) -> String {
+        format!("{}:{}", self.files.path(self.at.0).display(), self.at.1)
+    }
+
+    // trace.value_*: name, value
+    fn show(&self, args: &[HostValue]) -> Result<Vec<HostValue>, String> {
+        let name = self.names.get(int(args, 0) as usize).map_or("?", String::as_str);
+        let value = match args.get(1) {
+            Some(HostValue::I32(v)) => v.to_string(),
+            Some(HostValue::F64(v)) => v.to_string(),
+            _ => "?".to_string(),
+        };
+        writeln!(io::stderr().lock(), "[{}] {} = {}", self.location(), name, value).map_err(|e| e.to_string())?;
+        Ok(Vec::new())
+    }
+}
+
+/// The tracing of a run: the instrumentation given to the code generator and the host
+/// functions given to the runner share it.
+#[derive(Clone, Default)]
+pub struct Tracer {
+    state: Arc<Mutex<State>>,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The calls to the trace.* imports around the statements.
+    pub fn hooks(&self) -> CodegenHooks {
+        let before = Arc::clone(&self.state);
+        let after = Arc::clone(&self.state);
+        CodegenHooks::new()
+            .import("trace", "statement", &[ValType::I32, ValType::I32], &[])
+            .import("trace", "value_i32", &[ValType::I32, ValType::I32], &[])
+            .import("trace", "value_f64", &[ValType::I32, ValType::F64], &[])
+            .on_statement(move |ctx, stdm, instr| {
+                let Some(pos) = stdm.pos() else {
+                    return;
+                };
+                let file = before.lock().unwrap().files.id(&pos.file_name);
+                instr.i32_const(file as i32);
+                instr.i32_const(pos.line as i32);
+                instr.call(ctx.func_index("trace.statement").expect("trace imports declared"));
+            })
+            .on_statement_end(move |ctx, stdm, instr| {
+                let Stadment::Assignment { var, .. } = stdm else {
+                    return;
+                };
+                let import = match var.ty {
+                    Ty::I32 | Ty::Char => "trace.value_i32",
+                    Ty::F64 => "trace.value_f64",
+                    Ty::Fn | Ty::Map | Ty::Builder => return,
+                };
+                let Some(k) = find_variable_index(&ctx.function.variables, &var.name) else {
+                    return;
+                };
+                let name = after.lock().unwrap().name_id(&var.name);
+                instr.i32_const(name as i32);
+                instr.local_get(k as u32);
+                instr.call(ctx.func_index(import).expect("trace imports declared"));
+            })
+    }
+
+    /// The trace.* functions of the instrumented module.
+    pub fn host_functions(&self) -> Vec<HostFunction> {
+        use HostType::{F64, I32};
+        let statement = Arc::clone(&self.state);
+        let value_i32 = Arc::clone(&self.state);
+        let value_f64 = Arc::clone(&self.state);
+        vec![
+            HostFunction::new("trace", "statement", &[I32, I32], &[], move |args| {
+                let mut state = statement.lock().unwrap();
+                state.at = (int(args, 0) as u32, int(args, 1) as usize);
+                let location = state.location();
+                let (file, line) = state.at;
+                let text = state.files.line(file, line).unwrap_or_default().trim();
+                writeln!(io::stderr().lock(), "[{}] {}", location, text).map_err(|e| e.to_string())?;
+                Ok(Vec::new())
+            }),
+            HostFunction::new("trace", "value_i32", &[I32, I32], &[], move |args| {
+                value_i32.lock().unwrap().show(args)
+            }),
+            HostFunction::new("trace", "value_f64", &[I32, F64], &[], move |args| {
+                value_f64.lock().unwrap().show(args)
+            }),
+        ]
+    }
+}
+
+fn int(args: &[HostValue], k: usize) -> i32 {
+    match args.get(k) {
+        Some(HostValue::I32(v)) => *v,
+        _ => 0,
+    }
+}
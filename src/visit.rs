@@ -0,0 +1,363 @@
+// My Programming Language
+// Walking the AST: a Visitor reads it, a MutVisitor changes it in place.
+//
+// Every visit_* method defaults to the matching walk_* function, which visits the children of
+// the node. A pass overrides the nodes it cares about and calls walk_* from there to go on
+// below them (or does not, to skip a subtree):
+//
+// struct Calls(Vec<String>);
+// impl Visitor for Calls {
+//     fn visit_stadment(&mut self, st: &Stadment) {
+//         if let Stadment::Call { name, .. } = st {
+//             self.0.push(name.clone());
+//         }
+//         walk_stadment(self, st);
+//     }
+// }
+//
+// The children are visited in source order; a program's functions are the imported ones, the
+// main program's, then main.
+
+use crate::parser::{Expr, Function, NumExpr, Program, Stadment, StrExpr};
+
+pub trait Visitor {
+    fn visit_program(&mut self, prog: &Program) {
+        walk_program(self, prog);
+    }
+
+    fn visit_function(&mut self, f: &Function) {
+        walk_function(self, f);
+    }
+
+    fn visit_stadment(&mut self, st: &Stadment) {
+        walk_stadment(self, st);
+    }
+
+    fn visit_expr(&mut self, e: &Expr) {
+        walk_expr(self, e);
+    }
+
+    fn visit_num_expr(&mut self, e: &NumExpr) {
+        walk_num_expr(self, e);
+    }
+
+    fn visit_str_expr(&mut self, e: &StrExpr) {
+        walk_str_expr(self, e);
+    }
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(v: &mut V, prog: &Program) {
+    let functions = prog
+        .functions
+        .iter()
+        .chain(&prog.main_program.functions)
+        .chain(&prog.main_program.main);
+    for f in functions {
+        v.visit_function(f);
+    }
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(v: &mut V, f: &Function) {
+    walk_body(v, &f.body);
+}
+
+pub fn walk_body<V: Visitor + ?Sized>(v: &mut V, body: &[Stadment]) {
+    for st in body {
+        v.visit_stadment(st);
+    }
+}
+
+pub fn walk_stadment<V: Visitor + ?Sized>(v: &mut V, st: &Stadment) {
+    match st {
+        Stadment::Print { items, .. }
+        | Stadment::Println { items, .. }
+        | Stadment::EPrint { items, .. }
+        | Stadment::EPrintln { items, .. }
+        | Stadment::Append { items, .. } => {
+            items.iter().for_each(|s| v.visit_str_expr(s));
+        }
+        Stadment::Assignment { expr, .. } | Stadment::Return { expr, .. } => v.visit_expr(expr),
+        Stadment::ForLoop {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            v.visit_expr(start);
+            v.visit_expr(end);
+            if let Some(step) = step {
+                v.visit_expr(step);
+            }
+            walk_body(v, body);
+        }
+        Stadment::MapSet { key, value, .. } => {
+            v.visit_str_expr(key);
+            v.visit_num_expr(value);
+        }
+        Stadment::MapDelete { key, .. } => v.visit_str_expr(key),
+        Stadment::Match { value, arms, default, .. } => {
+            v.visit_num_expr(value);
+            for arm in arms {
+                walk_body(v, &arm.body);
+            }
+            if let Some(default) = default {
+                walk_body(v, default);
+            }
+        }
+        Stadment::DumpHeap { region, .. } => {
+            if let Some((start, len)) = region {
+                v.visit_num_expr(start);
+                v.visit_num_expr(len);
+            }
+        }
+        Stadment::Call { args, .. } => args.iter().for_each(|arg| v.visit_num_expr(arg)),
+        Stadment::CallIndirect { .. } | Stadment::Clear { .. } | Stadment::Flush => {}
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, e: &Expr) {
+    match e {
+        Expr::Num(n) => v.visit_num_expr(n),
+        Expr::Str(s) => v.visit_str_expr(s),
+        Expr::Fn(_) => {}
+    }
+}
+
+pub fn walk_num_expr<V: Visitor + ?Sized>(v: &mut V, e: &NumExpr) {
+    match e {
+        NumExpr::Binary { left, right, .. } => {
+            v.visit_num_expr(left);
+            v.visit_num_expr(right);
+        }
+        NumExpr::Neg(inner) | NumExpr::Ord(inner) | NumExpr::Chr(inner) => v.visit_num_expr(inner),
+        NumExpr::RandomInt { lo, hi } => {
+            v.visit_num_expr(lo);
+            v.visit_num_expr(hi);
+        }
+        NumExpr::Math { args, .. } => args.iter().for_each(|a| v.visit_num_expr(a)),
+        NumExpr::Len(s) | NumExpr::ToInt(s) | NumExpr::ToFloat(s) => v.visit_str_expr(s),
+        NumExpr::MapGet { key, .. } | NumExpr::MapHas { key, .. } => v.visit_str_expr(key),
+        NumExpr::StrEq { left, right, .. } => {
+            v.visit_str_expr(left);
+            v.visit_str_expr(right);
+        }
+        NumExpr::CharAt { s, index } => {
+            v.visit_str_expr(s);
+            v.visit_num_expr(index);
+        }
+        NumExpr::Int(_)
+        | NumExpr::Float(_)
+        | NumExpr::Char(_)
+        | NumExpr::Var { .. }
+        | NumExpr::ArgCount
+        | NumExpr::Random => {}
+    }
+}
+
+pub fn walk_str_expr<V: Visitor + ?Sized>(v: &mut V, e: &StrExpr) {
+    match e {
+        StrExpr::NumToStr(n) | StrExpr::Arg(n) => v.visit_num_expr(n),
+        StrExpr::Substr { s, start, len } => {
+            v.visit_str_expr(s);
+            v.visit_num_expr(start);
+            v.visit_num_expr(len);
+        }
+        StrExpr::CharAt { s, index } => {
+            v.visit_str_expr(s);
+            v.visit_num_expr(index);
+        }
+        StrExpr::ToFixed { n, decimals } => {
+            v.visit_num_expr(n);
+            v.visit_num_expr(decimals);
+        }
+        StrExpr::Thousands { n, decimals } => {
+            v.visit_num_expr(n);
+            if let Some(decimals) = decimals {
+                v.visit_num_expr(decimals);
+            }
+        }
+        StrExpr::DecimalComma(s) => v.visit_str_expr(s),
+        StrExpr::Repeat { s, count } => {
+            v.visit_str_expr(s);
+            v.visit_num_expr(count);
+        }
+        StrExpr::Join { sep, parts } => {
+            v.visit_str_expr(sep);
+            parts.iter().for_each(|p| v.visit_str_expr(p));
+        }
+        StrExpr::Str(_) | StrExpr::Nl | StrExpr::Builder { .. } => {}
+    }
+}
+
+/// Same walk as Visitor, with mutable access: a method can rewrite its node (replace it,
+/// change its children) before or after walking it.
+pub trait MutVisitor {
+    fn visit_program_mut(&mut self, prog: &mut Program) {
+        walk_program_mut(self, prog);
+    }
+
+    fn visit_function_mut(&mut self, f: &mut Function) {
+        walk_function_mut(self, f);
+    }
+
+    fn visit_stadment_mut(&mut self, st: &mut Stadment) {
+        walk_stadment_mut(self, st);
+    }
+
+    fn visit_expr_mut(&mut self, e: &mut Expr) {
+        walk_expr_mut(self, e);
+    }
+
+    fn visit_num_expr_mut(&mut self, e: &mut NumExpr) {
+        walk_num_expr_mut(self, e);
+    }
+
+    fn visit_str_expr_mut(&mut self, e: &mut StrExpr) {
+        walk_str_expr_mut(self, e);
+    }
+}
+
+pub fn walk_program_mut<V: MutVisitor + ?Sized>(v: &mut V, prog: &mut Program) {
+    let functions = prog
+        .functions
+        .iter_mut()
+        .chain(&mut prog.main_program.functions)
+        .chain(&mut prog.main_program.main);
+    for f in functions {
+        v.visit_function_mut(f);
+    }
+}
+
+pub fn walk_function_mut<V: MutVisitor + ?Sized>(v: &mut V, f: &mut Function) {
+    walk_body_mut(v, &mut f.body);
+}
+
+pub fn walk_body_mut<V: MutVisitor + ?Sized>(v: &mut V, body: &mut [Stadment]) {
+    for st in body {
+        v.visit_stadment_mut(st);
+    }
+}
+
+pub fn walk_stadment_mut<V: MutVisitor + ?Sized>(v: &mut V, st: &mut Stadment) {
+    match st {
+        Stadment::Print { items, .. }
+        | Stadment::Println { items, .. }
+        | Stadment::EPrint { items, .. }
+        | Stadment::EPrintln { items, .. }
+        | Stadment::Append { items, .. } => {
+            items.iter_mut().for_each(|s| v.visit_str_expr_mut(s));
+        }
+        Stadment::Assignment { expr, .. } | Stadment::Return { expr, .. } => v.visit_expr_mut(expr),
+        Stadment::ForLoop {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            v.visit_expr_mut(start);
+            v.visit_expr_mut(end);
+            if let Some(step) = step {
+                v.visit_expr_mut(step);
+            }
+            walk_body_mut(v, body);
+        }
+        Stadment::MapSet { key, value, .. } => {
+            v.visit_str_expr_mut(key);
+            v.visit_num_expr_mut(value);
+        }
+        Stadment::MapDelete { key, .. } => v.visit_str_expr_mut(key),
+        Stadment::Match { value, arms, default, .. } => {
+            v.visit_num_expr_mut(value);
+            for arm in arms {
+                walk_body_mut(v, &mut arm.body);
+            }
+            if let Some(default) = default {
+                walk_body_mut(v, default);
+            }
+        }
+        Stadment::DumpHeap { region, .. } => {
+            if let Some((start, len)) = region {
+                v.visit_num_expr_mut(start);
+                v.visit_num_expr_mut(len);
+            }
+        }
+        Stadment::Call { args, .. } => args.iter_mut().for_each(|arg| v.visit_num_expr_mut(arg)),
+        Stadment::CallIndirect { .. } | Stadment::Clear { .. } | Stadment::Flush => {}
+    }
+}
+
+pub fn walk_expr_mut<V: MutVisitor + ?Sized>(v: &mut V, e: &mut Expr) {
+    match e {
+        Expr::Num(n) => v.visit_num_expr_mut(n),
+        Expr::Str(s) => v.visit_str_expr_mut(s),
+        Expr::Fn(_) => {}
+    }
+}
+
+pub fn walk_num_expr_mut<V: MutVisitor + ?Sized>(v: &mut V, e: &mut NumExpr) {
+    match e {
+        NumExpr::Binary { left, right, .. } => {
+            v.visit_num_expr_mut(left);
+            v.visit_num_expr_mut(right);
+        }
+        NumExpr::Neg(inner) | NumExpr::Ord(inner) | NumExpr::Chr(inner) => v.visit_num_expr_mut(inner),
+        NumExpr::RandomInt { lo, hi } => {
+            v.visit_num_expr_mut(lo);
+            v.visit_num_expr_mut(hi);
+        }
+        NumExpr::Math { args, .. } => args.iter_mut().for_each(|a| v.visit_num_expr_mut(a)),
+        NumExpr::Len(s) | NumExpr::ToInt(s) | NumExpr::ToFloat(s) => v.visit_str_expr_mut(s),
+        NumExpr::MapGet { key, .. } | NumExpr::MapHas { key, .. } => v.visit_str_expr_mut(key),
+        NumExpr::StrEq { left, right, .. } => {
+            v.visit_str_expr_mut(left);
+            v.visit_str_expr_mut(right);
+        }
+        NumExpr::CharAt { s, index } => {
+            v.visit_str_expr_mut(s);
+            v.visit_num_expr_mut(index);
+        }
+        NumExpr::Int(_)
+        | NumExpr::Float(_)
+        | NumExpr::Char(_)
+        | NumExpr::Var { .. }
+        | NumExpr::ArgCount
+        | NumExpr::Random => {}
+    }
+}
+
+pub fn walk_str_expr_mut<V: MutVisitor + ?Sized>(v: &mut V, e: &mut StrExpr) {
+    match e {
+        StrExpr::NumToStr(n) | StrExpr::Arg(n) => v.visit_num_expr_mut(n),
+        StrExpr::Substr { s, start, len } => {
+            v.visit_str_expr_mut(s);
+            v.visit_num_expr_mut(start);
+            v.visit_num_expr_mut(len);
+        }
+        StrExpr::CharAt { s, index } => {
+            v.visit_str_expr_mut(s);
+            v.visit_num_expr_mut(index);
+        }
+        StrExpr::ToFixed { n, decimals } => {
+            v.visit_num_expr_mut(n);
+            v.visit_num_expr_mut(decimals);
+        }
+        StrExpr::Thousands { n, decimals } => {
+            v.visit_num_expr_mut(n);
+            if let Some(decimals) = decimals {
+                v.visit_num_expr_mut(decimals);
+            }
+        }
+        StrExpr::DecimalComma(s) => v.visit_str_expr_mut(s),
+        StrExpr::Repeat { s, count } => {
+            v.visit_str_expr_mut(s);
+            v.visit_num_expr_mut(count);
+        }
+        StrExpr::Join { sep, parts } => {
+            v.visit_str_expr_mut(sep);
+            parts.iter_mut().for_each(|p| v.visit_str_expr_mut(p));
+        }
+        StrExpr::Str(_) | StrExpr::Nl | StrExpr::Builder { .. } => {}
+    }
+}
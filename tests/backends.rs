@@ -0,0 +1,98 @@
+// My Programming Language
+// End to end: the C and JavaScript sources of a program print what its wasm prints and exit
+// with the same code. A backend whose tool (cc, node) is not installed is skipped.
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use mpl::codegen::FloatFormat;
+use mpl::runner::RunOptions;
+use mpl::{csource, jssource};
+
+const PROGRAM: &str = r#"fn banner() {
+    println("squares")
+}
+main() {
+    local int i
+    local int total
+    local float x
+    local map m
+    banner()
+    for i = 1 to 10
+        let m[to_str(i)] = i * i
+        let total = total + m[to_str(i)]
+    next
+    let x = 1.0 / 3.0
+    match total - total / 3 * 3 {
+    case 0:
+        println("divisible")
+    default:
+        println("total ", to_str(total), " ", to_str(x), " ", to_str(x / 4000000.0))
+    }
+    delete(m, "4")
+    println(to_str(has(m, "4")), " ", to_str(has(m, "5")), " ", to_str(m["10"]))
+    return total - 300
+}
+"#;
+
+// The directory of the files of a backend's test
+fn scratch(backend: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mpl-{}-{}", backend, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// What the wasm of PROGRAM prints and its exit code
+fn expected() -> (String, i32) {
+    let (outcome, stdout) = common::run(PROGRAM, &RunOptions::default());
+    (stdout, outcome.expect("the program runs").exit_code)
+}
+
+// The output of a command, None when it cannot be started
+fn output(command: &mut Command) -> Option<Output> {
+    match command.output() {
+        Ok(output) => Some(output),
+        Err(e) => {
+            eprintln!("skipped: {:?}: {}", command, e);
+            None
+        }
+    }
+}
+
+fn printed(output: &Output) -> (String, i32) {
+    (String::from_utf8_lossy(&output.stdout).into_owned(), output.status.code().expect("an exit code"))
+}
+
+#[test]
+fn c_prints_what_wasm_prints() {
+    let dir = scratch("c");
+    let (c_file, exe) = (dir.join("test.c"), dir.join("test"));
+    let source = csource::program_c(&common::program(PROGRAM), "test.c", FloatFormat::default()).expect("C source");
+    fs::write(&c_file, source).unwrap();
+    let cc = output(Command::new("cc").args(["-O2", "-fwrapv", "-o"]).arg(&exe).arg(&c_file).arg("-lm"));
+    let run = cc.as_ref().filter(|cc| cc.status.success()).map(|_| output(&mut Command::new(&exe)));
+    fs::remove_dir_all(&dir).unwrap();
+    let Some(cc) = cc else {
+        return;
+    };
+    assert!(cc.status.success(), "{}", String::from_utf8_lossy(&cc.stderr));
+    assert_eq!(run.flatten().map(|run| printed(&run)), Some(expected()));
+}
+
+#[test]
+fn javascript_prints_what_wasm_prints() {
+    let dir = scratch("js");
+    let js_file = dir.join("test.js");
+    let source = jssource::program_js(&common::program(PROGRAM), "test.js", FloatFormat::default()).expect("JS source");
+    fs::write(&js_file, source).unwrap();
+    let run = output(Command::new("node").arg(&js_file));
+    fs::remove_dir_all(&dir).unwrap();
+    let Some(run) = run else {
+        return;
+    };
+    assert_eq!(printed(&run), expected());
+}
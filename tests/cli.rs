@@ -0,0 +1,156 @@
+// My Programming Language
+// End to end: the mpl command, its subcommands run on files of a scratch directory, checking
+// their output and exit status
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const COUNT: &str = "main() {\n    local int i\n    for i = 1 to 3\n        println(to_str(i))\n    next\n    return 7\n}\n";
+
+// A directory of source files, removed with it
+struct Scratch {
+    dir: PathBuf,
+}
+
+impl Scratch {
+    fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let dir = env::temp_dir().join(format!("mpl-cli-{}-{}", name, std::process::id()));
+        for (file, text) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        Scratch { dir }
+    }
+
+    // `mpl args` run in the directory, given `stdin`
+    fn mpl(&self, args: &[&str], stdin: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_mpl"))
+            .args(args)
+            .current_dir(&self.dir)
+            .env_remove("MPLPATH")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("mpl starts");
+        child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn run_exits_with_what_main_returns() {
+    let scratch = Scratch::new("run", &[("count.mpl", COUNT)]);
+    for command in ["run", "-r"] {
+        let output = scratch.mpl(&[command, "count.mpl"], "");
+        assert_eq!(stdout(&output), "1\n2\n3\n", "{}", command);
+        assert_eq!(output.status.code(), Some(7), "{}", command);
+    }
+}
+
+#[test]
+fn a_compiled_wasm_runs_and_tells_how_it_was_built() {
+    let scratch = Scratch::new("compile", &[("count.mpl", COUNT)]);
+    let output = scratch.mpl(&["compile", "count.mpl", "-o", "out.wasm"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = scratch.mpl(&["run-wasm", "out.wasm"], "");
+    assert_eq!((stdout(&output), output.status.code()), ("1\n2\n3\n".to_string(), Some(7)));
+    let info = stdout(&scratch.mpl(&["info", "out.wasm"], ""));
+    assert!(info.starts_with("compiler: mpl "), "{}", info);
+    assert!(info.contains("\nsource: count.mpl fnv1a64="), "{}", info);
+}
+
+#[test]
+fn check_reports_an_error_and_writes_nothing() {
+    let scratch = Scratch::new("check", &[("count.mpl", COUNT), ("bad.mpl", "main() {\n    greet()\n}\n")]);
+    let output = scratch.mpl(&["check", "count.mpl"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = scratch.mpl(&["check", "bad.mpl"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("[E0302]"), "{}", stderr(&output));
+    let mut files: Vec<_> = fs::read_dir(&scratch.dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+    files.sort();
+    assert_eq!(files, ["bad.mpl", "count.mpl"]);
+}
+
+#[test]
+fn fmt_checks_files_and_formats_stdin() {
+    let messy = "main() {\nlocal int i\n  let i=1\n    return i\n}\n";
+    let formatted = "main() {\n  local int i\n  let i = 1\n  return i\n}\n";
+    let scratch = Scratch::new("fmt", &[("messy.mpl", messy), ("formatted.mpl", formatted)]);
+    let output = scratch.mpl(&["fmt", "--check", "messy.mpl", "formatted.mpl"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "messy.mpl:2: not formatted\n");
+    let output = scratch.mpl(&["fmt", "-"], messy);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), formatted);
+    assert_eq!(fs::read_to_string(scratch.dir.join("messy.mpl")).unwrap(), messy);
+}
+
+#[test]
+fn lint_fails_only_on_denied_warnings() {
+    let unused = "main() {\n    local int i\n    println(\"hi\")\n}\n";
+    let scratch = Scratch::new("lint", &[("unused.mpl", unused)]);
+    let output = scratch.mpl(&["lint", "unused.mpl"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[W0401]"), "{}", stderr(&output));
+    let output = scratch.mpl(&["lint", "--deny", "unused-variable", "unused.mpl"], "");
+    assert_eq!(output.status.code(), Some(1));
+    let output = scratch.mpl(&["lint", "--allow", "unused-variable", "unused.mpl"], "");
+    assert!(!stderr(&output).contains("[W0401]"), "{}", stderr(&output));
+}
+
+#[test]
+fn build_follows_the_manifest() {
+    let scratch = Scratch::new(
+        "build",
+        &[
+            ("mpl.toml", "[package]\nname = \"demo\"\n\n[build]\nimport-paths = [\"lib\"]\n"),
+            ("main.mpl", "import \"greet.mpl\" as g\nmain() {\n    g.greet()\n}\n"),
+            ("lib/greet.mpl", "pub fn greet() {\n    println(\"hi from lib\")\n}\n"),
+        ],
+    );
+    let output = scratch.mpl(&["build"], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = scratch.mpl(&["run-wasm", "build/demo.wasm"], "");
+    assert_eq!(stdout(&output), "hi from lib\n");
+}
+
+#[test]
+fn the_debugger_stops_at_a_breakpoint_and_prints_a_variable() {
+    let program = "main() {\n    local int i\n    let i = 41\n    println(to_str(i + 1))\n}\n";
+    let scratch = Scratch::new("debug", &[("main.mpl", program)]);
+    let output = scratch.mpl(&["run", "--debug", "--break", "4", "main.mpl"], "p i\nc\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "42\n");
+    let session = stderr(&output);
+    assert!(session.contains("stopped at main.mpl:4 in main\n"), "{}", session);
+    assert!(session.contains("(mpl-dbg) i = 41\n"), "{}", session);
+}
+
+#[test]
+fn the_messages_follow_the_language() {
+    let scratch = Scratch::new("lang", &[("bad.mpl", "main() {\n    greet()\n}\n")]);
+    let english = stderr(&scratch.mpl(&["check", "bad.mpl"], ""));
+    let french = stderr(&scratch.mpl(&["--lang", "fr", "check", "bad.mpl"], ""));
+    assert!(english.contains("unknown function 'greet'"), "{}", english);
+    assert!(french.contains("fonction inconnue 'greet'"), "{}", french);
+}
@@ -0,0 +1,31 @@
+// My Programming Language
+// What the end-to-end tests share: a program given as text, loaded as the main file test.mpl,
+// compiled and run with its output captured. Each test file uses a part of it.
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use anyhow::Result;
+use mpl::codegen::CodeGenerator;
+use mpl::modules;
+use mpl::parser::Program;
+use mpl::runner::{self, RunOptions, RunOutcome};
+
+/// `text` loaded with what it imports
+pub fn program(text: &str) -> Program {
+    modules::load_program(Path::new("test.mpl"), text, &[], &[])
+        .expect("the program loads")
+        .program
+}
+
+/// `text` compiled by `generator`
+pub fn compile_with(mut generator: CodeGenerator, text: &str) -> Vec<u8> {
+    generator
+        .generate_wasm("test".to_string(), &program(text))
+        .expect("the program compiles")
+}
+
+/// A run of `text` and what it printed
+pub fn run(text: &str, options: &RunOptions) -> (Result<RunOutcome>, String) {
+    runner::run_wasm_bytes_with_output(&compile_with(CodeGenerator::new(), text), options)
+}
@@ -0,0 +1,41 @@
+// My Programming Language
+// End to end: for loops leave once past their end, nested, counting down and with a float counter
+
+mod common;
+
+use mpl::runner::RunOptions;
+
+const PROGRAM: &str = r#"main() {
+    local int i
+    local int j
+    local float f
+    for i = 1 to 3
+        print(to_str(i), " ")
+    next
+    println("")
+    for i = 3 to 1 step -1
+        for j = 1 to 2
+            print(to_str(i * 10 + j), " ")
+        next
+    next
+    println("")
+    for f = 0.5 to 1.5 step 0.5
+        print(to_str(f), " ")
+    next
+    println("")
+    return i
+}
+"#;
+
+#[test]
+fn runs_each_loop_to_its_end() {
+    // a loop that does not end runs out of fuel instead of hanging the test
+    let options = RunOptions {
+        fuel: Some(1_000_000),
+        ..RunOptions::default()
+    };
+    let (outcome, stdout) = common::run(PROGRAM, &options);
+    // i stops one step past the end of the loop counting down to 1
+    assert_eq!(outcome.expect("the program runs").exit_code, 0);
+    assert_eq!(stdout, "1 2 3 \n31 32 21 22 11 12 \n0.5 1 1.5 \n");
+}
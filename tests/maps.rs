@@ -0,0 +1,84 @@
+// My Programming Language
+// End to end: the `local map` of a program, set, read, updated and deleted from while it grows
+
+mod common;
+
+use mpl::runner::RunOptions;
+
+// The output of `text`, run to its end
+fn printed(text: &str) -> String {
+    let (outcome, stdout) = common::run(text, &RunOptions::default());
+    outcome.expect("the program runs");
+    stdout
+}
+
+#[test]
+fn a_missing_key_reads_zero() {
+    let text = "main() {\n    local map m\n    let m[\"a\"] = 1\n    println(to_str(m[\"b\"]), \" \", to_str(has(m, \"b\")), \" \", to_str(has(m, \"a\")))\n}\n";
+    assert_eq!(printed(text), "0 0 1\n");
+}
+
+#[test]
+fn setting_a_key_again_updates_it() {
+    let text = "main() {\n    local map m\n    let m[\"k\"] = 1\n    let m[\"k\"] = m[\"k\"] + 41\n    println(to_str(m[\"k\"]))\n}\n";
+    assert_eq!(printed(text), "42\n");
+}
+
+#[test]
+fn keeps_its_entries_while_it_grows() {
+    // far more keys than the first table holds, read back after every growth
+    let text = r#"main() {
+    local int i
+    local int total
+    local map m
+    for i = 1 to 1000
+        let m[to_str(i)] = i * 2
+    next
+    for i = 1 to 1000
+        let total = total + m[to_str(i)]
+    next
+    println(to_str(total), " ", to_str(m["1000"]), " ", to_str(has(m, "1001")))
+}
+"#;
+    assert_eq!(printed(text), "1001000 2000 0\n");
+}
+
+#[test]
+fn a_deleted_key_is_gone_and_can_be_set_again() {
+    let text = r#"main() {
+    local int i
+    local int left
+    local map m
+    for i = 1 to 100
+        let m[to_str(i)] = i
+    next
+    for i = 1 to 100 step 2
+        delete(m, to_str(i))
+    next
+    for i = 1 to 100
+        let left = left + has(m, to_str(i))
+    next
+    delete(m, "missing")
+    let m["1"] = 7
+    println(to_str(left), " ", to_str(m["3"]), " ", to_str(m["4"]), " ", to_str(m["1"]))
+}
+"#;
+    assert_eq!(printed(text), "50 0 4 7\n");
+}
+
+#[test]
+fn a_builder_is_a_key() {
+    let text = r#"main() {
+    local int i
+    local builder b
+    local map m
+    for i = 1 to 3
+        clear(b)
+        append(b, "k", to_str(i))
+        let m[b] = i * 10
+    next
+    println(to_str(m["k2"]), " ", to_str(has(m, "k4")))
+}
+"#;
+    assert_eq!(printed(text), "20 0\n");
+}
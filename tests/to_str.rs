@@ -0,0 +1,23 @@
+// My Programming Language
+// End to end: a program printing an int and a float, compiled and run with its output captured
+
+mod common;
+
+use mpl::runner::RunOptions;
+
+const PROGRAM: &str = r#"main() {
+    local int i
+    local float f
+    let i = -42
+    let f = 2.5 / 4.0
+    println(to_str(i), " ", to_str(f), " ", to_str(1.0 / 3.0))
+    return 3
+}
+"#;
+
+#[test]
+fn prints_an_int_and_a_float() {
+    let (outcome, stdout) = common::run(PROGRAM, &RunOptions::default());
+    assert_eq!(outcome.expect("the program runs").exit_code, 3);
+    assert_eq!(stdout, "-42 0.625 0.333333333333333\n");
+}